@@ -0,0 +1,18 @@
+use std::env;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Records the commit and time of the build, for the `/version` endpoint. The commit can be given in the
+/// `GIT_SHA` environment variable where the build has no git checkout.
+fn main() {
+    let git_sha = env::var("GIT_SHA").ok()
+        .or_else(|| Command::new("git").args(["rev-parse", "--short", "HEAD"]).output().ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|sha| sha.trim().to_string()))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_SHA={}", git_sha);
+
+    let built_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    println!("cargo:rustc-env=BUILT_AT={}", built_at);
+}
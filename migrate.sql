@@ -1,2 +1,31 @@
 alter table session_type add column cost smallint default 0 check (cost >= 0);
-alter table session add column cost smallint default 0 check (cost >= 0);
\ No newline at end of file
+alter table session add column cost smallint default 0 check (cost >= 0);
+alter table session add column tags text[] default '{}' not null;
+alter table person add column created timestamptz default now() not null;
+alter table temp_password add column check_attempts int4 default 0 not null;
+alter table person add column notification_prefs jsonb default '{}' not null;
+alter table session add column private bool default false not null;
+alter table person add column must_change_pwd bool default false not null;
+alter table person add column auto_use_credits bool default false not null;
+alter table booking add column reference text unique;
+alter table booking add column created_at timestamptz default now() not null;
+alter table session add column booking_opens_at timestamptz;
+alter table session add column booking_closes_at timestamptz;
+alter table session_type add column prerequisite_session_type_id int4 references session_type;
+alter table session add column checkin_code text;
+alter table session add column max_waitlist_count int8;
+alter table booking add column attended_at timestamptz;
+alter table session_type add column color text;
+alter table person add column two_factor_enabled bool default false not null;
+alter table location add column capacity int4;
+alter table session_type add column requires_approval bool default false not null;
+alter table session_type add column waiver_url text;
+alter table session_type add column requires_waiver bool default false not null;
+alter table session_type add constraint session_type_waiver_check check (not requires_waiver or waiver_url is not null);
+alter table booking add column checkin_attempts int4 default 0 not null;
+insert into trainer_qualification (trainer_id, session_type_id) select distinct trainer, session_type from session where trainer is not null on conflict do nothing;
+-- emails are looked up ignoring case, so must also be unique ignoring case. People whose emails differ only in case
+-- must be merged by hand first (moving their bookings to one of them and deleting the other), so stop if there are any:
+-- select lower(email), array_agg(id order by id) from person group by lower(email) having count(*) > 1;
+do $$ begin if exists (select 1 from person group by lower(email) having count(*) > 1) then raise exception 'person emails differ only in case, merge them first'; end if; end $$;
+create unique index if not exists person_email_lower_key on person (lower(email));
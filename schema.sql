@@ -8,20 +8,39 @@ CREATE TABLE IF NOT EXISTS person (
     phone text,
     pwd text,
     roles text,
-    credits int2 DEFAULT 0 NOT NULL CHECK (credits >= 0)
+    credits int2 DEFAULT 0 NOT NULL CHECK (credits >= 0),
+    created timestamptz DEFAULT now() NOT NULL,
+    notification_prefs jsonb DEFAULT '{}' NOT NULL,
+    must_change_pwd bool DEFAULT false NOT NULL,
+    auto_use_credits bool DEFAULT false NOT NULL,
+    two_factor_enabled bool DEFAULT false NOT NULL
 );
 CREATE TABLE IF NOT EXISTS temp_password (
     person_id bigint UNIQUE NOT NULL REFERENCES person ON DELETE CASCADE,
     pwd text NOT NULL,
     sent timestamp with time zone NOT NULL,
-    expiry timestamp with time zone NOT NULL
+    expiry timestamp with time zone NOT NULL,
+    check_attempts int4 DEFAULT 0 NOT NULL
+);
+
+-- single-use tokens sent to users by email to confirm an action, e.g. deleting their profile
+CREATE TABLE IF NOT EXISTS person_token (
+    person_id bigint NOT NULL REFERENCES person ON DELETE CASCADE,
+    purpose text NOT NULL,
+    token text NOT NULL,
+    payload text NULL,
+    sent timestamp with time zone NOT NULL,
+    expiry timestamp with time zone NOT NULL,
+    check_attempts int4 DEFAULT 0 NOT NULL,
+    PRIMARY KEY (person_id, purpose)
 );
 
 -- location table and data
 CREATE TABLE IF NOT EXISTS location (
     id serial PRIMARY KEY,
     name varchar(255) UNIQUE NOT NULL,
-    address varchar(1023)
+    address varchar(1023),
+    capacity int4 NULL
 );
 INSERT INTO location
     (name, address)
@@ -36,7 +55,13 @@ CREATE TABLE IF NOT EXISTS session_type (
 	name varchar(255) NOT NULL,
 	requires_trainer bool DEFAULT true NULL,
 	cost int2 DEFAULT 0 NULL,
+	prerequisite_session_type_id int4 NULL REFERENCES session_type,
+	color text NULL,
+	requires_approval bool DEFAULT false NOT NULL,
+	waiver_url text NULL,
+	requires_waiver bool DEFAULT false NOT NULL,
 	CONSTRAINT session_type_cost_check CHECK (cost >= 0),
+	CONSTRAINT session_type_waiver_check CHECK (NOT requires_waiver OR waiver_url IS NOT NULL),
 	CONSTRAINT session_type_name_key UNIQUE (name),
 	CONSTRAINT session_type_pkey PRIMARY KEY (id)
 );
@@ -57,13 +82,94 @@ CREATE TABLE IF NOT EXISTS session(
 	trainer int8 NULL REFERENCES person,
 	max_booking_count int8 NULL,
 	notes text NULL,
-	cost int2 DEFAULT 0 NOT NULL CHECK ((cost >= 0))
+	cost int2 DEFAULT 0 NOT NULL CHECK ((cost >= 0)),
+	tags text[] DEFAULT '{}' NOT NULL,
+	private bool DEFAULT false NOT NULL,
+	booking_opens_at timestamptz NULL,
+	booking_closes_at timestamptz NULL,
+	checkin_code text NULL,
+	max_waitlist_count int8 NULL
 );
 
 CREATE TABLE IF NOT EXISTS booking (
     person_id bigint NOT NULL REFERENCES person ON DELETE CASCADE,
     session_id bigint NOT NULL REFERENCES session ON DELETE CASCADE,
     attended bool DEFAULT false NOT NULL,
+    attended_at timestamptz NULL,
 	credits_used int2 DEFAULT 0 NULL CHECK ((credits_used >= 0)),
+    reference text UNIQUE,
+    created_at timestamptz DEFAULT now() NOT NULL,
+    checkin_attempts int4 DEFAULT 0 NOT NULL,
+    PRIMARY KEY (person_id, session_id)
+);
+
+-- session types that each trainer is qualified to lead
+CREATE TABLE IF NOT EXISTS trainer_qualification (
+    trainer_id bigint NOT NULL REFERENCES person ON DELETE CASCADE,
+    session_type_id int4 NOT NULL REFERENCES session_type ON DELETE CASCADE,
+    PRIMARY KEY (trainer_id, session_type_id)
+);
+
+-- reminders sent for each booking, one for each lead time before the session
+CREATE TABLE IF NOT EXISTS booking_reminder (
+    person_id bigint NOT NULL,
+    session_id bigint NOT NULL,
+    lead_time_mins int4 NOT NULL,
+    sent timestamptz DEFAULT now() NOT NULL,
+    PRIMARY KEY (person_id, session_id, lead_time_mins),
+    FOREIGN KEY (person_id, session_id) REFERENCES booking ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- members waiting for a space on a full session
+CREATE TABLE IF NOT EXISTS waitlist (
+    person_id bigint NOT NULL REFERENCES person ON DELETE CASCADE,
+    session_id bigint NOT NULL REFERENCES session ON DELETE CASCADE,
+    joined timestamptz DEFAULT now() NOT NULL,
+    PRIMARY KEY (person_id, session_id)
+);
+
+-- bookings waiting for an admin to approve them, on sessions whose type requires approval; the credits are held, not yet debited
+CREATE TABLE IF NOT EXISTS booking_request (
+    person_id bigint NOT NULL REFERENCES person ON DELETE CASCADE,
+    session_id bigint NOT NULL REFERENCES session ON DELETE CASCADE,
+    credits_held int2 DEFAULT 0 NOT NULL CHECK ((credits_held >= 0)),
+    requested timestamptz DEFAULT now() NOT NULL,
     PRIMARY KEY (person_id, session_id)
 );
+
+-- waivers accepted by members, by URL so that a replaced waiver must be accepted again
+CREATE TABLE IF NOT EXISTS person_waiver (
+    person_id bigint NOT NULL REFERENCES person ON DELETE CASCADE,
+    session_type_id int4 NOT NULL REFERENCES session_type ON DELETE CASCADE,
+    waiver_url text NOT NULL,
+    accepted timestamptz DEFAULT now() NOT NULL,
+    PRIMARY KEY (person_id, session_type_id, waiver_url)
+);
+
+-- messages sent by admins and trainers to the members booked on a session
+CREATE TABLE IF NOT EXISTS session_message (
+    id bigserial PRIMARY KEY,
+    session_id bigint NOT NULL REFERENCES session ON DELETE CASCADE,
+    sender_id bigint NULL REFERENCES person ON DELETE SET NULL,
+    subject text NOT NULL,
+    sent timestamptz DEFAULT now() NOT NULL
+);
+
+-- cancelled bookings, with the reason given if any. Bookings on cancelled sessions are kept without the session.
+CREATE TABLE IF NOT EXISTS cancellation (
+    id bigserial PRIMARY KEY,
+    person_id bigint NOT NULL REFERENCES person ON DELETE CASCADE,
+    session_id bigint NULL REFERENCES session ON DELETE SET NULL,
+    reason text NULL,
+    credits_forfeited int2 DEFAULT 0 NOT NULL,
+    cancelled timestamptz DEFAULT now() NOT NULL
+);
+
+-- record of changes made by admins and other privileged users
+CREATE TABLE IF NOT EXISTS audit_log (
+    id bigserial PRIMARY KEY,
+    actor_id bigint NULL REFERENCES person ON DELETE SET NULL,
+    action text NOT NULL,
+    target text NULL,
+    logged timestamptz DEFAULT now() NOT NULL
+);
@@ -0,0 +1,92 @@
+use chrono::{DateTime, Utc};
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Serialize;
+use sqlx::{FromRow, PgPool, Postgres, query, query_as, QueryBuilder};
+
+use crate::{AppState, db_error, Page, parse_opt_date};
+use crate::claims::Claims;
+
+#[derive(FromRow, Serialize, Debug)]
+pub struct AuditEntry {
+    id: i64,
+    actor_id: Option<i64>,
+    actor_name: Option<String>,
+    action: String,
+    target: Option<String>,
+    logged: DateTime<Utc>
+}
+
+/// Records an action in the audit log. Failures are logged rather than returned, so that the action
+/// itself is not reported as failed after it has already been done.
+pub(crate) async fn record(pool: &PgPool, actor_id: i64, action: &str, target: String) {
+    insert_entry(pool, Some(actor_id), action, target).await;
+}
+
+/// Records an action done by the server itself, such as a scheduled task, rather than by a user
+pub(crate) async fn record_system(pool: &PgPool, action: &str, target: String) {
+    insert_entry(pool, None, action, target).await;
+}
+
+async fn insert_entry(pool: &PgPool, actor_id: Option<i64>, action: &str, target: String) {
+    let _ = query("INSERT INTO audit_log (actor_id, action, target) VALUES ($1, $2, $3)")
+        .bind(actor_id)
+        .bind(action)
+        .bind(&target)
+        .execute(pool)
+        .await
+        .inspect_err(|e| error!("Failed to record audit log entry {} on {} by user id {:?}: {}", action, target, actor_id, e));
+}
+
+/// The entries about a person, e.g. changes to their profile or bookings, as opposed to those that they made
+pub(crate) async fn entries_about(pool: &PgPool, person_id: i64) -> Result<Vec<AuditEntry>, sqlx::Error> {
+    // Targets name the people they affect as e.g. "person 12" or "trainer 12"
+    query_as("SELECT a.id, a.actor_id, p.name AS actor_name, a.action, a.target, a.logged \
+        FROM audit_log AS a \
+        LEFT JOIN person AS p ON a.actor_id = p.id \
+        WHERE a.target ~ ('\\m(person|trainer) ' || $1 || '\\M') \
+        ORDER BY a.logged, a.id")
+        .bind(person_id.to_string())
+        .fetch_all(pool)
+        .await
+}
+
+#[get("/admin/audit?<from>&<to>&<actor_id>&<page..>")]
+pub async fn list_audit_log(
+    state: &State<AppState>,
+    claim: Claims,
+    from: Option<String>,
+    to: Option<String>,
+    actor_id: Option<i64>,
+    page: Page
+) -> Result<Json<Vec<AuditEntry>>, Custom<String>> {
+    claim.assert_roles_contains("admin")?;
+
+    let mut qb: QueryBuilder<Postgres> = QueryBuilder::new("SELECT a.id, a.actor_id, p.name AS actor_name, a.action, a.target, a.logged \
+        FROM audit_log AS a \
+        LEFT JOIN person AS p ON a.actor_id = p.id");
+    let mut where_op = String::from(" WHERE");
+    if let Some(from) = parse_opt_date(from)? {
+        qb.push(where_op + " a.logged >= ");
+        qb.push_bind(from);
+        where_op = String::from(" AND");
+    }
+    if let Some(to) = parse_opt_date(to)? {
+        qb.push(where_op + " a.logged <= ");
+        qb.push_bind(to);
+        where_op = String::from(" AND");
+    }
+    if let Some(actor_id) = actor_id {
+        qb.push(where_op + " a.actor_id = ");
+        qb.push_bind(actor_id);
+    }
+    qb.push(" ORDER BY a.logged DESC, a.id DESC");
+    page.push_limit_offset(&mut qb)?;
+
+    let entries = qb.build_query_as()
+        .fetch_all(&state.pool)
+        .await
+        .map_err(db_error)?;
+    Ok(Json(entries))
+}
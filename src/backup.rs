@@ -1,5 +1,5 @@
 use chrono::{DateTime, Utc};
-use rocket::http::Status;
+use rocket::http::{Header, Status};
 use rocket::response::status::Custom;
 use rocket::serde::json::Json;
 use rocket::State;
@@ -49,7 +49,19 @@ pub struct BookingRow {
     person_email: String,
     session_datetime: DateTime<Utc>,
     session_location_name: Option<String>,
-    session_trainer_email: Option<String>
+    session_trainer_email: Option<String>,
+    attended: bool,
+    credits_used: Option<i16>
+}
+
+#[derive(Serialize)]
+pub struct BackupSummary {
+    session_type_count: usize,
+    location_count: usize,
+    person_count: usize,
+    session_count: usize,
+    booking_count: usize,
+    attended_booking_count: usize
 }
 
 #[derive(Serialize)]
@@ -58,19 +70,46 @@ pub struct AllTables {
     location: Vec<LocationRow>,
     person: Vec<PersonRow>,
     session: Vec<SessionRow>,
-    booking: Vec<BookingRow>
+    booking: Vec<BookingRow>,
+    summary: BackupSummary
+}
+
+/// The backup as a JSON file download, rather than a document to be shown inline
+#[derive(Responder)]
+#[response(status = 200, content_type = "application/json")]
+pub struct BackupResponse {
+    inner: Json<AllTables>,
+    disposition: Header<'static>
 }
 
 #[get("/backup")]
-pub async fn backup_all(state: &State<AppState>, claim: Claims) -> Result<Json<AllTables>, Custom<String>> {
+pub async fn backup_all(state: &State<AppState>, claim: Claims) -> Result<BackupResponse, Custom<String>> {
     claim.assert_roles_contains("admin")?;
-    Ok(Json(AllTables{
-        session_type: session_type_table(state).await?,
-        location: location_table(state).await?,
-        person: person_table(state).await?,
-        session: session_table(state).await?,
-        booking: booking_table(state).await?
-    }))
+    let session_type = session_type_table(state).await?;
+    let location = location_table(state).await?;
+    let person = person_table(state).await?;
+    let session = session_table(state).await?;
+    let booking = booking_table(state).await?;
+    let summary = BackupSummary {
+        session_type_count: session_type.len(),
+        location_count: location.len(),
+        person_count: person.len(),
+        session_count: session.len(),
+        booking_count: booking.len(),
+        attended_booking_count: booking.iter().filter(|b| b.attended).count()
+    };
+    let filename = format!("backup-{}.json", Utc::now().format("%Y%m%dT%H%M%SZ"));
+    Ok(BackupResponse {
+        inner: Json(AllTables{
+            session_type,
+            location,
+            person,
+            session,
+            booking,
+            summary
+        }),
+        disposition: Header::new("Content-Disposition", format!("attachment; filename=\"{}\"", filename))
+    })
 }
 
 async fn person_table(state: &State<AppState>) -> Result<Vec<PersonRow>, Custom<String>> {
@@ -106,7 +145,7 @@ async fn session_table(state: &State<AppState>) -> Result<Vec<SessionRow>, Custo
 }
 
 async fn booking_table(state: &State<AppState>) -> Result<Vec<BookingRow>, Custom<String>> {
-    query_as("SELECT p.email AS person_email, s.datetime AS session_datetime, l.name AS session_location_name, t.email AS session_trainer_email \
+    query_as("SELECT p.email AS person_email, s.datetime AS session_datetime, l.name AS session_location_name, t.email AS session_trainer_email, b.attended, b.credits_used \
             FROM booking as b \
             LEFT JOIN person AS p ON b.person_id = p.id \
             LEFT JOIN session AS s ON b.session_id = s.id \
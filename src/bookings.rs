@@ -1,22 +1,101 @@
-use chrono::{Datelike, DateTime, Days, NaiveTime, TimeZone, Utc};
+use std::ops::Add;
+
+use chrono::{DateTime, FixedOffset, TimeDelta, TimeZone, Utc, Weekday};
 use chrono_tz::Tz;
+use mail_send::mail_builder::headers::address::Address;
+use mail_send::mail_builder::MessageBuilder;
+use mail_send::smtp::message::IntoMessage;
+use passwords::PasswordGenerator;
 use rocket::futures::StreamExt;
-use rocket::futures::stream::BoxStream;
-use rocket::http::Status;
+use rocket::http::{ContentType, Status};
 use rocket::response::status::{Created, Custom, NoContent};
+use rocket::response::stream::TextStream;
 use rocket::serde::json::Json;
 use rocket::serde::Serialize;
 use rocket::State;
 use serde::Deserialize;
-use sqlx::{Error, Executor, FromRow, PgPool, query_as, QueryBuilder, raw_sql, Row};
-use sqlx::postgres::{PgQueryResult, PgRow};
+use sqlx::{Error, Executor, FromRow, PgConnection, PgPool, Postgres, query, query_as, query_scalar, QueryBuilder, Row};
+use sqlx::postgres::PgRow;
 
-use crate::{AppState, parse_opt_date, SessionLocation, SessionType, UserLoginRecord};
-use crate::claims::Claims;
+use crate::{AppState, begin_with_timeout, Config, CountResult, csv_field, CsvDownload, db_error, EXPLAIN, explain_query, JsonBody, Page, parse_opt_date, query_error, QueryExplanation, SessionLocation, SessionType, UserLoginRecord, week_bounds};
+use crate::audit;
+use crate::claims::{Claims, OverrideRequested};
+use crate::email::{EmailTemplate, NotificationEvent, NotificationPrefs, render_body, render_subject, should_notify};
+use crate::login::{parse_roles, send_email};
 
 const ROLE_ADMIN: &str = "admin";
+const ROLE_SUPER_ADMIN: &str = "super-admin";
 const ROLE_FULL_MEMBER: &str = "member";
 const ROLE_LIMITED_MEMBER: &str = "limited-member";
+const ROLE_TRAINER: &str = "trainer";
+
+/// Short booking references that members can read out or type, e.g. when contacting support
+const BOOKING_REFERENCE_GENERATOR: PasswordGenerator = PasswordGenerator {
+    length: 6,
+    numbers: true,
+    lowercase_letters: false,
+    uppercase_letters: true,
+    symbols: false,
+    spaces: false,
+    exclude_similar_characters: true,
+    strict: false
+};
+const BOOKING_REFERENCE_MAX_ATTEMPTS: usize = 10;
+/// Codes that trainers show at a session for members to check themselves in
+const CHECKIN_CODE_GENERATOR: PasswordGenerator = PasswordGenerator {
+    length: 6,
+    numbers: true,
+    lowercase_letters: false,
+    uppercase_letters: false,
+    symbols: false,
+    spaces: false,
+    exclude_similar_characters: false,
+    strict: false
+};
+/// How long before a session starts, and after it ends, that members can check in
+const CHECKIN_WINDOW: TimeDelta = TimeDelta::minutes(30);
+/// How many times a member can try to check in to a session, so that its code cannot be guessed
+const CHECKIN_MAX_ATTEMPTS: i32 = 5;
+/// Longest period of bookings that can be exported at once
+const BOOKING_EXPORT_MAX_DAYS: i64 = 366;
+
+/// How a member pays for bookings, in the order of precedence that booking eligibility checks them
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MembershipStatus {
+    Full,
+    Limited,
+    Payg,
+    None
+}
+
+impl MembershipStatus {
+    /// Members without a membership are PAYG only if their credits pay for the cheapest type of session that costs any
+    pub(crate) fn from_roles_and_credits(roles: &[String], credits: i16, min_credits_cost: i16) -> MembershipStatus {
+        if roles.iter().any(|r| r == ROLE_FULL_MEMBER) {
+            MembershipStatus::Full
+        } else if roles.iter().any(|r| r == ROLE_LIMITED_MEMBER) {
+            MembershipStatus::Limited
+        } else if credits_cover(credits, min_credits_cost) {
+            MembershipStatus::Payg
+        } else {
+            MembershipStatus::None
+        }
+    }
+}
+
+/// The fewest credits that any type of session costs, ignoring those that are free
+pub(crate) async fn min_credits_cost(pool: &PgPool) -> Result<i16, Custom<String>> {
+    query_scalar("SELECT COALESCE(MIN(cost), 1::int2) FROM session_type WHERE cost > 0")
+        .fetch_one(pool)
+        .await
+        .map_err(db_error)
+}
+
+/// Whether a balance of credits pays for a booking at the given cost
+fn credits_cover(credits: i16, cost: i16) -> bool {
+    credits >= cost
+}
 
 #[derive(Serialize, Deserialize, FromRow, Debug, Clone)]
 pub struct SessionBooking {
@@ -25,18 +104,83 @@ pub struct SessionBooking {
     credits_used: Option<i16>
 }
 
+#[derive(Serialize, Debug)]
+pub struct BookingCreated {
+    #[serde(flatten)]
+    booking: SessionBooking,
+    reference: String
+}
+
 #[derive(Serialize, Debug)]
 pub struct SessionBookingFull {
     person_id: i64,
     person_name: String,
-    person_email: String,
+    /// Contact details are only shown to admins and to the person themselves
+    #[serde(skip_serializing_if = "Option::is_none")]
+    person_email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    person_phone: Option<String>,
     session_id: i64,
     session_datetime: DateTime<Utc>,
     session_duration_mins: i32,
     session_location: Option<SessionLocation>,
     session_type: SessionType,
     attended: bool,
-    credits_used: i16
+    credits_used: i16,
+    /// Bookings made before references were introduced have none
+    reference: Option<String>,
+    created_at: Option<DateTime<Utc>>,
+    /// Whether the member can cancel the booking themselves, only included in their own bookings
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cancellable: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cancel_blocked_reason: Option<CancelBlockedReason>
+}
+
+/// Why a member cannot cancel their own booking
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum CancelBlockedReason {
+    SessionInPast,
+    WithinCutoff
+}
+
+/// Whether a session has started by the given time. Session times are stored and compared in UTC; the local
+/// timezone only matters for local days and weeks, such as for the weekly booking limit, never for this.
+/// A session starting exactly at the given time has not yet started.
+fn session_started_by(session_datetime: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+    session_datetime < now
+}
+
+/// Whether a session is in the past, and so can no longer be booked, cancelled or waited for
+pub(crate) fn is_session_in_past(session_datetime: DateTime<Utc>) -> bool {
+    session_started_by(session_datetime, Utc::now())
+}
+
+/// The rule that `_delete_booking` applies to members cancelling their own bookings
+fn cancel_blocked_reason(session_datetime: DateTime<Utc>, cancellation_cutoff_mins: Option<u32>) -> Option<CancelBlockedReason> {
+    let now = Utc::now();
+    if session_started_by(session_datetime, now) {
+        return Some(CancelBlockedReason::SessionInPast);
+    }
+    match cancellation_cutoff_mins {
+        Some(cutoff_mins) if session_datetime < now + TimeDelta::minutes(cutoff_mins as i64) => Some(CancelBlockedReason::WithinCutoff),
+        _ => None
+    }
+}
+
+/// The cancellation cutoff that the caller is held to. Admins can cancel bookings up until the session starts.
+fn cancellation_cutoff_for(claim: &Claims, cancellation_cutoff_mins: Option<u32>) -> Option<u32> {
+    cancellation_cutoff_mins.filter(|_| !claim.has_role(ROLE_ADMIN))
+}
+
+/// Fails with the reason that a booking for a session at the given time can no longer be cancelled, if any
+fn check_cancellable(session_datetime: DateTime<Utc>, cancellation_cutoff_mins: Option<u32>) -> Result<(), Custom<String>> {
+    match cancel_blocked_reason(session_datetime, cancellation_cutoff_mins) {
+        Some(CancelBlockedReason::SessionInPast) => Err(Custom(Status::Forbidden, "Cannot cancel past booking.".to_string())),
+        Some(CancelBlockedReason::WithinCutoff) => Err(Custom(Status::Forbidden, format!("Cannot cancel booking less than {} minutes before the session.", cancellation_cutoff_mins.unwrap_or_default()))),
+        None => Ok(())
+    }
 }
 
 impl FromRow<'_, PgRow> for SessionBookingFull {
@@ -47,6 +191,7 @@ impl FromRow<'_, PgRow> for SessionBookingFull {
                 id,
                 name: row.try_get("session_location_name")?,
                 address: row.try_get("session_location_address")?,
+                capacity: row.try_get("session_location_capacity").ok().flatten()
             }),
             None => None
         };
@@ -55,6 +200,7 @@ impl FromRow<'_, PgRow> for SessionBookingFull {
             person_id: row.try_get("person_id")?,
             person_name: row.try_get("person_name")?,
             person_email: row.try_get("person_email")?,
+            person_phone: row.try_get("person_phone").ok().flatten(),
             session_id: row.try_get("session_id")?,
             session_datetime: row.try_get("session_datetime")?,
             session_duration_mins: row.try_get("session_duration_mins")?,
@@ -63,42 +209,134 @@ impl FromRow<'_, PgRow> for SessionBookingFull {
                 id: row.try_get("session_type_id")?,
                 name: row.try_get("session_type_name")?,
                 requires_trainer: row.try_get("session_type_requires_trainer").ok().unwrap_or(true),
-                cost: row.try_get("session_type_cost")?
+                cost: row.try_get("session_type_cost")?,
+                prerequisite_session_type_id: row.try_get("session_type_prerequisite_id").ok().flatten(),
+                color: row.try_get("session_type_color").ok().flatten(),
+                requires_approval: row.try_get("session_type_requires_approval").ok().unwrap_or(false),
+                waiver_url: row.try_get("session_type_waiver_url").ok().flatten(),
+                requires_waiver: row.try_get("session_type_requires_waiver").ok().unwrap_or(false)
             },
             attended: row.try_get("attended").ok().unwrap_or(false),
-            credits_used: row.try_get("credits_used")?
+            credits_used: row.try_get("credits_used")?,
+            reference: row.try_get("reference").ok().flatten(),
+            created_at: row.try_get("created_at").ok(),
+            cancellable: None,
+            cancel_blocked_reason: None
         })
     }
 }
 
-#[get("/bookings?<session_id>&<person_id>&<from>&<to>")]
+impl SessionBookingFull {
+    /// Hides the contact details of other people from anyone but admins
+    fn visible_to(self, claim: &Claims) -> SessionBookingFull {
+        if claim.has_role(ROLE_ADMIN) || claim.uid == self.person_id {
+            self
+        } else {
+            SessionBookingFull { person_email: None, person_phone: None, ..self }
+        }
+    }
+
+    /// Says whether the caller can cancel the booking, if it is their own. Admins are not held to the cutoff,
+    /// but need an override once the session has started.
+    fn with_cancellation(self, claim: &Claims, cancellation_cutoff_mins: Option<u32>) -> SessionBookingFull {
+        if claim.uid != self.person_id {
+            return self;
+        }
+        let reason = cancel_blocked_reason(self.session_datetime, cancellation_cutoff_for(claim, cancellation_cutoff_mins));
+        SessionBookingFull { cancellable: Some(reason.is_none()), cancel_blocked_reason: reason, ..self }
+    }
+}
+
+const SELECT_BOOKING_FULL: &str = "SELECT b.person_id, p.name AS person_name, p.email AS person_email, p.phone AS person_phone, b.session_id, b.credits_used, b.reference, b.created_at, \
+        s.datetime AS session_datetime, s.duration_mins AS session_duration_mins, s.location AS session_location_id, l.name AS session_location_name, l.address AS session_location_address, l.capacity AS session_location_capacity, \
+        s.session_type AS session_type_id, t.name AS session_type_name, t.requires_trainer AS session_type_requires_trainer, t.cost AS session_type_cost, \
+        t.prerequisite_session_type_id AS session_type_prerequisite_id, t.color AS session_type_color, t.requires_approval AS session_type_requires_approval, \
+        t.waiver_url AS session_type_waiver_url, t.requires_waiver AS session_type_requires_waiver, b.attended \
+    FROM booking as b \
+    JOIN person AS p ON b.person_id = p.id \
+    JOIN session AS s ON b.session_id = s.id \
+    JOIN session_type AS t ON s.session_type = t.id \
+    LEFT JOIN location AS l ON s.location = l.id ";
+
+/// Bookings, or how the query to list them would run
+#[derive(Responder)]
+pub enum BookingListing {
+    Bookings(Json<Vec<SessionBookingFull>>),
+    Explained(Json<QueryExplanation>)
+}
+
+/// Lists bookings or, for admins with `explain=true`, explains the query that would list them
+#[get("/bookings?<session_id>&<person_id>&<from>&<to>&<explain>&<page..>")]
+#[allow(clippy::too_many_arguments)]
 pub async fn list_bookings(
     state: &State<AppState>,
     claim: Claims,
     session_id: Option<i64>,
     person_id: Option<i64>,
     from: Option<String>,
-    to: Option<String>
-) -> Result<Json<Vec<SessionBookingFull>>, Custom<String>> {
-    _list_bookings(&state.pool, &claim, session_id, person_id, from, to).await
+    to: Option<String>,
+    explain: bool,
+    page: Page
+) -> Result<BookingListing, Custom<String>> {
+    let mut tx = begin_with_timeout(&state.pool, &state.config).await?;
+    if explain {
+        let explanation = _explain_bookings(&mut *tx, &claim, session_id, person_id, from, to, page).await?;
+        tx.commit().await.map_err(query_error)?;
+        return Ok(BookingListing::Explained(Json(explanation)));
+    }
+    let bookings = _list_bookings(&mut *tx, &claim, session_id, person_id, from, to, page).await?;
+    tx.commit().await.map_err(query_error)?;
+    Ok(BookingListing::Bookings(Json(bookings.into_inner().into_iter()
+        .map(|b| b.with_cancellation(&claim, state.config.cancellation_cutoff_mins))
+        .collect())))
 }
 
-async fn _list_bookings(
-    pool: &PgPool,
+async fn _explain_bookings<'c, E: Executor<'c, Database = Postgres>>(
+    executor: E,
     claim: &Claims,
     session_id: Option<i64>,
     person_id: Option<i64>,
     from: Option<String>,
-    to: Option<String>
+    to: Option<String>,
+    page: Page
+) -> Result<QueryExplanation, Custom<String>> {
+    // The SQL reveals the schema, so is only for admins
+    claim.assert_roles_contains(ROLE_ADMIN)?;
+    let mut qb = QueryBuilder::new(EXPLAIN);
+    build_bookings_query(&mut qb, claim, session_id, person_id, from, to, page)?;
+    explain_query(executor, qb).await
+}
+
+async fn _list_bookings<'c, E: Executor<'c, Database = Postgres>>(
+    executor: E,
+    claim: &Claims,
+    session_id: Option<i64>,
+    person_id: Option<i64>,
+    from: Option<String>,
+    to: Option<String>,
+    page: Page
 ) -> Result<Json<Vec<SessionBookingFull>>, Custom<String>> {
-    let mut qb = QueryBuilder::new("SELECT b.person_id, p.name AS person_name, p.email AS person_email, b.session_id, b.credits_used, \
-                s.datetime AS session_datetime, s.duration_mins AS session_duration_mins, s.location AS session_location_id, l.name AS session_location_name, l.address AS session_location_address, \
-                s.session_type AS session_type_id, t.name AS session_type_name, t.requires_trainer AS session_type_requires_trainer, t.cost AS session_type_cost, b.attended \
-            FROM booking as b \
-            JOIN person AS p ON b.person_id = p.id \
-            JOIN session AS s ON b.session_id = s.id \
-            JOIN session_type AS t ON s.session_type = t.id \
-            LEFT JOIN location AS l ON s.location = l.id ");
+    let mut qb = QueryBuilder::default();
+    build_bookings_query(&mut qb, claim, session_id, person_id, from, to, page)?;
+    info!("list_bookings compiled SQL: {}", qb.sql());
+    let bookings: Vec<SessionBookingFull> = qb.build_query_as()
+        .fetch_all(executor)
+        .await
+        .map_err(query_error)?;
+    Ok(Json(bookings.into_iter().map(|b| b.visible_to(claim)).collect()))
+}
+
+/// Builds the query for the bookings that the caller asked for, after checking that they may see them
+fn build_bookings_query(
+    qb: &mut QueryBuilder<Postgres>,
+    claim: &Claims,
+    session_id: Option<i64>,
+    person_id: Option<i64>,
+    from: Option<String>,
+    to: Option<String>,
+    page: Page
+) -> Result<(), Custom<String>> {
+    qb.push(SELECT_BOOKING_FULL);
 
     let mut where_op = String::from(" WHERE");
 
@@ -110,7 +348,13 @@ async fn _list_bookings(
         qb.push_bind(person_id);
         where_op = String::from(" AND");
     } else if !claim.has_role("admin") {
-        return Err(Custom(Status::Forbidden, "only admins can view bookings for other users".to_string()))
+        // Trainers can see who is booked on their own sessions
+        if session_id.is_none() || !claim.has_role(ROLE_TRAINER) {
+            return Err(Custom(Status::Forbidden, "only admins can view bookings for other users".to_string()))
+        }
+        qb.push(where_op + " s.trainer = ");
+        qb.push_bind(claim.uid);
+        where_op = String::from(" AND");
     }
 
     if let Some(session_id) = session_id {
@@ -128,74 +372,342 @@ async fn _list_bookings(
         qb.push_bind(to);
     }
 
-    qb.push(" ORDER BY session_datetime, person_name");
-    info!("list_bookings compiled SQL: {}", qb.sql());
-    let bookings = qb.build_query_as()
-        .fetch_all(pool)
+    // Sort on the primary key last so that the order, and therefore pagination, is deterministic
+    qb.push(" ORDER BY session_datetime, person_name, b.session_id, b.person_id");
+    page.push_limit_offset(qb)?;
+    Ok(())
+}
+
+/// Exports all bookings for sessions in the given period, e.g. for monthly reports, as newline-delimited JSON or,
+/// with `format=csv`, as CSV. Rows are streamed as they are read rather than all being held in memory, so an
+/// export that fails part way through ends with an error line instead of an error status.
+#[get("/bookings/all?<from>&<to>&<format>")]
+pub async fn export_bookings(
+    state: &State<AppState>,
+    claim: Claims,
+    from: Option<String>,
+    to: Option<String>,
+    format: Option<String>
+) -> Result<(ContentType, TextStream![String + '_]), Custom<String>> {
+    claim.assert_roles_contains(ROLE_ADMIN)?;
+    _export_bookings(&state.pool, &state.config, from, to, format).await
+}
+
+async fn _export_bookings(pool: &PgPool, config: &Config, from: Option<String>, to: Option<String>, format: Option<String>) -> Result<(ContentType, TextStream![String]), Custom<String>> {
+    let (from, to) = match (parse_opt_date(from)?, parse_opt_date(to)?) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return Err(Custom(Status::BadRequest, "from and to are required".to_string()))
+    };
+    if to < from || to - from > TimeDelta::days(BOOKING_EXPORT_MAX_DAYS) {
+        return Err(Custom(Status::BadRequest, format!("to must be after from, and no more than {} days later", BOOKING_EXPORT_MAX_DAYS)));
+    }
+    let csv = match format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(format) => return Err(Custom(Status::BadRequest, format!("unsupported format '{}', must be json or csv", format)))
+    };
+
+    // The export runs in its own transaction so that the query timeout applies to it
+    let mut tx = begin_with_timeout(pool, config).await?;
+    let stream = TextStream! {
+        if csv {
+            yield "person_id,person_name,person_email,person_phone,session_id,session_datetime,session_type,location,attended,credits_used,reference\r\n".to_string();
+        }
+        let mut qb = QueryBuilder::new(SELECT_BOOKING_FULL);
+        qb.push(" WHERE s.datetime >= ");
+        qb.push_bind(from);
+        qb.push(" AND s.datetime <= ");
+        qb.push_bind(to);
+        qb.push(" ORDER BY session_datetime, person_name, b.session_id, b.person_id");
+        let mut bookings = qb.build_query_as::<SessionBookingFull>().fetch(&mut *tx);
+        while let Some(booking) = bookings.next().await {
+            let row = match booking {
+                Ok(booking) if csv => Ok(booking_csv_row(&booking)),
+                Ok(booking) => rocket::serde::json::to_string(&booking).map(|json| json + "\n").map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string())
+            };
+            match row {
+                Ok(row) => yield row,
+                Err(e) => {
+                    // The response has already started, so the export can only be cut short, saying so at the end
+                    error!("Failed to export bookings from {} to {}: {}", from, to, e);
+                    yield export_failed_row(csv);
+                    break;
+                }
+            }
+        }
+    };
+    let content_type = if csv { ContentType::CSV } else { ContentType::new("application", "x-ndjson") };
+    Ok((content_type, stream))
+}
+
+/// The last line of an export that failed part way through, so that it cannot be mistaken for a complete one
+fn export_failed_row(csv: bool) -> String {
+    if csv {
+        "ERROR,export failed: the bookings above are incomplete\r\n".to_string()
+    } else {
+        "{\"error\":\"export failed: the bookings above are incomplete\"}\n".to_string()
+    }
+}
+
+fn booking_csv_row(booking: &SessionBookingFull) -> String {
+    let fields = [
+        booking.person_id.to_string(),
+        booking.person_name.clone(),
+        booking.person_email.clone().unwrap_or_default(),
+        booking.person_phone.clone().unwrap_or_default(),
+        booking.session_id.to_string(),
+        booking.session_datetime.to_rfc3339(),
+        booking.session_type.name.clone(),
+        booking.session_location.as_ref().map(|l| l.name.clone()).unwrap_or_default(),
+        booking.attended.to_string(),
+        booking.credits_used.to_string(),
+        booking.reference.clone().unwrap_or_default()
+    ];
+    fields.iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(",") + "\r\n"
+}
+
+#[derive(Responder)]
+pub enum NextBooking {
+    Found(Box<Json<SessionBookingFull>>),
+    None(NoContent)
+}
+
+/// The caller's earliest upcoming booking, or 204 No Content if they have none
+#[get("/me/next")]
+pub async fn get_next_booking(state: &State<AppState>, claim: Claims) -> Result<NextBooking, Custom<String>> {
+    Ok(match _get_next_booking(&state.pool, &claim).await? {
+        NextBooking::Found(booking) => NextBooking::Found(Box::new(Json(booking.into_inner().with_cancellation(&claim, state.config.cancellation_cutoff_mins)))),
+        none => none
+    })
+}
+
+async fn _get_next_booking(pool: &PgPool, claim: &Claims) -> Result<NextBooking, Custom<String>> {
+    let booking: Option<SessionBookingFull> = query_as(&format!("{} WHERE b.person_id = $1 AND s.datetime > now() ORDER BY s.datetime LIMIT 1", SELECT_BOOKING_FULL))
+        .bind(claim.uid)
+        .fetch_optional(pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
-    Ok(Json(bookings))
+        .map_err(db_error)?;
+    Ok(match booking {
+        Some(booking) => NextBooking::Found(Box::new(Json(booking))),
+        None => NextBooking::None(NoContent)
+    })
 }
 
-async fn take_result_from_stream<'a>(stream: &mut BoxStream<'a, Result<PgQueryResult, Error>>) -> Result<PgQueryResult, Custom<String>> {
-    stream.next()
+/// A single booking. Members can fetch their own, trainers those on their own sessions, and admins any.
+#[get("/bookings/<session_id>/<person_id>")]
+pub async fn get_booking(state: &State<AppState>, claim: Claims, session_id: i64, person_id: i64) -> Result<Json<SessionBookingFull>, Custom<String>> {
+    _get_booking(&state.pool, &claim, session_id, person_id).await
+        .map(|b| Json(b.into_inner().with_cancellation(&claim, state.config.cancellation_cutoff_mins)))
+}
+
+async fn _get_booking(pool: &PgPool, claim: &Claims, session_id: i64, person_id: i64) -> Result<Json<SessionBookingFull>, Custom<String>> {
+    let mut qb = QueryBuilder::new(SELECT_BOOKING_FULL);
+    qb.push(" WHERE b.session_id = ");
+    qb.push_bind(session_id);
+    qb.push(" AND b.person_id = ");
+    qb.push_bind(person_id);
+    if person_id != claim.uid && !claim.has_role(ROLE_ADMIN) {
+        if !claim.has_role(ROLE_TRAINER) {
+            return Err(Custom(Status::Forbidden, "only admins can view bookings for other users".to_string()))
+        }
+        qb.push(" AND s.trainer = ");
+        qb.push_bind(claim.uid);
+    }
+
+    let booking: Option<SessionBookingFull> = qb.build_query_as()
+        .fetch_optional(pool)
         .await
-        .ok_or(Custom(Status::InternalServerError, "no more results".to_string()))?
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))
+        .map_err(db_error)?;
+    booking
+        .map(|b| Json(b.visible_to(claim)))
+        .ok_or_else(|| Custom(Status::NotFound, format!("No booking found for person {} on session {}", person_id, session_id)))
+}
+
+/// A booking waiting for an admin to approve it
+#[derive(Serialize, FromRow, Debug)]
+pub struct BookingRequest {
+    person_id: i64,
+    session_id: i64,
+    /// Credits to be debited if the booking is approved; they are not taken from the balance until then
+    credits_held: i16,
+    requested: DateTime<Utc>
+}
+
+#[derive(Responder)]
+pub enum BookingOutcome {
+    Booked(Created<Json<BookingCreated>>),
+    #[response(status = 202)]
+    Pending(Json<BookingRequest>)
 }
 
+/// Books a session, or for members booking a session whose type requires approval, requests the booking.
+/// Admins always book directly, as making the booking approves it.
 #[post("/bookings", data="<booking>")]
-pub async fn create_booking(state: &State<AppState>, claim: Claims, booking: Json<SessionBooking>) -> Result<Created<Json<SessionBooking>>, Custom<String>> {
-    _create_booking(&state.pool, &state.timezone, &claim, booking).await
+pub async fn create_booking(state: &State<AppState>, claim: Claims, override_requested: OverrideRequested, booking: JsonBody<SessionBooking>) -> Result<BookingOutcome, Custom<String>> {
+    let booking = booking.into_inner();
+    if !claim.has_role(ROLE_ADMIN) && session_requires_approval(&state.pool, booking.session_id).await? {
+        return _request_booking(&state.pool, &state.timezone, &state.config, &claim, &booking).await
+            .map(|request| BookingOutcome::Pending(Json(request)));
+    }
+    let (person_id, session_id) = (booking.person_id, booking.session_id);
+    let created = _create_booking(&state.pool, &state.timezone, &state.config, &claim, override_requested.0, Json(booking)).await?;
+    send_booking_confirmation(state, person_id, session_id).await;
+    Ok(BookingOutcome::Booked(created))
 }
 
-async fn _create_booking(pool: &PgPool, timezone: &Tz, claim: &Claims, booking: Json<SessionBooking>) -> Result<Created<Json<SessionBooking>>, Custom<String>> {
-    let mut credits_cost: i16 = 0;
+#[derive(FromRow)]
+struct BookingConfirmation {
+    name: String,
+    email: String,
+    session_datetime: DateTime<Utc>,
+    session_type_name: String,
+    location_name: Option<String>
+}
 
-    // Admins can always make a booking for any user
-    if !claim.has_role(ROLE_ADMIN) {
-        // Non-admins can only book on their own behalf
-        if claim.uid != booking.person_id {
-            info!("person id {} attempted to book session on behalf of person id {}; denied: missing admin role", claim.uid, booking.person_id);
-            return Err(Custom(Status::Forbidden, "Cannot create a booking for another user!".to_string()));
+/// Emails a member to confirm their booking, unless they have opted out. The booking is already made,
+/// so failing to send the email is only logged.
+async fn send_booking_confirmation(state: &AppState, person_id: i64, session_id: i64) {
+    match NotificationPrefs::load(&state.pool, person_id).await {
+        Ok(prefs) if !should_notify(&prefs, NotificationEvent::BookingConfirmation) => return,
+        Ok(_) => {},
+        Err(e) => {
+            error!("Failed to load notification preferences of person id {}: {}", person_id, e);
+            return;
+        }
+    }
+    let confirmation: BookingConfirmation = match query_as("SELECT p.name, p.email, s.datetime AS session_datetime, \
+            t.name AS session_type_name, loc.name AS location_name \
+            FROM session AS s \
+            JOIN person AS p ON p.id = $1 \
+            JOIN session_type AS t ON s.session_type = t.id \
+            LEFT JOIN location AS loc ON s.location = loc.id \
+            WHERE s.id = $2")
+        .bind(person_id)
+        .bind(session_id)
+        .fetch_one(&state.pool)
+        .await {
+        Ok(confirmation) => confirmation,
+        Err(e) => {
+            error!("Failed to load booking of person id {} on session id {} to confirm: {}", person_id, session_id, e);
+            return;
         }
+    };
+    let sender = Address::new_address(Some(&state.config.email_sender_name), &state.config.email_sender_address);
+    let session_datetime = confirmation.session_datetime.with_timezone(&state.timezone);
+    let session_time = session_datetime.format("%H:%M");
+    let session_date = session_datetime.format("%A %-d %B %Y");
+    let location = confirmation.location_name.as_deref().unwrap_or("the usual place");
+    let email = MessageBuilder::new()
+        .from(sender.clone())
+        .reply_to(sender)
+        .to(Address::new_address(Some(&confirmation.name), &confirmation.email))
+        .subject(render_subject(&state.config, EmailTemplate::BookingConfirmation))
+        .text_body(render_body(&state.config, EmailTemplate::BookingConfirmation, &[&confirmation.session_type_name, &session_time, &session_date, &location]))
+        .into_message();
+    let result = match email {
+        Ok(email) => send_email(email, &state.secrets).await.map_err(|e| e.1),
+        Err(e) => Err(e.to_string())
+    };
+    match result {
+        Ok(()) => info!("Sent booking confirmation to person id {} for session id {}", person_id, session_id),
+        Err(e) => error!("Failed to send booking confirmation to {}: {}", &confirmation.email, e)
+    }
+}
 
-        // Non-admins can only book future sessions
-        let session_date_and_cost = get_session_date_and_cost(pool, &booking.session_id).await?;
-        if session_date_and_cost.datetime.lt(&Utc::now()) {
-            info!("person id {} attempted to book session in past (session id {}, date {}); denied: missing admin role", claim.uid, session_date_and_cost.id, session_date_and_cost.datetime);
-            return Err(Custom(Status::Forbidden, "Cannot create booking in the past!".to_string()));
+/// Reasons why a booking cannot be made. Each has a stable code so that clients can
+/// explain the reason to the user before they attempt to book.
+#[derive(Debug, PartialEq)]
+enum BookingRejection {
+    ForOtherUser,
+    SessionInPast,
+    NoMembership,
+    WeeklyLimitReached(usize),
+    ActiveBookingLimitReached(u32),
+    CreditsOptInRequired,
+    AlreadyBooked,
+    SessionFull(i64),
+    SessionUnstaffed,
+    PrivateSession,
+    BookingNotOpen(DateTime<Utc>),
+    BookingClosed,
+    PrerequisiteNotMet(String),
+    WaiverNotAccepted(String),
+    InsufficientCredits(i16),
+    Failed(Custom<String>)
+}
+
+impl BookingRejection {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::ForOtherUser => "FOR_OTHER_USER",
+            Self::SessionInPast => "SESSION_IN_PAST",
+            Self::NoMembership => "NO_MEMBERSHIP",
+            Self::WeeklyLimitReached(_) => "WEEKLY_LIMIT_REACHED",
+            Self::ActiveBookingLimitReached(_) => "ACTIVE_BOOKING_LIMIT_REACHED",
+            Self::CreditsOptInRequired => "CREDITS_OPT_IN_REQUIRED",
+            Self::AlreadyBooked => "ALREADY_BOOKED",
+            Self::SessionFull(_) => "SESSION_FULL",
+            Self::SessionUnstaffed => "SESSION_UNSTAFFED",
+            Self::PrivateSession => "PRIVATE_SESSION",
+            Self::BookingNotOpen(_) => "BOOKING_NOT_OPEN",
+            Self::BookingClosed => "BOOKING_CLOSED",
+            Self::PrerequisiteNotMet(_) => "PREREQUISITE_NOT_MET",
+            Self::WaiverNotAccepted(_) => "WAIVER_NOT_ACCEPTED",
+            Self::InsufficientCredits(_) => "INSUFFICIENT_CREDITS",
+            Self::Failed(_) => "FAILED"
         }
+    }
+}
 
-        // Check whether the user has full membership or a usable limited membership
-        let membership_check: Result<(), Custom<String>>;
-        if claim.has_role(ROLE_FULL_MEMBER) {
-            membership_check = Ok(());
-        } else if claim.has_role(ROLE_LIMITED_MEMBER) {
-            membership_check = check_limited_member_has_no_bookings_in_same_week(pool, timezone, claim.uid, &session_date_and_cost).await;
-        } else {
-            info!("person id {} attempted to book session id {} (cost {}) without active membership or PAYG credits", claim.uid, session_date_and_cost.id, session_date_and_cost.cost);
-            membership_check = Err(Custom(Status::Forbidden, "Missing or expired membership, and no PAYG credits.".to_string()));
+impl From<BookingRejection> for Custom<String> {
+    fn from(rejection: BookingRejection) -> Self {
+        match rejection {
+            BookingRejection::ForOtherUser => Custom(Status::Forbidden, "Cannot create a booking for another user!".to_string()),
+            BookingRejection::SessionInPast => Custom(Status::Forbidden, "Cannot create booking in the past!".to_string()),
+            BookingRejection::NoMembership => Custom(Status::Forbidden, "Missing or expired membership, and no PAYG credits.".to_string()),
+            BookingRejection::WeeklyLimitReached(count) => Custom(Status::Forbidden, format!("Cannot book session: member already has {} booking(s) in this week.", count)),
+            BookingRejection::ActiveBookingLimitReached(max) => Custom(Status::Forbidden, format!("Cannot book session: member already has the maximum of {} future booking(s).", max)),
+            BookingRejection::CreditsOptInRequired => Custom(Status::PaymentRequired, "Opt in to use credits for booking.".to_string()),
+            BookingRejection::AlreadyBooked => Custom(Status::Conflict, "Session is already booked.".to_string()),
+            BookingRejection::SessionFull(max_bookings) => Custom(Status::Conflict, format!("Session has reached it maximum number of bookings: {}.", max_bookings)),
+            BookingRejection::SessionUnstaffed => Custom(Status::Forbidden, "Session cannot be booked until a trainer is assigned.".to_string()),
+            BookingRejection::PrivateSession => Custom(Status::Forbidden, "Session is private: only admins can book members onto it.".to_string()),
+            BookingRejection::BookingNotOpen(opens_at) => Custom(Status::Forbidden, format!("Booking for this session opens at {}.", opens_at.to_rfc3339())),
+            BookingRejection::BookingClosed => Custom(Status::Forbidden, "Booking for this session has closed.".to_string()),
+            BookingRejection::PrerequisiteNotMet(prerequisite) => Custom(Status::Forbidden, format!("Cannot book session: attend a {} session first.", prerequisite)),
+            BookingRejection::WaiverNotAccepted(waiver_url) => Custom(Status::Forbidden, format!("Cannot book session: accept the waiver at {} first.", waiver_url)),
+            BookingRejection::InsufficientCredits(balance) => Custom(Status::PaymentRequired, format!("Not enough credits for booking: current balance is {}.", balance)),
+            BookingRejection::Failed(custom) => custom
         }
+    }
+}
 
-        // If no usable membership, check for credits
-        if membership_check.is_err() && membership_check.as_ref().err().unwrap().0 == Status::Forbidden {
-            let user_record = UserLoginRecord::load_by_id(pool, booking.person_id).await
-                .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?
-                .ok_or(Custom(Status::Unauthorized, "missing user record".to_string()))?;
-            if user_record.credits >= session_date_and_cost.cost {
-                if booking.credits_used.unwrap_or(0) < session_date_and_cost.cost {
-                    return Err(Custom(Status::PaymentRequired, "Opt in to use credits for booking.".to_string()));
-                } else {
-                    credits_cost = session_date_and_cost.cost;
-                }
-            } else {
-                membership_check?;
-            }
-        } else {
-            // Technical errors other than forbidden should break out
-            membership_check?;
+impl From<Custom<String>> for BookingRejection {
+    fn from(custom: Custom<String>) -> Self {
+        BookingRejection::Failed(custom)
+    }
+}
+
+/// How a non-admin member will pay for a booking they are eligible to make
+#[derive(Debug, PartialEq)]
+enum BookingPayment {
+    Membership,
+    Credits(i16)
+}
+
+async fn _create_booking(pool: &PgPool, timezone: &Tz, config: &Config, claim: &Claims, override_requested: bool, booking: Json<SessionBooking>) -> Result<Created<Json<BookingCreated>>, Custom<String>> {
+    let mut credits_cost: i16 = 0;
+    let admin_override = admin_override(claim, override_requested);
+
+    // Admins can make a booking for any user, but only onto past or full sessions by overriding the checks
+    if claim.has_role(ROLE_ADMIN) {
+        if !admin_override {
+            let session_date_and_cost = get_session_date_and_cost(pool, &booking.session_id).await?;
+            check_session_not_past(claim, &session_date_and_cost)?;
         }
+    } else {
+        credits_cost = check_member_booking(pool, timezone, config, claim, &booking).await?;
     }
 
     // Read the max_booking_count for the session if present
@@ -203,562 +715,3202 @@ async fn _create_booking(pool: &PgPool, timezone: &Tz, claim: &Claims, booking:
         .bind(&booking.session_id)
         .fetch_optional(pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?
+        .map_err(db_error)?
         .ok_or(Custom(Status::NotFound, format!("no session with id {}", &booking.session_id)))?;
 
-    // Make the booking
-    match session_with_max_booking_count.max_booking_count {
-        Some(max_booking_count) => book_session_with_max_bookings(pool, booking.person_id, booking.session_id, max_booking_count, credits_cost).await,
-        None => book_session_no_max_bookings(pool, booking.person_id, booking.session_id, credits_cost).await
+    // Make the booking and debit any credits in a single transaction, so that neither happens without the other
+    let mut tx = pool.begin()
+        .await
+        .map_err(db_error)?;
+    let reference = generate_booking_reference(&mut tx).await?;
+    match session_with_max_booking_count.max_booking_count.filter(|_| !admin_override) {
+        Some(max_booking_count) => book_session_with_max_bookings(&mut tx, booking.person_id, booking.session_id, max_booking_count, credits_cost, &reference).await,
+        None => book_session_no_max_bookings(&mut tx, booking.person_id, booking.session_id, credits_cost, &reference).await
     }?;
 
-    info!("Created booking: {:?}", &booking);
+    // Once booked, e.g. by being promoted, the member no longer waits for a space
+    query("DELETE FROM waitlist WHERE person_id = $1 AND session_id = $2")
+        .bind(booking.person_id)
+        .bind(booking.session_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+    // Only the credits actually debited are recorded, whatever the client offered to use
+    let booking_created = SessionBooking { person_id: booking.person_id, session_id: booking.session_id, credits_used: Some(credits_cost) };
+    info!("Created booking: {:?}", &booking_created);
 
     // Debit the credits used from the user if required
     if credits_cost > 0 {
-        query_as("UPDATE person SET credits = credits - $1 WHERE id = $2 RETURNING id, credits")
-            .bind(credits_cost)
-            .bind(booking.person_id)
-            .fetch_one(pool)
-            .await.map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+        debit_credits(&mut tx, booking.person_id, credits_cost).await?;
+    }
+    tx.commit()
+        .await
+        .map_err(db_error)?;
+    if admin_override {
+        audit::record(pool, claim.uid, "override_create_booking", format!("booking person {} session {}", booking.person_id, booking.session_id)).await;
+    } else if claim.uid != booking.person_id {
+        audit::record(pool, claim.uid, "create_booking", format!("booking person {} session {}", booking.person_id, booking.session_id)).await;
     }
 
-    Ok(Created::new(format!("/bookings?sessionid={},person_id={}", booking.session_id, booking.person_id)))
+    Ok(Created::new(format!("/bookings?sessionid={},person_id={}", booking.session_id, booking.person_id)).body(Json(BookingCreated { booking: booking_created, reference })))
 }
 
-#[derive(FromRow)]
-pub struct SessionDateAndCost {
-    id: i64,
-    datetime: DateTime<Utc>,
-    cost: i16
+/// Books a member onto a session on their behalf, e.g. from the waitlist. Unlike bookings made by admins, the
+/// member's eligibility and credits apply, as if they had made the booking themselves. Staff choosing to book them
+/// stands in for approval, on sessions that require it.
+pub(crate) async fn book_as_member(pool: &PgPool, timezone: &Tz, config: &Config, person_id: i64, session_id: i64) -> Result<Created<Json<BookingCreated>>, Custom<String>> {
+    let member = member_claims(pool, person_id).await?;
+    _create_booking(pool, timezone, config, &member, false, Json(SessionBooking { person_id, session_id, credits_used: None })).await
 }
 
-#[derive(FromRow, Debug)]
-struct MemberExistingBooking {
-    person_id: i64,
-    session_id: i64,
-    datetime: DateTime<Utc>
+/// Short-lived claims for a member as they are now, to check what they could do themselves
+async fn member_claims(pool: &PgPool, person_id: i64) -> Result<Claims, Custom<String>> {
+    let user_record = UserLoginRecord::load_by_id(pool, person_id).await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("no person with id {}", person_id)))?;
+    Ok(Claims::create(user_record.id, &user_record.email, &user_record.phone, &parse_roles(&user_record.roles), TimeDelta::minutes(1)))
 }
 
-async fn check_limited_member_has_no_bookings_in_same_week(pool: &PgPool, timezone: &Tz, uid: i64, session_date_and_cost: &SessionDateAndCost) -> Result<(), Custom<String>> {
-    // Can always book a zero-cost session even if you already have other bookings.
-    if session_date_and_cost.cost == 0 {
-        return Ok(());
+/// Checks that a non-admin can make a booking themselves, returning the credits that it will cost
+async fn check_member_booking(pool: &PgPool, timezone: &Tz, config: &Config, claim: &Claims, booking: &SessionBooking) -> Result<i16, Custom<String>> {
+    // Non-admins can only book on their own behalf
+    if claim.uid != booking.person_id {
+        info!("person id {} attempted to book session on behalf of person id {}; denied: missing admin role", claim.uid, booking.person_id);
+        return Err(BookingRejection::ForOtherUser.into());
     }
 
-    // Get the date/time of the session and work out the start and end of the week that the session occurs in
-    let datetime_in_local = timezone.from_utc_datetime(&session_date_and_cost.datetime.naive_utc());
-    let start_of_week_local = datetime_in_local
-        .checked_sub_days(Days::new(datetime_in_local.weekday().num_days_from_monday() as u64)).unwrap()
-        .with_time(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
-        .unwrap();
-    let end_of_week_local = start_of_week_local
-        .checked_add_days(Days::new(7)).unwrap();
-
-    // Find other bookings in the same week (only sessions with nonzero cost)
-    let existing_bookings: Vec<MemberExistingBooking> = query_as("SELECT b.person_id AS person_id, b.session_id AS session_id, s.datetime AS datetime, s.cost AS cost \
-            FROM booking AS b \
-            JOIN session AS s ON b.session_id = s.id \
-            WHERE b.person_id = $1 \
-            AND s.cost > 0 \
-            AND s.datetime >= $2 \
-            AND s.datetime < $3")
-        .bind(uid)
-        .bind(start_of_week_local)
-        .bind(end_of_week_local)
-        .fetch_all(pool)
-        .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
-
-    // Error if there is at least one existing booking
-    if !existing_bookings.is_empty() {
-        return Err(Custom(Status::Forbidden, format!("Cannot book session: member already has {} booking(s) in this week.", existing_bookings.len())));
+    let session_date_and_cost = get_session_date_and_cost(pool, &booking.session_id).await?;
+    let mut conn = pool.acquire().await.map_err(db_error)?;
+    if let BookingPayment::Credits(cost) = check_booking_eligibility(&mut conn, timezone, config, claim, &session_date_and_cost).await? {
+        // Members who have chosen to always use credits need not opt in for each booking
+        if booking.credits_used.unwrap_or(0) < cost && !auto_use_credits(&mut conn, booking.person_id).await? {
+            return Err(BookingRejection::CreditsOptInRequired.into());
+        }
+        return Ok(cost);
     }
-
-    Ok(())
+    Ok(0)
 }
 
-async fn book_session_no_max_bookings(pool: &PgPool, person_id: i64, session_id: i64, credits_used: i16) -> Result<(), Custom<String>> {
-    query_as("INSERT INTO booking (person_id, session_id, credits_used) VALUES ($1, $2, $3) RETURNING person_id, session_id")
-        .bind(person_id)
+/// Whether bookings by members on a session are pending until approved. A missing session is reported when booking it.
+async fn session_requires_approval(pool: &PgPool, session_id: i64) -> Result<bool, Custom<String>> {
+    query_scalar("SELECT t.requires_approval FROM session AS s JOIN session_type AS t ON s.session_type = t.id WHERE s.id = $1")
         .bind(session_id)
-        .bind(credits_used)
-        .fetch_one(pool)
+        .fetch_optional(pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))
-}
-
-#[derive(FromRow)]
-struct SessionWithMaxBookingCount {
-    id: i64,
-    max_booking_count: Option<i64>
+        .map_err(db_error)
+        .map(|requires_approval| requires_approval.unwrap_or(false))
 }
 
-
-async fn book_session_with_max_bookings(pool: &PgPool, person_id: i64, session_id: i64, max_bookings: i64, credits_used: i16) -> Result<(), Custom<String>> {
-    // Atomically update the booking table to insert a new booking if and only if the count of
-    // bookings for the referenced session is less than the maximum. Adapted from this StackOverflow
-    // answer: https://dba.stackexchange.com/a/167283
-    // NB simple string interpolation without prepared statements is safe because the arguments all
-    // are numeric.
-    let sql = format!("BEGIN; \
-        SELECT id FROM session WHERE id = {} FOR NO KEY UPDATE; \
-        INSERT INTO booking (person_id, session_id, credits_used) \
-        SELECT {}, {}, {} FROM booking \
-        WHERE session_id = {} \
-        HAVING count(*) < {} \
-        ON CONFLICT DO NOTHING \
-        RETURNING person_id, session_id; \
-        END;", session_id, person_id, session_id, credits_used, session_id, max_bookings);
-    info!("Executing raw SQL: {}", &sql);
-    let mut result_stream = raw_sql(sql.as_str()).execute_many(pool);
-
-    let _ = take_result_from_stream(&mut result_stream).await?; // result from BEGIN;
-    let _ = take_result_from_stream(&mut result_stream).await?; // result from SELECT..FOR UPDATE;
-    let insert_result = take_result_from_stream(&mut result_stream).await?; // result from INSERT..RETURNING;
-    let _ = take_result_from_stream(&mut result_stream).await?; // result from COMMIT;
-    info!("Insert result: {:?}", insert_result);
-
-    if insert_result.rows_affected() == 0 {
-        return Err(Custom(Status::Conflict, format!("Session has reached it maximum number of bookings: {}.", max_bookings)));
+/// Requests a booking on a session whose type requires approval. The member must be able to make the booking
+/// now, but it does not count towards the session's bookings, nor are the credits debited, until it is approved.
+/// The credits are held though, so that the member cannot request more bookings than they can pay for.
+async fn _request_booking(pool: &PgPool, timezone: &Tz, config: &Config, claim: &Claims, booking: &SessionBooking) -> Result<BookingRequest, Custom<String>> {
+    let credits_held = check_member_booking(pool, timezone, config, claim, booking).await?;
+    let already_booked: bool = query_scalar("SELECT EXISTS (SELECT 1 FROM booking WHERE person_id = $1 AND session_id = $2)")
+        .bind(booking.person_id)
+        .bind(booking.session_id)
+        .fetch_one(pool)
+        .await
+        .map_err(db_error)?;
+    if already_booked {
+        return Err(BookingRejection::AlreadyBooked.into());
     }
-    Ok(())
-}
 
-async fn get_session_date_and_cost(pool: &PgPool, session_id: &i64) -> Result<SessionDateAndCost, Custom<String>> {
-    query_as("SELECT id, datetime, cost FROM session WHERE id = $1")
-        .bind(&session_id)
-        .fetch_optional(pool)
+    // The balance is locked while checking it against the credits already held, as for debiting credits
+    let mut tx = pool.begin()
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?
-        .ok_or(Custom(Status::NotFound, format!("no session with id {}", &session_id)))
+        .map_err(db_error)?;
+    if credits_held > 0 {
+        let available: i64 = query_scalar("SELECT p.credits - COALESCE((SELECT SUM(r.credits_held) FROM booking_request AS r WHERE r.person_id = p.id), 0) \
+                FROM person AS p WHERE p.id = $1 FOR NO KEY UPDATE OF p")
+            .bind(booking.person_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(db_error)?;
+        if available < credits_held as i64 {
+            info!("person id {} has {} credit(s) not held for other requests, not the {} required", booking.person_id, available, credits_held);
+            return Err(BookingRejection::InsufficientCredits(available.clamp(0, i16::MAX as i64) as i16).into());
+        }
+    }
+    let request: BookingRequest = query_as("INSERT INTO booking_request (person_id, session_id, credits_held) VALUES ($1, $2, $3) \
+            ON CONFLICT DO NOTHING \
+            RETURNING person_id, session_id, credits_held, requested")
+        .bind(booking.person_id)
+        .bind(booking.session_id)
+        .bind(credits_held)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::Conflict, "Booking is already waiting for approval.".to_string()))?;
+    tx.commit()
+        .await
+        .map_err(db_error)?;
+    info!("Requested booking: {:?}", &request);
+    Ok(request)
 }
 
-#[delete("/bookings?<session_id>&<person_id>")]
-pub async fn delete_booking(state: &State<AppState>, claim: Claims, person_id: i64, session_id: i64) -> Result<Json<SessionBooking>, Custom<String>> {
-    _delete_booking(&state.pool, &claim, person_id, session_id).await
+/// Lists the bookings waiting for approval: all of them for admins, or only their own for members
+#[get("/bookings/pending?<session_id>")]
+pub async fn list_booking_requests(state: &State<AppState>, claim: Claims, session_id: Option<i64>) -> Result<Json<Vec<BookingRequest>>, Custom<String>> {
+    _list_booking_requests(&state.pool, &claim, session_id).await.map(Json)
 }
 
-async fn _delete_booking(pool: &PgPool, claim: &Claims, person_id: i64, session_id: i64) -> Result<Json<SessionBooking>, Custom<String>> {
-    if !claim.has_role("admin") {
-        if person_id != claim.uid {
-            return Err(Custom(Status::Forbidden, "Not allowed to cancel bookings for other users.".to_string()));
-        }
-        // Error if session is in the past
-        if get_session_date_and_cost(pool, &session_id).await?.datetime.lt(&Utc::now()) {
-            return Err(Custom(Status::Forbidden, "Cannot cancel past booking.".to_string()));
-        }
-    }
-    let booking_deleted: SessionBooking = query_as("DELETE FROM booking WHERE person_id = $1 AND session_id = $2 RETURNING person_id, session_id, credits_used")
+async fn _list_booking_requests(pool: &PgPool, claim: &Claims, session_id: Option<i64>) -> Result<Vec<BookingRequest>, Custom<String>> {
+    let person_id = if claim.has_role(ROLE_ADMIN) { None } else { Some(claim.uid) };
+    query_as("SELECT person_id, session_id, credits_held, requested FROM booking_request \
+            WHERE ($1::int8 IS NULL OR person_id = $1) AND ($2::int8 IS NULL OR session_id = $2) \
+            ORDER BY requested, person_id")
         .bind(person_id)
         .bind(session_id)
-        .fetch_optional(pool)
+        .fetch_all(pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?
-        .ok_or(Custom(Status::NotFound, format!("No booking found with person_id={} and session_id={}.", person_id, session_id)))?;
-
-    // Restore the credits used for this booking
-    if booking_deleted.credits_used.unwrap_or(0) > 0 {
-        query_as("UPDATE person SET credits = credits + $1 WHERE id = $2 RETURNING id, credits")
-            .bind(booking_deleted.credits_used)
-            .bind(person_id)
-            .fetch_one(pool)
-            .await.map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
-    }
-
-    Ok(Json(booking_deleted))
+        .map_err(db_error)
 }
 
-#[derive(Deserialize)]
-pub struct BookingUpdate {
-    attended: bool
+/// Withdraws the caller's own booking request, releasing any credits held for it
+#[delete("/bookings/pending?<session_id>")]
+pub async fn delete_booking_request(state: &State<AppState>, claim: Claims, session_id: i64) -> Result<NoContent, Custom<String>> {
+    _delete_booking_request(&state.pool, &claim, session_id).await
 }
 
-#[put("/bookings?<session_id>&<person_id>", data="<booking_update>")]
-pub async fn update_booking(state: &State<AppState>, claim: Claims, person_id: i64, session_id: i64, booking_update: Json<BookingUpdate>) -> Result<NoContent, Custom<String>> {
-    claim.assert_roles_contains("admin")?;
-    let _ = query_as("UPDATE booking SET attended = $1 WHERE person_id = $2 AND session_id = $3 RETURNING person_id, session_id")
-        .bind(booking_update.attended)
-        .bind(person_id)
+async fn _delete_booking_request(pool: &PgPool, claim: &Claims, session_id: i64) -> Result<NoContent, Custom<String>> {
+    let result = query("DELETE FROM booking_request WHERE person_id = $1 AND session_id = $2")
+        .bind(claim.uid)
         .bind(session_id)
-        .fetch_optional(&state.pool)
+        .execute(pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?
-        .ok_or(Custom(Status::NotFound, format!("No booking found with person_id={} and session_id={}.", person_id, session_id)))?;
+        .map_err(db_error)?;
+    if result.rows_affected() == 0 {
+        return Err(Custom(Status::NotFound, format!("No booking waiting for approval with session_id={}.", session_id)));
+    }
+    info!("person id {} withdrew their booking request for session id {}", claim.uid, session_id);
     Ok(NoContent)
 }
 
-#[derive(Serialize, FromRow)]
-pub struct AttendanceStat {
+#[derive(Deserialize, Debug)]
+pub struct BookingApproval {
     person_id: i64,
-    name: String,
-    email: String,
-    attended_count: i64
+    session_id: i64,
+    approved: bool
 }
 
-#[get("/stats/attendance?<from>&<to>&<session_type>")]
-pub async fn get_attendance_stats(state: &State<AppState>, claim: Claims, from: Option<String>, to: Option<String>, session_type: Vec<i32>) -> Result<Json<Vec<AttendanceStat>>, Custom<String>> {
-    claim.assert_roles_contains("admin")?;
-    let mut qb = QueryBuilder::new("\
-        SELECT p.id AS person_id, p.name AS name, p.email AS email, ( \
-            SELECT COUNT(*) \
-            FROM booking \
-            JOIN session ON booking.session_id = session.id \
-            WHERE booking.person_id = p.id \
-            AND booking.attended = TRUE ");
+#[derive(Responder)]
+pub enum ApprovalOutcome {
+    Approved(Created<Json<BookingCreated>>),
+    Rejected(NoContent)
+}
 
-    if let Some(from) = parse_opt_date(from)? {
-        qb.push(" AND session.datetime >= ");
-        qb.push_bind(from);
+/// Approves or rejects a booking waiting for approval
+#[post("/bookings/approve", data="<approval>")]
+pub async fn approve_booking(state: &State<AppState>, claim: Claims, approval: JsonBody<BookingApproval>) -> Result<ApprovalOutcome, Custom<String>> {
+    let outcome = _approve_booking(&state.pool, &state.timezone, &state.config, &claim, &approval).await?;
+    if let ApprovalOutcome::Approved(_) = outcome {
+        send_booking_confirmation(state, approval.person_id, approval.session_id).await;
     }
-    if let Some(to) = parse_opt_date(to)? {
-        qb.push(" AND session.datetime <= ");
-        qb.push_bind(to);
+    Ok(outcome)
+}
+
+/// Approving a booking makes it as it was requested, and it must still be in the future, fit in the session, be
+/// within the member's booking limits and be paid for; if not, the request is kept so that it can be rejected
+/// instead. Rejecting it releases the held credits.
+async fn _approve_booking(pool: &PgPool, timezone: &Tz, config: &Config, claim: &Claims, approval: &BookingApproval) -> Result<ApprovalOutcome, Custom<String>> {
+    if !claim.has_role(ROLE_ADMIN) {
+        return Err(Custom(Status::Forbidden, "Only admins can approve bookings.".to_string()));
+    }
+    let mut tx = pool.begin()
+        .await
+        .map_err(db_error)?;
+    let credits_held: i16 = query_scalar("DELETE FROM booking_request WHERE person_id = $1 AND session_id = $2 RETURNING credits_held")
+        .bind(approval.person_id)
+        .bind(approval.session_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("No booking waiting for approval with person_id={} and session_id={}.", approval.person_id, approval.session_id)))?;
+    let target = format!("booking person {} session {}", approval.person_id, approval.session_id);
+    if !approval.approved {
+        tx.commit()
+            .await
+            .map_err(db_error)?;
+        info!("Rejected booking of person id {} on session id {}", approval.person_id, approval.session_id);
+        audit::record(pool, claim.uid, "reject_booking", target).await;
+        return Ok(ApprovalOutcome::Rejected(NoContent));
     }
 
-    if !session_type.is_empty() {
-        let session_types_str = session_type.into_iter()
-            .map(|i| i.to_string())
-            .collect::<Vec<_>>()
-            .join(", ");
-        qb.push(" AND session.session_type IN (");
-        qb.push(session_types_str);
-        qb.push(")");
-    } else {
-        // Cannot write "IN ()" so we create a clause that is always false
-        qb.push(" AND FALSE");
+    // The member may have made other bookings since requesting this one, or the session may have passed
+    let session_date_and_cost = get_session_date_and_cost(pool, &approval.session_id).await?;
+    check_session_not_past(claim, &session_date_and_cost)?;
+    check_active_booking_limit(&mut tx, config, approval.person_id, &session_date_and_cost).await?;
+    if credits_held == 0 {
+        let member = member_claims(pool, approval.person_id).await?;
+        if member.has_role(ROLE_LIMITED_MEMBER) && !member.has_role(ROLE_FULL_MEMBER) {
+            check_limited_member_has_no_bookings_in_same_week(&mut tx, timezone, config.week_start_day, approval.person_id, &session_date_and_cost).await?;
+        }
     }
 
+    let max_booking_count: Option<i64> = query_scalar("SELECT max_booking_count FROM session WHERE id = $1")
+        .bind(approval.session_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    let reference = generate_booking_reference(&mut tx).await?;
+    match max_booking_count {
+        Some(max_booking_count) => book_session_with_max_bookings(&mut tx, approval.person_id, approval.session_id, max_booking_count, credits_held, &reference).await,
+        None => book_session_no_max_bookings(&mut tx, approval.person_id, approval.session_id, credits_held, &reference).await
+    }?;
+    if credits_held > 0 {
+        debit_credits(&mut tx, approval.person_id, credits_held).await?;
+    }
+    tx.commit()
+        .await
+        .map_err(db_error)?;
+    let booking = SessionBooking { person_id: approval.person_id, session_id: approval.session_id, credits_used: Some(credits_held) };
+    info!("Approved booking: {:?}", &booking);
+    audit::record(pool, claim.uid, "approve_booking", target).await;
 
-    qb.push(") AS attended_count \
-        FROM person AS p \
-        ORDER BY attended_count DESC, name \
-        LIMIT 10");
-    info!("fetching: {}", qb.sql());
+    Ok(ApprovalOutcome::Approved(Created::new(format!("/bookings?sessionid={},person_id={}", booking.session_id, booking.person_id)).body(Json(BookingCreated { booking, reference }))))
+}
 
-    let stats = qb.build_query_as()
-        .fetch_all(&state.pool)
+/// Generates a booking reference that is not already in use. The unique constraint on the column
+/// is the final guard against a concurrent booking taking the same one.
+async fn generate_booking_reference(conn: &mut PgConnection) -> Result<String, Custom<String>> {
+    for _ in 0..BOOKING_REFERENCE_MAX_ATTEMPTS {
+        let reference = BOOKING_REFERENCE_GENERATOR.generate_one()
+            .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+        let in_use: bool = query_scalar("SELECT EXISTS (SELECT 1 FROM booking WHERE reference = $1)")
+            .bind(&reference)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(db_error)?;
+        if !in_use {
+            return Ok(reference);
+        }
+    }
+    Err(Custom(Status::InternalServerError, "Failed to generate a unique booking reference".to_string()))
+}
+
+async fn auto_use_credits(conn: &mut PgConnection, person_id: i64) -> Result<bool, Custom<String>> {
+    query_scalar("SELECT auto_use_credits FROM person WHERE id = $1")
+        .bind(person_id)
+        .fetch_one(conn)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+        .map_err(db_error)
+}
 
-    Ok(Json(stats))
+/// Debits credits for a booking. The balance is locked and checked again here, whatever was checked before,
+/// as it may have been spent by a concurrent booking in the meantime.
+/// The lock must not conflict with the key share lock taken on the person by inserting the booking,
+/// or two concurrent bookings would deadlock.
+async fn debit_credits(conn: &mut PgConnection, person_id: i64, credits: i16) -> Result<(), BookingRejection> {
+    let balance: i16 = query_scalar("SELECT credits FROM person WHERE id = $1 FOR NO KEY UPDATE")
+        .bind(person_id)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| Custom(Status::NotFound, format!("no person with id {}", person_id)))?;
+    if balance < credits {
+        info!("person id {} has {} credit(s), not the {} required", person_id, balance, credits);
+        return Err(BookingRejection::InsufficientCredits(balance));
+    }
+    query("UPDATE person SET credits = credits - $1 WHERE id = $2")
+        .bind(credits)
+        .bind(person_id)
+        .execute(&mut *conn)
+        .await
+        .map_err(db_error)?;
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use std::ops::Add;
-    use chrono::{DateTime, Duration, TimeDelta, Utc};
-    use chrono_tz::Tz;
-    use rocket::http::Status;
-    use rocket::serde::json::Json;
-    use rocket::response::status::Custom;
-    use sqlx::{Executor, FromRow, PgPool, query_as};
-    use crate::bookings::{_delete_booking, _list_bookings, SessionBooking};
-    use crate::claims::Claims;
-    use crate::{CountResult, UserLoginRecord};
+/// Whether the caller asked to override the checks that hold even for admins, and is allowed to
+fn admin_override(claim: &Claims, override_requested: bool) -> bool {
+    if override_requested && !claim.has_role(ROLE_ADMIN) {
+        info!("person id {} requested an admin override; ignored: missing admin role", claim.uid);
+    }
+    override_requested && claim.has_role(ROLE_ADMIN)
+}
 
-    #[derive(FromRow)]
-    struct IntRecord {
-        id: i32
+/// Checks that the session is open for booking, which applies to everyone unless an admin overrides it
+fn check_booking_times(claim: &Claims, session_date_and_cost: &SessionDateAndCost) -> Result<(), BookingRejection> {
+    check_session_not_past(claim, session_date_and_cost)?;
+
+    // Sessions may limit when members can book them, e.g. to open bookings for next week's sessions all at once
+    if let Some(opens_at) = session_date_and_cost.booking_opens_at {
+        if Utc::now().lt(&opens_at) {
+            info!("person id {} attempted to book session id {} before booking opens at {}", claim.uid, session_date_and_cost.id, opens_at);
+            return Err(BookingRejection::BookingNotOpen(opens_at));
+        }
+    }
+    if let Some(closes_at) = session_date_and_cost.booking_closes_at {
+        if Utc::now().ge(&closes_at) {
+            info!("person id {} attempted to book session id {} after booking closed at {}", claim.uid, session_date_and_cost.id, closes_at);
+            return Err(BookingRejection::BookingClosed);
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the session has not already started, which applies to everyone unless an admin overrides it
+fn check_session_not_past(claim: &Claims, session_date_and_cost: &SessionDateAndCost) -> Result<(), BookingRejection> {
+    if is_session_in_past(session_date_and_cost.datetime) {
+        info!("person id {} attempted to book session in past (session id {}, date {}); denied: no admin override", claim.uid, session_date_and_cost.id, session_date_and_cost.datetime);
+        return Err(BookingRejection::SessionInPast);
     }
+    Ok(())
+}
 
-    #[derive(FromRow)]
-    struct BigintRecord {
-        id: i64
+/// Checks whether a non-admin member may book the given session on their own behalf, and if so
+/// whether the booking is covered by their membership or must be paid for with credits.
+async fn check_booking_eligibility(conn: &mut PgConnection, timezone: &Tz, config: &Config, claim: &Claims, session_date_and_cost: &SessionDateAndCost) -> Result<BookingPayment, BookingRejection> {
+    check_booking_times(claim, session_date_and_cost)?;
+
+    // Sessions that require a trainer cannot be booked while unstaffed
+    if !session_date_and_cost.bookable {
+        return Err(BookingRejection::SessionUnstaffed);
     }
-    async fn create_person(pool: &PgPool, email: &str, roles: &str, credits: i32) -> i64 {
-        let member_id: BigintRecord = query_as("insert into person (name, email, roles, credits) values ('Test User', $1, $2, $3) returning id")
-            .bind(email)
-            .bind(roles)
-            .bind(credits)
-            .fetch_one(pool)
-            .await.unwrap();
-        member_id.id
+
+    // Members are booked onto private sessions by admins
+    if session_date_and_cost.private {
+        info!("person id {} attempted to book private session id {}; denied: missing admin role", claim.uid, session_date_and_cost.id);
+        return Err(BookingRejection::PrivateSession);
     }
-    async fn create_session(pool: &PgPool, datetime: &DateTime<Utc>, trainer_id: i64, session_type_name: &str, location_name: &str) -> i64 {
-        create_session_max_bookings(pool, datetime, trainer_id, session_type_name, location_name, None).await
+
+    // Advanced sessions can require members to have attended a more basic type of session first
+    if let (Some(prerequisite_id), Some(prerequisite_name)) = (session_date_and_cost.prerequisite_session_type_id, &session_date_and_cost.prerequisite_session_type_name) {
+        let attended: bool = query_scalar("SELECT EXISTS (SELECT 1 FROM booking AS b \
+                JOIN session AS s ON b.session_id = s.id \
+                WHERE b.person_id = $1 AND b.attended AND s.session_type = $2)")
+            .bind(claim.uid)
+            .bind(prerequisite_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(db_error)?;
+        if !attended {
+            info!("person id {} attempted to book session id {} without attending prerequisite session type {}", claim.uid, session_date_and_cost.id, prerequisite_id);
+            return Err(BookingRejection::PrerequisiteNotMet(prerequisite_name.clone()));
+        }
     }
 
-    async fn create_session_max_bookings(pool: &PgPool, datetime: &DateTime<Utc>, trainer_id: i64, session_type_name: &str, location_name: &str, max_bookings: Option<i64>) -> i64 {
-        let session_type_id: IntRecord = query_as("select id from session_type where name = $1")
-            .bind(session_type_name)
-            .fetch_one(pool).await.unwrap();
+    // Some types of session need members to have accepted a waiver, and to accept it again if it is replaced
+    if let (true, Some(waiver_url)) = (session_date_and_cost.requires_waiver, &session_date_and_cost.waiver_url) {
+        let accepted: bool = query_scalar("SELECT EXISTS (SELECT 1 FROM person_waiver WHERE person_id = $1 AND session_type_id = $2 AND waiver_url = $3)")
+            .bind(claim.uid)
+            .bind(session_date_and_cost.session_type_id)
+            .bind(waiver_url)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(db_error)?;
+        if !accepted {
+            info!("person id {} attempted to book session id {} without accepting the waiver for session type {}", claim.uid, session_date_and_cost.id, session_date_and_cost.session_type_id);
+            return Err(BookingRejection::WaiverNotAccepted(waiver_url.clone()));
+        }
+    }
 
-        let location_id: IntRecord = query_as("select id from location where name = $1")
-            .bind(location_name)
-            .fetch_one(pool).await.unwrap();
+    check_active_booking_limit(&mut *conn, config, claim.uid, session_date_and_cost).await?;
 
-        let session_id_record: BigintRecord = query_as("insert into session (datetime, duration_mins, session_type, location, trainer, cost, max_booking_count) \
-            VALUES ($1, 60, $2, $3, $4, 1, $5) \
-            RETURNING id
-        ")
-            .bind(datetime)
-            .bind(session_type_id.id)
-            .bind(location_id.id)
-            .bind(trainer_id)
-            .bind(max_bookings)
-            .fetch_one(pool).await.unwrap();
+    // Check whether the user has full membership or a usable limited membership
+    let membership_check: Result<(), BookingRejection>;
+    if claim.has_role(ROLE_FULL_MEMBER) {
+        membership_check = Ok(());
+    } else if claim.has_role(ROLE_LIMITED_MEMBER) {
+        membership_check = check_limited_member_has_no_bookings_in_same_week(&mut *conn, timezone, config.week_start_day, claim.uid, session_date_and_cost).await;
+    } else {
+        info!("person id {} attempted to book session id {} (cost {}) without active membership or PAYG credits", claim.uid, session_date_and_cost.id, session_date_and_cost.cost);
+        membership_check = Err(BookingRejection::NoMembership);
+    }
 
-        session_id_record.id
+    match membership_check {
+        Ok(()) => Ok(BookingPayment::Membership),
+        // Technical errors should break out
+        Err(BookingRejection::Failed(e)) => Err(BookingRejection::Failed(e)),
+        // If no usable membership, check for credits
+        Err(rejection) => {
+            let credits: i16 = query_scalar("SELECT credits FROM person WHERE id = $1")
+                .bind(claim.uid)
+                .fetch_optional(&mut *conn)
+                .await
+                .map_err(db_error)?
+                .ok_or(Custom(Status::Unauthorized, "missing user record".to_string()))?;
+            if credits_cover(credits, session_date_and_cost.cost) {
+                Ok(BookingPayment::Credits(session_date_and_cost.cost))
+            } else {
+                Err(rejection)
+            }
+        }
     }
-    async fn count_bookings(pool: &PgPool) -> i64 {
-        let record: CountResult = query_as("select count(*) from booking")
-            .fetch_one(pool)
+}
+
+#[derive(Serialize, Debug)]
+pub struct BookingPreview {
+    can_book: bool,
+    reason: Option<&'static str>,
+    credits_required: i16
+}
+
+#[derive(FromRow)]
+struct SessionCapacity {
+    max_booking_count: Option<i64>,
+    booking_count: i64,
+    booked: bool
+}
+
+/// Previews whether the caller could book a session, without making the booking. Members are assumed
+/// to opt in to using credits, in which case `credits_required` shows how many will be used.
+#[get("/sessions/<session_id>/can_book")]
+pub async fn preview_booking(state: &State<AppState>, claim: Claims, session_id: i64) -> Result<Json<BookingPreview>, Custom<String>> {
+    _preview_booking(&state.pool, &state.timezone, &state.config, &claim, session_id).await
+}
+
+async fn _preview_booking(pool: &PgPool, timezone: &Tz, config: &Config, claim: &Claims, session_id: i64) -> Result<Json<BookingPreview>, Custom<String>> {
+    match check_booking_preview(pool, timezone, config, claim, session_id).await {
+        Ok(credits_required) => Ok(Json(BookingPreview { can_book: true, reason: None, credits_required })),
+        Err(BookingRejection::Failed(e)) => Err(e),
+        Err(rejection) => Ok(Json(BookingPreview { can_book: false, reason: Some(rejection.code()), credits_required: 0 }))
+    }
+}
+
+async fn check_booking_preview(pool: &PgPool, timezone: &Tz, config: &Config, claim: &Claims, session_id: i64) -> Result<i16, BookingRejection> {
+    let capacity: SessionCapacity = query_as("SELECT s.max_booking_count, \
+            (SELECT COUNT(*) FROM booking WHERE booking.session_id = s.id) AS booking_count, \
+            EXISTS (SELECT 1 FROM booking WHERE booking.session_id = s.id AND booking.person_id = $2) AS booked \
+            FROM session AS s WHERE s.id = $1")
+        .bind(session_id)
+        .bind(claim.uid)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("no session with id {}", session_id)))?;
+    if capacity.booked {
+        return Err(BookingRejection::AlreadyBooked);
+    }
+
+    let mut credits_required = 0;
+    let session_date_and_cost = get_session_date_and_cost(pool, &session_id).await?;
+    if claim.has_role(ROLE_ADMIN) {
+        check_session_not_past(claim, &session_date_and_cost)?;
+    } else {
+        let mut conn = pool.acquire().await.map_err(db_error)?;
+        if let BookingPayment::Credits(cost) = check_booking_eligibility(&mut conn, timezone, config, claim, &session_date_and_cost).await? {
+            credits_required = cost;
+        }
+    }
+
+    if let Some(max_booking_count) = capacity.max_booking_count {
+        if capacity.booking_count >= max_booking_count {
+            return Err(BookingRejection::SessionFull(max_booking_count));
+        }
+    }
+    Ok(credits_required)
+}
+
+#[derive(FromRow)]
+pub struct SessionDateAndCost {
+    id: i64,
+    datetime: DateTime<Utc>,
+    cost: i16,
+    bookable: bool,
+    private: bool,
+    booking_opens_at: Option<DateTime<Utc>>,
+    booking_closes_at: Option<DateTime<Utc>>,
+    prerequisite_session_type_id: Option<i32>,
+    prerequisite_session_type_name: Option<String>,
+    requires_approval: bool,
+    session_type_id: i32,
+    requires_waiver: bool,
+    waiver_url: Option<String>
+}
+
+#[derive(FromRow, Debug)]
+struct MemberExistingBooking {
+    person_id: i64,
+    session_id: i64,
+    datetime: DateTime<Utc>
+}
+
+/// Limits the future bookings a member can hold at once. As for the weekly limit, zero-cost sessions are exempt.
+async fn check_active_booking_limit(conn: &mut PgConnection, config: &Config, person_id: i64, session_date_and_cost: &SessionDateAndCost) -> Result<(), BookingRejection> {
+    if let Some(max_active_bookings) = config.max_active_bookings {
+        if session_date_and_cost.cost > 0 {
+            let active_bookings: CountResult = query_as("SELECT COUNT(*) AS count FROM booking AS b \
+                    JOIN session AS s ON b.session_id = s.id \
+                    WHERE b.person_id = $1 AND s.cost > 0 AND s.datetime >= now()")
+                .bind(person_id)
+                .fetch_one(&mut *conn)
+                .await
+                .map_err(db_error)?;
+            if active_bookings.count >= max_active_bookings as i64 {
+                info!("person id {} attempted to book session id {} with {} future booking(s); denied: limit reached", person_id, session_date_and_cost.id, active_bookings.count);
+                return Err(BookingRejection::ActiveBookingLimitReached(max_active_bookings));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn check_limited_member_has_no_bookings_in_same_week(conn: &mut PgConnection, timezone: &Tz, week_start: Weekday, uid: i64, session_date_and_cost: &SessionDateAndCost) -> Result<(), BookingRejection> {
+    // Can always book a zero-cost session even if you already have other bookings.
+    if session_date_and_cost.cost == 0 {
+        return Ok(());
+    }
+
+    // Get the date/time of the session and work out the start and end of the week that the session occurs in
+    let datetime_in_local = timezone.from_utc_datetime(&session_date_and_cost.datetime.naive_utc());
+    let (start_of_week_local, end_of_week_local) = week_bounds(datetime_in_local, week_start);
+
+    // Find other bookings in the same week (only sessions with nonzero cost). Cancelled bookings are deleted,
+    // with only a record kept in the cancellation table, so they never use up the week's booking.
+    let existing_bookings: Vec<MemberExistingBooking> = query_as("SELECT b.person_id AS person_id, b.session_id AS session_id, s.datetime AS datetime, s.cost AS cost \
+            FROM booking AS b \
+            JOIN session AS s ON b.session_id = s.id \
+            WHERE b.person_id = $1 \
+            AND s.cost > 0 \
+            AND s.datetime >= $2 \
+            AND s.datetime < $3")
+        .bind(uid)
+        .bind(start_of_week_local)
+        .bind(end_of_week_local)
+        .fetch_all(conn)
+        .await
+        .map_err(db_error)?;
+
+    // Error if there is at least one existing booking
+    if !existing_bookings.is_empty() {
+        return Err(BookingRejection::WeeklyLimitReached(existing_bookings.len()));
+    }
+
+    Ok(())
+}
+
+async fn book_session_no_max_bookings(conn: &mut PgConnection, person_id: i64, session_id: i64, credits_used: i16, reference: &str) -> Result<(), Custom<String>> {
+    query_as("INSERT INTO booking (person_id, session_id, credits_used, reference) VALUES ($1, $2, $3, $4) RETURNING person_id, session_id")
+        .bind(person_id)
+        .bind(session_id)
+        .bind(credits_used)
+        .bind(reference)
+        .fetch_one(conn)
+        .await
+        .map_err(db_error)
+}
+
+#[derive(FromRow)]
+struct SessionWithMaxBookingCount {
+    id: i64,
+    max_booking_count: Option<i64>
+}
+
+
+async fn book_session_with_max_bookings(conn: &mut PgConnection, person_id: i64, session_id: i64, max_bookings: i64, credits_used: i16, reference: &str) -> Result<(), Custom<String>> {
+    // Insert a new booking if and only if the count of bookings for the referenced session is less than
+    // the maximum, locking the session row until the end of the transaction so that concurrent bookings
+    // cannot both see the last space. Adapted from this StackOverflow answer: https://dba.stackexchange.com/a/167283
+    let _: Option<SessionWithMaxBookingCount> = query_as("SELECT id, max_booking_count FROM session WHERE id = $1 FOR NO KEY UPDATE")
+        .bind(session_id)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(db_error)?;
+    let insert_result = query("INSERT INTO booking (person_id, session_id, credits_used, reference) \
+            SELECT $1, $2, $3, $5 FROM booking \
+            WHERE session_id = $2 \
+            HAVING count(*) < $4 \
+            ON CONFLICT DO NOTHING")
+        .bind(person_id)
+        .bind(session_id)
+        .bind(credits_used)
+        .bind(max_bookings)
+        .bind(reference)
+        .execute(&mut *conn)
+        .await
+        .map_err(db_error)?;
+    info!("Insert result: {:?}", insert_result);
+
+    if insert_result.rows_affected() == 0 {
+        // Say when the last space was taken, so that anyone who thinks they booked first can see otherwise
+        let last_booked: Option<DateTime<Utc>> = query_scalar("SELECT max(created_at) FROM booking WHERE session_id = $1")
+            .bind(session_id)
+            .fetch_one(&mut *conn)
             .await
-            .unwrap();
-        record.count
+            .map_err(db_error)?;
+        info!("person id {} could not book full session id {}; last booking made at {:?}", person_id, session_id, last_booked);
+        let Custom(status, message) = BookingRejection::SessionFull(max_bookings).into();
+        return Err(match last_booked {
+            Some(last_booked) => Custom(status, format!("{} The last space was booked at {}.", message, last_booked.to_rfc3339())),
+            None => Custom(status, message)
+        });
+    }
+    Ok(())
+}
+
+async fn get_session_date_and_cost(pool: &PgPool, session_id: &i64) -> Result<SessionDateAndCost, Custom<String>> {
+    query_as("SELECT s.id, s.datetime, s.cost, NOT (COALESCE(t.requires_trainer, true) AND s.trainer IS NULL) AS bookable, s.private, \
+            s.booking_opens_at, s.booking_closes_at, \
+            t.prerequisite_session_type_id, pre.name AS prerequisite_session_type_name, t.requires_approval, \
+            t.id AS session_type_id, t.requires_waiver, t.waiver_url \
+            FROM session AS s JOIN session_type AS t ON s.session_type = t.id \
+            LEFT JOIN session_type AS pre ON t.prerequisite_session_type_id = pre.id \
+            WHERE s.id = $1")
+        .bind(&session_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("no session with id {}", &session_id)))
+}
+
+/// Optional `reason` and `refund` query parameters for cancelling a booking
+#[derive(FromForm, Debug)]
+pub struct BookingCancellation {
+    reason: Option<String>,
+    /// Admins can cancel without refunding the credits used, e.g. for a no-show, and the credits are forfeited
+    #[field(default = true)]
+    refund: bool
+}
+
+impl Default for BookingCancellation {
+    fn default() -> Self {
+        BookingCancellation { reason: None, refund: true }
+    }
+}
+
+/// Cancels a booking. The optional reason is kept in the cancellation log for reporting.
+#[delete("/bookings?<session_id>&<person_id>&<cancellation..>")]
+pub async fn delete_booking(state: &State<AppState>, claim: Claims, override_requested: OverrideRequested, person_id: i64, session_id: i64, cancellation: BookingCancellation) -> Result<Json<SessionBooking>, Custom<String>> {
+    _delete_booking(&state.pool, &claim, state.config.cancellation_cutoff_mins, override_requested.0, person_id, session_id, cancellation).await
+}
+
+async fn _delete_booking(pool: &PgPool, claim: &Claims, cancellation_cutoff_mins: Option<u32>, override_requested: bool, person_id: i64, session_id: i64, cancellation: BookingCancellation) -> Result<Json<SessionBooking>, Custom<String>> {
+    if !claim.has_role("admin") && person_id != claim.uid {
+        return Err(Custom(Status::Forbidden, "Not allowed to cancel bookings for other users.".to_string()));
+    }
+    // Members' own cancellations are always refunded
+    if !cancellation.refund && !claim.has_role(ROLE_ADMIN) {
+        info!("person id {} asked to cancel without a refund; ignored: missing admin role", claim.uid);
+    }
+    let refund = cancellation.refund || !claim.has_role(ROLE_ADMIN);
+    let admin_override = admin_override(claim, override_requested);
+    if !admin_override {
+        let session_datetime = get_session_date_and_cost(pool, &session_id).await?.datetime;
+        check_cancellable(session_datetime, cancellation_cutoff_for(claim, cancellation_cutoff_mins))?;
+    }
+    let mut tx = pool.begin()
+        .await
+        .map_err(db_error)?;
+    let booking_deleted: SessionBooking = query_as("DELETE FROM booking WHERE person_id = $1 AND session_id = $2 RETURNING person_id, session_id, credits_used")
+        .bind(person_id)
+        .bind(session_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("No booking found with person_id={} and session_id={}.", person_id, session_id)))?;
+
+    // Restore the credits used for this booking, unless they are forfeited
+    let credits_used = booking_deleted.credits_used.unwrap_or(0);
+    if refund && credits_used > 0 {
+        query_as("UPDATE person SET credits = credits + $1 WHERE id = $2 RETURNING id, credits")
+            .bind(credits_used)
+            .bind(person_id)
+            .fetch_one(&mut *tx)
+            .await.map_err(db_error)?;
+    }
+    let credits_forfeited = if refund { 0 } else { credits_used };
+    if credits_forfeited > 0 {
+        info!("person id {} forfeits {} credit(s) for cancelled booking on session id {}", person_id, credits_forfeited, session_id);
+    }
+    let reason = cancellation.reason.map(|r| r.trim().to_string()).filter(|r| !r.is_empty());
+    query("INSERT INTO cancellation (person_id, session_id, reason, credits_forfeited) VALUES ($1, $2, $3, $4)")
+        .bind(person_id)
+        .bind(session_id)
+        .bind(reason)
+        .bind(credits_forfeited)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    tx.commit()
+        .await
+        .map_err(db_error)?;
+    if admin_override {
+        audit::record(pool, claim.uid, "override_delete_booking", format!("booking person {} session {}", person_id, session_id)).await;
+    } else if claim.uid != person_id {
+        audit::record(pool, claim.uid, "delete_booking", format!("booking person {} session {}", person_id, session_id)).await;
+    }
+
+    Ok(Json(booking_deleted))
+}
+
+#[delete("/bookings/mine?<from>&<to>&<person_id>")]
+pub async fn delete_bookings_in_range(
+    state: &State<AppState>,
+    claim: Claims,
+    from: Option<String>,
+    to: Option<String>,
+    person_id: Option<i64>
+) -> Result<Json<CountResult>, Custom<String>> {
+    _delete_bookings_in_range(&state.pool, &claim, state.config.cancellation_cutoff_mins, person_id.unwrap_or(claim.uid), from, to).await
+}
+
+async fn _delete_bookings_in_range(
+    pool: &PgPool,
+    claim: &Claims,
+    cancellation_cutoff_mins: Option<u32>,
+    person_id: i64,
+    from: Option<String>,
+    to: Option<String>
+) -> Result<Json<CountResult>, Custom<String>> {
+    let mut qb = QueryBuilder::new("DELETE FROM booking AS b USING session AS s WHERE b.session_id = s.id AND b.person_id = ");
+    qb.push_bind(person_id);
+
+    if !claim.has_role(ROLE_ADMIN) {
+        if person_id != claim.uid {
+            return Err(Custom(Status::Forbidden, "Not allowed to cancel bookings for other users.".to_string()));
+        }
+        // Past bookings, and those within the cancellation cutoff, cannot be cancelled
+        qb.push(" AND s.datetime >= ");
+        qb.push_bind(Utc::now() + TimeDelta::minutes(cancellation_cutoff_mins.unwrap_or_default() as i64));
+    }
+    if let Some(from) = parse_opt_date(from)? {
+        qb.push(" AND s.datetime >= ");
+        qb.push_bind(from);
+    }
+    if let Some(to) = parse_opt_date(to)? {
+        qb.push(" AND s.datetime <= ");
+        qb.push_bind(to);
+    }
+    qb.push(" RETURNING b.person_id, b.session_id, b.credits_used");
+    info!("delete_bookings_in_range compiled SQL: {}", qb.sql());
+
+    let mut tx = pool.begin()
+        .await
+        .map_err(db_error)?;
+    let bookings_deleted: Vec<SessionBooking> = qb.build_query_as()
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+    // Restore the credits used for all of the cancelled bookings
+    let credits_used: i16 = bookings_deleted.iter()
+        .map(|b| b.credits_used.unwrap_or(0))
+        .sum();
+    if credits_used > 0 {
+        query_as("UPDATE person SET credits = credits + $1 WHERE id = $2 RETURNING id, credits")
+            .bind(credits_used)
+            .bind(person_id)
+            .fetch_one(&mut *tx)
+            .await.map_err(db_error)?;
+    }
+    for booking in &bookings_deleted {
+        query("INSERT INTO cancellation (person_id, session_id) VALUES ($1, $2)")
+            .bind(booking.person_id)
+            .bind(booking.session_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(db_error)?;
+    }
+    tx.commit()
+        .await
+        .map_err(db_error)?;
+    info!("Cancelled {} booking(s) for person id {}, restoring {} credit(s)", bookings_deleted.len(), person_id, credits_used);
+    if claim.uid != person_id {
+        audit::record(pool, claim.uid, "delete_bookings", format!("{} booking(s) of person {}", bookings_deleted.len(), person_id)).await;
+    }
+
+    Ok(Json(CountResult { count: bookings_deleted.len() as i64 }))
+}
+
+#[derive(FromRow, Serialize, Debug)]
+pub struct Cancellation {
+    person_id: i64,
+    person_name: String,
+    /// Missing if the session itself has since been cancelled
+    session_id: Option<i64>,
+    session_datetime: Option<DateTime<Utc>>,
+    session_type: Option<String>,
+    reason: Option<String>,
+    credits_forfeited: i16,
+    cancelled: DateTime<Utc>
+}
+
+#[get("/bookings/cancellations?<from>&<to>&<page..>")]
+pub async fn list_cancellations(
+    state: &State<AppState>,
+    claim: Claims,
+    from: Option<String>,
+    to: Option<String>,
+    page: Page
+) -> Result<Json<Vec<Cancellation>>, Custom<String>> {
+    claim.assert_roles_contains(ROLE_ADMIN)?;
+    _list_cancellations(&state.pool, parse_opt_date(from)?, parse_opt_date(to)?, page).await
+        .map(Json)
+}
+
+async fn _list_cancellations(
+    pool: &PgPool,
+    from: Option<DateTime<FixedOffset>>,
+    to: Option<DateTime<FixedOffset>>,
+    page: Page
+) -> Result<Vec<Cancellation>, Custom<String>> {
+    let mut qb: QueryBuilder<Postgres> = QueryBuilder::new("SELECT c.person_id, p.name AS person_name, c.session_id, \
+        s.datetime AS session_datetime, t.name AS session_type, c.reason, c.credits_forfeited, c.cancelled \
+        FROM cancellation AS c \
+        JOIN person AS p ON c.person_id = p.id \
+        LEFT JOIN session AS s ON c.session_id = s.id \
+        LEFT JOIN session_type AS t ON s.session_type = t.id");
+    let mut where_op = " WHERE";
+    if let Some(from) = from {
+        qb.push(where_op).push(" c.cancelled >= ").push_bind(from);
+        where_op = " AND";
+    }
+    if let Some(to) = to {
+        qb.push(where_op).push(" c.cancelled <= ").push_bind(to);
+    }
+    qb.push(" ORDER BY c.cancelled DESC, c.id DESC");
+    page.push_limit_offset(&mut qb)?;
+
+    qb.build_query_as()
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)
+}
+
+/// An event in a member's booking history. Cancelled bookings are deleted, so they appear only as
+/// a cancellation, and attendance recorded before `attended_at` was added has no time so is left out.
+#[derive(Serialize, FromRow, Debug)]
+pub struct TimelineEvent {
+    /// One of `booked`, `attended` or `cancelled`
+    event: String,
+    at: DateTime<Utc>,
+    /// Missing for bookings cancelled along with their session
+    session_id: Option<i64>,
+    session_datetime: Option<DateTime<Utc>>,
+    session_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>
+}
+
+/// Lists a member's bookings, attendances and cancellations in the order they happened.
+/// Members can only see their own timeline.
+#[get("/users/<person_id>/timeline?<from>&<to>&<page..>")]
+pub async fn get_timeline(
+    state: &State<AppState>,
+    claim: Claims,
+    person_id: i64,
+    from: Option<String>,
+    to: Option<String>,
+    page: Page
+) -> Result<Json<Vec<TimelineEvent>>, Custom<String>> {
+    if claim.uid != person_id && !claim.has_role(ROLE_ADMIN) {
+        return Err(Custom(Status::Forbidden, "Not allowed to view the timeline of other users.".to_string()));
+    }
+    _get_timeline(&state.pool, person_id, parse_opt_date(from)?, parse_opt_date(to)?, page).await
+        .map(Json)
+}
+
+async fn _get_timeline(
+    pool: &PgPool,
+    person_id: i64,
+    from: Option<DateTime<FixedOffset>>,
+    to: Option<DateTime<FixedOffset>>,
+    page: Page
+) -> Result<Vec<TimelineEvent>, Custom<String>> {
+    let mut qb: QueryBuilder<Postgres> = QueryBuilder::new("SELECT e.event, e.at, e.session_id, s.datetime AS session_datetime, t.name AS session_type, e.reason \
+        FROM (SELECT 'booked' AS event, 0 AS seq, created_at AS at, session_id, NULL::text AS reason FROM booking WHERE person_id = ");
+    qb.push_bind(person_id);
+    qb.push(" UNION ALL SELECT 'attended', 1, attended_at, session_id, NULL FROM booking WHERE attended_at IS NOT NULL AND person_id = ");
+    qb.push_bind(person_id);
+    qb.push(" UNION ALL SELECT 'cancelled', 2, cancelled, session_id, reason FROM cancellation WHERE person_id = ");
+    qb.push_bind(person_id);
+    qb.push(") AS e \
+        LEFT JOIN session AS s ON e.session_id = s.id \
+        LEFT JOIN session_type AS t ON s.session_type = t.id");
+    let mut where_op = " WHERE";
+    if let Some(from) = from {
+        qb.push(where_op).push(" e.at >= ").push_bind(from);
+        where_op = " AND";
+    }
+    if let Some(to) = to {
+        qb.push(where_op).push(" e.at <= ").push_bind(to);
+    }
+    qb.push(" ORDER BY e.at, e.seq, e.session_id");
+    page.push_limit_offset(&mut qb)?;
+
+    qb.build_query_as()
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BookingTransfer {
+    session_id: i64,
+    from_person_id: i64,
+    to_person_id: i64
+}
+
+/// Moves a booking from one member to another, e.g. for a substitution. Any credits used are refunded
+/// to the original member and charged to the new member instead.
+#[post("/bookings/transfer", data="<transfer>")]
+pub async fn transfer_booking(state: &State<AppState>, claim: Claims, transfer: JsonBody<BookingTransfer>) -> Result<Json<SessionBooking>, Custom<String>> {
+    _transfer_booking(&state.pool, &claim, Json(transfer.into_inner())).await
+}
+
+async fn _transfer_booking(pool: &PgPool, claim: &Claims, transfer: Json<BookingTransfer>) -> Result<Json<SessionBooking>, Custom<String>> {
+    if !claim.has_role(ROLE_ADMIN) {
+        return Err(Custom(Status::Forbidden, "Only admins can transfer bookings.".to_string()));
+    }
+    if transfer.from_person_id == transfer.to_person_id {
+        return Err(Custom(Status::BadRequest, "Cannot transfer a booking to the same member.".to_string()));
+    }
+
+    let mut tx = pool.begin()
+        .await
+        .map_err(db_error)?;
+
+    let target_booking: Option<SessionBooking> = query_as("SELECT person_id, session_id, credits_used FROM booking WHERE person_id = $1 AND session_id = $2")
+        .bind(transfer.to_person_id)
+        .bind(transfer.session_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    if target_booking.is_some() {
+        return Err(Custom(Status::Conflict, format!("Person id {} already has a booking for session id {}.", transfer.to_person_id, transfer.session_id)));
+    }
+
+    // Reassigning the existing booking leaves the number of bookings for the session unchanged. The original member's
+    // attendance and check-in attempts do not carry over to the new member.
+    let booking_transferred: SessionBooking = query_as("UPDATE booking SET person_id = $1, attended = false, attended_at = NULL, checkin_attempts = 0 \
+            WHERE person_id = $2 AND session_id = $3 RETURNING person_id, session_id, credits_used")
+        .bind(transfer.to_person_id)
+        .bind(transfer.from_person_id)
+        .bind(transfer.session_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("No booking found with person_id={} and session_id={}.", transfer.from_person_id, transfer.session_id)))?;
+
+    // Refund the original member and charge the new member
+    let credits_used = booking_transferred.credits_used.unwrap_or(0);
+    if credits_used > 0 {
+        query_as("UPDATE person SET credits = credits + $1 WHERE id = $2 RETURNING id, credits")
+            .bind(credits_used)
+            .bind(transfer.from_person_id)
+            .fetch_one(&mut *tx)
+            .await.map_err(db_error)?;
+        let charged: Option<(i64, i16)> = query_as("UPDATE person SET credits = credits - $1 WHERE id = $2 AND credits >= $1 RETURNING id, credits")
+            .bind(credits_used)
+            .bind(transfer.to_person_id)
+            .fetch_optional(&mut *tx)
+            .await.map_err(db_error)?;
+        if charged.is_none() {
+            return Err(Custom(Status::PaymentRequired, format!("Person id {} does not have the {} credit(s) required for this booking.", transfer.to_person_id, credits_used)));
+        }
+    }
+    tx.commit()
+        .await
+        .map_err(db_error)?;
+    info!("Transferred booking for session id {} from person id {} to person id {}", transfer.session_id, transfer.from_person_id, transfer.to_person_id);
+    audit::record(pool, claim.uid, "transfer_booking", format!("booking session {} from person {} to person {}", transfer.session_id, transfer.from_person_id, transfer.to_person_id)).await;
+
+    Ok(Json(booking_transferred))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BookingSwap {
+    from_session_id: i64,
+    to_session_id: i64,
+    /// As for a new booking, credits are only used for the new session with the member's agreement
+    #[serde(default)]
+    credits_used: Option<i16>
+}
+
+/// Moves the caller's booking from one session to another in one step, so that they only give up their
+/// place if the other session can be booked. Within the transaction the original booking is released first,
+/// so that it does not count against the member's booking limits, and its credits are refunded before those
+/// for the new session are taken.
+#[post("/bookings/swap", data="<swap>")]
+pub async fn swap_booking(state: &State<AppState>, claim: Claims, swap: JsonBody<BookingSwap>) -> Result<Json<BookingCreated>, Custom<String>> {
+    _swap_booking(&state.pool, &state.timezone, &state.config, &claim, swap.into_inner()).await
+}
+
+async fn _swap_booking(pool: &PgPool, timezone: &Tz, config: &Config, claim: &Claims, swap: BookingSwap) -> Result<Json<BookingCreated>, Custom<String>> {
+    if swap.from_session_id == swap.to_session_id {
+        return Err(Custom(Status::BadRequest, "Cannot swap a booking to the same session.".to_string()));
+    }
+    let from_session = get_session_date_and_cost(pool, &swap.from_session_id).await?;
+    check_cancellable(from_session.datetime, config.cancellation_cutoff_mins)?;
+    let to_session = get_session_date_and_cost(pool, &swap.to_session_id).await?;
+    if to_session.requires_approval && !claim.has_role(ROLE_ADMIN) {
+        return Err(Custom(Status::Forbidden, "Session requires approval: book it instead of swapping onto it.".to_string()));
+    }
+
+    let mut tx = pool.begin()
+        .await
+        .map_err(db_error)?;
+    let refund: Option<i16> = query_scalar("DELETE FROM booking WHERE person_id = $1 AND session_id = $2 RETURNING credits_used")
+        .bind(claim.uid)
+        .bind(swap.from_session_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("No booking found with person_id={} and session_id={}.", claim.uid, swap.from_session_id)))?;
+    if refund.unwrap_or(0) > 0 {
+        query("UPDATE person SET credits = credits + $1 WHERE id = $2")
+            .bind(refund)
+            .bind(claim.uid)
+            .execute(&mut *tx)
+            .await
+            .map_err(db_error)?;
+    }
+    let already_booked: bool = query_scalar("SELECT EXISTS (SELECT 1 FROM booking WHERE person_id = $1 AND session_id = $2)")
+        .bind(claim.uid)
+        .bind(swap.to_session_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    if already_booked {
+        return Err(BookingRejection::AlreadyBooked.into());
+    }
+
+    // The new booking is made just as the member would make it themselves
+    let mut credits_cost: i16 = 0;
+    if claim.has_role(ROLE_ADMIN) {
+        check_session_not_past(claim, &to_session)?;
+    } else if let BookingPayment::Credits(cost) = check_booking_eligibility(&mut tx, timezone, config, claim, &to_session).await? {
+        if swap.credits_used.unwrap_or(0) < cost && !auto_use_credits(&mut tx, claim.uid).await? {
+            return Err(BookingRejection::CreditsOptInRequired.into());
+        }
+        credits_cost = cost;
+    }
+    let max_booking_count: Option<i64> = query_scalar("SELECT max_booking_count FROM session WHERE id = $1")
+        .bind(swap.to_session_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    let reference = generate_booking_reference(&mut tx).await?;
+    match max_booking_count {
+        Some(max_booking_count) => book_session_with_max_bookings(&mut tx, claim.uid, swap.to_session_id, max_booking_count, credits_cost, &reference).await,
+        None => book_session_no_max_bookings(&mut tx, claim.uid, swap.to_session_id, credits_cost, &reference).await
+    }?;
+    if credits_cost > 0 {
+        debit_credits(&mut tx, claim.uid, credits_cost).await?;
+    }
+    query("INSERT INTO cancellation (person_id, session_id, reason) VALUES ($1, $2, $3)")
+        .bind(claim.uid)
+        .bind(swap.from_session_id)
+        .bind(format!("Swapped to session {}", swap.to_session_id))
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    tx.commit()
+        .await
+        .map_err(db_error)?;
+    info!("person id {} swapped their booking from session id {} to session id {}", claim.uid, swap.from_session_id, swap.to_session_id);
+
+    let booking = SessionBooking { person_id: claim.uid, session_id: swap.to_session_id, credits_used: Some(credits_cost) };
+    Ok(Json(BookingCreated { booking, reference }))
+}
+
+#[derive(Deserialize)]
+pub struct BookingUpdate {
+    attended: bool,
+    /// Corrects the credits used after the event, e.g. to partly refund a member who left early.
+    /// The member's balance is adjusted by the difference.
+    #[serde(default)]
+    credits_used: Option<i16>
+}
+
+#[put("/bookings?<session_id>&<person_id>", data="<booking_update>")]
+pub async fn update_booking(state: &State<AppState>, claim: Claims, person_id: i64, session_id: i64, booking_update: JsonBody<BookingUpdate>) -> Result<NoContent, Custom<String>> {
+    _update_booking(&state.pool, &claim, state.config.attendance_lock_days, person_id, session_id, Json(booking_update.into_inner())).await
+}
+
+async fn _update_booking(pool: &PgPool, claim: &Claims, attendance_lock_days: Option<u32>, person_id: i64, session_id: i64, booking_update: Json<BookingUpdate>) -> Result<NoContent, Custom<String>> {
+    claim.assert_roles_contains("admin")?;
+
+    // Attendance of sessions older than the lock period can only be changed by super-admins
+    if let Some(lock_days) = attendance_lock_days {
+        if !claim.has_role(ROLE_SUPER_ADMIN) {
+            let session_date_and_cost = get_session_date_and_cost(pool, &session_id).await?;
+            if session_date_and_cost.datetime.add(TimeDelta::days(lock_days as i64)).lt(&Utc::now()) {
+                return Err(Custom(Status::Forbidden, format!("Attendance for sessions more than {} days ago can no longer be changed.", lock_days)));
+            }
+        }
+    }
+
+    if booking_update.credits_used.is_some_and(|credits_used| credits_used < 0) {
+        return Err(Custom(Status::BadRequest, "Credits used cannot be negative.".to_string()));
+    }
+
+    let mut tx = pool.begin()
+        .await
+        .map_err(db_error)?;
+    let booking: SessionBooking = query_as("SELECT person_id, session_id, credits_used FROM booking WHERE person_id = $1 AND session_id = $2 FOR UPDATE")
+        .bind(person_id)
+        .bind(session_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("No booking found with person_id={} and session_id={}.", person_id, session_id)))?;
+    let previous_credits_used = booking.credits_used.unwrap_or(0);
+    let credits_used = booking_update.credits_used.unwrap_or(previous_credits_used);
+
+    // Credits used are left as they were if not given, even if they were never recorded
+    query("UPDATE booking SET attended = $1, attended_at = CASE WHEN $1 THEN COALESCE(attended_at, now()) END, credits_used = $2 \
+            WHERE person_id = $3 AND session_id = $4")
+        .bind(booking_update.attended)
+        .bind(booking_update.credits_used.or(booking.credits_used))
+        .bind(person_id)
+        .bind(session_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+    // Refund any credits no longer used, or charge any extra credits used
+    let refund = previous_credits_used - credits_used;
+    if refund != 0 {
+        let reconciled: Option<(i64, i16)> = query_as("UPDATE person SET credits = credits + $1 WHERE id = $2 AND credits + $1 >= 0 RETURNING id, credits")
+            .bind(refund)
+            .bind(person_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(db_error)?;
+        if reconciled.is_none() {
+            return Err(Custom(Status::PaymentRequired, format!("Person id {} does not have the {} extra credit(s) required.", person_id, -refund)));
+        }
+    }
+    tx.commit()
+        .await
+        .map_err(db_error)?;
+
+    audit::record(pool, claim.uid, "update_attendance", format!("booking person {} session {} attended {}", person_id, session_id, booking_update.attended)).await;
+    if refund != 0 {
+        audit::record(pool, claim.uid, "update_credits_used", format!("booking person {} session {} credits used {} -> {}", person_id, session_id, previous_credits_used, credits_used)).await;
+    }
+    Ok(NoContent)
+}
+
+#[derive(Serialize, Debug)]
+pub struct CheckinCode {
+    code: String
+}
+
+/// The code for members to check in to a session, for admins or the session's trainer to show to them.
+/// The code is created the first time it is asked for.
+#[get("/sessions/<session_id>/checkin_code")]
+pub async fn get_checkin_code(state: &State<AppState>, claim: Claims, session_id: i64) -> Result<Json<CheckinCode>, Custom<String>> {
+    _get_checkin_code(&state.pool, &claim, session_id).await
+}
+
+async fn _get_checkin_code(pool: &PgPool, claim: &Claims, session_id: i64) -> Result<Json<CheckinCode>, Custom<String>> {
+    if !claim.has_role(ROLE_ADMIN) && !claim.has_role(ROLE_TRAINER) {
+        return Err(Custom(Status::Forbidden, "only admins and trainers can view check-in codes".to_string()));
+    }
+    let new_code = CHECKIN_CODE_GENERATOR.generate_one()
+        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+    let code: String = query_scalar("UPDATE session SET checkin_code = COALESCE(checkin_code, $2) \
+            WHERE id = $1 AND ($3 OR trainer = $4) \
+            RETURNING checkin_code")
+        .bind(session_id)
+        .bind(new_code)
+        .bind(claim.has_role(ROLE_ADMIN))
+        .bind(claim.uid)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("no session with id {} for trainer id {}", session_id, claim.uid)))?;
+    Ok(Json(CheckinCode { code }))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Checkin {
+    code: String
+}
+
+/// Marks the caller's own booking as attended, given the code shown at the session
+#[post("/sessions/<session_id>/checkin", data="<checkin>")]
+pub async fn checkin(state: &State<AppState>, claim: Claims, session_id: i64, checkin: JsonBody<Checkin>) -> Result<NoContent, Custom<String>> {
+    _checkin(&state.pool, &claim, session_id, &checkin).await
+}
+
+async fn _checkin(pool: &PgPool, claim: &Claims, session_id: i64, checkin: &Checkin) -> Result<NoContent, Custom<String>> {
+    let (datetime, duration_mins, checkin_code): (DateTime<Utc>, i32, Option<String>) = query_as("SELECT datetime, duration_mins, checkin_code FROM session WHERE id = $1")
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("no session with id {}", session_id)))?;
+
+    let opens = datetime - CHECKIN_WINDOW;
+    let closes = datetime + TimeDelta::minutes(duration_mins as i64) + CHECKIN_WINDOW;
+    let now = Utc::now();
+    if now < opens || now > closes {
+        return Err(Custom(Status::Forbidden, format!("Check-in for this session is open from {} to {}.", opens.to_rfc3339(), closes.to_rfc3339())));
+    }
+
+    // Count the attempt before checking the code, so that failed attempts count even if the request is abandoned
+    let checkin_attempts: i32 = query_scalar("UPDATE booking SET checkin_attempts = checkin_attempts + 1 WHERE person_id = $1 AND session_id = $2 RETURNING checkin_attempts")
+        .bind(claim.uid)
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("No booking found for person id {} on session id {}.", claim.uid, session_id)))?;
+    if checkin_attempts > CHECKIN_MAX_ATTEMPTS {
+        info!("person id {} has made too many check-in attempts for session id {}", claim.uid, session_id);
+        return Err(Custom(Status::Forbidden, "Too many check-in attempts: ask the trainer to mark your attendance.".to_string()));
+    }
+    if checkin_code.as_deref() != Some(checkin.code.trim()) {
+        info!("person id {} gave an incorrect check-in code for session id {}", claim.uid, session_id);
+        return Err(Custom(Status::Forbidden, "Incorrect check-in code.".to_string()));
+    }
+
+    query("UPDATE booking SET attended = true, attended_at = COALESCE(attended_at, now()) WHERE person_id = $1 AND session_id = $2")
+        .bind(claim.uid)
+        .bind(session_id)
+        .execute(pool)
+        .await
+        .map_err(db_error)?;
+    info!("person id {} checked in to session id {}", claim.uid, session_id);
+    Ok(NoContent)
+}
+
+#[derive(Serialize, FromRow)]
+pub struct AttendanceStat {
+    person_id: i64,
+    name: String,
+    email: String,
+    attended_count: i64
+}
+
+#[derive(Responder)]
+pub enum AttendanceStats {
+    Json(Json<Vec<AttendanceStat>>),
+    Csv(CsvDownload)
+}
+
+/// Lists the members who attended the most sessions, as JSON or, with `format=csv`, as a CSV download
+#[get("/stats/attendance?<from>&<to>&<session_type>&<format>")]
+pub async fn get_attendance_stats(state: &State<AppState>, claim: Claims, from: Option<String>, to: Option<String>, session_type: Vec<i32>, format: Option<String>) -> Result<AttendanceStats, Custom<String>> {
+    claim.assert_roles_contains("admin")?;
+    let mut qb = QueryBuilder::new("\
+        SELECT p.id AS person_id, p.name AS name, p.email AS email, ( \
+            SELECT COUNT(*) \
+            FROM booking \
+            JOIN session ON booking.session_id = session.id \
+            WHERE booking.person_id = p.id \
+            AND booking.attended = TRUE ");
+
+    if let Some(from) = parse_opt_date(from)? {
+        qb.push(" AND session.datetime >= ");
+        qb.push_bind(from);
+    }
+    if let Some(to) = parse_opt_date(to)? {
+        qb.push(" AND session.datetime <= ");
+        qb.push_bind(to);
+    }
+
+    if !session_type.is_empty() {
+        let session_types_str = session_type.into_iter()
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        qb.push(" AND session.session_type IN (");
+        qb.push(session_types_str);
+        qb.push(")");
+    } else {
+        // Cannot write "IN ()" so we create a clause that is always false
+        qb.push(" AND FALSE");
+    }
+
+
+    qb.push(") AS attended_count \
+        FROM person AS p \
+        ORDER BY attended_count DESC, name \
+        LIMIT 10");
+    info!("fetching: {}", qb.sql());
+
+    let mut tx = begin_with_timeout(&state.pool, &state.config).await?;
+    let stats = qb.build_query_as()
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(query_error)?;
+    tx.commit().await.map_err(query_error)?;
+
+    match format.as_deref() {
+        None | Some("json") => Ok(AttendanceStats::Json(Json(stats))),
+        Some("csv") => {
+            let rows = stats.into_iter()
+                .map(|s: AttendanceStat| vec![s.person_id.to_string(), s.name, s.email, s.attended_count.to_string()])
+                .collect();
+            Ok(AttendanceStats::Csv(CsvDownload::new("attendance.csv", &["person_id", "name", "email", "attended_count"], rows)))
+        },
+        Some(format) => Err(Custom(Status::BadRequest, format!("unsupported format '{}', must be json or csv", format)))
+    }
+}
+
+#[derive(Serialize, FromRow)]
+pub struct OccupancyStat {
+    session_type_id: i32,
+    session_type_name: String,
+    session_count: i64,
+    booking_count: i64,
+    capacity: i64,
+    /// Mean of each session's bookings as a fraction of its capacity
+    utilization: f64
+}
+
+/// How full each type of session runs on average. Sessions with unlimited capacity are left out.
+#[get("/stats/occupancy?<from>&<to>")]
+pub async fn get_occupancy_stats(state: &State<AppState>, claim: Claims, from: Option<String>, to: Option<String>) -> Result<Json<Vec<OccupancyStat>>, Custom<String>> {
+    claim.assert_roles_contains("admin")?;
+    let mut qb = QueryBuilder::new("\
+        SELECT t.id AS session_type_id, t.name AS session_type_name, COUNT(*) AS session_count, \
+            SUM(c.booking_count)::int8 AS booking_count, SUM(s.max_booking_count)::int8 AS capacity, \
+            AVG(c.booking_count::float8 / s.max_booking_count)::float8 AS utilization \
+        FROM session AS s \
+        JOIN session_type AS t ON s.session_type = t.id, \
+        LATERAL (SELECT COUNT(*) AS booking_count FROM booking WHERE booking.session_id = s.id) AS c \
+        WHERE s.max_booking_count > 0");
+    if let Some(from) = parse_opt_date(from)? {
+        qb.push(" AND s.datetime >= ");
+        qb.push_bind(from);
+    }
+    if let Some(to) = parse_opt_date(to)? {
+        qb.push(" AND s.datetime <= ");
+        qb.push_bind(to);
+    }
+    qb.push(" GROUP BY t.id, t.name ORDER BY utilization DESC, t.name");
+
+    let mut tx = begin_with_timeout(&state.pool, &state.config).await?;
+    let stats = qb.build_query_as()
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(query_error)?;
+    tx.commit().await.map_err(query_error)?;
+    Ok(Json(stats))
+}
+
+/// Capacity is suggested from this percentile of the bookings that a slot's sessions have had...
+const CAPACITY_SUGGESTION_PERCENTILE: f64 = 0.9;
+/// ...plus this much headroom
+const CAPACITY_SUGGESTION_HEADROOM: f64 = 1.1;
+
+#[derive(Serialize, FromRow, Debug)]
+pub struct CapacitySuggestion {
+    session_type_id: i32,
+    session_type_name: String,
+    /// Local start time of the sessions, as HH:MM
+    time_slot: String,
+    session_count: i64,
+    average_occupancy: f64,
+    peak_occupancy: i64,
+    /// The largest capacity of the slot's sessions, or null if they were all unlimited
+    current_max_booking_count: Option<i64>,
+    suggested_max_booking_count: i64
+}
+
+/// Suggests a capacity for each session type at each time of day, from how many bookings its past sessions
+/// had. Slots whose suggestion differs most from their current capacity come first.
+#[get("/stats/capacity_suggestions?<from>&<to>")]
+pub async fn get_capacity_suggestions(state: &State<AppState>, claim: Claims, from: Option<String>, to: Option<String>) -> Result<Json<Vec<CapacitySuggestion>>, Custom<String>> {
+    _get_capacity_suggestions(&state.pool, &state.config, &state.timezone, &claim, from, to).await.map(Json)
+}
+
+async fn _get_capacity_suggestions(pool: &PgPool, config: &Config, timezone: &Tz, claim: &Claims, from: Option<String>, to: Option<String>) -> Result<Vec<CapacitySuggestion>, Custom<String>> {
+    claim.assert_roles_contains(ROLE_ADMIN)?;
+    let mut qb = QueryBuilder::new("\
+        SELECT * FROM ( \
+            SELECT t.id AS session_type_id, t.name AS session_type_name, \
+                to_char(s.datetime AT TIME ZONE ");
+    qb.push_bind(timezone.name());
+    qb.push(", 'HH24:MI') AS time_slot, COUNT(*) AS session_count, \
+                AVG(c.booking_count)::float8 AS average_occupancy, MAX(c.booking_count) AS peak_occupancy, \
+                NULLIF(MAX(COALESCE(s.max_booking_count, 0)), 0) AS current_max_booking_count, \
+                GREATEST(1, CEIL(percentile_cont(");
+    qb.push_bind(CAPACITY_SUGGESTION_PERCENTILE);
+    qb.push(") WITHIN GROUP (ORDER BY c.booking_count) * ");
+    qb.push_bind(CAPACITY_SUGGESTION_HEADROOM);
+    qb.push("))::int8 AS suggested_max_booking_count \
+            FROM session AS s \
+            JOIN session_type AS t ON s.session_type = t.id, \
+            LATERAL (SELECT COUNT(*) AS booking_count FROM booking WHERE booking.session_id = s.id) AS c \
+            WHERE s.datetime < now()");
+    if let Some(from) = parse_opt_date(from)? {
+        qb.push(" AND s.datetime >= ");
+        qb.push_bind(from);
+    }
+    if let Some(to) = parse_opt_date(to)? {
+        qb.push(" AND s.datetime <= ");
+        qb.push_bind(to);
+    }
+    qb.push(" GROUP BY t.id, t.name, time_slot) AS slots \
+        ORDER BY abs(suggested_max_booking_count - COALESCE(current_max_booking_count, suggested_max_booking_count)) DESC, \
+            session_type_name, time_slot");
+
+    let mut tx = begin_with_timeout(pool, config).await?;
+    let suggestions = qb.build_query_as()
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(query_error)?;
+    tx.commit().await.map_err(query_error)?;
+    Ok(suggestions)
+}
+
+#[derive(Serialize, FromRow, Debug)]
+pub struct DisengagedMember {
+    person_id: i64,
+    name: String,
+    email: String,
+    /// Bookings for sessions since the given date, none of which were attended
+    booking_count: i64,
+    /// Date of the latest session the member booked, at any time, or null if they never have
+    last_booked_session_at: Option<DateTime<Utc>>
+}
+
+/// Lists the members who have not attended any session since a date, whether or not they booked, for
+/// re-engaging them. Admins and trainers are left out. Those who booked most recently come first.
+#[get("/stats/disengaged?<since>")]
+pub async fn get_disengaged_members(state: &State<AppState>, claim: Claims, since: Option<String>) -> Result<Json<Vec<DisengagedMember>>, Custom<String>> {
+    _get_disengaged_members(&state.pool, &state.config, &claim, parse_opt_date(since)?).await.map(Json)
+}
+
+async fn _get_disengaged_members(pool: &PgPool, config: &Config, claim: &Claims, since: Option<DateTime<FixedOffset>>) -> Result<Vec<DisengagedMember>, Custom<String>> {
+    claim.assert_roles_contains(ROLE_ADMIN)?;
+    let mut tx = begin_with_timeout(pool, config).await?;
+    let members = query_as("SELECT p.id AS person_id, p.name, p.email, b.booking_count, b.last_booked_session_at \
+            FROM person AS p, \
+            LATERAL (SELECT COUNT(*) FILTER (WHERE $1::timestamptz IS NULL OR s.datetime >= $1) AS booking_count, \
+                COUNT(*) FILTER (WHERE booking.attended AND ($1::timestamptz IS NULL OR s.datetime >= $1)) AS attended_count, \
+                MAX(s.datetime) AS last_booked_session_at \
+                FROM booking JOIN session AS s ON booking.session_id = s.id \
+                WHERE booking.person_id = p.id) AS b \
+            WHERE b.attended_count = 0 \
+            AND NOT string_to_array(replace(COALESCE(p.roles, ''), ' ', ''), ',') && $2 \
+            ORDER BY b.last_booked_session_at DESC NULLS LAST, p.name, p.id")
+        .bind(since)
+        .bind([ROLE_ADMIN, ROLE_TRAINER])
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(query_error)?;
+    tx.commit().await.map_err(query_error)?;
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Add;
+    use chrono::{DateTime, Datelike, Duration, NaiveTime, TimeDelta, TimeZone, Utc, Weekday};
+    use chrono_tz::Tz;
+    use rocket::futures::StreamExt;
+    use rocket::http::{ContentType, Status};
+    use rocket::serde::json::Json;
+    use rocket::response::status::Custom;
+    use sqlx::{Executor, FromRow, PgPool, query, query_as, query_scalar};
+    use crate::bookings::{ApprovalOutcome, BookingApproval, BookingCancellation, CancelBlockedReason, MembershipStatus, _approve_booking, _delete_booking_request, _explain_bookings, is_session_in_past, session_started_by, _checkin, _delete_booking, _export_bookings, _delete_bookings_in_range, _get_booking, _get_capacity_suggestions, _get_checkin_code, _get_disengaged_members, _get_next_booking, _get_timeline, _list_booking_requests, _list_bookings, _list_cancellations, _preview_booking, _request_booking, _swap_booking, _transfer_booking, _update_booking, BookingSwap, BookingTransfer, BookingUpdate, Checkin, NextBooking, SessionBooking};
+    use crate::claims::Claims;
+    use crate::{Config, CountResult, Page, UserLoginRecord};
+    use crate::test_support::{bearer, test_client};
+
+    #[derive(FromRow)]
+    struct IntRecord {
+        id: i32
+    }
+
+    #[derive(FromRow)]
+    struct BigintRecord {
+        id: i64
+    }
+    async fn create_person(pool: &PgPool, email: &str, roles: &str, credits: i32) -> i64 {
+        let member_id: BigintRecord = query_as("insert into person (name, email, roles, credits) values ('Test User', $1, $2, $3) returning id")
+            .bind(email)
+            .bind(roles)
+            .bind(credits)
+            .fetch_one(pool)
+            .await.unwrap();
+        member_id.id
+    }
+    async fn create_session(pool: &PgPool, datetime: &DateTime<Utc>, trainer_id: i64, session_type_name: &str, location_name: &str) -> i64 {
+        create_session_max_bookings(pool, datetime, trainer_id, session_type_name, location_name, None).await
+    }
+
+    async fn create_session_max_bookings(pool: &PgPool, datetime: &DateTime<Utc>, trainer_id: i64, session_type_name: &str, location_name: &str, max_bookings: Option<i64>) -> i64 {
+        let session_type_id: IntRecord = query_as("select id from session_type where name = $1")
+            .bind(session_type_name)
+            .fetch_one(pool).await.unwrap();
+
+        let location_id: IntRecord = query_as("select id from location where name = $1")
+            .bind(location_name)
+            .fetch_one(pool).await.unwrap();
+
+        let session_id_record: BigintRecord = query_as("insert into session (datetime, duration_mins, session_type, location, trainer, cost, max_booking_count) \
+            VALUES ($1, 60, $2, $3, $4, 1, $5) \
+            RETURNING id
+        ")
+            .bind(datetime)
+            .bind(session_type_id.id)
+            .bind(location_id.id)
+            .bind(trainer_id)
+            .bind(max_bookings)
+            .fetch_one(pool).await.unwrap();
+
+        session_id_record.id
+    }
+    async fn count_bookings(pool: &PgPool) -> i64 {
+        let record: CountResult = query_as("select count(*) from booking")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        record.count
+    }
+
+    #[sqlx::test]
+    async fn book_session_full_member(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let booking = SessionBooking {
+            person_id: member_id,
+            session_id,
+            credits_used: None
+        };
+
+        // Precondition: zero bookings
+        assert_eq!(0, count_bookings(&pool).await);
+
+        // Create booking
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "joe@example.com", &Some("011111".to_string()), &vec!["member".to_string()], Duration::minutes(1));
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await.unwrap();
+
+        // Postcondition: 1 booking
+        assert_eq!(1, count_bookings(&pool).await);
+    }
+
+    #[sqlx::test]
+    async fn book_session_non_member(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let booking = SessionBooking {
+            person_id: member_id,
+            session_id,
+            credits_used: None
+        };
+
+        // Precondition: zero bookings
+        assert_eq!(0, count_bookings(&pool).await);
+
+        // Create booking
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "joe@example.com", &Some("011111".to_string()), &vec![], Duration::minutes(1));
+        let result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await;
+        assert!(result.is_err());
+        assert_eq!(Custom(Status::Forbidden, "Missing or expired membership, and no PAYG credits.".to_string()), result.err().unwrap());
+
+        // Postcondition: still zero bookings
+        assert_eq!(0, count_bookings(&pool).await);
+    }
+
+    #[sqlx::test]
+    async fn book_session_limited_member_existing_session_same_week(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "limited-member", 0).await;
+        let datetime = Utc::now().add(TimeDelta::days(1));
+        let session_id_1 = create_session(&pool, &datetime, trainer_id, "HIIT", "Oak Hill Park").await;
+        let booking_1 = SessionBooking {
+            person_id: member_id,
+            session_id: session_id_1,
+            credits_used: None
+        };
+        let session_id_2 = create_session(&pool, &datetime, trainer_id, "On The Move", "Oak Hill Park").await;
+        let booking_2 = SessionBooking {
+            person_id: member_id,
+            session_id: session_id_2,
+            credits_used: None
+        };
+        let timezone: Tz = "Europe/London".parse().unwrap();
+
+        // Precondition: zero bookings
+        assert_eq!(0, count_bookings(&pool).await);
+
+        // Create booking 1
+        let claim = Claims::create(member_id, "member@example.com", &Some("011111".to_string()), &vec!["limited-member".to_string()], Duration::minutes(1));
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking_1)).await.unwrap();
+
+        // Postcondition 1: one booking
+        assert_eq!(1, count_bookings(&pool).await);
+
+        // Create booking 2: fails
+        let claim = Claims::create(member_id, "member@example.com", &Some("011111".to_string()), &vec!["limited-member".to_string()], Duration::minutes(1));
+        let result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking_2.clone())).await;
+        assert!(result.is_err());
+        assert_eq!(Custom(Status::Forbidden, "Cannot book session: member already has 1 booking(s) in this week.".to_string()), result.err().unwrap());
+
+        // Postcondition 2: one booking
+        assert_eq!(1, count_bookings(&pool).await);
+
+        // Cancel booking 1
+        _delete_booking(&pool, &claim, None, false, member_id, session_id_1, BookingCancellation::default()).await.unwrap();
+
+        // Postcondition 3: zero bookings, with the cancellation recorded
+        assert_eq!(0, count_bookings(&pool).await);
+        let cancellations: CountResult = query_as("select count(*) as count from cancellation where person_id = $1 and session_id = $2")
+            .bind(member_id)
+            .bind(session_id_1)
+            .fetch_one(&pool).await.unwrap();
+        assert_eq!(1, cancellations.count);
+
+        // Create booking 2: succeeds now
+        let claim = Claims::create(member_id, "member@example.com", &Some("011111".to_string()), &vec!["limited-member".to_string()], Duration::minutes(1));
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking_2)).await.unwrap();
+
+        // Postcondition 4: one booking
+        assert_eq!(1, count_bookings(&pool).await);
+    }
+
+    #[sqlx::test]
+    async fn book_session_limited_member_existing_session_next_week(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "limited-member", 0).await;
+        let tomorrow = Utc::now().add(TimeDelta::days(1));
+        let next_week = tomorrow.add(TimeDelta::weeks(1));
+        let session_id_1 = create_session(&pool, &tomorrow, trainer_id, "HIIT", "Oak Hill Park").await;
+        let booking_1 = SessionBooking {
+            person_id: member_id,
+            session_id: session_id_1,
+            credits_used: None
+        };
+        let session_id_2 = create_session(&pool, &next_week, trainer_id, "On The Move", "Oak Hill Park").await;
+        let booking_2 = SessionBooking {
+            person_id: member_id,
+            session_id: session_id_2,
+            credits_used: None
+        };
+        let timezone: Tz = "Europe/London".parse().unwrap();
+
+        // Precondition: zero bookings
+        assert_eq!(0, count_bookings(&pool).await);
+
+        // Create booking 1
+        let claim = Claims::create(member_id, "member@example.com", &Some("011111".to_string()), &vec!["limited-member".to_string()], Duration::minutes(1));
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking_1)).await.unwrap();
+
+        // Postcondition 1: one booking
+        assert_eq!(1, count_bookings(&pool).await);
+
+        // Create booking 2: succeeds because it's next week
+        let claim = Claims::create(member_id, "member@example.com", &Some("011111".to_string()), &vec!["limited-member".to_string()], Duration::minutes(1));
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking_2.clone())).await.unwrap();
+
+        // Postcondition 2: two bookings
+        assert_eq!(2, count_bookings(&pool).await);
+    }
+
+    #[test]
+    fn session_in_past_at_boundary() {
+        // When the clocks go forward in London, to show that local time plays no part
+        let now = Utc.with_ymd_and_hms(2024, 3, 31, 1, 0, 0).unwrap();
+        assert!(!session_started_by(now, now));
+        assert!(session_started_by(now - TimeDelta::nanoseconds(1), now));
+        assert!(!session_started_by(now + TimeDelta::nanoseconds(1), now));
+        let london: Tz = "Europe/London".parse().unwrap();
+        let same_instant_in_london = london.with_ymd_and_hms(2024, 3, 31, 2, 0, 0).unwrap().with_timezone(&Utc);
+        assert!(!session_started_by(same_instant_in_london, now));
+        assert!(session_started_by(london.with_ymd_and_hms(2024, 3, 31, 0, 59, 59).unwrap().with_timezone(&Utc), now));
+
+        assert!(is_session_in_past(Utc::now() - TimeDelta::seconds(1)));
+        assert!(!is_session_in_past(Utc::now() + TimeDelta::minutes(1)));
+    }
+
+    #[test]
+    fn membership_status_from_roles_and_credits() {
+        let roles = |roles: &[&str]| roles.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        assert_eq!(MembershipStatus::Full, MembershipStatus::from_roles_and_credits(&roles(&["member"]), 0, 1));
+        assert_eq!(MembershipStatus::Full, MembershipStatus::from_roles_and_credits(&roles(&["limited-member", "member"]), 5, 1));
+        assert_eq!(MembershipStatus::Limited, MembershipStatus::from_roles_and_credits(&roles(&["limited-member"]), 0, 1));
+        assert_eq!(MembershipStatus::Limited, MembershipStatus::from_roles_and_credits(&roles(&["trainer", "limited-member"]), 5, 1));
+        assert_eq!(MembershipStatus::Payg, MembershipStatus::from_roles_and_credits(&roles(&[]), 1, 1));
+        assert_eq!(MembershipStatus::Payg, MembershipStatus::from_roles_and_credits(&roles(&["admin"]), 3, 1));
+        assert_eq!(MembershipStatus::None, MembershipStatus::from_roles_and_credits(&roles(&[]), 0, 1));
+        assert_eq!(MembershipStatus::None, MembershipStatus::from_roles_and_credits(&roles(&["trainer"]), 0, 1));
+
+        // Credits that do not pay for any session are not enough to book
+        assert_eq!(MembershipStatus::None, MembershipStatus::from_roles_and_credits(&roles(&[]), 1, 2));
+        assert_eq!(MembershipStatus::Payg, MembershipStatus::from_roles_and_credits(&roles(&[]), 2, 2));
+    }
+
+    #[sqlx::test]
+    async fn bookings_have_unique_references(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let session_id_1 = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let session_id_2 = create_session_max_bookings(&pool, &Utc::now().add(TimeDelta::days(2)), trainer_id, "HIIT", "Oak Hill Park", Some(10)).await;
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "member@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        for session_id in [session_id_1, session_id_2] {
+            let booking = SessionBooking { person_id: member_id, session_id, credits_used: None };
+            crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await.unwrap();
+        }
+
+        let bookings = _list_bookings(&pool, &claim, None, Some(member_id), None, None, Page::default()).await.unwrap();
+        let references: Vec<String> = bookings.iter().map(|b| b.reference.clone().unwrap()).collect();
+        assert_eq!(2, references.len());
+        assert!(references.iter().all(|r| r.len() == 6));
+        assert_ne!(references[0], references[1]);
+    }
+
+    #[sqlx::test]
+    async fn book_session_non_member_auto_using_credit(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 5).await;
+        pool.execute(format!("update person set auto_use_credits = true where id = {}", member_id).as_str()).await.unwrap();
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let booking = SessionBooking {
+            person_id: member_id,
+            session_id,
+            credits_used: None
+        };
+
+        // Booking succeeds without opting in, and the credits are debited
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "member@example.org", &None, &vec![], Duration::minutes(1));
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await.unwrap();
+        let credits_used: i16 = sqlx::query_scalar("select credits_used from booking").fetch_one(&pool).await.unwrap();
+        assert!(credits_used > 0);
+        assert_eq!(1, count_bookings(&pool).await);
+        assert_eq!(5 - credits_used, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+    }
+
+    #[sqlx::test]
+    async fn book_session_non_member_using_credit_not_opted_in(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 5).await;
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let booking = SessionBooking {
+            person_id: member_id,
+            session_id,
+            credits_used: None
+        };
+
+        // Precondition: zero bookings
+        assert_eq!(0, count_bookings(&pool).await);
+
+        // Create booking
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "joe@example.com", &Some("011111".to_string()), &vec![], Duration::minutes(1));
+        let result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await;
+        assert!(result.is_err());
+        assert_eq!(Custom(Status::PaymentRequired, "Opt in to use credits for booking.".to_string()), result.err().unwrap());
+
+        // Postcondition: still zero bookings
+        assert_eq!(0, count_bookings(&pool).await);
+    }
+
+    #[sqlx::test]
+    async fn book_session_non_member_using_credit_opted_in(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "", 5).await;
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let booking = SessionBooking {
+            person_id: member_id,
+            session_id,
+            credits_used: Some(1)
+        };
+
+        // Precondition: zero bookings
+        assert_eq!(0, count_bookings(&pool).await);
+
+        // Create booking
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "joe@example.com", &Some("011111".to_string()), &vec![], Duration::minutes(1));
+        let result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await.unwrap();
+
+        // Check that the booking has the used credits
+        let created_booking: SessionBooking = query_as("SELECT person_id, session_id, credits_used FROM booking WHERE person_id = $1 AND session_id = $2")
+            .bind(member_id)
+            .bind(session_id)
+            .fetch_one(&pool)
+            .await.unwrap();
+        assert_eq!(Some(1), created_booking.credits_used);
+        let bookings_list = _list_bookings(&pool, &claim, None, Some(member_id), None, None, Page::default()).await.unwrap();
+        assert_eq!(1, bookings_list.len());
+        assert_eq!(1, bookings_list.get(0).unwrap().credits_used);
+
+        // Check that the user has been debited one credit
+        let member_record = UserLoginRecord::load_by_id(&pool, member_id)
+            .await.unwrap().unwrap();
+        assert_eq!(4, member_record.credits);
+
+        // Cancel booking
+        _delete_booking(&pool, &claim, None, false, member_id, session_id, BookingCancellation { reason: Some(" Feeling unwell ".to_string()), ..BookingCancellation::default() }).await.unwrap();
+        let cancellations = _list_cancellations(&pool, None, None, Page::default()).await.unwrap();
+        assert_eq!(1, cancellations.len());
+        assert_eq!(Some("Feeling unwell".to_string()), cancellations[0].reason);
+        // Postcondition: zero bookings
+        assert_eq!(0, count_bookings(&pool).await);
+
+        // Check that the user's credit has been restored
+        let member_record = UserLoginRecord::load_by_id(&pool, member_id)
+            .await.unwrap().unwrap();
+        assert_eq!(5, member_record.credits);
+    }
+
+    #[sqlx::test]
+    async fn admin_cancel_without_refund(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "", 5).await;
+        let session_ids = [
+            create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await,
+            create_session(&pool, &Utc::now().add(TimeDelta::days(2)), trainer_id, "HIIT", "Oak Hill Park").await
+        ];
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let member = Claims::create(member_id, "member@example.com", &None, &vec![], Duration::minutes(1));
+        let admin = Claims::create(trainer_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        for session_id in session_ids {
+            let booking = SessionBooking { person_id: member_id, session_id, credits_used: Some(1) };
+            crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &member, false, Json(booking)).await.unwrap();
+        }
+
+        // The admin cancels without a refund, so the credit is forfeited and recorded as such
+        let no_refund = BookingCancellation { reason: Some("No-show".to_string()), refund: false };
+        _delete_booking(&pool, &admin, None, false, member_id, session_ids[0], no_refund).await.unwrap();
+        assert_eq!(3, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+        let cancellations = _list_cancellations(&pool, None, None, Page::default()).await.unwrap();
+        assert_eq!(1, cancellations[0].credits_forfeited);
+
+        // Members cannot forfeit their own credits
+        let no_refund = BookingCancellation { reason: None, refund: false };
+        _delete_booking(&pool, &member, None, false, member_id, session_ids[1], no_refund).await.unwrap();
+        assert_eq!(4, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+        let cancellations = _list_cancellations(&pool, None, None, Page::default()).await.unwrap();
+        assert_eq!(vec![0, 1], cancellations.iter().map(|c| c.credits_forfeited).collect::<Vec<_>>());
+    }
+
+    #[sqlx::test]
+    async fn book_full_session_reports_last_booking_time(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let first_id = create_person(&pool, "first@example.org", "member", 0).await;
+        let second_id = create_person(&pool, "second@example.org", "member", 0).await;
+        let session_id = create_session_max_bookings(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park", Some(1)).await;
+        let timezone: Tz = "Europe/London".parse().unwrap();
+
+        let first = Claims::create(first_id, "first@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let booking = SessionBooking { person_id: first_id, session_id, credits_used: None };
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &first, false, Json(booking)).await.unwrap();
+        let bookings = _list_bookings(&pool, &first, None, Some(first_id), None, None, Page::default()).await.unwrap();
+        let created_at = bookings[0].created_at.unwrap();
+
+        let second = Claims::create(second_id, "second@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let booking = SessionBooking { person_id: second_id, session_id, credits_used: None };
+        let result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &second, false, Json(booking)).await;
+        assert_eq!(Custom(Status::Conflict, format!("Session has reached it maximum number of bookings: 1. The last space was booked at {}.", created_at.to_rfc3339())),
+            result.err().unwrap());
+    }
+
+    #[sqlx::test]
+    async fn book_session_non_member_using_credit_max_bookings_reached(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "", 5).await;
+        let session_id = create_session_max_bookings(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park", Some(0)).await;
+        let booking = SessionBooking {
+            person_id: member_id,
+            session_id,
+            credits_used: Some(1)
+        };
+
+        // Precondition: zero bookings
+        assert_eq!(0, count_bookings(&pool).await);
+
+        // Create booking: fail due to max bookings reached
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "joe@example.com", &Some("011111".to_string()), &vec![], Duration::minutes(1));
+        let booking_result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await.err().unwrap();
+        assert_eq!(Custom(Status::Conflict, "Session has reached it maximum number of bookings: 0.".to_string()), booking_result);
+
+        // Still zero bookings
+        assert_eq!(0, count_bookings(&pool).await);
+
+        // Check that the user has NOT been debited any credits
+        let member_record = UserLoginRecord::load_by_id(&pool, member_id)
+            .await.unwrap().unwrap();
+        assert_eq!(5, member_record.credits);
+    }
+
+    #[sqlx::test]
+    async fn cancel_all_future_bookings(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "", 5).await;
+        let tomorrow = Utc::now().add(TimeDelta::days(1));
+        let yesterday = Utc::now().add(TimeDelta::days(-1));
+        let session_id_1 = create_session(&pool, &tomorrow, trainer_id, "HIIT", "Oak Hill Park").await;
+        let session_id_2 = create_session(&pool, &tomorrow.add(TimeDelta::weeks(1)), trainer_id, "Strong", "Oak Hill Park").await;
+        let past_session_id = create_session(&pool, &yesterday, trainer_id, "HIIT", "Oak Hill Park").await;
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "member@example.com", &Some("011111".to_string()), &vec![], Duration::minutes(1));
+
+        // Book two future sessions using credits, plus one past session booked directly
+        for session_id in [session_id_1, session_id_2] {
+            let booking = SessionBooking { person_id: member_id, session_id, credits_used: Some(1) };
+            crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await.unwrap();
+        }
+        pool.execute(format!("insert into booking (person_id, session_id, credits_used) values ({}, {}, 1)", member_id, past_session_id).as_str()).await.unwrap();
+        assert_eq!(3, count_bookings(&pool).await);
+        assert_eq!(3, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+
+        // Cancel everything: only the future bookings are cancelled and refunded
+        let cancelled = _delete_bookings_in_range(&pool, &claim, None, member_id, None, None).await.unwrap();
+        assert_eq!(2, cancelled.count);
+        assert_eq!(1, count_bookings(&pool).await);
+        assert_eq!(5, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+
+        // Each cancelled booking is recorded, as when cancelling one at a time
+        let cancelled_sessions: Vec<i64> = query_scalar("select session_id from cancellation where person_id = $1 order by session_id")
+            .bind(member_id)
+            .fetch_all(&pool).await.unwrap();
+        assert_eq!(vec![session_id_1, session_id_2], cancelled_sessions);
+
+        // Members cannot cancel bookings of other users
+        let other_member_id = create_person(&pool, "other@example.org", "member", 0).await;
+        let result = _delete_bookings_in_range(&pool, &claim, None, other_member_id, None, None).await;
+        assert_eq!(Custom(Status::Forbidden, "Not allowed to cancel bookings for other users.".to_string()), result.err().unwrap());
+    }
+
+    #[sqlx::test]
+    async fn preview_session_non_member(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "", 0).await;
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "joe@example.com", &Some("011111".to_string()), &vec![], Duration::minutes(1));
+        let preview = _preview_booking(&pool, &timezone, &Config::default(), &claim, session_id).await.unwrap();
+        assert!(!preview.can_book);
+        assert_eq!(Some("NO_MEMBERSHIP"), preview.reason);
+
+        // Nothing was booked
+        assert_eq!(0, count_bookings(&pool).await);
+    }
+
+    #[sqlx::test]
+    async fn preview_session_in_past(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(-1)), trainer_id, "HIIT", "Oak Hill Park").await;
+
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "joe@example.com", &Some("011111".to_string()), &vec!["member".to_string()], Duration::minutes(1));
+        let preview = _preview_booking(&pool, &timezone, &Config::default(), &claim, session_id).await.unwrap();
+        assert!(!preview.can_book);
+        assert_eq!(Some("SESSION_IN_PAST"), preview.reason);
+    }
+
+    #[sqlx::test]
+    async fn preview_session_limited_member_existing_session_same_week(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "limited-member", 0).await;
+        let datetime = Utc::now().add(TimeDelta::days(1));
+        let session_id_1 = create_session(&pool, &datetime, trainer_id, "HIIT", "Oak Hill Park").await;
+        let session_id_2 = create_session(&pool, &datetime, trainer_id, "On The Move", "Oak Hill Park").await;
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "member@example.com", &Some("011111".to_string()), &vec!["limited-member".to_string()], Duration::minutes(1));
+
+        // Bookable before any other booking is made in the week
+        let preview = _preview_booking(&pool, &timezone, &Config::default(), &claim, session_id_2).await.unwrap();
+        assert!(preview.can_book);
+        assert_eq!(0, preview.credits_required);
+
+        let booking_1 = SessionBooking {
+            person_id: member_id,
+            session_id: session_id_1,
+            credits_used: None
+        };
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking_1)).await.unwrap();
+
+        let preview = _preview_booking(&pool, &timezone, &Config::default(), &claim, session_id_2).await.unwrap();
+        assert!(!preview.can_book);
+        assert_eq!(Some("WEEKLY_LIMIT_REACHED"), preview.reason);
+
+        let preview = _preview_booking(&pool, &timezone, &Config::default(), &claim, session_id_1).await.unwrap();
+        assert!(!preview.can_book);
+        assert_eq!(Some("ALREADY_BOOKED"), preview.reason);
+    }
+
+    #[sqlx::test]
+    async fn preview_session_non_member_using_credit(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "", 5).await;
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "joe@example.com", &Some("011111".to_string()), &vec![], Duration::minutes(1));
+        let preview = _preview_booking(&pool, &timezone, &Config::default(), &claim, session_id).await.unwrap();
+        assert!(preview.can_book);
+        assert_eq!(None, preview.reason);
+        assert_eq!(1, preview.credits_required);
+
+        // The preview does not debit any credits
+        let member_record = UserLoginRecord::load_by_id(&pool, member_id)
+            .await.unwrap().unwrap();
+        assert_eq!(5, member_record.credits);
+    }
+
+    #[sqlx::test]
+    async fn preview_session_non_member_using_credit_max_bookings_reached(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "", 5).await;
+        let session_id = create_session_max_bookings(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park", Some(0)).await;
+
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "joe@example.com", &Some("011111".to_string()), &vec![], Duration::minutes(1));
+        let preview = _preview_booking(&pool, &timezone, &Config::default(), &claim, session_id).await.unwrap();
+        assert!(!preview.can_book);
+        assert_eq!(Some("SESSION_FULL"), preview.reason);
+    }
+
+    #[sqlx::test]
+    async fn update_attendance_lock_period(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let lock_days = 7;
+        let inside_window = Utc::now().add(TimeDelta::days(-lock_days)).add(TimeDelta::hours(1));
+        let outside_window = Utc::now().add(TimeDelta::days(-lock_days)).add(TimeDelta::hours(-1));
+        let session_id_inside = create_session(&pool, &inside_window, trainer_id, "HIIT", "Oak Hill Park").await;
+        let session_id_outside = create_session(&pool, &outside_window, trainer_id, "HIIT", "Oak Hill Park").await;
+        pool.execute(format!("insert into booking (person_id, session_id) values ({}, {}), ({}, {})", member_id, session_id_inside, member_id, session_id_outside).as_str()).await.unwrap();
+
+        let admin = Claims::create(trainer_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let super_admin = Claims::create(trainer_id, "admin@example.com", &None, &vec!["admin".to_string(), "super-admin".to_string()], Duration::minutes(1));
+
+        // Just inside the window attendance can be changed
+        _update_booking(&pool, &admin, Some(lock_days as u32), member_id, session_id_inside, Json(BookingUpdate { attended: true, credits_used: None })).await.unwrap();
+
+        // Just outside the window attendance is locked, except for super-admins
+        let result = _update_booking(&pool, &admin, Some(lock_days as u32), member_id, session_id_outside, Json(BookingUpdate { attended: true, credits_used: None })).await;
+        assert_eq!(Custom(Status::Forbidden, "Attendance for sessions more than 7 days ago can no longer be changed.".to_string()), result.err().unwrap());
+        _update_booking(&pool, &super_admin, Some(lock_days as u32), member_id, session_id_outside, Json(BookingUpdate { attended: true, credits_used: None })).await.unwrap();
+
+        // Without a lock period configured, attendance can always be changed
+        _update_booking(&pool, &admin, None, member_id, session_id_outside, Json(BookingUpdate { attended: false, credits_used: None })).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn update_booking_partial_refund(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "", 0).await;
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(-1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        pool.execute(format!("insert into booking (person_id, session_id, credits_used) values ({}, {}, 3)", member_id, session_id).as_str()).await.unwrap();
+        let admin = Claims::create(trainer_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+
+        // Member left early, so only one of the three credits is kept
+        _update_booking(&pool, &admin, None, member_id, session_id, Json(BookingUpdate { attended: true, credits_used: Some(1) })).await.unwrap();
+        assert_eq!(2, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+        let booking: SessionBooking = query_as("SELECT person_id, session_id, credits_used FROM booking WHERE person_id = $1 AND session_id = $2")
+            .bind(member_id)
+            .bind(session_id)
+            .fetch_one(&pool)
+            .await.unwrap();
+        assert_eq!(Some(1), booking.credits_used);
+
+        // Leaving out the credits used keeps them unchanged
+        _update_booking(&pool, &admin, None, member_id, session_id, Json(BookingUpdate { attended: false, credits_used: None })).await.unwrap();
+        assert_eq!(2, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+
+        // Charging more credits than the member has is rejected
+        let result = _update_booking(&pool, &admin, None, member_id, session_id, Json(BookingUpdate { attended: true, credits_used: Some(4) })).await;
+        assert_eq!(Status::PaymentRequired, result.err().unwrap().0);
+        assert_eq!(2, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+
+        // Credits used that were never recorded stay unrecorded
+        pool.execute(format!("update booking set credits_used = null where person_id = {} and session_id = {}", member_id, session_id).as_str()).await.unwrap();
+        _update_booking(&pool, &admin, None, member_id, session_id, Json(BookingUpdate { attended: true, credits_used: None })).await.unwrap();
+        let credits_used: Option<i16> = query_scalar("SELECT credits_used FROM booking WHERE person_id = $1 AND session_id = $2")
+            .bind(member_id)
+            .bind(session_id)
+            .fetch_one(&pool)
+            .await.unwrap();
+        assert_eq!(None, credits_used);
+    }
+
+    #[sqlx::test]
+    async fn transfer_booking(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let admin_id = create_person(&pool, "admin@example.org", "admin", 0).await;
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "", 5).await;
+        let other_member_id = create_person(&pool, "other@example.org", "", 1).await;
+        let broke_member_id = create_person(&pool, "broke@example.org", "", 0).await;
+        let tomorrow = Utc::now().add(TimeDelta::days(1));
+        let session_id = create_session_max_bookings(&pool, &tomorrow, trainer_id, "HIIT", "Oak Hill Park", Some(1)).await;
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let member_claim = Claims::create(member_id, "member@example.com", &None, &vec![], Duration::minutes(1));
+        let admin_claim = Claims::create(admin_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+
+        let booking = SessionBooking { person_id: member_id, session_id, credits_used: Some(1) };
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &member_claim, false, Json(booking)).await.unwrap();
+        assert_eq!(4, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+        pool.execute("update booking set attended = true, attended_at = now(), checkin_attempts = 1").await.unwrap();
+
+        // Only admins can transfer
+        let transfer = BookingTransfer { session_id, from_person_id: member_id, to_person_id: other_member_id };
+        let result = _transfer_booking(&pool, &member_claim, Json(transfer)).await;
+        assert_eq!(Status::Forbidden, result.err().unwrap().0);
+
+        // Target without enough credits is refused and nothing changes
+        let transfer = BookingTransfer { session_id, from_person_id: member_id, to_person_id: broke_member_id };
+        let result = _transfer_booking(&pool, &admin_claim, Json(transfer)).await;
+        assert_eq!(Status::PaymentRequired, result.err().unwrap().0);
+        assert_eq!(4, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+
+        // Transfer into a full session succeeds, moving the credits
+        let transfer = BookingTransfer { session_id, from_person_id: member_id, to_person_id: other_member_id };
+        let transferred = _transfer_booking(&pool, &admin_claim, Json(transfer)).await.unwrap();
+        assert_eq!(other_member_id, transferred.person_id);
+        assert_eq!(1, count_bookings(&pool).await);
+        assert_eq!(5, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+        assert_eq!(0, UserLoginRecord::load_by_id(&pool, other_member_id).await.unwrap().unwrap().credits);
+        // The new member has not attended just because the original member did
+        let (attended, attended_at, checkin_attempts): (bool, Option<DateTime<Utc>>, i32) = query_as("select attended, attended_at, checkin_attempts from booking where person_id = $1")
+            .bind(other_member_id)
+            .fetch_one(&pool)
+            .await.unwrap();
+        assert_eq!((false, None, 0), (attended, attended_at, checkin_attempts));
+        let audit_count: CountResult = query_as("select count(*) from audit_log where actor_id = $1 and action = 'transfer_booking'")
+            .bind(admin_id)
+            .fetch_one(&pool)
+            .await.unwrap();
+        assert_eq!(1, audit_count.count);
+
+        // Cannot transfer onto a member who already holds a booking
+        pool.execute(format!("insert into booking (person_id, session_id, credits_used) values ({}, {}, 0)", member_id, session_id).as_str()).await.unwrap();
+        let transfer = BookingTransfer { session_id, from_person_id: member_id, to_person_id: other_member_id };
+        let result = _transfer_booking(&pool, &admin_claim, Json(transfer)).await;
+        assert_eq!(Status::Conflict, result.err().unwrap().0);
+    }
+
+    #[sqlx::test]
+    async fn limited_member_week_start_day(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "limited-member", 0).await;
+        let timezone: Tz = "Europe/London".parse().unwrap();
+
+        // Sessions at 10am local time on a Saturday, Sunday and Monday at least a week in the future
+        let mut sunday = Utc::now().with_timezone(&timezone).date_naive().checked_add_days(chrono::Days::new(7)).unwrap();
+        while sunday.weekday() != Weekday::Sun {
+            sunday = sunday.succ_opt().unwrap();
+        }
+        let at_ten = |date: chrono::NaiveDate| timezone.from_local_datetime(&date.and_time(NaiveTime::from_hms_opt(10, 0, 0).unwrap())).unwrap().with_timezone(&Utc);
+        let saturday_session_id = create_session(&pool, &at_ten(sunday.pred_opt().unwrap()), trainer_id, "HIIT", "Oak Hill Park").await;
+        let sunday_session_id = create_session(&pool, &at_ten(sunday), trainer_id, "HIIT", "Oak Hill Park").await;
+        let monday_session_id = create_session(&pool, &at_ten(sunday.succ_opt().unwrap()), trainer_id, "HIIT", "Oak Hill Park").await;
+
+        let claim = Claims::create(member_id, "member@example.com", &None, &vec!["limited-member".to_string()], Duration::minutes(1));
+        let booking = SessionBooking { person_id: member_id, session_id: sunday_session_id, credits_used: None };
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await.unwrap();
+
+        // Monday-start weeks: the Sunday session is in the same week as the Saturday, not the Monday
+        let preview = _preview_booking(&pool, &timezone, &Config::default(), &claim, saturday_session_id).await.unwrap();
+        assert_eq!(Some("WEEKLY_LIMIT_REACHED"), preview.reason);
+        let preview = _preview_booking(&pool, &timezone, &Config::default(), &claim, monday_session_id).await.unwrap();
+        assert!(preview.can_book);
+
+        // Sunday-start weeks: the Sunday session is in the same week as the Monday, not the Saturday
+        let sunday_start = Config { week_start_day: Weekday::Sun, ..Config::default() };
+        let preview = _preview_booking(&pool, &timezone, &sunday_start, &claim, saturday_session_id).await.unwrap();
+        assert!(preview.can_book);
+        let preview = _preview_booking(&pool, &timezone, &sunday_start, &claim, monday_session_id).await.unwrap();
+        assert_eq!(Some("WEEKLY_LIMIT_REACHED"), preview.reason);
+    }
+
+    #[sqlx::test]
+    async fn next_booking(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let claim = Claims::create(member_id, "member@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
+        assert!(matches!(_get_next_booking(&pool, &claim).await.unwrap(), NextBooking::None(_)));
+
+        let yesterday = Utc::now().add(TimeDelta::days(-1));
+        let tomorrow = Utc::now().add(TimeDelta::days(1));
+        for datetime in [yesterday, tomorrow.add(TimeDelta::days(1)), tomorrow] {
+            let session_id = create_session(&pool, &datetime, trainer_id, "HIIT", "Oak Hill Park").await;
+            pool.execute(format!("insert into booking (person_id, session_id) values ({}, {})", member_id, session_id).as_str()).await.unwrap();
+        }
+        match _get_next_booking(&pool, &claim).await.unwrap() {
+            NextBooking::Found(booking) => assert_eq!(tomorrow.timestamp(), booking.session_datetime.timestamp()),
+            NextBooking::None(_) => panic!("expected a booking")
+        }
+    }
+
+    #[sqlx::test]
+    async fn book_session_non_member_inflated_credits_used(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "", 5).await;
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "member@example.com", &None, &vec![], Duration::minutes(1));
+
+        // Offer far more credits than the session costs
+        let booking = SessionBooking { person_id: member_id, session_id, credits_used: Some(1000) };
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await.unwrap();
+
+        // Only the real cost is debited and recorded against the booking
+        let stored: SessionBooking = query_as("select person_id, session_id, credits_used from booking where person_id = $1 and session_id = $2")
+            .bind(member_id)
+            .bind(session_id)
+            .fetch_one(&pool)
+            .await.unwrap();
+        assert_eq!(Some(1), stored.credits_used);
+        assert_eq!(4, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+    }
+
+    #[sqlx::test]
+    async fn list_bookings_paginated(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let admin_id = create_person(&pool, "admin@example.org", "admin", 0).await;
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), admin_id, "HIIT", "Oak Hill Park").await;
+        let mut member_ids = Vec::new();
+        for email in ["a@example.org", "b@example.org", "c@example.org"] {
+            // All test users share the same name, so only the final sort keys distinguish them
+            let member_id = create_person(&pool, email, "member", 0).await;
+            pool.execute(format!("insert into booking (person_id, session_id) values ({}, {})", member_id, session_id).as_str()).await.unwrap();
+            member_ids.push(member_id);
+        }
+        let claim = Claims::create(admin_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+
+        // Each booking is on exactly one page, in the order of the person ids
+        let first_page = _list_bookings(&pool, &claim, Some(session_id), None, None, None, Page { limit: Some(2), offset: None }).await.unwrap();
+        let second_page = _list_bookings(&pool, &claim, Some(session_id), None, None, None, Page { limit: Some(2), offset: Some(2) }).await.unwrap();
+        assert_eq!(member_ids[..2], first_page.iter().map(|b| b.person_id).collect::<Vec<_>>());
+        assert_eq!(member_ids[2..], second_page.iter().map(|b| b.person_id).collect::<Vec<_>>());
+
+        let result = _list_bookings(&pool, &claim, Some(session_id), None, None, None, Page { limit: Some(-1), offset: None }).await;
+        assert_eq!(Status::BadRequest, result.unwrap_err().0);
+        let result = _list_bookings(&pool, &claim, Some(session_id), None, None, None, Page { limit: None, offset: Some(-1) }).await;
+        assert_eq!(Status::BadRequest, result.unwrap_err().0);
+    }
+
+    #[sqlx::test]
+    async fn list_bookings_contact_details_by_role(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let admin_id = create_person(&pool, "admin@example.org", "admin", 0).await;
+        let trainer_id = create_person(&pool, "trainer@example.org", "trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        pool.execute(format!("update person set phone = '0123' where id = {}", member_id).as_str()).await.unwrap();
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        pool.execute(format!("insert into booking (person_id, session_id) values ({}, {})", member_id, session_id).as_str()).await.unwrap();
+
+        // Admins see contact details
+        let admin = Claims::create(admin_id, "admin@example.org", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let bookings = _list_bookings(&pool, &admin, Some(session_id), None, None, None, Page::default()).await.unwrap();
+        assert_eq!(Some("member@example.org".to_string()), bookings[0].person_email);
+        assert_eq!(Some("0123".to_string()), bookings[0].person_phone);
+
+        // Trainers see only the names of those booked on their sessions
+        let trainer = Claims::create(trainer_id, "trainer@example.org", &None, &vec!["trainer".to_string()], Duration::minutes(1));
+        let bookings = _list_bookings(&pool, &trainer, Some(session_id), None, None, None, Page::default()).await.unwrap();
+        assert_eq!(1, bookings.len());
+        assert_eq!("Test User", bookings[0].person_name);
+        assert_eq!(None, bookings[0].person_email);
+        assert_eq!(None, bookings[0].person_phone);
+        let other_trainer = Claims::create(admin_id, "other@example.org", &None, &vec!["trainer".to_string()], Duration::minutes(1));
+        assert!(_list_bookings(&pool, &other_trainer, Some(session_id), None, None, None, Page::default()).await.unwrap().is_empty());
+        let result = _list_bookings(&pool, &trainer, None, None, None, None, Page::default()).await;
+        assert_eq!(Status::Forbidden, result.err().unwrap().0);
+
+        // Members see their own details in full
+        let member = Claims::create(member_id, "member@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let bookings = _list_bookings(&pool, &member, None, Some(member_id), None, None, Page::default()).await.unwrap();
+        assert_eq!(Some("member@example.org".to_string()), bookings[0].person_email);
+        assert_eq!(Some("0123".to_string()), bookings[0].person_phone);
+    }
+
+    #[sqlx::test]
+    async fn get_booking_by_role(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let admin_id = create_person(&pool, "admin@example.org", "admin", 0).await;
+        let trainer_id = create_person(&pool, "trainer@example.org", "trainer", 0).await;
+        let other_trainer_id = create_person(&pool, "other@example.org", "trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let other_member_id = create_person(&pool, "other.member@example.org", "member", 0).await;
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        pool.execute(format!("insert into booking (person_id, session_id) values ({}, {})", member_id, session_id).as_str()).await.unwrap();
+
+        // Members can fetch their own booking but not anyone else's
+        let member = Claims::create(member_id, "member@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let booking = _get_booking(&pool, &member, session_id, member_id).await.unwrap();
+        assert_eq!(Some("member@example.org".to_string()), booking.person_email);
+        let other_member = Claims::create(other_member_id, "other.member@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        assert_eq!(Status::Forbidden, _get_booking(&pool, &other_member, session_id, member_id).await.err().unwrap().0);
+        assert_eq!(Status::NotFound, _get_booking(&pool, &other_member, session_id, other_member_id).await.err().unwrap().0);
+
+        // Trainers can fetch bookings on their own sessions only
+        let trainer = Claims::create(trainer_id, "trainer@example.org", &None, &vec!["trainer".to_string()], Duration::minutes(1));
+        let booking = _get_booking(&pool, &trainer, session_id, member_id).await.unwrap();
+        assert_eq!(None, booking.person_email);
+        let other_trainer = Claims::create(other_trainer_id, "other@example.org", &None, &vec!["trainer".to_string()], Duration::minutes(1));
+        assert_eq!(Status::NotFound, _get_booking(&pool, &other_trainer, session_id, member_id).await.err().unwrap().0);
+
+        // Admins can fetch any booking
+        let admin = Claims::create(admin_id, "admin@example.org", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let booking = _get_booking(&pool, &admin, session_id, member_id).await.unwrap();
+        assert_eq!(Some("member@example.org".to_string()), booking.person_email);
+        assert_eq!(Status::NotFound, _get_booking(&pool, &admin, session_id, admin_id).await.err().unwrap().0);
+    }
+
+    #[sqlx::test]
+    async fn book_session_rolled_back_when_debit_fails(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "", 5).await;
+        let session_id = create_session_max_bookings(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park", Some(10)).await;
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "member@example.com", &None, &vec![], Duration::minutes(1));
+
+        // Force the credit debit to fail after the booking has been inserted
+        pool.execute("alter table person add constraint test_credits_check check (credits > 4) not valid").await.unwrap();
+        let booking = SessionBooking { person_id: member_id, session_id, credits_used: Some(1) };
+        let result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await;
+        assert_eq!(Status::InternalServerError, result.err().unwrap().0);
+
+        // The booking was rolled back along with the debit
+        assert_eq!(0, count_bookings(&pool).await);
+        assert_eq!(5, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+    }
+
+    #[sqlx::test]
+    async fn concurrent_bookings_cannot_overspend_credits(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "", 1).await;
+        let session_id_1 = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let session_id_2 = create_session(&pool, &Utc::now().add(TimeDelta::days(2)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "member@example.com", &None, &vec![], Duration::minutes(1));
+
+        // Hold a lock on the member until both bookings are waiting to debit their credits, so that both see
+        // enough credits when checking eligibility, but only one can be paid for
+        let mut lock = pool.begin().await.unwrap();
+        sqlx::query("select id from person where id = $1 for no key update").bind(member_id).execute(&mut *lock).await.unwrap();
+        let release = async {
+            while sqlx::query_scalar::<_, i64>("select count(*) from pg_stat_activity where datname = current_database() and wait_event_type = 'Lock'")
+                .fetch_one(&pool).await.unwrap() < 2 {
+                rocket::tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            lock.commit().await.unwrap();
+        };
+        let booking_1 = SessionBooking { person_id: member_id, session_id: session_id_1, credits_used: Some(1) };
+        let booking_2 = SessionBooking { person_id: member_id, session_id: session_id_2, credits_used: Some(1) };
+        let config = Config::default();
+        let (result_1, result_2, _) = rocket::tokio::join!(
+            crate::bookings::_create_booking(&pool, &timezone, &config, &claim, false, Json(booking_1)),
+            crate::bookings::_create_booking(&pool, &timezone, &config, &claim, false, Json(booking_2)),
+            release);
+
+        let failures: Vec<Custom<String>> = [result_1, result_2].into_iter().filter_map(|r| r.err()).collect();
+        assert_eq!(1, failures.len());
+        assert_eq!(Status::PaymentRequired, failures[0].0);
+        assert_eq!(1, count_bookings(&pool).await);
+        assert_eq!(0, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+    }
+
+    #[sqlx::test]
+    async fn book_private_session(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        pool.execute(format!("update session set private = true where id = {}", session_id).as_str()).await.unwrap();
+        let timezone: Tz = "Europe/London".parse().unwrap();
+
+        // Members cannot book themselves onto a private session
+        let claim = Claims::create(member_id, "member@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let preview = _preview_booking(&pool, &timezone, &Config::default(), &claim, session_id).await.unwrap();
+        assert_eq!(Some("PRIVATE_SESSION"), preview.reason);
+        let booking = SessionBooking { person_id: member_id, session_id, credits_used: None };
+        let result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await;
+        assert_eq!(Status::Forbidden, result.err().unwrap().0);
+        assert_eq!(0, count_bookings(&pool).await);
+
+        // Admins can book them in
+        let admin = Claims::create(trainer_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let booking = SessionBooking { person_id: member_id, session_id, credits_used: None };
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &admin, false, Json(booking)).await.unwrap();
+        assert_eq!(1, count_bookings(&pool).await);
+    }
+
+    #[sqlx::test]
+    async fn book_session_within_booking_window(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "member@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
+
+        // Before booking opens
+        pool.execute(format!("update session set booking_opens_at = now() + interval '1 hour' where id = {}", session_id).as_str()).await.unwrap();
+        let preview = _preview_booking(&pool, &timezone, &Config::default(), &claim, session_id).await.unwrap();
+        assert_eq!(Some("BOOKING_NOT_OPEN"), preview.reason);
+        let booking = SessionBooking { person_id: member_id, session_id, credits_used: None };
+        let result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await;
+        let Custom(status, message) = result.err().unwrap();
+        assert_eq!(Status::Forbidden, status);
+        assert!(message.starts_with("Booking for this session opens at "), "{}", message);
+        assert_eq!(0, count_bookings(&pool).await);
+
+        // After booking closes
+        pool.execute(format!("update session set booking_opens_at = null, booking_closes_at = now() - interval '1 hour' where id = {}", session_id).as_str()).await.unwrap();
+        let booking = SessionBooking { person_id: member_id, session_id, credits_used: None };
+        let result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await;
+        assert_eq!(Custom(Status::Forbidden, "Booking for this session has closed.".to_string()), result.err().unwrap());
+        assert_eq!(0, count_bookings(&pool).await);
+
+        // Admins are not restricted by the window
+        let admin = Claims::create(trainer_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let preview = _preview_booking(&pool, &timezone, &Config::default(), &admin, session_id).await.unwrap();
+        assert!(preview.can_book);
+
+        // Within the window
+        pool.execute(format!("update session set booking_opens_at = now() - interval '1 hour', booking_closes_at = now() + interval '1 hour' where id = {}", session_id).as_str()).await.unwrap();
+        let booking = SessionBooking { person_id: member_id, session_id, credits_used: None };
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await.unwrap();
+        assert_eq!(1, count_bookings(&pool).await);
+    }
+
+    #[sqlx::test]
+    async fn admin_override_ignored_for_members(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let past_id = create_session(&pool, &Utc::now().add(TimeDelta::days(-1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "member@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
+
+        let booking = SessionBooking { person_id: member_id, session_id: past_id, credits_used: None };
+        let result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, true, Json(booking)).await;
+        assert_eq!(Status::Forbidden, result.err().unwrap().0);
+        assert_eq!(0, count_bookings(&pool).await);
+
+        pool.execute(format!("insert into booking (person_id, session_id) values ({}, {})", member_id, past_id).as_str()).await.unwrap();
+        let result = _delete_booking(&pool, &claim, None, true, member_id, past_id, BookingCancellation::default()).await;
+        assert_eq!(Custom(Status::Forbidden, "Cannot cancel past booking.".to_string()), result.err().unwrap());
+        assert_eq!(1, count_bookings(&pool).await);
+
+        let audit_count: CountResult = query_as("select count(*) from audit_log where action like 'override_%'")
+            .fetch_one(&pool)
+            .await.unwrap();
+        assert_eq!(0, audit_count.count);
+    }
+
+    #[sqlx::test]
+    async fn admin_override_logged(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let admin_id = create_person(&pool, "admin@example.org", "member,admin", 0).await;
+        let past_id = create_session(&pool, &Utc::now().add(TimeDelta::days(-1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let full_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        pool.execute(format!("update session set max_booking_count = 0 where id = {}", full_id).as_str()).await.unwrap();
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let admin = Claims::create(admin_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+
+        // Without the override, admins cannot book past or full sessions
+        let booking = SessionBooking { person_id: member_id, session_id: past_id, credits_used: None };
+        let result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &admin, false, Json(booking)).await;
+        assert_eq!(Status::Forbidden, result.err().unwrap().0);
+        let booking = SessionBooking { person_id: member_id, session_id: full_id, credits_used: None };
+        let result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &admin, false, Json(booking)).await;
+        assert_eq!(Status::Conflict, result.err().unwrap().0);
+        assert_eq!(0, count_bookings(&pool).await);
+
+        // With it, they can book past and full sessions
+        let booking = SessionBooking { person_id: member_id, session_id: past_id, credits_used: None };
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &admin, true, Json(booking)).await.unwrap();
+        let booking = SessionBooking { person_id: member_id, session_id: full_id, credits_used: None };
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &admin, true, Json(booking)).await.unwrap();
+        assert_eq!(2, count_bookings(&pool).await);
+
+        // Admins are not held to the cancellation cutoff, but can only cancel past bookings with the override
+        _delete_booking(&pool, &admin, Some(2 * 24 * 60), false, member_id, full_id, BookingCancellation::default()).await.unwrap();
+        let result = _delete_booking(&pool, &admin, None, false, member_id, past_id, BookingCancellation::default()).await;
+        assert_eq!(Custom(Status::Forbidden, "Cannot cancel past booking.".to_string()), result.err().unwrap());
+        _delete_booking(&pool, &admin, None, true, member_id, past_id, BookingCancellation::default()).await.unwrap();
+        assert_eq!(0, count_bookings(&pool).await);
+
+        let overrides: Vec<(Option<i64>, String, String)> = query_as("select actor_id, action, target from audit_log where action like 'override_%' order by id")
+            .fetch_all(&pool).await.unwrap();
+        assert_eq!(vec![
+            (Some(admin_id), "override_create_booking".to_string(), format!("booking person {} session {}", member_id, past_id)),
+            (Some(admin_id), "override_create_booking".to_string(), format!("booking person {} session {}", member_id, full_id)),
+            (Some(admin_id), "override_delete_booking".to_string(), format!("booking person {} session {}", member_id, past_id)),
+        ], overrides);
     }
 
     #[sqlx::test]
-    async fn book_session_full_member(pool: PgPool) {
+    async fn book_session_with_prerequisite(pool: PgPool) {
         pool.execute(include_str!("../schema.sql")).await.unwrap();
 
         let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
         let member_id = create_person(&pool, "member@example.org", "member", 0).await;
-        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
-        let booking = SessionBooking {
-            person_id: member_id,
-            session_id,
-            credits_used: None
-        };
+        let other_member_id = create_person(&pool, "other@example.org", "member", 0).await;
+        pool.execute("update session_type set prerequisite_session_type_id = (select id from session_type where name = 'HIIT') where name = 'Strong'").await.unwrap();
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "Strong", "Oak Hill Park").await;
+        let timezone: Tz = "Europe/London".parse().unwrap();
 
-        // Precondition: zero bookings
+        // Without having attended the prerequisite
+        let claim = Claims::create(member_id, "member@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let preview = _preview_booking(&pool, &timezone, &Config::default(), &claim, session_id).await.unwrap();
+        assert_eq!(Some("PREREQUISITE_NOT_MET"), preview.reason);
+        let booking = SessionBooking { person_id: member_id, session_id, credits_used: None };
+        let result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await;
+        assert_eq!(Custom(Status::Forbidden, "Cannot book session: attend a HIIT session first.".to_string()), result.err().unwrap());
         assert_eq!(0, count_bookings(&pool).await);
 
-        // Create booking
-        let timezone: Tz = "Europe/London".parse().unwrap();
-        let claim = Claims::create(member_id, "joe@example.com", &Some("011111".to_string()), &vec!["member".to_string()], Duration::minutes(1));
-        crate::bookings::_create_booking(&pool, &timezone, &claim, Json(booking)).await.unwrap();
+        // Having booked but not attended the prerequisite is not enough
+        let past_session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(-7)), trainer_id, "HIIT", "Oak Hill Park").await;
+        pool.execute(format!("insert into booking (person_id, session_id) values ({}, {})", member_id, past_session_id).as_str()).await.unwrap();
+        let booking = SessionBooking { person_id: member_id, session_id, credits_used: None };
+        let result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await;
+        assert_eq!(Status::Forbidden, result.err().unwrap().0);
+
+        // With the prerequisite attended
+        pool.execute(format!("update booking set attended = true where person_id = {} and session_id = {}", member_id, past_session_id).as_str()).await.unwrap();
+        let booking = SessionBooking { person_id: member_id, session_id, credits_used: None };
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &claim, false, Json(booking)).await.unwrap();
+        assert_eq!(2, count_bookings(&pool).await);
 
-        // Postcondition: 1 booking
-        assert_eq!(1, count_bookings(&pool).await);
+        // Admins can book in members who have not attended the prerequisite
+        let admin = Claims::create(trainer_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let booking = SessionBooking { person_id: other_member_id, session_id, credits_used: None };
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &admin, false, Json(booking)).await.unwrap();
+        assert_eq!(3, count_bookings(&pool).await);
     }
 
     #[sqlx::test]
-    async fn book_session_non_member(pool: PgPool) {
+    async fn checkin_with_session_code(pool: PgPool) {
         pool.execute(include_str!("../schema.sql")).await.unwrap();
 
         let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
         let member_id = create_person(&pool, "member@example.org", "member", 0).await;
-        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
-        let booking = SessionBooking {
-            person_id: member_id,
-            session_id,
-            credits_used: None
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::minutes(10)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let later_session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(2)), trainer_id, "HIIT", "Oak Hill Park").await;
+        pool.execute(format!("insert into booking (person_id, session_id) values ({member_id}, {session_id}), ({member_id}, {later_session_id})").as_str()).await.unwrap();
+        let attended = |session_id: i64| {
+            let pool = pool.clone();
+            async move {
+                let (attended,): (bool,) = query_as("select attended from booking where person_id = $1 and session_id = $2")
+                    .bind(member_id)
+                    .bind(session_id)
+                    .fetch_one(&pool).await.unwrap();
+                attended
+            }
         };
 
-        // Precondition: zero bookings
-        assert_eq!(0, count_bookings(&pool).await);
+        // The trainer gets the same code each time, but members cannot see it
+        let trainer = Claims::create(trainer_id, "trainer@example.org", &None, &vec!["member".to_string(), "trainer".to_string()], Duration::minutes(1));
+        let code = _get_checkin_code(&pool, &trainer, session_id).await.unwrap().code.clone();
+        assert_eq!(code, _get_checkin_code(&pool, &trainer, session_id).await.unwrap().code);
+        let member = Claims::create(member_id, "member@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        assert_eq!(Status::Forbidden, _get_checkin_code(&pool, &member, session_id).await.err().unwrap().0);
+
+        // Wrong code
+        let wrong_code = if code == "000000" { "111111" } else { "000000" };
+        let result = _checkin(&pool, &member, session_id, &Checkin { code: wrong_code.to_string() }).await;
+        assert_eq!(Custom(Status::Forbidden, "Incorrect check-in code.".to_string()), result.err().unwrap());
+        assert!(!attended(session_id).await);
+
+        // Correct code
+        _checkin(&pool, &member, session_id, &Checkin { code: code.clone() }).await.unwrap();
+        assert!(attended(session_id).await);
+
+        // Outside the check-in window, even with the right code
+        let later_code = _get_checkin_code(&pool, &trainer, later_session_id).await.unwrap().code.clone();
+        let result = _checkin(&pool, &member, later_session_id, &Checkin { code: later_code }).await;
+        let Custom(status, message) = result.err().unwrap();
+        assert_eq!(Status::Forbidden, status);
+        assert!(message.starts_with("Check-in for this session is open from "), "{}", message);
+        assert!(!attended(later_session_id).await);
+    }
 
-        // Create booking
-        let timezone: Tz = "Europe/London".parse().unwrap();
-        let claim = Claims::create(member_id, "joe@example.com", &Some("011111".to_string()), &vec![], Duration::minutes(1));
-        let result = crate::bookings::_create_booking(&pool, &timezone, &claim, Json(booking)).await;
-        assert!(result.is_err());
-        assert_eq!(Custom(Status::Forbidden, "Missing or expired membership, and no PAYG credits.".to_string()), result.err().unwrap());
+    #[sqlx::test]
+    async fn checkin_attempts_limited(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
 
-        // Postcondition: still zero bookings
-        assert_eq!(0, count_bookings(&pool).await);
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::minutes(10)), trainer_id, "HIIT", "Oak Hill Park").await;
+        pool.execute(format!("insert into booking (person_id, session_id) values ({member_id}, {session_id})").as_str()).await.unwrap();
+        let trainer = Claims::create(trainer_id, "trainer@example.org", &None, &vec!["member".to_string(), "trainer".to_string()], Duration::minutes(1));
+        let code = _get_checkin_code(&pool, &trainer, session_id).await.unwrap().code.clone();
+        let wrong_code = if code == "000000" { "111111" } else { "000000" };
+        let member = Claims::create(member_id, "member@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+
+        // Once the attempts are used up, even the right code is refused
+        for _ in 0..5 {
+            let result = _checkin(&pool, &member, session_id, &Checkin { code: wrong_code.to_string() }).await;
+            assert_eq!(Custom(Status::Forbidden, "Incorrect check-in code.".to_string()), result.err().unwrap());
+        }
+        let result = _checkin(&pool, &member, session_id, &Checkin { code }).await;
+        assert_eq!(Custom(Status::Forbidden, "Too many check-in attempts: ask the trainer to mark your attendance.".to_string()), result.err().unwrap());
+        let (attended,): (bool,) = query_as("select attended from booking where person_id = $1 and session_id = $2")
+            .bind(member_id)
+            .bind(session_id)
+            .fetch_one(&pool).await.unwrap();
+        assert!(!attended);
     }
 
     #[sqlx::test]
-    async fn book_session_limited_member_existing_session_same_week(pool: PgPool) {
+    async fn export_bookings_in_range(pool: PgPool) {
         pool.execute(include_str!("../schema.sql")).await.unwrap();
 
         let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
-        let member_id = create_person(&pool, "member@example.org", "limited-member", 0).await;
-        let datetime = Utc::now().add(TimeDelta::days(1));
-        let session_id_1 = create_session(&pool, &datetime, trainer_id, "HIIT", "Oak Hill Park").await;
-        let booking_1 = SessionBooking {
-            person_id: member_id,
-            session_id: session_id_1,
-            credits_used: None
-        };
-        let session_id_2 = create_session(&pool, &datetime, trainer_id, "On The Move", "Oak Hill Park").await;
-        let booking_2 = SessionBooking {
-            person_id: member_id,
-            session_id: session_id_2,
-            credits_used: None
-        };
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let in_range_id = create_session(&pool, &DateTime::parse_from_rfc3339("2024-05-10T09:00:00Z").unwrap().to_utc(), trainer_id, "HIIT", "Oak Hill Park").await;
+        let out_of_range_id = create_session(&pool, &DateTime::parse_from_rfc3339("2024-06-10T09:00:00Z").unwrap().to_utc(), trainer_id, "HIIT", "Oak Hill Park").await;
+        pool.execute(format!("insert into booking (person_id, session_id, attended) values ({member_id}, {in_range_id}, true), ({member_id}, {out_of_range_id}, false)").as_str()).await.unwrap();
+        let may = || (Some("2024-05-01T00:00:00Z".to_string()), Some("2024-06-01T00:00:00Z".to_string()));
+
+        // A date range is required, and must not be too long
+        let config = Config::default();
+        assert_eq!(Status::BadRequest, _export_bookings(&pool, &config, None, may().1, None).await.err().unwrap().0);
+        assert_eq!(Status::BadRequest, _export_bookings(&pool, &config, Some("2020-01-01T00:00:00Z".to_string()), may().1, None).await.err().unwrap().0);
+
+        let (from, to) = may();
+        let lines: Vec<String> = _export_bookings(&pool, &config, from, to, None).await.unwrap().1.0.collect().await;
+        assert_eq!(1, lines.len());
+        let booking: rocket::serde::json::Value = rocket::serde::json::from_str(&lines[0]).unwrap();
+        assert_eq!(in_range_id, booking["session_id"].as_i64().unwrap());
+        assert_eq!(Some(true), booking["attended"].as_bool());
+
+        let (from, to) = may();
+        let lines: Vec<String> = _export_bookings(&pool, &config, from, to, Some("csv".to_string())).await.unwrap().1.0.collect().await;
+        assert_eq!(2, lines.len());
+        assert!(lines[0].starts_with("person_id,person_name,"));
+        assert!(lines[1].starts_with(&format!("{},Test User,member@example.org,", member_id)), "{}", lines[1]);
+    }
+
+    #[sqlx::test]
+    async fn member_timeline(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let attended_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let cancelled_id = create_session(&pool, &Utc::now().add(TimeDelta::days(2)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let member = Claims::create(member_id, "member@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let admin = Claims::create(trainer_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+
+        pool.execute(format!("insert into booking (person_id, session_id, created_at) values ({}, {}, now() - interval '3 days')", member_id, attended_id).as_str()).await.unwrap();
+        pool.execute(format!("insert into booking (person_id, session_id, created_at) values ({}, {}, now() - interval '2 days')", member_id, cancelled_id).as_str()).await.unwrap();
+        _delete_booking(&pool, &member, None, false, member_id, cancelled_id, BookingCancellation { reason: Some("Injured".to_string()), ..BookingCancellation::default() }).await.unwrap();
+        _update_booking(&pool, &admin, None, member_id, attended_id, Json(BookingUpdate { attended: true, credits_used: None })).await.unwrap();
+
+        // The cancelled booking was deleted, so only its cancellation remains
+        let timeline = _get_timeline(&pool, member_id, None, None, Page::default()).await.unwrap();
+        let events: Vec<(&str, Option<i64>)> = timeline.iter().map(|e| (e.event.as_str(), e.session_id)).collect();
+        assert_eq!(vec![("booked", Some(attended_id)), ("cancelled", Some(cancelled_id)), ("attended", Some(attended_id))], events);
+        assert_eq!(Some("Injured".to_string()), timeline[1].reason);
+        assert!(timeline.windows(2).all(|w| w[0].at <= w[1].at));
+
+        let timeline = _get_timeline(&pool, member_id, Some(Utc::now().add(TimeDelta::days(-1)).fixed_offset()), None, Page::default()).await.unwrap();
+        assert_eq!(2, timeline.len());
+        let timeline = _get_timeline(&pool, member_id, None, None, Page { limit: Some(1), offset: Some(2) }).await.unwrap();
+        assert_eq!("attended", timeline[0].event);
+        assert_eq!(1, timeline.len());
+
+        // Unmarking attendance removes it from the timeline
+        _update_booking(&pool, &admin, None, member_id, attended_id, Json(BookingUpdate { attended: false, credits_used: None })).await.unwrap();
+        assert_eq!(2, _get_timeline(&pool, member_id, None, None, Page::default()).await.unwrap().len());
+    }
+
+    #[sqlx::test]
+    async fn swap_booking_to_full_session(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "", 5).await;
+        let other_member_id = create_person(&pool, "other@example.org", "member", 0).await;
+        let from_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let to_id = create_session_max_bookings(&pool, &Utc::now().add(TimeDelta::days(2)), trainer_id, "HIIT", "Oak Hill Park", Some(1)).await;
+        pool.execute(format!("update session set cost = 2 where id in ({from_id}, {to_id})").as_str()).await.unwrap();
+        pool.execute(format!("insert into booking (person_id, session_id, credits_used) values ({member_id}, {from_id}, 2), ({other_member_id}, {to_id}, 0)").as_str()).await.unwrap();
         let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "member@example.com", &None, &vec![], Duration::minutes(1));
+
+        // The member keeps their original booking and credits
+        let result = _swap_booking(&pool, &timezone, &Config::default(), &claim, BookingSwap { from_session_id: from_id, to_session_id: to_id, credits_used: Some(2) }).await;
+        assert_eq!(Status::Conflict, result.err().unwrap().0);
+        let booked: Vec<i64> = query_scalar("select session_id from booking where person_id = $1").bind(member_id).fetch_all(&pool).await.unwrap();
+        assert_eq!(vec![from_id], booked);
+        assert_eq!(5, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+        let cancellations = _list_cancellations(&pool, None, None, Page::default()).await.unwrap();
+        assert!(cancellations.is_empty());
+    }
 
-        // Precondition: zero bookings
-        assert_eq!(0, count_bookings(&pool).await);
+    #[sqlx::test]
+    async fn swap_booking_between_sessions(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
 
-        // Create booking 1
-        let claim = Claims::create(member_id, "member@example.com", &Some("011111".to_string()), &vec!["limited-member".to_string()], Duration::minutes(1));
-        crate::bookings::_create_booking(&pool, &timezone, &claim, Json(booking_1)).await.unwrap();
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "", 5).await;
+        let limited_member_id = create_person(&pool, "limited@example.org", "limited-member", 0).await;
+        let from_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let to_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)).add(TimeDelta::hours(2)), trainer_id, "HIIT", "Oak Hill Park").await;
+        pool.execute(format!("update session set cost = 2 where id = {from_id}").as_str()).await.unwrap();
+        pool.execute(format!("update session set cost = 3 where id = {to_id}").as_str()).await.unwrap();
+        pool.execute(format!("insert into booking (person_id, session_id, credits_used) values ({member_id}, {from_id}, 2), ({limited_member_id}, {from_id}, 0)").as_str()).await.unwrap();
+        let timezone: Tz = "Europe/London".parse().unwrap();
 
-        // Postcondition 1: one booking
-        assert_eq!(1, count_bookings(&pool).await);
+        // Credits are refunded for the original session and taken for the new one
+        let claim = Claims::create(member_id, "member@example.com", &None, &vec![], Duration::minutes(1));
+        let swapped = _swap_booking(&pool, &timezone, &Config::default(), &claim, BookingSwap { from_session_id: from_id, to_session_id: to_id, credits_used: Some(3) }).await.unwrap();
+        assert_eq!((to_id, Some(3)), (swapped.booking.session_id, swapped.booking.credits_used));
+        let booked: Vec<i64> = query_scalar("select session_id from booking where person_id = $1").bind(member_id).fetch_all(&pool).await.unwrap();
+        assert_eq!(vec![to_id], booked);
+        assert_eq!(4, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+        let cancellations = _list_cancellations(&pool, None, None, Page::default()).await.unwrap();
+        assert_eq!(Some(format!("Swapped to session {}", to_id)), cancellations[0].reason);
+
+        // The original booking does not count towards a limited member's weekly booking
+        let limited = Claims::create(limited_member_id, "limited@example.com", &None, &vec!["limited-member".to_string()], Duration::minutes(1));
+        _swap_booking(&pool, &timezone, &Config::default(), &limited, BookingSwap { from_session_id: from_id, to_session_id: to_id, credits_used: None }).await.unwrap();
+        assert_eq!(2, count_bookings(&pool).await);
+    }
 
-        // Create booking 2: fails
-        let claim = Claims::create(member_id, "member@example.com", &Some("011111".to_string()), &vec!["limited-member".to_string()], Duration::minutes(1));
-        let result = crate::bookings::_create_booking(&pool, &timezone, &claim, Json(booking_2.clone())).await;
-        assert!(result.is_err());
-        assert_eq!(Custom(Status::Forbidden, "Cannot book session: member already has 1 booking(s) in this week.".to_string()), result.err().unwrap());
+    #[sqlx::test]
+    async fn cancellable_own_bookings(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
 
-        // Postcondition 2: one booking
-        assert_eq!(1, count_bookings(&pool).await);
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let past_id = create_session(&pool, &Utc::now().add(TimeDelta::days(-1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let soon_id = create_session(&pool, &Utc::now().add(TimeDelta::minutes(30)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let later_id = create_session(&pool, &Utc::now().add(TimeDelta::days(2)), trainer_id, "HIIT", "Oak Hill Park").await;
+        pool.execute(format!("insert into booking (person_id, session_id) values ({member_id}, {past_id}), ({member_id}, {soon_id}), ({member_id}, {later_id})").as_str()).await.unwrap();
+        let cutoff_mins = Some(60);
+
+        let member = Claims::create(member_id, "member@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let bookings: Vec<(Option<bool>, Option<CancelBlockedReason>)> = _list_bookings(&pool, &member, None, Some(member_id), None, None, Page::default()).await.unwrap()
+            .into_inner().into_iter()
+            .map(|b| b.with_cancellation(&member, cutoff_mins))
+            .map(|b| (b.cancellable, b.cancel_blocked_reason))
+            .collect();
+        assert_eq!(vec![
+            (Some(false), Some(CancelBlockedReason::SessionInPast)),
+            (Some(false), Some(CancelBlockedReason::WithinCutoff)),
+            (Some(true), None)
+        ], bookings);
+
+        // Not included for other people's bookings
+        let trainer = Claims::create(trainer_id, "trainer@example.org", &None, &vec!["member".to_string(), "trainer".to_string()], Duration::minutes(1));
+        let booking = _get_booking(&pool, &trainer, later_id, member_id).await.unwrap().into_inner().with_cancellation(&trainer, cutoff_mins);
+        assert_eq!(None, booking.cancellable);
+
+        // Cancelling follows the same rules
+        let result = _delete_booking(&pool, &member, cutoff_mins, false, member_id, past_id, BookingCancellation::default()).await;
+        assert_eq!(Custom(Status::Forbidden, "Cannot cancel past booking.".to_string()), result.err().unwrap());
+        let result = _delete_booking(&pool, &member, cutoff_mins, false, member_id, soon_id, BookingCancellation::default()).await;
+        assert_eq!(Custom(Status::Forbidden, "Cannot cancel booking less than 60 minutes before the session.".to_string()), result.err().unwrap());
+        _delete_booking(&pool, &member, cutoff_mins, false, member_id, later_id, BookingCancellation::default()).await.unwrap();
+        assert_eq!(2, count_bookings(&pool).await);
+    }
 
-        // Cancel booking 1
-        _delete_booking(&pool, &claim, member_id, session_id_1).await.unwrap();
+    #[sqlx::test]
+    async fn debit_more_credits_than_balance(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
 
-        // Postcondition 3: zero bookings
-        assert_eq!(0, count_bookings(&pool).await);
+        let member_id = create_person(&pool, "member@example.org", "", 2).await;
+        let mut conn = pool.acquire().await.unwrap();
+        let result = crate::bookings::debit_credits(&mut conn, member_id, 3).await;
+        assert_eq!(Some(Custom(Status::PaymentRequired, "Not enough credits for booking: current balance is 2.".to_string())), result.err().map(Custom::from));
+        assert_eq!(2, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
 
-        // Create booking 2: succeeds now
-        let claim = Claims::create(member_id, "member@example.com", &Some("011111".to_string()), &vec!["limited-member".to_string()], Duration::minutes(1));
-        crate::bookings::_create_booking(&pool, &timezone, &claim, Json(booking_2)).await.unwrap();
+        crate::bookings::debit_credits(&mut conn, member_id, 2).await.unwrap();
+        assert_eq!(0, UserLoginRecord::load_by_id(&pool, member_id).await.unwrap().unwrap().credits);
+    }
 
-        // Postcondition 4: one booking
-        assert_eq!(1, count_bookings(&pool).await);
+    #[sqlx::test]
+    async fn max_active_bookings(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let config = Config { max_active_bookings: Some(2), ..Config::default() };
+        let mut session_ids = Vec::new();
+        for days in 1..=3 {
+            session_ids.push(create_session(&pool, &Utc::now().add(TimeDelta::days(days)), trainer_id, "HIIT", "Oak Hill Park").await);
+        }
+        // Past bookings do not count towards the limit
+        let past_session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(-1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        pool.execute(format!("insert into booking (person_id, session_id) values ({}, {})", member_id, past_session_id).as_str()).await.unwrap();
+
+        // Up to the limit
+        let claim = Claims::create(member_id, "member@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
+        for session_id in &session_ids[0..2] {
+            let booking = SessionBooking { person_id: member_id, session_id: *session_id, credits_used: None };
+            crate::bookings::_create_booking(&pool, &timezone, &config, &claim, false, Json(booking)).await.unwrap();
+        }
+
+        // Over the limit
+        let preview = _preview_booking(&pool, &timezone, &config, &claim, session_ids[2]).await.unwrap();
+        assert_eq!(Some("ACTIVE_BOOKING_LIMIT_REACHED"), preview.reason);
+        let booking = SessionBooking { person_id: member_id, session_id: session_ids[2], credits_used: None };
+        let result = crate::bookings::_create_booking(&pool, &timezone, &config, &claim, false, Json(booking)).await;
+        assert_eq!(Custom(Status::Forbidden, "Cannot book session: member already has the maximum of 2 future booking(s).".to_string()), result.err().unwrap());
+
+        // Admins are not limited
+        let admin = Claims::create(trainer_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let booking = SessionBooking { person_id: member_id, session_id: session_ids[2], credits_used: None };
+        crate::bookings::_create_booking(&pool, &timezone, &config, &admin, false, Json(booking)).await.unwrap();
     }
 
     #[sqlx::test]
-    async fn book_session_limited_member_existing_session_next_week(pool: PgPool) {
+    async fn preview_session_unstaffed(pool: PgPool) {
         pool.execute(include_str!("../schema.sql")).await.unwrap();
 
         let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
-        let member_id = create_person(&pool, "member@example.org", "limited-member", 0).await;
-        let tomorrow = Utc::now().add(TimeDelta::days(1));
-        let next_week = tomorrow.add(TimeDelta::weeks(1));
-        let session_id_1 = create_session(&pool, &tomorrow, trainer_id, "HIIT", "Oak Hill Park").await;
-        let booking_1 = SessionBooking {
-            person_id: member_id,
-            session_id: session_id_1,
-            credits_used: None
-        };
-        let session_id_2 = create_session(&pool, &next_week, trainer_id, "On The Move", "Oak Hill Park").await;
-        let booking_2 = SessionBooking {
-            person_id: member_id,
-            session_id: session_id_2,
-            credits_used: None
-        };
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
         let timezone: Tz = "Europe/London".parse().unwrap();
+        let claim = Claims::create(member_id, "member@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
 
-        // Precondition: zero bookings
-        assert_eq!(0, count_bookings(&pool).await);
+        // HIIT requires a trainer, so the session cannot be booked without one
+        pool.execute(format!("update session set trainer = null where id = {}", session_id).as_str()).await.unwrap();
+        let preview = _preview_booking(&pool, &timezone, &Config::default(), &claim, session_id).await.unwrap();
+        assert_eq!(Some("SESSION_UNSTAFFED"), preview.reason);
 
-        // Create booking 1
-        let claim = Claims::create(member_id, "member@example.com", &Some("011111".to_string()), &vec!["limited-member".to_string()], Duration::minutes(1));
-        crate::bookings::_create_booking(&pool, &timezone, &claim, Json(booking_1)).await.unwrap();
+        // Bookable again once a trainer is assigned
+        pool.execute(format!("update session set trainer = {} where id = {}", trainer_id, session_id).as_str()).await.unwrap();
+        let preview = _preview_booking(&pool, &timezone, &Config::default(), &claim, session_id).await.unwrap();
+        assert!(preview.can_book);
+    }
 
-        // Postcondition 1: one booking
-        assert_eq!(1, count_bookings(&pool).await);
+    #[sqlx::test]
+    async fn suggest_capacity_from_past_sessions(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
 
-        // Create booking 2: succeeds because it's next week
-        let claim = Claims::create(member_id, "member@example.com", &Some("011111".to_string()), &vec!["limited-member".to_string()], Duration::minutes(1));
-        crate::bookings::_create_booking(&pool, &timezone, &claim, Json(booking_2.clone())).await.unwrap();
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let mut member_ids = Vec::new();
+        for i in 0..6 {
+            member_ids.push(create_person(&pool, &format!("member{}@example.org", i), "member", 0).await);
+        }
+        // Three past 7am sessions with 2, 4 and 6 bookings out of 10, and a future one that is left out
+        let seven_am = Utc::now().date_naive().and_time(NaiveTime::from_hms_opt(7, 0, 0).unwrap()).and_utc();
+        for (days, booked) in [(-1, 2), (-8, 4), (-15, 6), (6, 6)] {
+            let session_id = create_session_max_bookings(&pool, &seven_am.add(TimeDelta::days(days)), trainer_id, "HIIT", "Oak Hill Park", Some(10)).await;
+            for person_id in &member_ids[..booked] {
+                pool.execute(format!("insert into booking (person_id, session_id) values ({}, {})", person_id, session_id).as_str()).await.unwrap();
+            }
+        }
 
-        // Postcondition 2: two bookings
-        assert_eq!(2, count_bookings(&pool).await);
+        let member = Claims::create(member_ids[0], "member@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let result = _get_capacity_suggestions(&pool, &Config::default(), &Tz::UTC, &member, None, None).await;
+        assert_eq!(Status::Forbidden, result.unwrap_err().0);
+
+        let admin = Claims::create(trainer_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let suggestions = _get_capacity_suggestions(&pool, &Config::default(), &Tz::UTC, &admin, None, None).await.unwrap();
+        assert_eq!(1, suggestions.len());
+        let suggestion = &suggestions[0];
+        assert_eq!(("HIIT", "07:00", 3), (suggestion.session_type_name.as_str(), suggestion.time_slot.as_str(), suggestion.session_count));
+        assert_eq!((4.0, 6, Some(10)), (suggestion.average_occupancy, suggestion.peak_occupancy, suggestion.current_max_booking_count));
+        // The 90th percentile of 2, 4 and 6 is 5.6, plus 10% headroom
+        assert_eq!(7, suggestion.suggested_max_booking_count);
     }
 
     #[sqlx::test]
-    async fn book_session_non_member_using_credit_not_opted_in(pool: PgPool) {
+    async fn list_disengaged_members(pool: PgPool) {
         pool.execute(include_str!("../schema.sql")).await.unwrap();
 
         let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
-        let member_id = create_person(&pool, "member@example.org", "member", 5).await;
-        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
-        let booking = SessionBooking {
-            person_id: member_id,
-            session_id,
-            credits_used: None
-        };
+        let never_booked_id = create_person(&pool, "never-booked@example.org", "member", 0).await;
+        let no_show_id = create_person(&pool, "no-show@example.org", "member", 0).await;
+        let lapsed_id = create_person(&pool, "lapsed@example.org", "member", 0).await;
+        let regular_id = create_person(&pool, "regular@example.org", "member", 0).await;
+        let last_month_id = create_session(&pool, &Utc::now().add(TimeDelta::days(-30)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let last_week_id = create_session(&pool, &Utc::now().add(TimeDelta::days(-7)), trainer_id, "HIIT", "Oak Hill Park").await;
+        for (person_id, session_id, attended) in [(no_show_id, last_week_id, false), (lapsed_id, last_month_id, true), (regular_id, last_week_id, true), (trainer_id, last_month_id, false)] {
+            pool.execute(format!("insert into booking (person_id, session_id, attended) values ({}, {}, {})", person_id, session_id, attended).as_str()).await.unwrap();
+        }
 
-        // Precondition: zero bookings
-        assert_eq!(0, count_bookings(&pool).await);
+        let member = Claims::create(regular_id, "regular@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let result = _get_disengaged_members(&pool, &Config::default(), &member, None).await;
+        assert_eq!(Status::Forbidden, result.unwrap_err().0);
 
-        // Create booking
-        let timezone: Tz = "Europe/London".parse().unwrap();
-        let claim = Claims::create(member_id, "joe@example.com", &Some("011111".to_string()), &vec![], Duration::minutes(1));
-        let result = crate::bookings::_create_booking(&pool, &timezone, &claim, Json(booking)).await;
-        assert!(result.is_err());
-        assert_eq!(Custom(Status::PaymentRequired, "Opt in to use credits for booking.".to_string()), result.err().unwrap());
+        // Since two weeks ago, the member who attended last month has lapsed, and those who booked most recently come first
+        let admin = Claims::create(trainer_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let since = Utc::now().add(TimeDelta::days(-14)).fixed_offset();
+        let members = _get_disengaged_members(&pool, &Config::default(), &admin, Some(since)).await.unwrap();
+        assert_eq!(vec![(no_show_id, 1), (lapsed_id, 0), (never_booked_id, 0)], members.iter().map(|m| (m.person_id, m.booking_count)).collect::<Vec<_>>());
+        assert!(members[2].last_booked_session_at.is_none());
 
-        // Postcondition: still zero bookings
-        assert_eq!(0, count_bookings(&pool).await);
+        // Over all time, the lapsed member has attended
+        let members = _get_disengaged_members(&pool, &Config::default(), &admin, None).await.unwrap();
+        assert_eq!(vec![no_show_id, never_booked_id], members.iter().map(|m| m.person_id).collect::<Vec<_>>());
     }
 
     #[sqlx::test]
-    async fn book_session_non_member_using_credit_opted_in(pool: PgPool) {
+    async fn book_with_and_without_waiver(pool: PgPool) {
         pool.execute(include_str!("../schema.sql")).await.unwrap();
+        pool.execute("update session_type set waiver_url = 'https://example.org/waivers/hiit-v1.pdf', requires_waiver = true where name = 'HIIT'").await.unwrap();
 
         let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
-        let member_id = create_person(&pool, "member@example.org", "", 5).await;
-        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
-        let booking = SessionBooking {
-            person_id: member_id,
-            session_id,
-            credits_used: Some(1)
-        };
+        let member_id = create_person(&pool, "member@example.org", "member", 0).await;
+        let mut session_ids = Vec::new();
+        for days in 1..=3 {
+            session_ids.push(create_session(&pool, &Utc::now().add(TimeDelta::days(days)), trainer_id, "HIIT", "Oak Hill Park").await);
+        }
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let member = Claims::create(member_id, "member@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let admin = Claims::create(trainer_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let booking = |session_id: i64| Json(SessionBooking { person_id: member_id, session_id, credits_used: None });
+
+        // Without an accepted waiver, members cannot book but admins can still book them
+        let preview = _preview_booking(&pool, &timezone, &Config::default(), &member, session_ids[0]).await.unwrap();
+        assert_eq!(Some("WAIVER_NOT_ACCEPTED"), preview.reason);
+        let result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &member, false, booking(session_ids[0])).await;
+        assert_eq!(Custom(Status::Forbidden, "Cannot book session: accept the waiver at https://example.org/waivers/hiit-v1.pdf first.".to_string()), result.unwrap_err());
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &admin, false, booking(session_ids[0])).await.unwrap();
+
+        // With the waiver accepted
+        pool.execute(format!("insert into person_waiver (person_id, session_type_id, waiver_url) select {}, id, waiver_url from session_type where name = 'HIIT'", member_id).as_str()).await.unwrap();
+        crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &member, false, booking(session_ids[1])).await.unwrap();
+
+        // A replaced waiver must be accepted again
+        pool.execute("update session_type set waiver_url = 'https://example.org/waivers/hiit-v2.pdf' where name = 'HIIT'").await.unwrap();
+        let result = crate::bookings::_create_booking(&pool, &timezone, &Config::default(), &member, false, booking(session_ids[2])).await;
+        assert_eq!(Status::Forbidden, result.unwrap_err().0);
+        assert_eq!(2, count_bookings(&pool).await);
+    }
 
-        // Precondition: zero bookings
-        assert_eq!(0, count_bookings(&pool).await);
+    #[sqlx::test]
+    async fn explain_bookings_query(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
 
-        // Create booking
-        let timezone: Tz = "Europe/London".parse().unwrap();
-        let claim = Claims::create(member_id, "joe@example.com", &Some("011111".to_string()), &vec![], Duration::minutes(1));
-        let result = crate::bookings::_create_booking(&pool, &timezone, &claim, Json(booking)).await.unwrap();
+        let admin = Claims::create(1, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let explanation = _explain_bookings(&pool, &admin, None, Some(2), Some("2024-01-01T00:00:00Z".to_string()), None, Page::default()).await.unwrap();
+        assert!(explanation.sql.starts_with("SELECT b.person_id"));
+        assert!(explanation.sql.contains("b.person_id = $1 AND s.datetime >= $2"));
+        assert!(!explanation.plan.is_empty());
 
-        // Check that the booking has the used credits
-        let created_booking: SessionBooking = query_as("SELECT person_id, session_id, credits_used FROM booking WHERE person_id = $1 AND session_id = $2")
-            .bind(member_id)
-            .bind(session_id)
-            .fetch_one(&pool)
-            .await.unwrap();
-        assert_eq!(Some(1), created_booking.credits_used);
-        let bookings_list = _list_bookings(&pool, &claim, None, Some(member_id), None, None).await.unwrap();
-        assert_eq!(1, bookings_list.len());
-        assert_eq!(1, bookings_list.get(0).unwrap().credits_used);
+        // The SQL reveals the schema, so members cannot see it, even for their own bookings
+        let member = Claims::create(2, "member@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let result = _explain_bookings(&pool, &member, None, Some(2), None, None, Page::default()).await;
+        assert_eq!(Status::Forbidden, result.unwrap_err().0);
+    }
 
-        // Check that the user has been debited one credit
-        let member_record = UserLoginRecord::load_by_id(&pool, member_id)
-            .await.unwrap().unwrap();
-        assert_eq!(4, member_record.credits);
+    #[sqlx::test]
+    async fn approve_booking_request(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        pool.execute("update session_type set requires_approval = true where name = 'HIIT'").await.unwrap();
 
-        // Cancel booking
-        _delete_booking(&pool, &claim, member_id, session_id).await.unwrap();
-        // Postcondition: zero bookings
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "", 5).await;
+        let other_id = create_person(&pool, "other@example.org", "", 5).await;
+        let session_id = create_session_max_bookings(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park", Some(1)).await;
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let member = Claims::create(member_id, "member@example.com", &None, &vec![], Duration::minutes(1));
+        let other = Claims::create(other_id, "other@example.com", &None, &vec![], Duration::minutes(1));
+        let admin = Claims::create(trainer_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+
+        // Pending requests neither fill the session nor debit credits, so both members can request the last space
+        for (claim, person_id) in [(&member, member_id), (&other, other_id)] {
+            let booking = SessionBooking { person_id, session_id, credits_used: Some(1) };
+            let request = _request_booking(&pool, &timezone, &Config::default(), claim, &booking).await.unwrap();
+            assert_eq!(1, request.credits_held);
+        }
+        let booking = SessionBooking { person_id: member_id, session_id, credits_used: Some(1) };
+        let result = _request_booking(&pool, &timezone, &Config::default(), &member, &booking).await;
+        assert_eq!(Status::Conflict, result.unwrap_err().0);
         assert_eq!(0, count_bookings(&pool).await);
+        let credits: i16 = query_scalar("select credits from person where id = $1").bind(member_id).fetch_one(&pool).await.unwrap();
+        assert_eq!(5, credits);
 
-        // Check that the user's credit has been restored
-        let member_record = UserLoginRecord::load_by_id(&pool, member_id)
-            .await.unwrap().unwrap();
-        assert_eq!(5, member_record.credits);
+        // Members only see their own requests, and cannot approve them
+        assert_eq!(1, _list_booking_requests(&pool, &member, None).await.unwrap().len());
+        assert_eq!(2, _list_booking_requests(&pool, &admin, Some(session_id)).await.unwrap().len());
+        let approval = BookingApproval { person_id: member_id, session_id, approved: true };
+        assert_eq!(Status::Forbidden, _approve_booking(&pool, &timezone, &Config::default(), &member, &approval).await.err().unwrap().0);
+
+        // Approving makes the booking and debits the held credits
+        assert!(matches!(_approve_booking(&pool, &timezone, &Config::default(), &admin, &approval).await.unwrap(), ApprovalOutcome::Approved(_)));
+        assert_eq!(1, count_bookings(&pool).await);
+        let credits: i16 = query_scalar("select credits from person where id = $1").bind(member_id).fetch_one(&pool).await.unwrap();
+        assert_eq!(4, credits);
+        assert_eq!(Status::NotFound, _approve_booking(&pool, &timezone, &Config::default(), &admin, &approval).await.err().unwrap().0);
+
+        // The approved booking counts towards the session's capacity, so the other request cannot be approved while it is full
+        let approval = BookingApproval { person_id: other_id, session_id, approved: true };
+        assert_eq!(Status::Conflict, _approve_booking(&pool, &timezone, &Config::default(), &admin, &approval).await.err().unwrap().0);
+        assert_eq!(1, _list_booking_requests(&pool, &admin, Some(session_id)).await.unwrap().len());
     }
 
     #[sqlx::test]
-    async fn book_session_non_member_using_credit_max_bookings_reached(pool: PgPool) {
+    async fn reject_booking_request(pool: PgPool) {
         pool.execute(include_str!("../schema.sql")).await.unwrap();
+        pool.execute("update session_type set requires_approval = true where name = 'HIIT'").await.unwrap();
 
         let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
         let member_id = create_person(&pool, "member@example.org", "", 5).await;
-        let session_id = create_session_max_bookings(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park", Some(0)).await;
-        let booking = SessionBooking {
-            person_id: member_id,
-            session_id,
-            credits_used: Some(1)
-        };
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let member = Claims::create(member_id, "member@example.com", &None, &vec![], Duration::minutes(1));
+        let admin = Claims::create(trainer_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
 
-        // Precondition: zero bookings
+        let booking = SessionBooking { person_id: member_id, session_id, credits_used: Some(1) };
+        _request_booking(&pool, &timezone, &Config::default(), &member, &booking).await.unwrap();
+
+        // Rejecting releases the held credits without making a booking
+        let approval = BookingApproval { person_id: member_id, session_id, approved: false };
+        assert!(matches!(_approve_booking(&pool, &timezone, &Config::default(), &admin, &approval).await.unwrap(), ApprovalOutcome::Rejected(_)));
         assert_eq!(0, count_bookings(&pool).await);
+        let credits: i16 = query_scalar("select credits from person where id = $1").bind(member_id).fetch_one(&pool).await.unwrap();
+        assert_eq!(5, credits);
+        assert!(_list_booking_requests(&pool, &member, None).await.unwrap().is_empty());
+    }
 
-        // Create booking: fail due to max bookings reached
-        let timezone: Tz = "Europe/London".parse().unwrap();
-        let claim = Claims::create(member_id, "joe@example.com", &Some("011111".to_string()), &vec![], Duration::minutes(1));
-        let booking_result = crate::bookings::_create_booking(&pool, &timezone, &claim, Json(booking)).await.err().unwrap();
-        assert_eq!(Custom(Status::Conflict, "Session has reached it maximum number of bookings: 0.".to_string()), booking_result);
+    #[sqlx::test]
+    async fn booking_requests_hold_credits(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        pool.execute("update session_type set requires_approval = true where name = 'HIIT'").await.unwrap();
 
-        // Still zero bookings
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer", 0).await;
+        let member_id = create_person(&pool, "member@example.org", "", 1).await;
+        let session_1 = create_session(&pool, &Utc::now().add(TimeDelta::days(1)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let session_2 = create_session(&pool, &Utc::now().add(TimeDelta::days(2)), trainer_id, "HIIT", "Oak Hill Park").await;
+        let timezone: Tz = "Europe/London".parse().unwrap();
+        let member = Claims::create(member_id, "member@example.com", &None, &vec![], Duration::minutes(1));
+        let admin = Claims::create(trainer_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+
+        // The only credit is held for the first request, so the second cannot be paid for
+        let booking_1 = SessionBooking { person_id: member_id, session_id: session_1, credits_used: Some(1) };
+        let booking_2 = SessionBooking { person_id: member_id, session_id: session_2, credits_used: Some(1) };
+        _request_booking(&pool, &timezone, &Config::default(), &member, &booking_1).await.unwrap();
+        let result = _request_booking(&pool, &timezone, &Config::default(), &member, &booking_2).await;
+        assert_eq!(Status::PaymentRequired, result.unwrap_err().0);
+
+        // Withdrawing the first request releases its credit for the second
+        _delete_booking_request(&pool, &member, session_1).await.unwrap();
+        assert_eq!(Status::NotFound, _delete_booking_request(&pool, &member, session_1).await.unwrap_err().0);
+        _request_booking(&pool, &timezone, &Config::default(), &member, &booking_2).await.unwrap();
+
+        // The member's booking limits apply again on approval, keeping the request
+        let config = Config { max_active_bookings: Some(0), ..Config::default() };
+        let approval = BookingApproval { person_id: member_id, session_id: session_2, approved: true };
+        assert_eq!(Status::Forbidden, _approve_booking(&pool, &timezone, &config, &admin, &approval).await.err().unwrap().0);
+        assert_eq!(1, _list_booking_requests(&pool, &member, None).await.unwrap().len());
+
+        // As does the rule against booking past sessions
+        pool.execute("update session set datetime = now() - interval '1 hour'").await.unwrap();
+        assert_eq!(Status::Forbidden, _approve_booking(&pool, &timezone, &Config::default(), &admin, &approval).await.err().unwrap().0);
         assert_eq!(0, count_bookings(&pool).await);
+    }
 
-        // Check that the user has NOT been debited any credits
-        let member_record = UserLoginRecord::load_by_id(&pool, member_id)
-            .await.unwrap().unwrap();
-        assert_eq!(5, member_record.credits);
+    #[sqlx::test]
+    async fn attendance_stats_as_csv(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let admin_id = create_person(&pool, "admin@example.org", "admin", 0).await;
+        let member_id: i64 = query_scalar("insert into person (name, email, roles) values ('Smith, \"Jo\"', 'jo@example.org', 'member') returning id")
+            .fetch_one(&pool).await.unwrap();
+        let session_id = create_session(&pool, &Utc::now().add(TimeDelta::days(-1)), admin_id, "HIIT", "Oak Hill Park").await;
+        query("insert into booking (person_id, session_id, attended) values ($1, $2, true)")
+            .bind(member_id)
+            .bind(session_id)
+            .execute(&pool).await.unwrap();
+        let session_type_id: i32 = query_scalar("select id from session_type where name = 'HIIT'")
+            .fetch_one(&pool).await.unwrap();
+        let client = test_client(pool.clone(), routes![super::get_attendance_stats]).await;
+        let admin = || Claims::create(admin_id, "admin@example.org", &None, &vec!["admin".to_string()], Duration::minutes(1));
+
+        // The same leaderboard as the JSON, with names quoted where they would break the columns
+        let response = client.get(format!("/stats/attendance?session_type={}&format=csv", session_type_id)).header(bearer(admin())).dispatch().await;
+        assert_eq!(Status::Ok, response.status());
+        assert_eq!(Some(ContentType::CSV), response.content_type());
+        assert_eq!(Some("attachment; filename=\"attendance.csv\""), response.headers().get_one("Content-Disposition"));
+        let expected = format!("person_id,name,email,attended_count\r\n{},\"Smith, \"\"Jo\"\"\",jo@example.org,1\r\n{},Test User,admin@example.org,0\r\n", member_id, admin_id);
+        assert_eq!(expected, response.into_string().await.unwrap());
+
+        let response = client.get(format!("/stats/attendance?session_type={}&format=xml", session_type_id)).header(bearer(admin())).dispatch().await;
+        assert_eq!(Status::BadRequest, response.status());
     }
-}
 
+    #[sqlx::test]
+    async fn occupancy_stats_per_session_type(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let admin_id = create_person(&pool, "admin@example.org", "admin", 0).await;
+        let member_ids = [create_person(&pool, "one@example.org", "member", 0).await, create_person(&pool, "two@example.org", "member", 0).await];
+        // Sessions with their type, capacity and number of bookings
+        let sessions = [("HIIT", Some(4), 1), ("HIIT", Some(2), 2), ("Strong", None, 2)];
+        for (session_type_name, max_bookings, booking_count) in sessions {
+            let session_id = create_session_max_bookings(&pool, &Utc::now().add(TimeDelta::days(-1)), admin_id, session_type_name, "Oak Hill Park", max_bookings).await;
+            for person_id in &member_ids[..booking_count] {
+                query("insert into booking (person_id, session_id) values ($1, $2)")
+                    .bind(person_id)
+                    .bind(session_id)
+                    .execute(&pool).await.unwrap();
+            }
+        }
+        let client = test_client(pool.clone(), routes![super::get_occupancy_stats]).await;
+        let admin = Claims::create(admin_id, "admin@example.org", &None, &vec!["admin".to_string()], Duration::minutes(1));
+
+        // Sessions with unlimited capacity cannot be full, so are left out
+        let stats: Vec<rocket::serde::json::Value> = client.get("/stats/occupancy").header(bearer(admin)).dispatch().await.into_json().await.unwrap();
+        assert_eq!(1, stats.len());
+        assert_eq!("HIIT", stats[0]["session_type_name"]);
+        assert_eq!((2, 3, 6), (stats[0]["session_count"].as_i64().unwrap(), stats[0]["booking_count"].as_i64().unwrap(), stats[0]["capacity"].as_i64().unwrap()));
+        // The mean of each session's utilization, not the total bookings over the total capacity
+        assert_eq!(0.625, stats[0]["utilization"].as_f64().unwrap());
+
+        let member = Claims::create(member_ids[0], "one@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        assert_eq!(Status::Forbidden, client.get("/stats/occupancy").header(bearer(member)).dispatch().await.status());
+    }
+}
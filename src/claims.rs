@@ -9,6 +9,14 @@ use crate::AppState;
 
 const BEARER: &str = "Bearer ";
 const AUTHORIZATION: &str = "Authorization";
+const ADMIN_OVERRIDE: &str = "X-Admin-Override";
+const ACCESS_TOKEN_KEY: &str = "ACCESS_TOKEN_KEY";
+const ACCESS_TOKEN_KEY_PREVIOUS: &str = "ACCESS_TOKEN_KEY_PREVIOUS";
+// Upper bound on the configured expiry leeway, so that it cannot meaningfully extend token lifetimes
+const MAX_TOKEN_LEEWAY_SECS: u64 = 60;
+// The only paths that users who must change their password can use until they have done so. Changing the
+// password itself is authenticated by the current password rather than a token.
+const PASSWORD_CHANGE_ALLOWED_PATHS: [&str; 2] = ["/me", "/validate_login"];
 
 // Used when decoding a token to `Claims`
 #[derive(Debug, PartialEq, Clone)]
@@ -16,6 +24,7 @@ pub(crate) enum AuthenticationError {
     Missing,
     Decoding(String),
     Expired,
+    PasswordChangeRequired,
 }
 
 impl Display for AuthenticationError {
@@ -23,7 +32,20 @@ impl Display for AuthenticationError {
         match self {
             Self::Missing => f.write_str("missing authorization header"),
             Self::Decoding(msg) => write!(f, "failed to decode authorization header: {}", msg),
-            Self::Expired => f.write_str("authorization token expired")
+            Self::Expired => f.write_str("authorization token expired"),
+            Self::PasswordChangeRequired => f.write_str("password must be changed before continuing")
+        }
+    }
+}
+
+impl AuthenticationError {
+    /// Stable code for error responses, so that clients can refresh an expired token but log out on an invalid one
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            Self::Missing => "TOKEN_MISSING",
+            Self::Decoding(_) => "TOKEN_INVALID",
+            Self::Expired => "TOKEN_EXPIRED",
+            Self::PasswordChangeRequired => "PASSWORD_CHANGE_REQUIRED"
         }
     }
 }
@@ -34,6 +56,8 @@ pub(crate) struct Claims {
     pub(crate) email: String,
     pub(crate) phone: Option<String>,
     pub(crate) roles: Vec<String>,
+    #[serde(default)]
+    pub(crate) must_change_pwd: bool,
     exp: usize,
 }
 
@@ -50,20 +74,30 @@ impl<'r> FromRequest<'r> for Claims {
                 Outcome::Error((Status::Forbidden, AuthenticationError::Missing))
             },
             Some(value) => {
-                // Get the secret encoding/decoding key from the Rocket state
-                let secret: Option<String> = request.rocket().state()
-                    .and_then(|s: &AppState| s.secrets.get("ACCESS_TOKEN_KEY"));
-                if secret.is_none() {
+                // Get the secret encoding/decoding keys from the Rocket state
+                let state: Option<&AppState> = request.rocket().state();
+                if state.is_none() {
                     return Outcome::Error((Status::InternalServerError, AuthenticationError::Decoding("Missing app state".to_string())));
                 }
+                let state = state.unwrap();
+                let keys = access_token_decoding_keys(&state.secrets);
+                if keys.is_empty() {
+                    return Outcome::Error((Status::InternalServerError, AuthenticationError::Decoding(format!("Missing {} secret", ACCESS_TOKEN_KEY))));
+                }
 
-                match Claims::from_authorization(value, &secret.unwrap()) {
+                match Claims::from_authorization(value, &keys, state.jwt_algorithm, state.config.token_leeway_secs) {
                     Err(e) => {
                         request.local_cache::<Option<AuthenticationError>, _>(|| Some(e.clone()));
                         Outcome::Error((Status::Forbidden, e))
                     },
                     Ok(claims) => {
-                        Outcome::Success(claims)
+                        match claims.check_password_change(request.uri().path().as_str()) {
+                            Err(e) => {
+                                request.local_cache::<Option<AuthenticationError>, _>(|| Some(e.clone()));
+                                Outcome::Error((Status::Forbidden, e))
+                            },
+                            Ok(()) => Outcome::Success(claims)
+                        }
                     },
                 }
             },
@@ -71,6 +105,40 @@ impl<'r> FromRequest<'r> for Claims {
     }
 }
 
+/// Whether the request asks, with an `X-Admin-Override: true` header, to bypass the checks that hold even for
+/// admins, i.e. that a session is not full and has not already started. Admins are otherwise exempt from members'
+/// booking windows, cutoffs and limits without it. Only admins can override, and each override is audited, so
+/// callers check the role themselves.
+pub(crate) struct OverrideRequested(pub(crate) bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for OverrideRequested {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        let requested = request.headers().get_one(ADMIN_OVERRIDE)
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"));
+        Outcome::Success(OverrideRequested(requested))
+    }
+}
+
+/// Parses the configured JWT algorithm. Only the HMAC algorithms are supported since the keys are shared secrets.
+pub(crate) fn parse_algorithm(name: &str) -> Result<Algorithm, String> {
+    match name.parse::<Algorithm>() {
+        Ok(algorithm @ (Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)) => Ok(algorithm),
+        Ok(algorithm) => Err(format!("unsupported JWT algorithm {:?}, must be one of HS256, HS384 or HS512", algorithm)),
+        Err(e) => Err(format!("invalid JWT algorithm '{}': {}", name, e))
+    }
+}
+
+/// Returns the keys that access tokens may be signed with: the current key first, then the previous
+/// key (if any) so that tokens issued before a key rotation remain valid until they expire.
+pub(crate) fn access_token_decoding_keys(secrets: &shuttle_runtime::SecretStore) -> Vec<String> {
+    [ACCESS_TOKEN_KEY, ACCESS_TOKEN_KEY_PREVIOUS].iter()
+        .filter_map(|name| secrets.get(name))
+        .collect()
+}
+
 impl Claims {
     pub(crate) fn create(uid: i64, email: &str, phone: &Option<String>, roles: &Vec<String>, duration: Duration) -> Self {
         let now = Utc::now();
@@ -82,14 +150,27 @@ impl Claims {
             email: email.to_string(),
             phone: phone.clone(),
             roles: roles.to_owned(),
+            must_change_pwd: false,
             exp: expiration.timestamp() as usize,
         }
     }
 
+    /// Flags that the user must change their password before using anything else
+    pub(crate) fn with_must_change_pwd(self, must_change_pwd: bool) -> Self {
+        Self { must_change_pwd, ..self }
+    }
+
+    fn check_password_change(&self, path: &str) -> Result<(), AuthenticationError> {
+        if self.must_change_pwd && !PASSWORD_CHANGE_ALLOWED_PATHS.contains(&path) {
+            return Err(AuthenticationError::PasswordChangeRequired);
+        }
+        Ok(())
+    }
+
     /// Converts this claims into a token string
-    pub(crate) fn into_token(self, secret: &str) -> Result<String, Custom<String>> {
+    pub(crate) fn into_token(self, secret: &str, algorithm: Algorithm) -> Result<String, Custom<String>> {
         jsonwebtoken::encode(
-            &Header::default(),
+            &Header::new(algorithm),
             &self,
             &EncodingKey::from_secret(secret.as_ref()),
         ).map_err(|e| Custom(Status::InternalServerError, e.to_string()))
@@ -106,21 +187,34 @@ impl Claims {
         Ok(())
     }
 
-    /// Create a `Claims` from a 'Bearer <token>' value
-    fn from_authorization(value: &str, secret: &str) -> Result<Self, AuthenticationError> {
+    /// Create a `Claims` from a 'Bearer <token>' value, trying each of the given secrets in turn
+    fn from_authorization(value: &str, secrets: &[String], algorithm: Algorithm, leeway_secs: u64) -> Result<Self, AuthenticationError> {
         let token = value
             .strip_prefix(BEARER)
             .map(str::trim)
             .ok_or(AuthenticationError::Missing)?;
 
-        let mut validation = Validation::new(Algorithm::HS256);
-        validation.leeway = 0;
-        let token = jsonwebtoken::decode::<Claims>(token, &DecodingKey::from_secret(secret.as_ref()), &validation)
-            .map_err(|e| match e.kind() {
-                ErrorKind::ExpiredSignature => AuthenticationError::Expired,
-                _                           => AuthenticationError::Decoding(e.to_string()),
-            })?;
-        Ok(token.claims)
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = leeway_secs.min(MAX_TOKEN_LEEWAY_SECS);
+        let mut last_error = None;
+        for secret in secrets {
+            match jsonwebtoken::decode::<Claims>(token, &DecodingKey::from_secret(secret.as_ref()), &validation) {
+                Ok(token) => return Ok(token.claims),
+                // Only a signature mismatch means the token might have been signed with another key
+                Err(e) if *e.kind() == ErrorKind::InvalidSignature => last_error = Some(e),
+                Err(e) => return Err(Self::authentication_error(e))
+            }
+        }
+        Err(last_error
+            .map(Self::authentication_error)
+            .unwrap_or(AuthenticationError::Decoding("no decoding keys available".to_string())))
+    }
+
+    fn authentication_error(e: jsonwebtoken::errors::Error) -> AuthenticationError {
+        match e.kind() {
+            ErrorKind::ExpiredSignature => AuthenticationError::Expired,
+            _                           => AuthenticationError::Decoding(e.to_string()),
+        }
     }
 }
 
@@ -128,30 +222,108 @@ impl Claims {
 mod tests {
     
     use chrono::Duration;
-    use rocket::http::Status;
+    use jsonwebtoken::Algorithm;
+    use rocket::http::{ContentType, Status};
     use rocket::response::status::Custom;
+    use sqlx::{Executor, PgPool, query_scalar};
     use crate::claims::AuthenticationError;
+    use crate::test_support::{bearer, test_client};
 
     use super::Claims;
 
     #[test]
     fn missing_bearer() {
-        let claim_err = Claims::from_authorization("no-Bearer-prefix", "let me in").unwrap_err();
+        let claim_err = Claims::from_authorization("no-Bearer-prefix", &["let me in".to_string()], Algorithm::HS256, 0).unwrap_err();
 
         assert_eq!(claim_err, AuthenticationError::Missing);
+        assert_eq!("TOKEN_MISSING", claim_err.code());
     }
 
     #[test]
     fn to_token_and_back() {
         let claim = Claims::create(1, "joe@example.com", &Some(String::from("010101")), &vec!("member".to_string()), Duration::minutes(1));
-        let token = claim.into_token("let me in").unwrap();
+        let token = claim.into_token("let me in", Algorithm::HS256).unwrap();
         let token = format!("Bearer {token}");
 
-        let claim = Claims::from_authorization(&token, "let me in").unwrap();
+        let claim = Claims::from_authorization(&token, &["let me in".to_string()], Algorithm::HS256, 0).unwrap();
 
         assert_eq!(claim.email, "joe@example.com");
     }
 
+    #[test]
+    fn previous_key_still_valid_after_rotation() {
+        let claim = Claims::create(1, "joe@example.com", &Some(String::from("010101")), &vec!("member".to_string()), Duration::minutes(1));
+        let token = claim.into_token("old key", Algorithm::HS256).unwrap();
+        let token = format!("Bearer {token}");
+
+        let claim = Claims::from_authorization(&token, &["new key".to_string(), "old key".to_string()], Algorithm::HS256, 0).unwrap();
+        assert_eq!(claim.email, "joe@example.com");
+
+        // Once the previous key is retired, the token is no longer accepted
+        let claim_err = Claims::from_authorization(&token, &["new key".to_string()], Algorithm::HS256, 0).unwrap_err();
+        assert!(matches!(claim_err, AuthenticationError::Decoding(_)));
+        assert_eq!("TOKEN_INVALID", claim_err.code());
+    }
+
+    #[test]
+    fn expiry_leeway() {
+        let claim = Claims::create(1, "joe@example.com", &Some(String::from("010101")), &vec!("member".to_string()), Duration::seconds(-2));
+        let token = claim.into_token("let me in", Algorithm::HS256).unwrap();
+        let token = format!("Bearer {token}");
+
+        let claim_err = Claims::from_authorization(&token, &["let me in".to_string()], Algorithm::HS256, 0).unwrap_err();
+        assert_eq!(claim_err, AuthenticationError::Expired);
+        assert_eq!("TOKEN_EXPIRED", claim_err.code());
+
+        let claim = Claims::from_authorization(&token, &["let me in".to_string()], Algorithm::HS256, 5).unwrap();
+        assert_eq!(claim.email, "joe@example.com");
+    }
+
+    #[test]
+    fn only_hmac_algorithms_allowed() {
+        assert_eq!(super::parse_algorithm("HS512"), Ok(Algorithm::HS512));
+        assert!(super::parse_algorithm("RS256").is_err());
+        assert!(super::parse_algorithm("nonsense").is_err());
+    }
+
+    #[test]
+    fn must_change_pwd_blocks_other_paths() {
+        let claim = Claims::create(1, "joe@example.com", &None, &vec!("member".to_string()), Duration::minutes(1))
+            .with_must_change_pwd(true);
+        let token = format!("Bearer {}", claim.into_token("let me in", Algorithm::HS256).unwrap());
+        let claim = Claims::from_authorization(&token, &["let me in".to_string()], Algorithm::HS256, 0).unwrap();
+
+        assert_eq!(claim.check_password_change("/bookings"), Err(AuthenticationError::PasswordChangeRequired));
+        assert_eq!(claim.check_password_change("/me"), Ok(()));
+
+        let claim = claim.with_must_change_pwd(false);
+        assert_eq!(claim.check_password_change("/bookings"), Ok(()));
+    }
+
+    #[sqlx::test]
+    async fn must_change_pwd_blocks_booking(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let member_id: i64 = query_scalar("insert into person (name, email, roles, must_change_pwd) values ('Member', 'member@example.org', 'member', true) returning id")
+            .fetch_one(&pool).await.unwrap();
+        let session_id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, trainer) \
+                select now() + interval '1 day', 60, id, $1 from session_type where name = 'HIIT' returning id")
+            .bind(member_id)
+            .fetch_one(&pool).await.unwrap();
+        let client = test_client(pool.clone(), routes![crate::bookings::create_booking, crate::login::get_me]).await;
+        let claim = || Claims::create(member_id, "member@example.org", &None, &vec!("member".to_string()), Duration::minutes(1));
+        let booking = format!(r#"{{"person_id": {}, "session_id": {}}}"#, member_id, session_id);
+
+        // Until the password is changed, the member can see who they are but not book
+        let response = client.post("/bookings").header(bearer(claim().with_must_change_pwd(true))).header(ContentType::JSON).body(&booking).dispatch().await;
+        assert_eq!(Status::Forbidden, response.status());
+        assert!(response.into_string().await.unwrap().contains("PASSWORD_CHANGE_REQUIRED"));
+        let response = client.get("/me").header(bearer(claim().with_must_change_pwd(true))).dispatch().await;
+        assert_eq!(Status::Ok, response.status());
+
+        let response = client.post("/bookings").header(bearer(claim())).header(ContentType::JSON).body(&booking).dispatch().await;
+        assert_eq!(Status::Created, response.status());
+    }
+
     #[test]
     fn assert_roles_any() {
         let claim = Claims::create(1, "joe@example.com", &Some(String::from("010101")), &vec!("member".to_string()), Duration::minutes(1));
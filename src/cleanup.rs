@@ -0,0 +1,67 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{PgPool, query};
+
+use crate::audit;
+use crate::Config;
+
+/// Starts a background task that periodically purges rows that are only kept for a while after the
+/// fact. Members' profiles are deleted outright rather than soft-deleted, so there are none to purge.
+pub(crate) fn spawn_cleanup_task(pool: PgPool, config: &Config) {
+    let interval = std::time::Duration::from_secs(config.cleanup_interval_hours.max(1) * 60 * 60);
+    let retention = Duration::days(config.cleanup_retention_days as i64);
+    rocket::tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let cutoff = Utc::now() - retention;
+            if let Err(e) = purge_cancellations(&pool, cutoff).await {
+                error!("Failed to purge cancellations before {}: {}", cutoff, e);
+            }
+        }
+    });
+}
+
+/// Deletes cancelled bookings from before the cutoff, returning the number deleted
+async fn purge_cancellations(pool: &PgPool, cutoff: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+    let purged = query("DELETE FROM cancellation WHERE cancelled < $1")
+        .bind(cutoff)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    if purged > 0 {
+        info!("Purged {} cancellation(s) before {}", purged, cutoff);
+        audit::record_system(pool, "purge_cancellations", format!("{} cancellation(s) before {}", purged, cutoff.to_rfc3339())).await;
+    }
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeDelta, Utc};
+    use sqlx::{Executor, PgPool, query_scalar};
+    use crate::cleanup::purge_cancellations;
+    use crate::test_support::{create_person, create_session};
+
+    #[sqlx::test]
+    async fn purge_old_cancellations(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let person_id = create_person(&pool, "member@example.org", "member").await;
+        let session_id = create_session(&pool, TimeDelta::zero()).await;
+        pool.execute(format!("insert into cancellation (person_id, session_id, cancelled) values \
+            ({person_id}, {session_id}, now() - interval '400 days'), \
+            ({person_id}, {session_id}, now() - interval '10 days')").as_str()).await.unwrap();
+
+        // Only cancellations from before the retention period are purged, and the purge is audited
+        assert_eq!(1, purge_cancellations(&pool, Utc::now() - Duration::days(365)).await.unwrap());
+        let remaining: i64 = query_scalar("select count(*) from cancellation").fetch_one(&pool).await.unwrap();
+        assert_eq!(1, remaining);
+        let audited: i64 = query_scalar("select count(*) from audit_log where action = 'purge_cancellations' and actor_id is null").fetch_one(&pool).await.unwrap();
+        assert_eq!(1, audited);
+
+        // Nothing more to purge, and nothing audited
+        assert_eq!(0, purge_cancellations(&pool, Utc::now() - Duration::days(365)).await.unwrap());
+        let audited: i64 = query_scalar("select count(*) from audit_log").fetch_one(&pool).await.unwrap();
+        assert_eq!(1, audited);
+    }
+}
@@ -0,0 +1,235 @@
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Serialize;
+use sqlx::{Error, FromRow, PgPool, query_as, query_scalar, Row};
+use sqlx::postgres::PgRow;
+
+use crate::{AppState, db_error};
+use crate::audit;
+use crate::audit::AuditEntry;
+use crate::bookings::BookingRequest;
+use crate::claims::Claims;
+use crate::email::NotificationPrefs;
+use crate::login::parse_roles;
+use crate::waitlist::{waitlist_of, WaitlistEntry};
+use crate::waivers::{AcceptedWaiver, waivers_of};
+
+const ROLE_ADMIN: &str = "admin";
+
+/// Everything held about a person, for answering subject access requests. Passwords, temporary passwords
+/// and emailed tokens are left out. There is no separate credit ledger: the balance is in the profile, the
+/// credits used by each booking are with the booking, and changes made by admins are in the audit entries.
+#[derive(Serialize, Debug)]
+pub struct PersonalData {
+    exported: DateTime<Utc>,
+    profile: Profile,
+    notification_prefs: NotificationPrefs,
+    bookings: Vec<BookingDetail>,
+    /// Bookings waiting for approval
+    booking_requests: Vec<BookingRequest>,
+    cancellations: Vec<CancellationDetail>,
+    waitlist: Vec<WaitlistEntry>,
+    reminders: Vec<ReminderSent>,
+    waivers: Vec<AcceptedWaiver>,
+    /// Names of the session types that the person is qualified to train
+    trainer_qualifications: Vec<String>,
+    audit_entries: Vec<AuditEntry>
+}
+
+#[derive(Serialize, Debug)]
+struct Profile {
+    id: i64,
+    name: String,
+    email: String,
+    phone: Option<String>,
+    roles: Vec<String>,
+    credits: i16,
+    auto_use_credits: bool,
+    must_change_pwd: bool,
+    two_factor_enabled: bool,
+    created: DateTime<Utc>
+}
+
+impl FromRow<'_, PgRow> for Profile {
+    fn from_row(row: &PgRow) -> Result<Self, Error> {
+        Ok(Profile {
+            id: row.try_get("id")?,
+            name: row.try_get("name")?,
+            email: row.try_get("email")?,
+            phone: row.try_get("phone")?,
+            roles: row.try_get::<Option<String>, _>("roles")?.as_deref().map(parse_roles).unwrap_or_default(),
+            credits: row.try_get("credits")?,
+            auto_use_credits: row.try_get("auto_use_credits")?,
+            must_change_pwd: row.try_get("must_change_pwd")?,
+            two_factor_enabled: row.try_get("two_factor_enabled")?,
+            created: row.try_get("created")?
+        })
+    }
+}
+
+#[derive(FromRow, Serialize, Debug)]
+struct BookingDetail {
+    session_id: i64,
+    session_datetime: DateTime<Utc>,
+    duration_mins: i32,
+    session_type: String,
+    location_name: Option<String>,
+    trainer_name: Option<String>,
+    reference: Option<String>,
+    credits_used: Option<i16>,
+    attended: bool,
+    attended_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>
+}
+
+#[derive(FromRow, Serialize, Debug)]
+struct CancellationDetail {
+    session_id: Option<i64>,
+    session_datetime: Option<DateTime<Utc>>,
+    session_type: Option<String>,
+    reason: Option<String>,
+    credits_forfeited: i16,
+    cancelled: DateTime<Utc>
+}
+
+#[derive(FromRow, Serialize, Debug)]
+struct ReminderSent {
+    session_id: i64,
+    lead_time_mins: i32,
+    sent: DateTime<Utc>
+}
+
+/// Exports everything held about a person as one JSON document, for the person themselves or an admin
+#[get("/users/<person_id>/export")]
+pub async fn export_personal_data(state: &State<AppState>, claim: Claims, person_id: i64) -> Result<Json<PersonalData>, Custom<String>> {
+    _export_personal_data(&state.pool, &claim, person_id).await.map(Json)
+}
+
+async fn _export_personal_data(pool: &PgPool, claim: &Claims, person_id: i64) -> Result<PersonalData, Custom<String>> {
+    if claim.uid != person_id && !claim.has_role(ROLE_ADMIN) {
+        return Err(Custom(Status::Forbidden, "Not allowed to export the data of other users.".to_string()));
+    }
+    let profile: Profile = query_as("SELECT id, name, email, phone, roles, credits, auto_use_credits, must_change_pwd, two_factor_enabled, created \
+        FROM person WHERE id = $1")
+        .bind(person_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("user id not found: {}", person_id)))?;
+    let notification_prefs = NotificationPrefs::load(pool, person_id)
+        .await
+        .map_err(db_error)?;
+    let bookings = query_as("SELECT b.session_id, s.datetime AS session_datetime, s.duration_mins, t.name AS session_type, \
+            loc.name AS location_name, trainer.name AS trainer_name, b.reference, b.credits_used, b.attended, b.attended_at, b.created_at \
+        FROM booking AS b \
+        JOIN session AS s ON b.session_id = s.id \
+        JOIN session_type AS t ON s.session_type = t.id \
+        LEFT JOIN location AS loc ON s.location = loc.id \
+        LEFT JOIN person AS trainer ON s.trainer = trainer.id \
+        WHERE b.person_id = $1 \
+        ORDER BY s.datetime, b.session_id")
+        .bind(person_id)
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)?;
+    let booking_requests = query_as("SELECT person_id, session_id, credits_held, requested FROM booking_request WHERE person_id = $1 ORDER BY requested, session_id")
+        .bind(person_id)
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)?;
+    let cancellations = query_as("SELECT c.session_id, s.datetime AS session_datetime, t.name AS session_type, c.reason, c.credits_forfeited, c.cancelled \
+        FROM cancellation AS c \
+        LEFT JOIN session AS s ON c.session_id = s.id \
+        LEFT JOIN session_type AS t ON s.session_type = t.id \
+        WHERE c.person_id = $1 \
+        ORDER BY c.cancelled, c.id")
+        .bind(person_id)
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)?;
+    let waitlist = waitlist_of(pool, person_id)
+        .await
+        .map_err(db_error)?;
+    let reminders = query_as("SELECT session_id, lead_time_mins, sent FROM booking_reminder WHERE person_id = $1 ORDER BY sent, session_id")
+        .bind(person_id)
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)?;
+    let waivers = waivers_of(pool, person_id)
+        .await
+        .map_err(db_error)?;
+    let trainer_qualifications = query_scalar("SELECT t.name FROM trainer_qualification AS q \
+        JOIN session_type AS t ON q.session_type_id = t.id \
+        WHERE q.trainer_id = $1 \
+        ORDER BY t.name")
+        .bind(person_id)
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)?;
+    let audit_entries = audit::entries_about(pool, person_id)
+        .await
+        .map_err(db_error)?;
+    info!("Exported the personal data of person id {}", person_id);
+    Ok(PersonalData {
+        exported: Utc::now(),
+        profile,
+        notification_prefs,
+        bookings,
+        booking_requests,
+        cancellations,
+        waitlist,
+        reminders,
+        waivers,
+        trainer_qualifications,
+        audit_entries
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeDelta};
+    use rocket::http::Status;
+    use sqlx::{Executor, PgPool, query_scalar};
+    use crate::claims::Claims;
+    use crate::data_export::_export_personal_data;
+    use crate::test_support::{create_person, create_session};
+
+    #[sqlx::test]
+    async fn export_personal_data(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let member_id: i64 = query_scalar("insert into person (name, email, pwd, roles, credits) values ('Test User', 'member@example.org', 'secret-hash', 'member, limited-member', 3) returning id")
+            .fetch_one(&pool).await.unwrap();
+        let other_id = create_person(&pool, "other@example.org", "member").await;
+        let session_id = create_session(&pool, TimeDelta::days(1)).await;
+        for person_id in [member_id, other_id] {
+            pool.execute(format!("insert into booking (person_id, session_id, credits_used) values ({person_id}, {session_id}, 1)").as_str()).await.unwrap();
+            pool.execute(format!("insert into cancellation (person_id, session_id, reason) values ({person_id}, {session_id}, 'Busy')").as_str()).await.unwrap();
+            pool.execute(format!("insert into audit_log (action, target) values ('update_user', 'person {person_id}')").as_str()).await.unwrap();
+        }
+        pool.execute(format!("insert into audit_log (action, target) values ('update_user', 'person {member_id}0')").as_str()).await.unwrap();
+
+        let member = Claims::create(member_id, "member@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let export = _export_personal_data(&pool, &member, member_id).await.unwrap();
+        assert_eq!(vec!["member", "limited-member"], export.profile.roles);
+        assert_eq!(3, export.profile.credits);
+        assert_eq!(vec![(session_id, Some(1))], export.bookings.iter().map(|b| (b.session_id, b.credits_used)).collect::<Vec<_>>());
+        assert_eq!(1, export.cancellations.len());
+        // Only the audit entries about this person, and not those about someone whose id starts the same way
+        assert_eq!(1, export.audit_entries.len());
+
+        let json = rocket::serde::json::to_string(&export).unwrap();
+        assert!(!json.contains("secret-hash"));
+        assert!(!json.contains("other@example.org"));
+
+        // Members cannot export the data of other users, unlike admins
+        assert_eq!(Status::Forbidden, _export_personal_data(&pool, &member, other_id).await.err().unwrap().0);
+        let admin = Claims::create(other_id + 100, "admin@example.org", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let export = _export_personal_data(&pool, &admin, other_id).await.unwrap();
+        assert_eq!("other@example.org", export.profile.email);
+        assert_eq!(Status::NotFound, _export_personal_data(&pool, &admin, other_id + 100).await.err().unwrap().0);
+    }
+}
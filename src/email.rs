@@ -0,0 +1,179 @@
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+
+use rocket::serde::{Deserialize, Serialize};
+use sqlx::{PgPool, query_scalar};
+
+use crate::Config;
+
+/// The emails sent to users and admins. Subjects can be overridden in the `email_subjects` config table,
+/// and bodies by placing a `<name>.txt` file in the configured `email_template_dir`. In both, each `{}`
+/// is replaced in order by the values for that email.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum EmailTemplate {
+    PasswordReset,
+    PasswordChanged,
+    NewUser,
+    NewUserNotification,
+    ProfileDeleted,
+    ConfirmProfileDeletion,
+    ConfirmEmailChange,
+    SessionCancelled,
+    SessionReminder,
+    BookingConfirmation,
+    LoginCode
+}
+
+impl EmailTemplate {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::PasswordReset => "password_reset",
+            Self::PasswordChanged => "password_changed",
+            Self::NewUser => "new_user",
+            Self::NewUserNotification => "new_user_notification",
+            Self::ProfileDeleted => "profile_deleted",
+            Self::ConfirmProfileDeletion => "confirm_profile_deletion",
+            Self::ConfirmEmailChange => "confirm_email_change",
+            Self::SessionCancelled => "session_cancelled",
+            Self::SessionReminder => "session_reminder",
+            Self::BookingConfirmation => "booking_confirmation",
+            Self::LoginCode => "login_code"
+        }
+    }
+
+    fn default_subject(&self) -> &'static str {
+        match self {
+            Self::PasswordReset => "Password Reset for {}",
+            Self::PasswordChanged => "Password Changed for {}",
+            Self::NewUser => "New User Registration for {}",
+            Self::NewUserNotification => "New User Registration for {}",
+            Self::ProfileDeleted => "User Profile Deleted for {}",
+            Self::ConfirmProfileDeletion => "Confirm User Profile Deletion for {}",
+            Self::ConfirmEmailChange => "Confirm Email Address Change for {}",
+            Self::SessionCancelled => "Session Cancelled at {}",
+            Self::SessionReminder => "Session Reminder from {}",
+            Self::BookingConfirmation => "Booking Confirmed with {}",
+            Self::LoginCode => "Login Code for {}"
+        }
+    }
+
+    fn default_body(&self) -> &'static str {
+        match self {
+            Self::PasswordReset => include_str!("reset_email.txt"),
+            Self::PasswordChanged => include_str!("post_reset_email.txt"),
+            Self::NewUser => include_str!("register_email.txt"),
+            Self::NewUserNotification => include_str!("register_notify_email.txt"),
+            Self::ProfileDeleted => include_str!("post_delete_profile_email.txt"),
+            Self::ConfirmProfileDeletion => include_str!("delete_profile_confirm_email.txt"),
+            Self::ConfirmEmailChange => include_str!("confirm_email_change_email.txt"),
+            Self::SessionCancelled => include_str!("session_cancelled_email.txt"),
+            Self::SessionReminder => include_str!("session_reminder_email.txt"),
+            Self::BookingConfirmation => include_str!("booking_confirmation_email.txt"),
+            Self::LoginCode => include_str!("login_code_email.txt")
+        }
+    }
+}
+
+/// The subject line for an email, with the branding substituted
+pub(crate) fn render_subject(config: &Config, template: EmailTemplate) -> String {
+    let subject = config.email_subjects.get(template.name())
+        .map(String::as_str)
+        .unwrap_or(template.default_subject());
+    fill_placeholders(subject, &[&config.branding])
+}
+
+/// The body text for an email, using the template file from the configured directory if there is one
+pub(crate) fn render_body(config: &Config, template: EmailTemplate, args: &[&dyn Display]) -> String {
+    let custom_body = config.email_template_dir.as_ref()
+        .map(|dir| Path::new(dir).join(format!("{}.txt", template.name())))
+        .filter(|path| path.is_file())
+        .and_then(|path| fs::read_to_string(&path)
+            .inspect_err(|e| error!("Failed to read email template {}: {}", path.display(), e))
+            .ok());
+    fill_placeholders(custom_body.as_deref().unwrap_or(template.default_body()), args)
+}
+
+fn fill_placeholders(template: &str, args: &[&dyn Display]) -> String {
+    let mut parts = template.split("{}");
+    let mut filled = parts.next().unwrap_or_default().to_string();
+    for (i, part) in parts.enumerate() {
+        if let Some(arg) = args.get(i) {
+            filled.push_str(&arg.to_string());
+        }
+        filled.push_str(part);
+    }
+    filled
+}
+
+/// Which optional emails a member wants to receive. Emails that are missing from the stored preferences
+/// are sent, so members receive new kinds of notification until they opt out.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct NotificationPrefs {
+    confirmations: bool,
+    reminders: bool,
+    cancellations: bool,
+    announcements: bool
+}
+
+impl Default for NotificationPrefs {
+    fn default() -> Self {
+        NotificationPrefs {
+            confirmations: true,
+            reminders: true,
+            cancellations: true,
+            announcements: true
+        }
+    }
+}
+
+impl NotificationPrefs {
+    pub(crate) async fn load(pool: &PgPool, person_id: i64) -> Result<NotificationPrefs, sqlx::Error> {
+        let prefs: Option<String> = query_scalar("SELECT notification_prefs::text FROM person WHERE id = $1")
+            .bind(person_id)
+            .fetch_optional(pool)
+            .await?;
+        Ok(prefs.and_then(|prefs| rocket::serde::json::from_str(&prefs).ok()).unwrap_or_default())
+    }
+}
+
+/// Emails that members can opt out of. Transactional emails such as password resets are always sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum NotificationEvent {
+    BookingConfirmation,
+    Reminder,
+    Cancellation,
+    /// Messages sent by admins or trainers to the members booked on a session
+    Announcement
+}
+
+pub(crate) fn should_notify(prefs: &NotificationPrefs, event: NotificationEvent) -> bool {
+    match event {
+        NotificationEvent::BookingConfirmation => prefs.confirmations,
+        NotificationEvent::Reminder => prefs.reminders,
+        NotificationEvent::Cancellation => prefs.cancellations,
+        NotificationEvent::Announcement => prefs.announcements
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::email::{fill_placeholders, NotificationEvent, NotificationPrefs, should_notify};
+
+    #[test]
+    fn fill_placeholders_in_order() {
+        assert_eq!("Reset at example.com within 10 minutes", fill_placeholders("Reset at {} within {} minutes", &[&"example.com", &10]));
+        assert_eq!("No placeholders", fill_placeholders("No placeholders", &[&"unused"]));
+        assert_eq!("Missing  value", fill_placeholders("Missing {} value", &[]));
+    }
+
+    #[test]
+    fn missing_notification_prefs_default_to_notify() {
+        let prefs: NotificationPrefs = rocket::serde::json::from_str(r#"{"reminders": false}"#).unwrap();
+        assert!(should_notify(&prefs, NotificationEvent::BookingConfirmation));
+        assert!(!should_notify(&prefs, NotificationEvent::Reminder));
+        assert!(should_notify(&prefs, NotificationEvent::Cancellation));
+        assert!(should_notify(&prefs, NotificationEvent::Announcement));
+    }
+}
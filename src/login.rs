@@ -1,4 +1,5 @@
-use std::ops::Add;
+use std::future::Future;
+use std::ops::{Add, Sub};
 
 use chrono::{DateTime, Duration, Utc};
 use mail_send::mail_builder::headers::address::Address;
@@ -7,22 +8,27 @@ use mail_send::smtp::message::{IntoMessage, Message};
 use mail_send::{Credentials, SmtpClientBuilder};
 use password_auth::{generate_hash, verify_password};
 use passwords::PasswordGenerator;
-use rocket::http::{Header, Status};
+use rocket::http::{ContentType, Header, Status};
 use rocket::response::status::{Accepted, Custom, NoContent};
+use rocket::response::stream::TextStream;
 use rocket::serde::{Deserialize, Serialize};
 use rocket::serde::json::Json;
 use rocket::State;
-use sqlx::{Error, FromRow, PgPool, query_as, raw_sql, Row};
+use sqlx::{Error, FromRow, PgPool, Postgres, query, query_as, query_scalar, QueryBuilder, raw_sql, Row};
 use sqlx::postgres::PgRow;
 use urlencoding::encode;
 
-use crate::{AppState, CountResult, UserLoginRecord};
+use crate::{AppState, Config, db_error, JsonBody, parse_opt_date, UserLoginRecord};
+use crate::audit;
+use crate::bookings::{min_credits_cost, MembershipStatus};
 use crate::claims::Claims;
+use crate::email::{EmailTemplate, NotificationPrefs, render_body, render_subject};
+use crate::sessions::can_manage_sessions;
 
 const ACCESS_TOKEN_TTL: Duration = Duration::hours(3);
 const REFRESH_TOKEN_EXIRATION: Duration = Duration::days(1);
 
-const PASSWORD_GENERATOR: PasswordGenerator = PasswordGenerator {
+const TOKEN_GENERATOR: PasswordGenerator = PasswordGenerator {
     length: 20,
     numbers: true,
     lowercase_letters: false,
@@ -32,9 +38,30 @@ const PASSWORD_GENERATOR: PasswordGenerator = PasswordGenerator {
     exclude_similar_characters: true,
     strict: true
 };
+const MIN_PASSWORD_LENGTH: usize = 8;
 const INVALID_LOGIN_MESSAGE: &str = "incorrect username or password";
 const TEMP_PASSWORD_MINIMUM_RESEND_WAIT: Duration = Duration::minutes(-2);
 const TEMP_PASSWORD_EXPIRY: Duration = Duration::minutes(10);
+const TEMP_PASSWORD_MAX_CHECK_ATTEMPTS: i32 = 5;
+const IMPORT_EMAIL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+const DELETION_TOKEN_EXPIRY: Duration = Duration::minutes(60);
+const TOKEN_PURPOSE_DELETE_PROFILE: &str = "delete_profile";
+const EMAIL_CHANGE_TOKEN_EXPIRY: Duration = Duration::minutes(60);
+const TOKEN_PURPOSE_CHANGE_EMAIL: &str = "change_email";
+const PERSON_TOKEN_MAX_CHECK_ATTEMPTS: i32 = 5;
+const LOGIN_CODE_GENERATOR: PasswordGenerator = PasswordGenerator {
+    length: 6,
+    numbers: true,
+    lowercase_letters: false,
+    uppercase_letters: false,
+    symbols: false,
+    spaces: false,
+    exclude_similar_characters: false,
+    strict: true
+};
+const LOGIN_CODE_EXPIRY: Duration = Duration::minutes(5);
+const TOKEN_PURPOSE_LOGIN_CODE: &str = "login_code";
+const INVALID_LOGIN_CODE_MESSAGE: &str = "incorrect or expired login code";
 
 #[derive(Deserialize)]
 pub struct LoginRequest {
@@ -49,6 +76,27 @@ pub struct LoginResponse {
     cookie: Header<'static>
 }
 
+/// Users with two-factor authentication are not logged in by their password alone, but are emailed a code
+/// with which to complete the login
+#[derive(Responder)]
+pub enum LoginOutcome {
+    LoggedIn(LoginResponse),
+    #[response(status = 202)]
+    CodeRequired(Json<LoginCodeRequired>)
+}
+
+#[derive(Serialize, Debug)]
+pub struct LoginCodeRequired {
+    two_factor_required: bool,
+    message: String
+}
+
+#[derive(Deserialize)]
+pub struct LoginCodeRequest {
+    email: String,
+    code: String
+}
+
 #[derive(Serialize)]
 pub struct LoggedInUser {
     id: i64,
@@ -56,19 +104,20 @@ pub struct LoggedInUser {
     email: String,
     phone: Option<String>,
     roles: Vec<String>,
+    membership_status: MembershipStatus,
     access_token: String
 }
 
 async fn verify_user_by_id(pool: &PgPool, user_id: i64, password: &str) -> Result<UserLoginRecord, Custom<String>> {
     let user_record = UserLoginRecord::load_by_id(pool, user_id)
-        .await.map_err(|e| Custom(Status::InternalServerError, e.to_string()))?
+        .await.map_err(db_error)?
         .ok_or_else(|| Custom(Status::Unauthorized, INVALID_LOGIN_MESSAGE.to_string()))?;
     verify_user(user_record, password)
 }
 
 async fn verify_user_by_email(pool: &PgPool, email: &str, password: &str) -> Result<UserLoginRecord, Custom<String>> {
     let user_record = UserLoginRecord::load_by_email(pool, email)
-        .await.map_err(|e| Custom(Status::InternalServerError, e.to_string()))?
+        .await.map_err(db_error)?
         .ok_or_else(|| Custom(Status::Unauthorized, INVALID_LOGIN_MESSAGE.to_string()))?;
     verify_user(user_record, password)
 }
@@ -84,9 +133,85 @@ fn verify_user(login_record: UserLoginRecord, password: &str) -> Result<UserLogi
 }
 
 #[post("/login", data = "<login>")]
-pub async fn login(state: &State<AppState>, login: Json<LoginRequest>) -> Result<LoginResponse, Custom<String>> {
+pub async fn login(state: &State<AppState>, login: JsonBody<LoginRequest>) -> Result<LoginOutcome, Custom<String>> {
     let login_record = verify_user_by_email(&state.pool, &login.email, &login.password).await?;
-    build_login_response(login_record, &state.secrets)
+    login_or_send_code(state, login_record).await
+}
+
+/// Second step of logging in for users with two-factor authentication, using the code emailed to them
+#[post("/login/verify_otp", data = "<login_code>")]
+pub async fn verify_login_code(state: &State<AppState>, login_code: JsonBody<LoginCodeRequest>) -> Result<LoginResponse, Custom<String>> {
+    let login_record = _verify_login_code(&state.pool, &login_code.email, &login_code.code).await?;
+    build_login_response(login_record, state).await
+}
+
+async fn _verify_login_code(pool: &PgPool, email: &str, code: &str) -> Result<UserLoginRecord, Custom<String>> {
+    let login_record = UserLoginRecord::load_by_email(pool, email)
+        .await.map_err(db_error)?
+        .ok_or_else(|| Custom(Status::Unauthorized, INVALID_LOGIN_CODE_MESSAGE.to_string()))?;
+    consume_person_token(pool, login_record.id, TOKEN_PURPOSE_LOGIN_CODE, code.trim())
+        .await
+        .map_err(|e| if e.0 == Status::Forbidden {
+            Custom(Status::Unauthorized, INVALID_LOGIN_CODE_MESSAGE.to_string())
+        } else {
+            e
+        })?;
+    info!("Verified login code for user id {}", login_record.id);
+    Ok(login_record)
+}
+
+async fn login_or_send_code(state: &AppState, login_record: UserLoginRecord) -> Result<LoginOutcome, Custom<String>> {
+    match start_login(&state.pool, login_record, |login_record, code| send_login_code_email(state, login_record, code)).await? {
+        Some(login_record) => build_login_response(login_record, state).await.map(LoginOutcome::LoggedIn),
+        None => Ok(LoginOutcome::CodeRequired(Json(LoginCodeRequired {
+            two_factor_required: true,
+            message: "A login code has been emailed to you.".to_string()
+        })))
+    }
+}
+
+/// Gives back the login record of a user whose password has been verified if that is all they need to log in.
+/// Users with two-factor authentication are instead sent a login code, and None is returned. Attempts at a code
+/// carry over to the codes that replace it until it would have expired, so that logging in again to get a new
+/// code does not allow more guesses.
+async fn start_login<F, Fut>(pool: &PgPool, login_record: UserLoginRecord, send_login_code: F) -> Result<Option<UserLoginRecord>, Custom<String>>
+where
+    F: FnOnce(UserLoginRecord, String) -> Fut,
+    Fut: Future<Output = Result<(), Custom<String>>>
+{
+    if !login_record.two_factor_enabled {
+        return Ok(Some(login_record));
+    }
+    let attempts: Option<i32> = query_scalar("SELECT check_attempts FROM person_token WHERE person_id = $1 AND purpose = $2 AND expiry > now()")
+        .bind(login_record.id)
+        .bind(TOKEN_PURPOSE_LOGIN_CODE)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?;
+    if attempts.is_some_and(|attempts| attempts >= PERSON_TOKEN_MAX_CHECK_ATTEMPTS) {
+        info!("Not sending a login code to user id {}: too many attempts", login_record.id);
+        return Err(Custom(Status::TooManyRequests, format!("Too many login code attempts: try again in {} minutes.", LOGIN_CODE_EXPIRY.num_minutes())));
+    }
+    let code = LOGIN_CODE_GENERATOR.generate_one()
+        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+    save_person_token(pool, login_record.id, TOKEN_PURPOSE_LOGIN_CODE, &code, None, LOGIN_CODE_EXPIRY, true).await?;
+    send_login_code(login_record, code).await?;
+    Ok(None)
+}
+
+async fn send_login_code_email(state: &AppState, login_record: UserLoginRecord, code: String) -> Result<(), Custom<String>> {
+    let sender = Address::new_address(Some(&state.config.email_sender_name), &state.config.email_sender_address);
+    let message = MessageBuilder::new()
+        .from(sender.clone())
+        .reply_to(sender)
+        .to(Address::new_address(Some(&login_record.name), &login_record.email))
+        .subject(render_subject(&state.config, EmailTemplate::LoginCode))
+        .text_body(render_body(&state.config, EmailTemplate::LoginCode, &[&state.config.branding, &code, &LOGIN_CODE_EXPIRY.num_minutes()]))
+        .into_message()
+        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+    send_email(message, &state.secrets).await?;
+    info!("Sent login code to user id {}", login_record.id);
+    Ok(())
 }
 
 #[get("/validate_login")]
@@ -103,22 +228,22 @@ pub struct UpdatePasswordRequest {
 }
 
 #[post("/change_password", data = "<password_update>")]
-pub async fn change_password(state: &State<AppState>, password_update: Json<UpdatePasswordRequest>) -> Result<LoginResponse, Custom<String>> {
+pub async fn change_password(state: &State<AppState>, password_update: JsonBody<UpdatePasswordRequest>) -> Result<LoginOutcome, Custom<String>> {
     let login_record = verify_user_by_email(&state.pool, &password_update.username, &password_update.current_password).await?;
 
     verify_suitable_password(&password_update.new_password, &password_update.current_password)?;
 
     // Update to new password and set must_change_pwd to false
     let pwd_hash = generate_hash(&password_update.new_password);
-    query_as("UPDATE person SET pwd = $1, must_change_pwd = FALSE WHERE email = $2 RETURNING id")
+    query_as("UPDATE person SET pwd = $1, must_change_pwd = FALSE WHERE id = $2 RETURNING id")
         .bind(pwd_hash)
-        .bind(&password_update.username)
+        .bind(login_record.id)
         .fetch_optional(&state.pool)
         .await
         .map_err(|_| Custom(Status::Unauthorized, "Failed to update password".to_string()))?
         .ok_or(Custom(Status::NotFound, "No user updated".to_string()))?;
 
-    build_login_response(login_record, &state.secrets)
+    login_or_send_code(state, UserLoginRecord { must_change_pwd: false, ..login_record }).await
 }
 
 #[derive(Deserialize, Debug)]
@@ -142,37 +267,62 @@ pub struct PasswordResetRequest {
     reset_url: String
 }
 
+#[derive(Responder, Debug)]
+pub enum PasswordResetError {
+    #[response(status = 429)]
+    Throttled(String, Header<'static>),
+    Failed(Custom<String>)
+}
+
+impl From<Custom<String>> for PasswordResetError {
+    fn from(e: Custom<String>) -> Self {
+        PasswordResetError::Failed(e)
+    }
+}
+
+#[derive(FromRow)]
+struct TempPasswordSent {
+    sent: DateTime<Utc>
+}
+
 #[post("/request_pwd_reset", data="<reset_request>")]
 pub async fn request_pwd_reset(
     state: &State<AppState>,
-    reset_request: Json<PasswordResetRequest>
-) -> Result<Accepted<String>, Custom<String>> {
-    let user_record = UserLoginRecord::load_by_email(&state.pool, &reset_request.email)
-        .await.map_err(|e| Custom(Status::InternalServerError, e.to_string()))?
+    reset_request: JsonBody<PasswordResetRequest>
+) -> Result<Accepted<String>, PasswordResetError> {
+    let email = validate_email(&reset_request.email)?;
+    let user_record = UserLoginRecord::load_by_email(&state.pool, &email)
+        .await.map_err(db_error)?
         .ok_or(Custom(Status::BadRequest, format!("user does not exist: {}", reset_request.email)))?;
 
-    // Fail if we have sent an email to this address within the last 2 mins
-    let latest_previous_sent_time = Utc::now().add(TEMP_PASSWORD_MINIMUM_RESEND_WAIT);
-    let latest_previous_sent_count: CountResult = query_as("SELECT count(*) FROM temp_password WHERE person_id = $1 AND sent > $2")
-        .bind(&user_record.id)
-        .bind(latest_previous_sent_time)
-        .fetch_one(&state.pool)
+    // Fail if we have sent an email to this address within the last 2 mins, telling the client when to retry
+    let previous_sent: Option<TempPasswordSent> = query_as("SELECT sent FROM temp_password WHERE person_id = $1")
+        .bind(user_record.id)
+        .fetch_optional(&state.pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
-    if latest_previous_sent_count.count > 0 {
-        return Err(Custom(Status::BadRequest, format!("Cannot send another reset email within {} minutes.", TEMP_PASSWORD_MINIMUM_RESEND_WAIT.num_minutes().abs())));
+        .map_err(db_error)?;
+    if let Some(previous_sent) = previous_sent {
+        let now = Utc::now();
+        let retry_time = previous_sent.sent.sub(TEMP_PASSWORD_MINIMUM_RESEND_WAIT);
+        if retry_time > now {
+            // Round up so that clients never retry too early
+            let retry_after_secs = (retry_time - now).num_seconds() + 1;
+            return Err(PasswordResetError::Throttled(
+                format!("Cannot send another reset email within {} minutes.", TEMP_PASSWORD_MINIMUM_RESEND_WAIT.num_minutes().abs()),
+                Header::new("Retry-After", retry_after_secs.to_string())));
+        }
     }
 
     // Create temp password and send
-    let temp_password = create_temp_password(&state.pool, user_record.id).await?;
+    let temp_password = create_temp_password(&state.pool, &state.config, user_record.id).await?;
     let reset_url_with_params = format!("{}?email={}&temp_pwd={}", &reset_request.reset_url, encode(&user_record.email), encode(&temp_password));
-    let text = format!(include_str!("reset_email.txt"), &reset_request.website_url, temp_password, reset_url_with_params, TEMP_PASSWORD_EXPIRY.num_minutes());
+    let text = render_body(&state.config, EmailTemplate::PasswordReset, &[&reset_request.website_url, &temp_password, &reset_url_with_params, &TEMP_PASSWORD_EXPIRY.num_minutes()]);
     let sender = Address::new_address(Some(&state.config.email_sender_name), &state.config.email_sender_address);
     let message = MessageBuilder::new()
         .from(sender.clone())
         .reply_to(sender)
         .to(Address::new_address(Some(&user_record.name), &user_record.email))
-        .subject(format!("Password Reset for {}", &state.config.branding))
+        .subject(render_subject(&state.config, EmailTemplate::PasswordReset))
         .text_body(text)
         .into_message()
         .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
@@ -184,61 +334,282 @@ pub async fn request_pwd_reset(
 #[post("/register_user", data="<new_user>")]
 pub async fn register_user(
     state: &State<AppState>,
-    new_user: Json<NewUserRequest>
+    new_user: JsonBody<NewUserRequest>
 ) -> Result<Accepted<String>, Custom<String>> {
+    let mut new_user = new_user.into_inner();
+    new_user.email = validate_email(&new_user.email)?;
+
     // Error if already existing record for the specified email
     let existing_user_record = UserLoginRecord::load_by_email(&state.pool, &new_user.email)
-        .await.map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+        .await.map_err(db_error)?;
     if let Some(_existing) = existing_user_record {
         return Err(Custom(Status::Conflict, "User already exists with this email address".to_string()));
     }
 
-    // Create user record with null password (must use password reset)
+    // Create the user and send them a temp password
+    _register_user(&state.pool, &new_user, |user_id| send_new_user_email(state, user_id, &new_user.name, &new_user.email, &new_user.website_url, &new_user.reset_url)).await?;
+
+    // Send notification email to admin. The user has their email by now, so a failure here is only logged.
+    let sender = Address::new_address(Some(&state.config.email_sender_name), &state.config.email_sender_address);
+    let notification_message = MessageBuilder::new()
+        .from(sender.clone())
+        .reply_to(sender.clone())
+        .to(state.config.email_admin_notifications.as_str())
+        .subject(render_subject(&state.config, EmailTemplate::NewUserNotification))
+        .text_body(render_body(&state.config, EmailTemplate::NewUserNotification, &[
+            &new_user.name,
+            &new_user.email,
+            &new_user.phone.as_deref().unwrap_or("<unspecified>")
+        ]))
+        .into_message()
+        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+    if let Err(e) = send_email(notification_message, &state.secrets).await {
+        error!("Failed to send new user notification for {} to admin: {:?}", &new_user.email, e);
+    }
+
+    Ok(Accepted(format!("New user instructions email sent to {}. Please check your spam folder if not received!", &new_user.email)))
+}
+
+/// Creates a user record with null password (they must use the temp password that is sent to them). If the
+/// email cannot be sent the user record is deleted again, since there would be no way for them to log in,
+/// and they can simply register again later.
+async fn _register_user<F, Fut>(pool: &PgPool, new_user: &NewUserRequest, send_new_user_email: F) -> Result<i64, Custom<String>>
+where
+    F: FnOnce(i64) -> Fut,
+    Fut: Future<Output = Result<(), Custom<String>>>
+{
     let user_updated: UserUpdated = query_as("INSERT INTO person (name, email, phone, credits, roles) VALUES ($1, $2, $3, 1, '') RETURNING id")
         .bind(&new_user.name)
         .bind(&new_user.email)
         .bind(&new_user.phone)
-        .fetch_one(&state.pool)
+        .fetch_one(pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
-    info!("Created new user id {} for {:?}", user_updated.id, &new_user);
+        .map_err(db_error)?;
+    info!("Created new user id {} for {:?}", user_updated.id, new_user);
+
+    if let Err(e) = send_new_user_email(user_updated.id).await {
+        error!("Failed to send new user email to {}, deleting user id {}: {:?}", &new_user.email, user_updated.id, e);
+        query("DELETE FROM person WHERE id = $1")
+            .bind(user_updated.id)
+            .execute(pool)
+            .await
+            .map_err(db_error)?;
+        return Err(Custom(Status::ServiceUnavailable, "Could not send the new user email, so the account was not created. Please try again later.".to_string()));
+    }
+    Ok(user_updated.id)
+}
 
-    // Create temp password and send to email
-    let temp_password = create_temp_password(&state.pool, user_updated.id).await?;
-    let reset_url_with_params = format!("{}?email={}&temp_pwd={}", &new_user.reset_url, encode(&new_user.email), encode(&temp_password));
-    let text = format!(include_str!("register_email.txt"), &new_user.website_url, temp_password, reset_url_with_params, TEMP_PASSWORD_EXPIRY.num_minutes());
+async fn send_new_user_email(
+    state: &AppState,
+    user_id: i64,
+    name: &str,
+    email: &str,
+    website_url: &str,
+    reset_url: &str
+) -> Result<(), Custom<String>> {
+    let temp_password = create_temp_password(&state.pool, &state.config, user_id).await?;
+    let reset_url_with_params = format!("{}?email={}&temp_pwd={}", reset_url, encode(email), encode(&temp_password));
+    let text = render_body(&state.config, EmailTemplate::NewUser, &[&website_url, &temp_password, &reset_url_with_params, &TEMP_PASSWORD_EXPIRY.num_minutes()]);
     let sender = Address::new_address(Some(&state.config.email_sender_name), &state.config.email_sender_address);
     let message = MessageBuilder::new()
         .from(sender.clone())
-        .reply_to(sender.clone())
-        .to(Address::new_address(Some(&new_user.name), &new_user.email))
-        .subject(format!("New User Registration for {}", &state.config.branding))
+        .reply_to(sender)
+        .to(Address::new_address(Some(name), email))
+        .subject(render_subject(&state.config, EmailTemplate::NewUser))
         .text_body(text)
         .into_message()
         .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
-    send_email(message, &state.secrets).await?;
+    send_email(message, &state.secrets).await
+}
 
-    // Send notification email to admin
-    let notification_message = MessageBuilder::new()
-        .from(sender.clone())
-        .reply_to(sender.clone())
-        .to(state.config.email_admin_notifications.as_str())
-        .subject(format!("New User Registration for {}", &state.config.branding))
-        .text_body(format!(include_str!("register_notify_email.txt"),
-            &new_user.name,
-            &new_user.email,
-            &new_user.phone.as_ref().unwrap_or(&"<unspecified>".to_string())
-        ))
-        .into_message()
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
-    send_email(notification_message, &state.secrets).await?;
+#[derive(Deserialize, Debug)]
+pub struct ImportedUser {
+    name: String,
+    email: String,
+    phone: Option<String>,
+    roles: Vec<String>,
+    credits: i16
+}
 
-    Ok(Accepted(format!("New user instructions email sent to {}. Please check your spam folder if not received!", &new_user.email)))
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum UserImportStatus {
+    Created,
+    SkippedDuplicate,
+    Error
 }
 
-async fn create_temp_password(pool: &PgPool, user_id: i64) -> Result<String, Custom<String>> {
+#[derive(Serialize, Debug)]
+pub struct UserImportResult {
+    email: String,
+    status: UserImportStatus,
+    id: Option<i64>,
+    error: Option<String>
+}
+
+/// Imports a list of users, streaming one JSON result line per user. Welcome emails are only sent
+/// when both `website_url` and `reset_url` are given, and are spaced out to stay under SMTP rate limits.
+#[post("/users/import?<website_url>&<reset_url>", data="<users>")]
+pub async fn import_users(
+    state: &State<AppState>,
+    claims: Claims,
+    website_url: Option<String>,
+    reset_url: Option<String>,
+    users: JsonBody<Vec<ImportedUser>>
+) -> Result<(ContentType, TextStream![String + '_]), Custom<String>> {
+    claims.assert_roles_contains("admin")?;
+    let welcome_urls = website_url.zip(reset_url);
+
+    let stream = TextStream! {
+        let mut emails_sent = 0;
+        for mut user in users.into_inner() {
+            let imported = match validate_email(&user.email) {
+                Ok(email) => {
+                    user.email = email;
+                    import_user(&state.pool, &user).await.map_err(|e| e.to_string())
+                },
+                Err(e) => Err(e.1)
+            };
+            let result = match imported {
+                Ok(Some(id)) => {
+                    info!("Imported new user id {} for {:?}", id, &user);
+                    audit::record(&state.pool, claims.uid, "import_user", format!("person {}", id)).await;
+                    let mut error = None;
+                    if let Some((website_url, reset_url)) = &welcome_urls {
+                        if emails_sent > 0 {
+                            tokio::time::sleep(IMPORT_EMAIL_INTERVAL).await;
+                        }
+                        emails_sent += 1;
+                        error = send_new_user_email(state, id, &user.name, &user.email, website_url, reset_url)
+                            .await
+                            .inspect_err(|e| error!("Failed to send welcome email to imported user {}: {:?}", &user.email, e))
+                            .err()
+                            .map(|e| format!("user created but welcome email failed: {}", e.1));
+                    }
+                    UserImportResult { email: user.email, status: UserImportStatus::Created, id: Some(id), error }
+                },
+                Ok(None) => UserImportResult { email: user.email, status: UserImportStatus::SkippedDuplicate, id: None, error: None },
+                Err(e) => UserImportResult { email: user.email, status: UserImportStatus::Error, id: None, error: Some(e) }
+            };
+            yield rocket::serde::json::to_string(&result).unwrap_or_default() + "\n";
+        }
+    };
+    Ok((ContentType::new("application", "x-ndjson"), stream))
+}
+
+async fn import_user(pool: &PgPool, user: &ImportedUser) -> Result<Option<i64>, Error> {
+    // Existing users are matched ignoring case, as when logging in
+    let user_updated: Option<UserUpdated> = query_as("INSERT INTO person (name, email, phone, roles, credits) \
+            SELECT $1, $2, $3, $4, $5 WHERE NOT EXISTS (SELECT 1 FROM person WHERE lower(email) = lower($2)) \
+            ON CONFLICT DO NOTHING \
+            RETURNING id")
+        .bind(&user.name)
+        .bind(&user.email)
+        .bind(&user.phone)
+        .bind(user.roles.join(","))
+        .bind(user.credits)
+        .fetch_optional(pool)
+        .await?;
+    Ok(user_updated.map(|u| u.id))
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UserUpsertStatus {
+    Created,
+    Updated
+}
+
+#[derive(Serialize, Debug)]
+pub struct UserUpsertResult {
+    id: i64,
+    status: UserUpsertStatus,
+    error: Option<String>
+}
+
+/// Creates or updates a user keyed by email, for integrations that sync members without knowing whether they
+/// already exist. As for imports, a welcome email is only sent to new users, and only when both urls are given.
+#[post("/users/upsert?<website_url>&<reset_url>", data="<user>")]
+pub async fn upsert_user(
+    state: &State<AppState>,
+    claims: Claims,
+    website_url: Option<String>,
+    reset_url: Option<String>,
+    user: JsonBody<ImportedUser>
+) -> Result<Json<UserUpsertResult>, Custom<String>> {
+    claims.assert_roles_contains("admin")?;
+    let mut user = user.into_inner();
+    user.email = validate_email(&user.email)?;
+
+    let (id, status) = _upsert_user(&state.pool, &user).await?;
+    audit::record(&state.pool, claims.uid, "upsert_user", format!("person {}", id)).await;
+    let mut error = None;
+    if let (UserUpsertStatus::Created, Some(website_url), Some(reset_url)) = (&status, website_url, reset_url) {
+        error = send_new_user_email(state, id, &user.name, &user.email, &website_url, &reset_url)
+            .await
+            .inspect_err(|e| error!("Failed to send welcome email to upserted user {}: {:?}", &user.email, e))
+            .err()
+            .map(|e| format!("user created but welcome email failed: {}", e.1));
+    }
+    Ok(Json(UserUpsertResult { id, status, error }))
+}
+
+async fn _upsert_user(pool: &PgPool, user: &ImportedUser) -> Result<(i64, UserUpsertStatus), Custom<String>> {
+    // Users are matched ignoring case, as when logging in, and keep the email they already have
+    let mut tx = pool.begin()
+        .await
+        .map_err(db_error)?;
+    let updated: Option<i64> = query_scalar("UPDATE person SET name = $1, phone = $3, roles = $4, credits = $5 \
+            WHERE id = (SELECT id FROM person WHERE lower(email) = lower($2) ORDER BY id LIMIT 1 FOR UPDATE) \
+            RETURNING id")
+        .bind(&user.name)
+        .bind(&user.email)
+        .bind(&user.phone)
+        .bind(user.roles.join(","))
+        .bind(user.credits)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    let (id, status) = match updated {
+        Some(id) => (id, UserUpsertStatus::Updated),
+        None => {
+            let id: i64 = query_scalar("INSERT INTO person (name, email, phone, roles, credits) VALUES ($1, $2, $3, $4, $5) RETURNING id")
+                .bind(&user.name)
+                .bind(&user.email)
+                .bind(&user.phone)
+                .bind(user.roles.join(","))
+                .bind(user.credits)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(db_error)?;
+            (id, UserUpsertStatus::Created)
+        }
+    };
+    tx.commit()
+        .await
+        .map_err(db_error)?;
+    info!("Upserted user id {} for {:?} ({:?})", id, user, status);
+    Ok((id, status))
+}
+
+/// Builds the generator for temp passwords from the config. Similar characters, such as 1 and l, are always
+/// left out as the passwords may be typed in by hand.
+pub(crate) fn temp_password_generator(config: &Config) -> PasswordGenerator {
+    PasswordGenerator {
+        length: config.temp_password_length.max(MIN_PASSWORD_LENGTH),
+        numbers: config.temp_password_numbers,
+        lowercase_letters: config.temp_password_lowercase,
+        uppercase_letters: config.temp_password_uppercase,
+        symbols: config.temp_password_symbols,
+        spaces: false,
+        exclude_similar_characters: true,
+        strict: true
+    }
+}
+
+async fn create_temp_password(pool: &PgPool, config: &Config, user_id: i64) -> Result<String, Custom<String>> {
     // Generate a temp password and expiry time
-    let temp_password = PASSWORD_GENERATOR.generate_one()
+    let temp_password = temp_password_generator(config).generate_one()
         .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
     let temp_password_hash = generate_hash(&temp_password);
     let now = Utc::now();
@@ -248,7 +619,7 @@ async fn create_temp_password(pool: &PgPool, user_id: i64) -> Result<String, Cus
     let user_updated: UserUpdated = query_as(
         "INSERT INTO temp_password (person_id, pwd, sent, expiry) \
             VALUES ($1, $2, $3, $4) \
-            ON CONFLICT (person_id) DO UPDATE SET pwd = $5, sent = $6, expiry = $7 \
+            ON CONFLICT (person_id) DO UPDATE SET pwd = $5, sent = $6, expiry = $7, check_attempts = 0 \
             RETURNING person_id AS id")
         .bind(user_id)
         .bind(&temp_password_hash)
@@ -287,16 +658,67 @@ struct TempPasswordRecord {
     expiry: DateTime<Utc>
 }
 
+#[derive(Serialize, Debug)]
+pub struct TempPasswordCheck {
+    valid: bool,
+    expired: bool
+}
+
+#[derive(FromRow)]
+struct TempPasswordCheckRecord {
+    pwd: String,
+    sent: DateTime<Utc>,
+    expiry: DateTime<Utc>,
+    check_attempts: i32
+}
+
+/// Checks whether a temporary password is still usable, without consuming it. Only a few checks are allowed
+/// per temporary password, to prevent it being brute-forced.
+#[get("/reset_pwd/check?<email>&<temp_pwd>")]
+pub async fn check_temp_password(state: &State<AppState>, email: &str, temp_pwd: &str) -> Result<Json<TempPasswordCheck>, PasswordResetError> {
+    let user_record = UserLoginRecord::load_by_email(&state.pool, email)
+        .await.map_err(db_error)?;
+    let Some(user_record) = user_record else {
+        return Ok(Json(TempPasswordCheck { valid: false, expired: false }));
+    };
+
+    let temp_pwd_record: Option<TempPasswordCheckRecord> = query_as("UPDATE temp_password SET check_attempts = check_attempts + 1 \
+            WHERE person_id = $1 \
+            RETURNING pwd, sent, expiry, check_attempts")
+        .bind(user_record.id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(db_error)?;
+    let Some(temp_pwd_record) = temp_pwd_record else {
+        return Ok(Json(TempPasswordCheck { valid: false, expired: false }));
+    };
+
+    if temp_pwd_record.check_attempts > TEMP_PASSWORD_MAX_CHECK_ATTEMPTS {
+        // A new temporary password can be requested once the minimum resend wait has passed
+        let retry_time = temp_pwd_record.sent.sub(TEMP_PASSWORD_MINIMUM_RESEND_WAIT);
+        let retry_after_secs = (retry_time - Utc::now()).num_seconds().max(0) + 1;
+        return Err(PasswordResetError::Throttled(
+            "Too many attempts, please request a new password reset.".to_string(),
+            Header::new("Retry-After", retry_after_secs.to_string())));
+    }
+
+    // Only tell callers that know the temporary password whether it has expired
+    let expired = temp_pwd_record.expiry < Utc::now();
+    let matches = verify_password(temp_pwd, &temp_pwd_record.pwd).is_ok();
+    Ok(Json(TempPasswordCheck { valid: matches && !expired, expired: matches && expired }))
+}
+
 #[post("/reset_pwd", data="<user_pwd_reset>")]
 pub async fn reset_pwd(
     state: &State<AppState>,
-    user_pwd_reset: Json<UserPasswordReset>
+    user_pwd_reset: JsonBody<UserPasswordReset>
 ) -> Result<Accepted<String>, Custom<String>> {
     verify_suitable_password(&user_pwd_reset.new_password, &user_pwd_reset.temp_password)?;
+    let email = validate_email(&user_pwd_reset.email)?;
 
     // Get the user => error if not found
-    let user_record = UserLoginRecord::load_by_email(&state.pool, &user_pwd_reset.email)
-        .await.map_err(|e| Custom(Status::InternalServerError, e.to_string()))?
+    let user_record = UserLoginRecord::load_by_email(&state.pool, &email)
+        .await.map_err(db_error)?
         .ok_or(Custom(Status::BadRequest, format!("User does not exist with email address {}", &user_pwd_reset.email)))?;
 
     // Get the temporary password record and verify against user input
@@ -309,12 +731,12 @@ pub async fn reset_pwd(
         .map_err(|_e| Custom(Status::Forbidden, INVALID_LOGIN_MESSAGE.to_string()))?;
 
     // Update the user's main password
-    let updated_user: UserUpdated = query_as("UPDATE person SET pwd = $1 WHERE id = $2 RETURNING id")
+    let updated_user: UserUpdated = query_as("UPDATE person SET pwd = $1, must_change_pwd = FALSE WHERE id = $2 RETURNING id")
         .bind(generate_hash(&user_pwd_reset.new_password))
         .bind(user_record.id)
         .fetch_one(&state.pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+        .map_err(db_error)?;
     info!("Updated password for user id {}", updated_user.id);
 
     // Clean up the temporary password record
@@ -326,13 +748,13 @@ pub async fn reset_pwd(
         .inspect_err(|e| error!("Failed to delete temporary password for user {}: {}", &user_record.email, e));
 
     // Send acknowledgement email
-    let text = format!(include_str!("post_reset_email.txt"), &user_record.name, &user_record.email, &user_pwd_reset.website_url);
+    let text = render_body(&state.config, EmailTemplate::PasswordChanged, &[&user_record.name, &user_record.email, &user_pwd_reset.website_url]);
     let sender = Address::new_address(Some(&state.config.email_sender_name), &state.config.email_sender_address);
     let message = MessageBuilder::new()
         .from(sender.clone())
         .reply_to(sender)
         .to(Address::new_address(Some(&user_record.name), &user_record.email))
-        .subject(format!("Password Changed for {}", &state.config.branding))
+        .subject(render_subject(&state.config, EmailTemplate::PasswordChanged))
         .text_body(text)
         .into_message()
         .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
@@ -366,6 +788,103 @@ impl FromRow<'_, PgRow> for UserListingEntry {
     }
 }
 
+#[derive(Serialize, Debug)]
+pub struct Me {
+    #[serde(flatten)]
+    user: UserListingEntry,
+    notification_prefs: NotificationPrefs,
+    auto_use_credits: bool,
+    two_factor_enabled: bool,
+    membership_status: MembershipStatus,
+    permissions: Permissions
+}
+
+/// What the user's roles allow them to do, so that clients do not have to repeat the server's rules
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Permissions {
+    can_create_sessions: bool,
+    can_manage_users: bool,
+    can_view_all_bookings: bool
+}
+
+impl Permissions {
+    fn from_claims(claims: &Claims, config: &Config) -> Self {
+        Permissions {
+            can_create_sessions: can_manage_sessions(claims, config),
+            can_manage_users: claims.has_role("admin"),
+            can_view_all_bookings: claims.has_role("admin")
+        }
+    }
+}
+
+#[get("/me")]
+pub async fn get_me(state: &State<AppState>, claims: Claims) -> Result<Json<Me>, Custom<String>> {
+    let user: UserListingEntry = query_as("SELECT id, name, email, phone, roles, credits FROM person WHERE id = $1")
+        .bind(claims.uid)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("user id not found: {}", claims.uid)))?;
+    let notification_prefs = NotificationPrefs::load(&state.pool, claims.uid)
+        .await
+        .map_err(db_error)?;
+    let (auto_use_credits, two_factor_enabled): (bool, bool) = query_as("SELECT auto_use_credits, two_factor_enabled FROM person WHERE id = $1")
+        .bind(claims.uid)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(db_error)?;
+    let membership_status = MembershipStatus::from_roles_and_credits(&user.roles, user.credits, min_credits_cost(&state.pool).await?);
+    let permissions = Permissions::from_claims(&claims, &state.config);
+    Ok(Json(Me { user, notification_prefs, auto_use_credits, two_factor_enabled, membership_status, permissions }))
+}
+
+/// Settings that members can change for themselves. Fields that are not supplied are left unchanged.
+#[derive(Deserialize, Debug)]
+pub struct MeUpdate {
+    notification_prefs: Option<NotificationPrefs>,
+    /// Use credits for bookings that need them without opting in each time
+    auto_use_credits: Option<bool>,
+    /// Require a code sent by email as well as the password to log in
+    two_factor_enabled: Option<bool>
+}
+
+#[put("/me", data="<update>")]
+pub async fn update_me(state: &State<AppState>, claims: Claims, update: JsonBody<MeUpdate>) -> Result<NoContent, Custom<String>> {
+    if let Some(notification_prefs) = &update.notification_prefs {
+        let prefs_json = rocket::serde::json::to_string(notification_prefs)
+            .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+        let _: UserUpdated = query_as("UPDATE person SET notification_prefs = $1::jsonb WHERE id = $2 RETURNING id")
+            .bind(prefs_json)
+            .bind(claims.uid)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(db_error)?
+            .ok_or(Custom(Status::NotFound, format!("user id not found: {}", claims.uid)))?;
+        info!("Updated notification preferences for user id {}: {:?}", claims.uid, notification_prefs);
+    }
+    if let Some(auto_use_credits) = update.auto_use_credits {
+        let _: UserUpdated = query_as("UPDATE person SET auto_use_credits = $1 WHERE id = $2 RETURNING id")
+            .bind(auto_use_credits)
+            .bind(claims.uid)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(db_error)?
+            .ok_or(Custom(Status::NotFound, format!("user id not found: {}", claims.uid)))?;
+        info!("Updated auto use credits for user id {}: {}", claims.uid, auto_use_credits);
+    }
+    if let Some(two_factor_enabled) = update.two_factor_enabled {
+        let _: UserUpdated = query_as("UPDATE person SET two_factor_enabled = $1 WHERE id = $2 RETURNING id")
+            .bind(two_factor_enabled)
+            .bind(claims.uid)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(db_error)?
+            .ok_or(Custom(Status::NotFound, format!("user id not found: {}", claims.uid)))?;
+        info!("Updated two-factor authentication for user id {}: {}", claims.uid, two_factor_enabled);
+    }
+    Ok(NoContent)
+}
+
 #[get("/users/<user_id>")]
 pub async fn get_user(state: &State<AppState>, claim: Claims, user_id: i64) -> Result<Json<Option<UserListingEntry>>, Custom<String>> {
     if !claim.has_role("admin") && !claim.uid == user_id {
@@ -375,26 +894,44 @@ pub async fn get_user(state: &State<AppState>, claim: Claims, user_id: i64) -> R
         .bind(user_id)
         .fetch_optional(&state.pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+        .map_err(db_error)?;
     Ok(Json(user))
 }
 
-#[get("/users/list?<role>")]
-pub async fn list_users(state: &State<AppState>, claim: Claims, role: Option<String>) -> Result<Json<Vec<UserListingEntry>>, Custom<String>> {
+#[get("/users/list?<role>&<has_credits>&<created_before>")]
+pub async fn list_users(
+    state: &State<AppState>,
+    claim: Claims,
+    role: Option<String>,
+    has_credits: Option<bool>,
+    created_before: Option<String>
+) -> Result<Json<Vec<UserListingEntry>>, Custom<String>> {
     if !claim.has_role("admin") {
         return Err(Custom(Status::Forbidden, "admin only".to_string()));
     }
 
-    let mut users: Vec<UserListingEntry> = query_as("SELECT id, name, email, phone, roles, credits FROM person ORDER BY name")
-        .fetch_all(&state.pool)
-        .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
-    if let Some(filter_role) = role {
-        users = users.into_iter()
-            .filter(|u| u.roles.contains(&filter_role))
-            .collect();
+    let mut qb: QueryBuilder<Postgres> = QueryBuilder::new("SELECT id, name, email, phone, roles, credits FROM person");
+    let mut where_op = String::from(" WHERE");
+    if let Some(role) = role {
+        qb.push(where_op + " ");
+        qb.push_bind(role);
+        qb.push(" = ANY(string_to_array(replace(roles, ' ', ''), ','))");
+        where_op = String::from(" AND");
+    }
+    if let Some(has_credits) = has_credits {
+        qb.push(where_op + if has_credits { " credits > 0" } else { " credits = 0" });
+        where_op = String::from(" AND");
+    }
+    if let Some(created_before) = parse_opt_date(created_before)? {
+        qb.push(where_op + " created < ");
+        qb.push_bind(created_before);
     }
+    qb.push(" ORDER BY name");
 
+    let users: Vec<UserListingEntry> = qb.build_query_as()
+        .fetch_all(&state.pool)
+        .await
+        .map_err(db_error)?;
     Ok(Json(users))
 }
 
@@ -405,10 +942,10 @@ pub struct UserDelete {
 }
 
 #[delete("/users/<user_id>", data="<deletion>")]
-pub async fn delete_user(state: &State<AppState>, claims: Claims, user_id: i64, deletion: Json<UserDelete>) -> Result<NoContent, Custom<String>> {
+pub async fn delete_user(state: &State<AppState>, claims: Claims, user_id: i64, deletion: JsonBody<UserDelete>) -> Result<NoContent, Custom<String>> {
     // Load the user record
     let mut login_record = UserLoginRecord::load_by_id(&state.pool, user_id)
-        .await.map_err(|e| Custom(Status::InternalServerError, e.to_string()))?
+        .await.map_err(db_error)?
         .ok_or(Custom(Status::NotFound, format!("user id not found: {}", user_id)))?;
 
     if user_id == claims.uid {
@@ -420,21 +957,52 @@ pub async fn delete_user(state: &State<AppState>, claims: Claims, user_id: i64,
         claims.assert_roles_contains("admin")?;
     }
 
+    delete_person_and_notify(state, &login_record, &deletion.website_url).await?;
+    if user_id != claims.uid {
+        audit::record(&state.pool, claims.uid, "delete_user", format!("person {}", user_id)).await;
+    }
+    Ok(NoContent)
+}
+
+/// Cancels a pending password reset, e.g. one requested by mistake, so that the temp password that was sent no longer works
+#[delete("/users/<user_id>/temp_password")]
+pub async fn delete_temp_password(state: &State<AppState>, claims: Claims, user_id: i64) -> Result<NoContent, Custom<String>> {
+    _delete_temp_password(&state.pool, &claims, user_id).await
+}
+
+async fn _delete_temp_password(pool: &PgPool, claims: &Claims, user_id: i64) -> Result<NoContent, Custom<String>> {
+    if user_id != claims.uid {
+        claims.assert_roles_contains("admin")?;
+    }
+    let _: UserUpdated = query_as("DELETE FROM temp_password WHERE person_id = $1 RETURNING person_id AS id")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| Custom(Status::NotFound, format!("no pending password reset for user id {}", user_id)))?;
+    info!("Deleted temporary password for user {}", user_id);
+    if user_id != claims.uid {
+        audit::record(pool, claims.uid, "delete_temp_password", format!("person {}", user_id)).await;
+    }
+    Ok(NoContent)
+}
+
+async fn delete_person_and_notify(state: &AppState, login_record: &UserLoginRecord, website_url: &str) -> Result<(), Custom<String>> {
     // Actually delete the data. Related records in bookings are removed by DELETE CASCADE
     let _ = query_as("DELETE FROM person WHERE id = $1 RETURNING id")
-        .bind(user_id)
+        .bind(login_record.id)
         .fetch_one(&state.pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+        .map_err(db_error)?;
 
     // Send an email to the user confirming their account has been deleted
-    let text = format!(include_str!("post_delete_profile_email.txt"), &login_record.email, &deletion.website_url);
+    let text = render_body(&state.config, EmailTemplate::ProfileDeleted, &[&login_record.email, &website_url]);
     let sender = Address::new_address(Some(&state.config.email_sender_name), &state.config.email_sender_address);
     let message = MessageBuilder::new()
         .from(sender.clone())
         .reply_to(sender)
         .to(Address::new_address(Some(&login_record.name), &login_record.email))
-        .subject(format!("User Profile Deleted for {}", &state.config.branding))
+        .subject(render_subject(&state.config, EmailTemplate::ProfileDeleted))
         .text_body(text)
         .into_message()
         .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
@@ -442,37 +1010,270 @@ pub async fn delete_user(state: &State<AppState>, claims: Claims, user_id: i64,
         .await
         .inspect_err(|e| error!("Failed to send deletion email to {}: {:?}", &login_record.email, e));
 
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct UserDeleteRequest {
+    website_url: String,
+    confirm_url: String
+}
+
+/// First step of a member deleting their own profile: emails them a link containing a single-use token
+/// which must be passed to `DELETE /me` to actually delete the profile.
+#[post("/me/delete_request", data="<delete_request>")]
+pub async fn request_delete_me(state: &State<AppState>, claims: Claims, delete_request: JsonBody<UserDeleteRequest>) -> Result<Accepted<String>, Custom<String>> {
+    let login_record = UserLoginRecord::load_by_id(&state.pool, claims.uid)
+        .await.map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("user id not found: {}", claims.uid)))?;
+
+    let token = create_person_token(&state.pool, login_record.id, TOKEN_PURPOSE_DELETE_PROFILE, Some(&delete_request.website_url), DELETION_TOKEN_EXPIRY).await?;
+    let confirm_url_with_params = format!("{}?token={}", &delete_request.confirm_url, encode(&token));
+    let text = render_body(&state.config, EmailTemplate::ConfirmProfileDeletion, &[&login_record.email, &delete_request.website_url, &confirm_url_with_params, &DELETION_TOKEN_EXPIRY.num_minutes()]);
+    let sender = Address::new_address(Some(&state.config.email_sender_name), &state.config.email_sender_address);
+    let message = MessageBuilder::new()
+        .from(sender.clone())
+        .reply_to(sender)
+        .to(Address::new_address(Some(&login_record.name), &login_record.email))
+        .subject(render_subject(&state.config, EmailTemplate::ConfirmProfileDeletion))
+        .text_body(text)
+        .into_message()
+        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+    send_email(message, &state.secrets).await?;
+
+    Ok(Accepted(format!("Confirmation email sent to {}.", &login_record.email)))
+}
+
+/// Second step of a member deleting their own profile, using the token from the confirmation email
+#[delete("/me?<token>")]
+pub async fn delete_me(state: &State<AppState>, claims: Claims, token: &str) -> Result<NoContent, Custom<String>> {
+    let login_record = UserLoginRecord::load_by_id(&state.pool, claims.uid)
+        .await.map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("user id not found: {}", claims.uid)))?;
+
+    let website_url = consume_person_token(&state.pool, login_record.id, TOKEN_PURPOSE_DELETE_PROFILE, token)
+        .await?
+        .unwrap_or_default();
+    delete_person_and_notify(state, &login_record, &website_url).await?;
     Ok(NoContent)
 }
 
+#[derive(FromRow)]
+struct PersonTokenRecord {
+    token: String,
+    payload: Option<String>,
+    expiry: DateTime<Utc>,
+    check_attempts: i32
+}
+
+/// Creates a single-use token for the given purpose, replacing any previous token for the same user and purpose.
+/// Only a hash of the token is stored.
+async fn create_person_token(pool: &PgPool, user_id: i64, purpose: &str, payload: Option<&str>, expiry: Duration) -> Result<String, Custom<String>> {
+    let token = TOKEN_GENERATOR.generate_one()
+        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+    save_person_token(pool, user_id, purpose, &token, payload, expiry, false).await?;
+    Ok(token)
+}
+
+/// Saves a token, replacing any previous one for the same user and purpose. Attempts at the previous token are
+/// forgotten, unless they are to be kept while it is unexpired.
+async fn save_person_token(pool: &PgPool, user_id: i64, purpose: &str, token: &str, payload: Option<&str>, expiry: Duration, keep_attempts: bool) -> Result<(), Custom<String>> {
+    let now = Utc::now();
+    let _: UserUpdated = query_as(
+        "INSERT INTO person_token (person_id, purpose, token, payload, sent, expiry) \
+            VALUES ($1, $2, $3, $4, $5, $6) \
+            ON CONFLICT (person_id, purpose) DO UPDATE SET token = EXCLUDED.token, payload = EXCLUDED.payload, sent = EXCLUDED.sent, expiry = EXCLUDED.expiry, \
+                check_attempts = CASE WHEN $7 AND person_token.expiry > now() THEN person_token.check_attempts ELSE 0 END \
+            RETURNING person_id AS id")
+        .bind(user_id)
+        .bind(purpose)
+        .bind(generate_hash(token))
+        .bind(payload)
+        .bind(now)
+        .bind(now.add(expiry))
+        .bind(keep_attempts)
+        .fetch_one(pool)
+        .await
+        .map_err(db_error)?;
+    info!("Created {} token for user with id {}", purpose, user_id);
+
+    // Since we are here, delete expired tokens
+    let _ = raw_sql("DELETE FROM person_token WHERE expiry < now()")
+        .execute(pool)
+        .await
+        .inspect_err(|e| error!("Failed to clean person tokens table: {}", e));
+
+    Ok(())
+}
+
+/// Verifies and deletes a single-use token, returning its payload. Only a few attempts are allowed per token,
+/// so that short tokens such as login codes cannot be guessed.
+async fn consume_person_token(pool: &PgPool, user_id: i64, purpose: &str, token: &str) -> Result<Option<String>, Custom<String>> {
+    let record: PersonTokenRecord = query_as("UPDATE person_token SET check_attempts = check_attempts + 1 \
+            WHERE person_id = $1 AND purpose = $2 \
+            RETURNING token, payload, expiry, check_attempts")
+        .bind(user_id)
+        .bind(purpose)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .filter(|record: &PersonTokenRecord| record.expiry > Utc::now())
+        .ok_or(Custom(Status::Forbidden, "Confirmation has not been requested, or it has expired.".to_string()))?;
+    if record.check_attempts > PERSON_TOKEN_MAX_CHECK_ATTEMPTS {
+        return Err(Custom(Status::Forbidden, "Too many attempts, please request a new confirmation.".to_string()));
+    }
+    verify_password(token, &record.token)
+        .map_err(|_e| Custom(Status::Forbidden, "Invalid confirmation token.".to_string()))?;
+
+    query_as("DELETE FROM person_token WHERE person_id = $1 AND purpose = $2 RETURNING person_id AS id")
+        .bind(user_id)
+        .bind(purpose)
+        .fetch_one(pool)
+        .await
+        .map(|user_updated: UserUpdated| info!("Deleted {} token for user {}", purpose, user_updated.id))
+        .map_err(db_error)?;
+    Ok(record.payload)
+}
+
 #[derive(Deserialize)]
 pub struct UserUpdate {
     name: String,
     email: String,
     phone: Option<String>,
     roles: Vec<String>,
-    credits: i32
+    credits: i32,
+    /// Forces the user to change their password the next time that they log in. Unchanged if not given.
+    #[serde(default)]
+    must_change_pwd: Option<bool>,
+    /// Where members confirm a change to their own email address, required if they make one
+    #[serde(default)]
+    website_url: Option<String>,
+    #[serde(default)]
+    confirm_url: Option<String>
+}
+
+/// A change to a member's own email address that waits for them to confirm it from the new address
+#[derive(Debug)]
+struct EmailChange {
+    email: String,
+    token: String,
+    website_url: String,
+    confirm_url: String
 }
 
 #[put("/users/<user_id>", data="<update>")]
-pub async fn update_user(state: &State<AppState>, claims: Claims, user_id: i64, update: Json<UserUpdate>) -> Result<Accepted<String>, Custom<String>> {
-    if !claims.uid == user_id {
-        let _ = claims.assert_roles_contains("admin")?;
+pub async fn update_user(state: &State<AppState>, claims: Claims, user_id: i64, update: JsonBody<UserUpdate>) -> Result<Accepted<String>, Custom<String>> {
+    let Some(email_change) = _update_user(&state.pool, &claims, user_id, update.into_inner()).await? else {
+        return Ok(Accepted(String::from("user updated")));
+    };
+
+    let confirm_url_with_params = format!("{}?token={}", &email_change.confirm_url, encode(&email_change.token));
+    let text = render_body(&state.config, EmailTemplate::ConfirmEmailChange, &[&email_change.website_url, &email_change.email, &confirm_url_with_params, &EMAIL_CHANGE_TOKEN_EXPIRY.num_minutes()]);
+    let sender = Address::new_address(Some(&state.config.email_sender_name), &state.config.email_sender_address);
+    let message = MessageBuilder::new()
+        .from(sender.clone())
+        .reply_to(sender)
+        .to(email_change.email.as_str())
+        .subject(render_subject(&state.config, EmailTemplate::ConfirmEmailChange))
+        .text_body(text)
+        .into_message()
+        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+    send_email(message, &state.secrets).await?;
+
+    Ok(Accepted(format!("user updated; confirmation email sent to {}", &email_change.email)))
+}
+
+/// Updates a user. Admins change email addresses directly, but members changing their own are returned a token
+/// to send to the new address, and the change is only made once they confirm it.
+async fn _update_user(pool: &PgPool, claims: &Claims, user_id: i64, update: UserUpdate) -> Result<Option<EmailChange>, Custom<String>> {
+    if claims.uid != user_id {
+        claims.assert_roles_contains("admin")?;
+    }
+
+    let mut email = validate_email(&update.email)?;
+    let mut email_change = None;
+    let mut roles_str = update.roles.join(",");
+    let mut credits = update.credits;
+    let mut must_change_pwd = update.must_change_pwd;
+    if !claims.has_role("admin") {
+        let current = UserLoginRecord::load_by_id(pool, user_id)
+            .await.map_err(db_error)?
+            .ok_or(Custom(Status::NotFound, format!("user id not found: {}", user_id)))?;
+        if email != current.email.to_lowercase() {
+            let (website_url, confirm_url) = update.website_url.clone().zip(update.confirm_url.clone())
+                .ok_or(Custom(Status::UnprocessableEntity, "website_url and confirm_url are required to change email address".to_string()))?;
+            let token = create_person_token(pool, user_id, TOKEN_PURPOSE_CHANGE_EMAIL, Some(&email), EMAIL_CHANGE_TOKEN_EXPIRY).await?;
+            email_change = Some(EmailChange { email, token, website_url, confirm_url });
+            email = current.email;
+        }
+
+        // Only admins can change roles, credits or whether a password must be changed, so members keep their own
+        if roles_str != current.roles || credits != i32::from(current.credits) || must_change_pwd.is_some_and(|m| m != current.must_change_pwd) {
+            info!("person id {} attempted to change their own roles, credits or password requirement; ignored: missing admin role", claims.uid);
+        }
+        roles_str = current.roles;
+        credits = i32::from(current.credits);
+        must_change_pwd = None;
     }
 
-    let roles_str = &update.roles.join(",");
-    let _: UserLoginRecord = query_as("UPDATE person SET name = $1, email = $2, phone = $3, roles = $4, credits = $5 WHERE id = $6 RETURNING id, name, email, phone, pwd, roles, credits")
+    let _: UserLoginRecord = query_as("UPDATE person SET name = $1, email = $2, phone = $3, roles = $4, credits = $5, must_change_pwd = COALESCE($6, must_change_pwd) WHERE id = $7 RETURNING id, name, email, phone, pwd, roles, credits, must_change_pwd")
         .bind(&update.name)
-        .bind(&update.email)
+        .bind(&email)
         .bind(&update.phone)
-        .bind(roles_str)
-        .bind(&update.credits)
+        .bind(&roles_str)
+        .bind(credits)
+        .bind(must_change_pwd)
         .bind(user_id)
-        .fetch_one(&state.pool)
+        .fetch_one(pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+        .map_err(db_error)?;
+    if user_id != claims.uid {
+        audit::record(pool, claims.uid, "update_user", format!("person {}", user_id)).await;
+    }
+
+    Ok(email_change)
+}
+
+/// Second step of a member changing their own email address, using the token sent to the new address
+#[post("/me/email/confirm?<token>")]
+pub async fn confirm_email_change(state: &State<AppState>, claims: Claims, token: &str) -> Result<NoContent, Custom<String>> {
+    _confirm_email_change(&state.pool, &claims, token).await
+}
 
-    Ok(Accepted(String::from("user updated")))
+async fn _confirm_email_change(pool: &PgPool, claims: &Claims, token: &str) -> Result<NoContent, Custom<String>> {
+    let email = consume_person_token(pool, claims.uid, TOKEN_PURPOSE_CHANGE_EMAIL, token)
+        .await?
+        .ok_or(Custom(Status::InternalServerError, "missing new email address".to_string()))?;
+    query("UPDATE person SET email = $1 WHERE id = $2")
+        .bind(&email)
+        .bind(claims.uid)
+        .execute(pool)
+        .await
+        .map_err(|e| match e.as_database_error() {
+            Some(db_error) if db_error.is_unique_violation() => Custom(Status::Conflict, "User already exists with this email address".to_string()),
+            _ => db_error(e)
+        })?;
+    info!("Changed email address for user id {} to {}", claims.uid, email);
+    Ok(NoContent)
+}
+
+/// Catches obvious typos in an email address, such as a missing `@` or domain, and normalizes it to lowercase.
+/// This is deliberately loose: whether the address really exists is only known by sending to it.
+pub(crate) fn validate_email(email: &str) -> Result<String, Custom<String>> {
+    let email = email.trim().to_lowercase();
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => !local.is_empty()
+            && !domain.contains('@')
+            && domain.contains('.')
+            && !domain.starts_with('.')
+            && !domain.ends_with('.')
+            && !domain.contains(".."),
+        None => false
+    };
+    if !valid || email.chars().any(char::is_whitespace) {
+        return Err(Custom(Status::UnprocessableEntity, format!("invalid email address: {}", email)));
+    }
+    Ok(email)
 }
 
 fn verify_suitable_password(new_password: &str, current_password: &str) -> Result<(), Custom<String>> {
@@ -480,45 +1281,48 @@ fn verify_suitable_password(new_password: &str, current_password: &str) -> Resul
     if new_password.eq(current_password) {
         return Err(Custom(Status::Forbidden, "new password cannot be the same as the current password".to_string()));
     }
-    if new_password.chars().count() < 8 {
-        return Err(Custom(Status::Forbidden, "new password must be at least 8 characters in length".to_string()));
+    if new_password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(Custom(Status::Forbidden, format!("new password must be at least {} characters in length", MIN_PASSWORD_LENGTH)));
     }
     Ok(())
 }
 
-fn parse_roles(roles_str: &str) -> Vec<String> {
-    let parsed_roles = roles_str
-        .split(",")
-        .map(|s| s.to_string())
-        .collect::<Vec<_>>();
-
-    if vec![String::from("")].eq(&parsed_roles) {
-        Vec::new()
-    } else {
-        parsed_roles
+pub(crate) fn parse_roles(roles_str: &str) -> Vec<String> {
+    let mut parsed_roles: Vec<String> = Vec::new();
+    for role in roles_str.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        if !parsed_roles.iter().any(|r| r == role) {
+            parsed_roles.push(role.to_string());
+        }
     }
+    parsed_roles
 }
 
-fn build_login_response(
+async fn build_login_response(
     login_record: UserLoginRecord,
-    secrets: &shuttle_runtime::SecretStore
+    state: &AppState
 ) -> Result<LoginResponse, Custom<String>> {
     // Create access and refresh tokens
     let roles = parse_roles(&login_record.roles);
-    let access_token_key = secrets.get("ACCESS_TOKEN_KEY")
+    let access_token_key = state.secrets.get("ACCESS_TOKEN_KEY")
         .ok_or(Custom(Status::InternalServerError, String::from("missing secret ACCESS_TOKEN_KEY")))?;
-    let access_token = Claims::create(login_record.id, &login_record.email, &login_record.phone, &roles, ACCESS_TOKEN_TTL).into_token(&access_token_key)?;
-    let refresh_token_key = secrets.get("REFRESH_TOKEN_KEY")
+    let access_token = Claims::create(login_record.id, &login_record.email, &login_record.phone, &roles, ACCESS_TOKEN_TTL)
+        .with_must_change_pwd(login_record.must_change_pwd)
+        .into_token(&access_token_key, state.jwt_algorithm)?;
+    let refresh_token_key = state.secrets.get("REFRESH_TOKEN_KEY")
         .ok_or(Custom(Status::InternalServerError, String::from("missing secret REFRESH_TOKEN_KEY")))?;
-    let refresh_token: String = Claims::create(login_record.id, &login_record.email, &login_record.phone, &roles, REFRESH_TOKEN_EXIRATION).into_token(&refresh_token_key)?;
+    let refresh_token: String = Claims::create(login_record.id, &login_record.email, &login_record.phone, &roles, REFRESH_TOKEN_EXIRATION)
+        .with_must_change_pwd(login_record.must_change_pwd)
+        .into_token(&refresh_token_key, state.jwt_algorithm)?;
 
     // Build login response body
+    let membership_status = MembershipStatus::from_roles_and_credits(&roles, login_record.credits, min_credits_cost(&state.pool).await?);
     let body = LoggedInUser {
         id: login_record.id,
         name: login_record.name,
         email: login_record.email,
         phone: login_record.phone,
         roles,
+        membership_status,
         access_token
     };
 
@@ -526,11 +1330,28 @@ fn build_login_response(
     let cookie_expiry = Utc::now().add(REFRESH_TOKEN_EXIRATION);
     Ok(LoginResponse {
         inner: Json(body),
-        cookie: Header::new("Set-Cookie", format!("refresh_token={};HttpOnly;Expires={}", refresh_token, cookie_expiry.to_rfc2822()))
+        cookie: Header::new("Set-Cookie", refresh_token_cookie(&state.config, &refresh_token, cookie_expiry))
     })
 }
 
-async fn send_email<'x>(
+fn refresh_token_cookie(config: &Config, refresh_token: &str, expiry: DateTime<Utc>) -> String {
+    let mut cookie = format!("refresh_token={};HttpOnly;Expires={}", refresh_token, expiry.to_rfc2822());
+    if config.cookie_secure {
+        cookie.push_str(";Secure");
+    }
+    if !config.cookie_same_site.is_empty() {
+        cookie.push_str(&format!(";SameSite={}", config.cookie_same_site));
+    }
+    if let Some(domain) = &config.cookie_domain {
+        cookie.push_str(&format!(";Domain={}", domain));
+    }
+    if let Some(path) = &config.cookie_path {
+        cookie.push_str(&format!(";Path={}", path));
+    }
+    cookie
+}
+
+pub(crate) async fn send_email<'x>(
     message: Message<'x>,
     secrets: &shuttle_runtime::SecretStore
 ) -> Result<(), Custom<String>> {
@@ -586,6 +1407,291 @@ mod tests {
         member_id.id
     }
 
+    #[test]
+    fn refresh_token_cookie_attributes() {
+        let expiry: chrono::DateTime<chrono::Utc> = "2024-01-02T03:04:05Z".parse().unwrap();
+        let mut config = crate::Config::default();
+        assert_eq!("refresh_token=abc;HttpOnly;Expires=Tue, 2 Jan 2024 03:04:05 +0000;Secure;SameSite=Strict",
+            crate::login::refresh_token_cookie(&config, "abc", expiry));
+
+        config.cookie_secure = false;
+        config.cookie_same_site = String::new();
+        config.cookie_domain = Some("example.com".to_string());
+        config.cookie_path = Some("/api".to_string());
+        assert_eq!("refresh_token=abc;HttpOnly;Expires=Tue, 2 Jan 2024 03:04:05 +0000;Domain=example.com;Path=/api",
+            crate::login::refresh_token_cookie(&config, "abc", expiry));
+    }
+
+    #[sqlx::test]
+    async fn register_user_rolled_back_when_email_fails(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let new_user = crate::login::NewUserRequest {
+            name: "Joe".to_string(),
+            email: "joe@example.com".to_string(),
+            phone: None,
+            website_url: "https://example.com".to_string(),
+            reset_url: "https://example.com/reset".to_string()
+        };
+        let result = crate::login::_register_user(&pool, &new_user, |_| async {
+            Err(Custom(Status::InternalServerError, "SMTP unavailable".to_string()))
+        }).await;
+        assert_eq!(Status::ServiceUnavailable, result.err().unwrap().0);
+        assert!(crate::UserLoginRecord::load_by_email(&pool, "joe@example.com").await.unwrap().is_none());
+
+        // Registering again once email is working creates the user
+        let user_id = crate::login::_register_user(&pool, &new_user, |_| async { Ok(()) }).await.unwrap();
+        assert_eq!(user_id, crate::UserLoginRecord::load_by_email(&pool, "joe@example.com").await.unwrap().unwrap().id);
+    }
+
+    #[sqlx::test]
+    async fn import_users_reports_each_row(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let admin_id = create_person(&pool, "admin@example.com", DEFAULT_PASSWORD_HASH, "admin", 0).await;
+        let existing_id = create_person(&pool, "existing@example.com", DEFAULT_PASSWORD_HASH, "member", 0).await;
+        let client = crate::test_support::test_client(pool.clone(), routes![crate::login::import_users]).await;
+        let claims = |uid: i64, role: &str| crate::claims::Claims::create(uid, "user@example.com", &None, &vec![role.to_string()], chrono::Duration::minutes(1));
+        let users = r#"[
+            {"name": "New", "email": "new@example.com", "phone": "0123", "roles": ["member", "limited-member"], "credits": 2},
+            {"name": "Existing", "email": "Existing@Example.com", "roles": ["admin"], "credits": 0},
+            {"name": "Negative", "email": "negative@example.com", "roles": [], "credits": -1},
+            {"name": "Invalid", "email": "invalid@example", "roles": ["member"], "credits": 0},
+            {"name": "Mixed", "email": " Mixed@Example.com ", "roles": ["member"], "credits": 0}
+        ]"#;
+
+        let response = client.post("/users/import").header(crate::test_support::bearer(claims(admin_id, "admin"))).header(rocket::http::ContentType::JSON).body(users).dispatch().await;
+        assert_eq!(Status::Ok, response.status());
+        let body = response.into_string().await.unwrap();
+        let results: Vec<rocket::serde::json::Value> = body.lines().map(|line| rocket::serde::json::from_str(line).unwrap()).collect();
+        assert_eq!(vec!["created", "skipped_duplicate", "error", "error", "created"], results.iter().map(|r| r["status"].as_str().unwrap()).collect::<Vec<_>>());
+        assert!(results[2]["error"].is_string());
+        assert_eq!("invalid@example", results[3]["email"]);
+        assert!(results[3]["error"].as_str().unwrap().contains("invalid email address"));
+        assert!(crate::UserLoginRecord::load_by_email(&pool, "invalid@example").await.unwrap().is_none());
+
+        // Valid addresses are stored normalised
+        assert_eq!("mixed@example.com", results[4]["email"]);
+        let email: String = sqlx::query_scalar("select email from person where id = $1").bind(results[4]["id"].as_i64().unwrap()).fetch_one(&pool).await.unwrap();
+        assert_eq!("mixed@example.com", email);
+
+        // New users have no password until they reset it, and existing users are left as they were
+        let new_user = crate::UserLoginRecord::load_by_email(&pool, "new@example.com").await.unwrap().unwrap();
+        assert_eq!(Some(new_user.id), results[0]["id"].as_i64());
+        assert_eq!(None, new_user.pwd);
+        assert_eq!("member,limited-member", new_user.roles);
+        let existing = crate::UserLoginRecord::load_by_id(&pool, existing_id).await.unwrap().unwrap();
+        assert_eq!("member", existing.roles);
+
+        let response = client.post("/users/import").header(crate::test_support::bearer(claims(existing_id, "member"))).header(rocket::http::ContentType::JSON).body(users).dispatch().await;
+        assert_eq!(Status::Forbidden, response.status());
+    }
+
+    #[sqlx::test]
+    async fn upsert_user_creates_then_updates(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let mut user = crate::login::ImportedUser {
+            name: "Joe".to_string(),
+            email: "joe@example.com".to_string(),
+            phone: None,
+            roles: vec!["member".to_string()],
+            credits: 0
+        };
+        let (id, status) = crate::login::_upsert_user(&pool, &user).await.unwrap();
+        assert_eq!(crate::login::UserUpsertStatus::Created, status);
+
+        user.name = "Joe Bloggs".to_string();
+        user.credits = 3;
+        let (updated_id, status) = crate::login::_upsert_user(&pool, &user).await.unwrap();
+        assert_eq!(crate::login::UserUpsertStatus::Updated, status);
+        assert_eq!(id, updated_id);
+        let record = crate::UserLoginRecord::load_by_id(&pool, id).await.unwrap().unwrap();
+        assert_eq!("Joe Bloggs", record.name);
+        assert_eq!(3, record.credits);
+
+        // Users are matched ignoring the case of their email
+        pool.execute("update person set email = 'Joe@Example.com'").await.unwrap();
+        let (updated_id, status) = crate::login::_upsert_user(&pool, &user).await.unwrap();
+        assert_eq!(crate::login::UserUpsertStatus::Updated, status);
+        assert_eq!(id, updated_id);
+    }
+
+    #[sqlx::test]
+    async fn member_email_change_needs_confirmation(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let person_id = create_person(&pool, "joe@example.com", DEFAULT_PASSWORD_HASH, "member", 0).await;
+        let claims = crate::claims::Claims::create(person_id, "joe@example.com", &None, &vec!["member".to_string()], chrono::Duration::minutes(1));
+        let update = |email: &str| crate::login::UserUpdate {
+            name: "Joe".to_string(),
+            email: email.to_string(),
+            phone: None,
+            roles: vec!["member".to_string()],
+            credits: 0,
+            must_change_pwd: None,
+            website_url: Some("https://example.com".to_string()),
+            confirm_url: Some("https://example.com/confirm".to_string())
+        };
+        let email = |pool: PgPool| async move {
+            crate::UserLoginRecord::load_by_id(&pool, person_id).await.unwrap().unwrap().email
+        };
+
+        // The email is unchanged until the token sent to the new address is confirmed
+        let email_change = crate::login::_update_user(&pool, &claims, person_id, update("joe.new@example.com")).await.unwrap().unwrap();
+        assert_eq!("joe.new@example.com", email_change.email);
+        assert_eq!("joe@example.com", email(pool.clone()).await);
+        let result = crate::login::_confirm_email_change(&pool, &claims, "wrong").await;
+        assert_eq!(Status::Forbidden, result.err().unwrap().0);
+        assert_eq!("joe@example.com", email(pool.clone()).await);
+        crate::login::_confirm_email_change(&pool, &claims, &email_change.token).await.unwrap();
+        assert_eq!("joe.new@example.com", email(pool.clone()).await);
+
+        // An address taken by someone else in the meantime cannot be confirmed
+        let email_change = crate::login::_update_user(&pool, &claims, person_id, update("taken@example.com")).await.unwrap().unwrap();
+        create_person(&pool, "taken@example.com", DEFAULT_PASSWORD_HASH, "member", 0).await;
+        let result = crate::login::_confirm_email_change(&pool, &claims, &email_change.token).await;
+        assert_eq!(Status::Conflict, result.err().unwrap().0);
+        assert_eq!("joe.new@example.com", email(pool.clone()).await);
+
+        // Admins change email addresses directly
+        let admin = crate::claims::Claims::create(0, "admin@example.com", &None, &vec!["admin".to_string()], chrono::Duration::minutes(1));
+        assert!(crate::login::_update_user(&pool, &admin, person_id, update("joe@example.com")).await.unwrap().is_none());
+        assert_eq!("joe@example.com", email(pool.clone()).await);
+    }
+
+    #[sqlx::test]
+    async fn member_cannot_grant_themselves_roles_or_credits(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let person_id = create_person(&pool, "joe@example.com", DEFAULT_PASSWORD_HASH, "member", 2).await;
+        let claims = crate::claims::Claims::create(person_id, "joe@example.com", &None, &vec!["member".to_string()], chrono::Duration::minutes(1));
+        let update = crate::login::UserUpdate {
+            name: "Joe Bloggs".to_string(),
+            email: "joe@example.com".to_string(),
+            phone: None,
+            roles: vec!["member".to_string(), "admin".to_string()],
+            credits: 100,
+            must_change_pwd: Some(true),
+            website_url: None,
+            confirm_url: None
+        };
+
+        // Other details are updated, but the member keeps their roles and credits
+        crate::login::_update_user(&pool, &claims, person_id, update).await.unwrap();
+        let record = crate::UserLoginRecord::load_by_id(&pool, person_id).await.unwrap().unwrap();
+        assert_eq!("Joe Bloggs", record.name);
+        assert_eq!("member", record.roles);
+        assert_eq!(2, record.credits);
+        assert!(!record.must_change_pwd);
+
+        // Members updating their own details are not audited
+        let audited: i64 = sqlx::query_scalar("select count(*) from audit_log where action = 'update_user'").fetch_one(&pool).await.unwrap();
+        assert_eq!(0, audited);
+    }
+
+    #[sqlx::test]
+    async fn delete_temp_password(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let person_id = create_person(&pool, "joe@example.com", DEFAULT_PASSWORD_HASH, "member", 0).await;
+        let other_id = create_person(&pool, "other@example.com", DEFAULT_PASSWORD_HASH, "member", 0).await;
+        crate::login::create_temp_password(&pool, &crate::Config::default(), person_id).await.unwrap();
+
+        // Other members cannot cancel the reset
+        let other = crate::claims::Claims::create(other_id, "other@example.com", &None, &vec!["member".to_string()], chrono::Duration::minutes(1));
+        assert_eq!(Status::Forbidden, crate::login::_delete_temp_password(&pool, &other, person_id).await.err().unwrap().0);
+
+        // The member themselves can, once
+        let claims = crate::claims::Claims::create(person_id, "joe@example.com", &None, &vec!["member".to_string()], chrono::Duration::minutes(1));
+        crate::login::_delete_temp_password(&pool, &claims, person_id).await.unwrap();
+        assert_eq!(Status::NotFound, crate::login::_delete_temp_password(&pool, &claims, person_id).await.err().unwrap().0);
+    }
+
+    #[test]
+    fn validate_email_cases() {
+        assert_eq!("joe@example.com", crate::login::validate_email("joe@example.com").unwrap());
+        assert_eq!("joe.bloggs+gym@example.co.uk", crate::login::validate_email(" Joe.Bloggs+Gym@Example.co.uk ").unwrap());
+        for invalid in ["", "joe", "joe@", "@example.com", "joe@example", "joe@@example.com", "joe@example..com", "joe@.example.com", "joe@example.com.", "joe bloggs@example.com"] {
+            assert_eq!(Status::UnprocessableEntity, crate::login::validate_email(invalid).err().unwrap().0, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parse_roles_trims_and_dedupes() {
+        assert_eq!(vec!["admin", "member"], crate::login::parse_roles("admin, member"));
+        assert_eq!(vec!["admin", "member"], crate::login::parse_roles(" admin ,member,admin, member "));
+        assert!(crate::login::parse_roles("").is_empty());
+        assert!(crate::login::parse_roles(" , ,").is_empty());
+        assert_eq!(vec!["trainer"], crate::login::parse_roles(",trainer,"));
+    }
+
+    #[sqlx::test]
+    async fn check_temp_password(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let member_id = create_person(&pool, "member@example.com", DEFAULT_PASSWORD_HASH, "member", 0).await;
+        sqlx::query("insert into temp_password (person_id, pwd, sent, expiry) values ($1, $2, now(), now() + interval '1 hour')")
+            .bind(member_id)
+            .bind(password_auth::generate_hash("temp-pwd"))
+            .execute(&pool).await.unwrap();
+        let client = crate::test_support::test_client(pool.clone(), routes![crate::login::check_temp_password]).await;
+        let check = |email: &str, temp_pwd: &str| {
+            let request = client.get(format!("/reset_pwd/check?email={}&temp_pwd={}", email, temp_pwd));
+            async move {
+                let response = request.dispatch().await;
+                let status = response.status();
+                let check: Option<rocket::serde::json::Value> = response.into_json().await;
+                (status, check.map(|c| (c["valid"].as_bool().unwrap(), c["expired"].as_bool().unwrap())))
+            }
+        };
+
+        assert_eq!((Status::Ok, Some((false, false))), check("nobody@example.com", "temp-pwd").await);
+        assert_eq!((Status::Ok, Some((false, false))), check("member@example.com", "wrong-pwd").await);
+        assert_eq!((Status::Ok, Some((true, false))), check("member@example.com", "temp-pwd").await);
+        // Checking does not use up the temporary password
+        assert_eq!((Status::Ok, Some((true, false))), check("member@example.com", "temp-pwd").await);
+
+        // Only callers that know the temporary password learn that it expired
+        pool.execute("update temp_password set expiry = now() - interval '1 minute'").await.unwrap();
+        assert_eq!((Status::Ok, Some((false, true))), check("member@example.com", "temp-pwd").await);
+        assert_eq!((Status::Ok, Some((false, false))), check("member@example.com", "wrong-pwd").await);
+
+        // Too many checks of the same temporary password are refused, even with the right one
+        let response = client.get("/reset_pwd/check?email=member@example.com&temp_pwd=temp-pwd").dispatch().await;
+        assert_eq!(Status::TooManyRequests, response.status());
+        assert!(response.headers().get_one("Retry-After").is_some());
+    }
+
+    #[sqlx::test]
+    async fn list_users_by_credits_and_creation(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let admin_id = create_person(&pool, "admin@example.com", DEFAULT_PASSWORD_HASH, "admin", 0).await;
+        let old_member_id = create_person(&pool, "old@example.com", DEFAULT_PASSWORD_HASH, "member", 0).await;
+        let limited_id = create_person(&pool, "limited@example.com", DEFAULT_PASSWORD_HASH, "member, limited-member", 2).await;
+        let payg_id = create_person(&pool, "payg@example.com", DEFAULT_PASSWORD_HASH, "", 3).await;
+        sqlx::query("update person set created = '2020-01-01T00:00:00Z' where id = $1")
+            .bind(old_member_id)
+            .execute(&pool).await.unwrap();
+        let client = crate::test_support::test_client(pool.clone(), routes![crate::login::list_users]).await;
+        let admin = || crate::claims::Claims::create(admin_id, "admin@example.com", &None, &vec!["admin".to_string()], chrono::Duration::minutes(1));
+        let list_user_ids = |params: &str| {
+            let request = client.get(format!("/users/list?{}", params)).header(crate::test_support::bearer(admin()));
+            async move {
+                let users: Vec<rocket::serde::json::Value> = request.dispatch().await.into_json().await.unwrap();
+                let mut ids: Vec<i64> = users.iter().map(|u| u["id"].as_i64().unwrap()).collect();
+                ids.sort();
+                ids
+            }
+        };
+
+        assert_eq!(vec![admin_id, old_member_id, limited_id, payg_id], list_user_ids("").await);
+        assert_eq!(vec![old_member_id, limited_id], list_user_ids("role=member").await);
+        assert_eq!(vec![limited_id], list_user_ids("role=limited-member").await);
+        assert_eq!(vec![limited_id, payg_id], list_user_ids("has_credits=true").await);
+        assert_eq!(vec![old_member_id], list_user_ids("role=member&has_credits=false").await);
+        assert_eq!(vec![old_member_id], list_user_ids("created_before=2024-01-01T00:00:00Z").await);
+        assert_eq!(Vec::<i64>::new(), list_user_ids("role=limited-member&created_before=2024-01-01T00:00:00Z").await);
+    }
+
     #[sqlx::test]
     async fn verify_user_by_email(pool: PgPool) {
         pool.execute(include_str!("../schema.sql")).await.unwrap();
@@ -595,6 +1701,20 @@ mod tests {
         assert_eq!(person_id, verify_result.id);
     }
 
+    #[sqlx::test]
+    async fn change_password_with_mixed_case_username(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let person_id = create_person(&pool, "joe@example.com", DEFAULT_PASSWORD_HASH, "member", 0).await;
+        let client = crate::test_support::test_client(pool.clone(), routes![crate::login::change_password]).await;
+
+        let response = client.post("/change_password")
+            .header(rocket::http::ContentType::JSON)
+            .body(r#"{"username": "Joe@Example.com", "current_password": "password", "new_password": "new password"}"#)
+            .dispatch().await;
+        assert_eq!(Status::Ok, response.status());
+        crate::login::verify_user_by_id(&pool, person_id, "new password").await.unwrap();
+    }
+
     #[sqlx::test]
     async fn verify_user_by_id(pool: PgPool) {
         pool.execute(include_str!("../schema.sql")).await.unwrap();
@@ -613,4 +1733,124 @@ mod tests {
         assert_eq!(Custom(Status::Unauthorized, "incorrect username or password".to_string()), verify_result.err().unwrap());
     }
 
+    #[sqlx::test]
+    async fn person_token_single_use(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let person_id = create_person(&pool, "joe@example.com", DEFAULT_PASSWORD_HASH, "member", 0).await;
+        let token = crate::login::create_person_token(&pool, person_id, "test", Some("payload"), chrono::Duration::minutes(1)).await.unwrap();
+
+        // Wrong token is refused, and doesn't use up the real one
+        let result = crate::login::consume_person_token(&pool, person_id, "test", "wrong").await;
+        assert_eq!(Status::Forbidden, result.err().unwrap().0);
+
+        let payload = crate::login::consume_person_token(&pool, person_id, "test", &token).await.unwrap();
+        assert_eq!(Some("payload".to_string()), payload);
+
+        // Cannot be used twice
+        let result = crate::login::consume_person_token(&pool, person_id, "test", &token).await;
+        assert_eq!(Status::Forbidden, result.err().unwrap().0);
+
+        // Expired tokens are refused
+        let token = crate::login::create_person_token(&pool, person_id, "test", None, chrono::Duration::minutes(-1)).await.unwrap();
+        let result = crate::login::consume_person_token(&pool, person_id, "test", &token).await;
+        assert_eq!(Status::Forbidden, result.err().unwrap().0);
+    }
+
+    #[sqlx::test]
+    async fn two_factor_login(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        // Without two-factor authentication, the password is enough
+        let person_id = create_person(&pool, "joe@example.com", DEFAULT_PASSWORD_HASH, "admin", 0).await;
+        let login_record = crate::login::verify_user_by_email(&pool, "joe@example.com", DEFAULT_PASSWORD).await.unwrap();
+        let result = crate::login::start_login(&pool, login_record, |_, _| async { panic!("no code should be sent") }).await.unwrap();
+        assert_eq!(Some(person_id), result.map(|record| record.id));
+
+        // With it, a code is sent instead
+        pool.execute(format!("update person set two_factor_enabled = true where id = {}", person_id).as_str()).await.unwrap();
+        let login_record = crate::login::verify_user_by_email(&pool, "joe@example.com", DEFAULT_PASSWORD).await.unwrap();
+        let mut sent_code = None;
+        let result = crate::login::start_login(&pool, login_record, |_, code| {
+            sent_code = Some(code);
+            async { Ok(()) }
+        }).await.unwrap();
+        assert!(result.is_none());
+        let code = sent_code.unwrap();
+        assert_eq!(6, code.len());
+
+        // Which completes the login once only
+        let result = crate::login::_verify_login_code(&pool, "joe@example.com", "wrong").await;
+        assert_eq!(Status::Unauthorized, result.err().unwrap().0);
+        let login_record = crate::login::_verify_login_code(&pool, "JOE@example.com", &code).await.unwrap();
+        assert_eq!(person_id, login_record.id);
+        let result = crate::login::_verify_login_code(&pool, "joe@example.com", &code).await;
+        assert_eq!(Status::Unauthorized, result.err().unwrap().0);
+
+        // Codes cannot be guessed by trying many of them
+        let login_record = crate::login::verify_user_by_email(&pool, "joe@example.com", DEFAULT_PASSWORD).await.unwrap();
+        let mut sent_code = None;
+        crate::login::start_login(&pool, login_record, |_, code| {
+            sent_code = Some(code);
+            async { Ok(()) }
+        }).await.unwrap();
+        for _ in 0..5 {
+            let _ = crate::login::_verify_login_code(&pool, "joe@example.com", "wrong").await;
+        }
+        let result = crate::login::_verify_login_code(&pool, "joe@example.com", &sent_code.unwrap()).await;
+        assert_eq!(Status::Unauthorized, result.err().unwrap().0);
+
+        // Nor by logging in again for a new code, until the guessed one would have expired
+        let login_record = crate::login::verify_user_by_email(&pool, "joe@example.com", DEFAULT_PASSWORD).await.unwrap();
+        let result = crate::login::start_login(&pool, login_record, |_, _| async { panic!("no code should be sent") }).await;
+        assert_eq!(Status::TooManyRequests, result.err().unwrap().0);
+        pool.execute("update person_token set expiry = now() - interval '1 minute'").await.unwrap();
+        let login_record = crate::login::verify_user_by_email(&pool, "joe@example.com", DEFAULT_PASSWORD).await.unwrap();
+        let mut sent_code = None;
+        crate::login::start_login(&pool, login_record, |_, code| {
+            sent_code = Some(code);
+            async { Ok(()) }
+        }).await.unwrap();
+        let login_record = crate::login::_verify_login_code(&pool, "joe@example.com", &sent_code.unwrap()).await.unwrap();
+        assert_eq!(person_id, login_record.id);
+    }
+
+    #[test]
+    fn temp_password_settings() {
+        // The defaults give 20 uppercase letters and digits
+        let temp_password = crate::login::temp_password_generator(&crate::Config::default()).generate_one().unwrap();
+        assert_eq!(20, temp_password.len());
+        assert!(temp_password.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()), "{}", temp_password);
+
+        // Short passwords are lengthened to pass the password rules
+        let config = crate::Config { temp_password_length: 4, temp_password_lowercase: true, temp_password_uppercase: false, temp_password_numbers: false, ..Default::default() };
+        let temp_password = crate::login::temp_password_generator(&config).generate_one().unwrap();
+        assert_eq!(8, temp_password.len());
+        assert!(temp_password.chars().all(|c| c.is_ascii_lowercase()), "{}", temp_password);
+        crate::login::verify_suitable_password(&temp_password, "").unwrap();
+
+        // At least one class of characters is needed
+        let config = crate::Config { temp_password_uppercase: false, temp_password_numbers: false, ..Default::default() };
+        assert!(crate::login::temp_password_generator(&config).generate_one().is_err());
+    }
+
+    #[test]
+    fn permissions_from_roles() {
+        let config = crate::Config::default();
+        let permissions = |roles: &[&str]| {
+            let roles = roles.iter().map(|role| role.to_string()).collect();
+            crate::login::Permissions::from_claims(&crate::claims::Claims::create(1, "joe@example.com", &None, &roles, chrono::Duration::minutes(1)), &config)
+        };
+
+        let member = permissions(&["member"]);
+        assert!(!member.can_create_sessions && !member.can_manage_users && !member.can_view_all_bookings);
+
+        // Trainers can create their own sessions, but not see everyone's bookings
+        let trainer = permissions(&["member", "trainer"]);
+        assert!(trainer.can_create_sessions && !trainer.can_manage_users && !trainer.can_view_all_bookings);
+
+        let admin = permissions(&["admin"]);
+        assert!(admin.can_create_sessions && admin.can_manage_users && admin.can_view_all_bookings);
+    }
+
 }
\ No newline at end of file
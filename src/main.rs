@@ -2,22 +2,30 @@
 #[macro_use]
 extern crate rocket;
 
+use std::collections::HashMap;
 use std::env;
+use std::ops::Deref;
 use std::error::Error;
 use std::path::{Path, PathBuf};
-use chrono::{DateTime, FixedOffset};
+use chrono::{Datelike, DateTime, Days, FixedOffset, NaiveTime, Utc, Weekday};
 use chrono_tz::Tz;
+use jsonwebtoken::Algorithm;
 
-use rocket::Request;
+use rocket::{Data, data, Request, State};
+use rocket::data::FromData;
 use rocket::fs::NamedFile;
 use rocket::fs::relative;
-use rocket::http::{Method, Status};
+use rocket::fairing::AdHoc;
+use rocket::http::{Header, Method, Status};
 use rocket::response::status::Custom;
+use rocket::outcome::Outcome;
+use rocket::serde::json;
+use rocket::serde::json::Json;
 use rocket::serde::Serialize;
 use rocket_cors::{AllowedHeaders, AllowedOrigins};
 use serde::Deserialize;
 use shuttle_runtime::CustomError;
-use sqlx::{Executor, FromRow, PgPool, query_as};
+use sqlx::{Executor, FromRow, PgPool, Postgres, query, query_as, QueryBuilder, Transaction};
 use crate::claims::AuthenticationError;
 
 mod claims;
@@ -25,8 +33,19 @@ mod sessions;
 mod login;
 mod bookings;
 mod backup;
+mod audit;
+mod email;
+mod waitlist;
+mod cleanup;
+mod reminders;
+mod messages;
+mod data_export;
+mod waivers;
+#[cfg(test)]
+mod test_support;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
 struct Config {
     branding: String,
     email_sender_name: String,
@@ -35,7 +54,32 @@ struct Config {
     email_replyto_address: String,
     email_admin_notifications: String,
     timezone_name: String,
-    cors_allowed: String
+    cors_allowed: String,
+    jwt_algorithm: String,
+    token_leeway_secs: u64,
+    session_manager_roles: Vec<String>,
+    attendance_lock_days: Option<u32>,
+    week_start_day: Weekday,
+    email_template_dir: Option<String>,
+    email_subjects: HashMap<String, String>,
+    query_timeout_ms: Option<u64>,
+    cookie_secure: bool,
+    cookie_same_site: String,
+    cookie_domain: Option<String>,
+    cookie_path: Option<String>,
+    max_active_bookings: Option<u32>,
+    cancellation_cutoff_mins: Option<u32>,
+    cleanup_interval_hours: u64,
+    cleanup_retention_days: u32,
+    temp_password_length: usize,
+    temp_password_lowercase: bool,
+    temp_password_uppercase: bool,
+    temp_password_numbers: bool,
+    temp_password_symbols: bool,
+    reminder_lead_times_mins: Vec<u32>,
+    attention_spaces_threshold: u32,
+    session_time_grid_mins: Option<u32>,
+    enforce_location_capacity: bool
 }
 impl ::std::default::Default for Config {
     fn default() -> Self {
@@ -47,16 +91,47 @@ impl ::std::default::Default for Config {
             email_replyto_address: String::from("unknown@example.com"),
             email_admin_notifications: String::from("admin@anotherlevelfitness.uk"),
             timezone_name: String::from("Europe/London"),
-            cors_allowed: String::from("^http://localhost")
+            cors_allowed: String::from("^http://localhost"),
+            jwt_algorithm: String::from("HS256"),
+            token_leeway_secs: 5,
+            session_manager_roles: vec![String::from("admin"), String::from("trainer")],
+            attendance_lock_days: None,
+            week_start_day: Weekday::Mon,
+            email_template_dir: None,
+            email_subjects: HashMap::new(),
+            query_timeout_ms: Some(30_000),
+            cookie_secure: true,
+            cookie_same_site: String::from("Strict"),
+            cookie_domain: None,
+            cookie_path: None,
+            max_active_bookings: None,
+            cancellation_cutoff_mins: None,
+            cleanup_interval_hours: 24,
+            cleanup_retention_days: 365,
+            temp_password_length: 20,
+            temp_password_lowercase: false,
+            temp_password_uppercase: true,
+            temp_password_numbers: true,
+            temp_password_symbols: false,
+            reminder_lead_times_mins: Vec::new(),
+            attention_spaces_threshold: 2,
+            session_time_grid_mins: None,
+            enforce_location_capacity: false
         }
     }
 }
 
+/// How long clients are asked to wait before retrying a request that failed with 503
+const RETRY_AFTER_SECS: u32 = 5;
+/// Response header giving the name of the server's timezone
+const TIMEZONE_HEADER: &str = "X-Timezone";
+
 struct AppState {
     pool: PgPool,
     secrets: shuttle_runtime::SecretStore,
     config: Config,
-    timezone: Tz
+    timezone: Tz,
+    jwt_algorithm: Algorithm
 }
 
 #[rocket::get("/<path..>")]
@@ -70,14 +145,186 @@ pub async fn static_files(path: PathBuf) -> Option<NamedFile> {
     NamedFile::open(path).await.ok()
 }
 
+#[derive(Serialize, Debug)]
+pub struct VersionInfo {
+    version: &'static str,
+    git_sha: &'static str,
+    built_at: Option<DateTime<Utc>>
+}
+
+/// Identifies the build that is running, e.g. to check that a deployment has gone live
+#[rocket::get("/version")]
+pub fn version() -> Json<VersionInfo> {
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("GIT_SHA"),
+        built_at: env!("BUILT_AT").parse().ok().and_then(|secs| DateTime::from_timestamp(secs, 0))
+    })
+}
+
+#[derive(Serialize, Debug)]
+pub struct PublicConfig {
+    branding: String,
+    /// IANA name of the timezone that local times and week boundaries are in, e.g. "Europe/London"
+    timezone: &'static str,
+    week_start_day: Weekday
+}
+
+/// The settings that clients need to present data as the server does, such as local times
+#[rocket::get("/config/public")]
+fn public_config(state: &State<AppState>) -> Json<PublicConfig> {
+    Json(PublicConfig {
+        branding: state.config.branding.clone(),
+        timezone: state.timezone.name(),
+        week_start_day: state.config.week_start_day
+    })
+}
+
+/// Liveness probe, which succeeds whenever the server is running. Like the other probes it also answers HEAD
+/// requests, which Rocket routes to the GET handler without the body.
+#[rocket::get("/livez")]
+pub fn livez() -> &'static str {
+    "OK"
+}
+
+/// Readiness probe, which succeeds only if the database can be queried
+#[rocket::get("/readyz")]
+async fn readyz(state: &State<AppState>) -> Result<&'static str, Custom<String>> {
+    query("SELECT 1")
+        .execute(&state.pool)
+        .await
+        .map_err(|e| {
+            error!("Readiness check failed: {}", e);
+            Custom(Status::ServiceUnavailable, "database unavailable".to_string())
+        })?;
+    Ok("OK")
+}
+
+/// The same as the readiness probe, for clients that expect a single health check
+#[rocket::get("/health")]
+async fn health(state: &State<AppState>) -> Result<&'static str, Custom<String>> {
+    readyz(state).await
+}
+
+#[derive(Serialize, Debug)]
+pub struct ErrorResponse {
+    error: String,
+    code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<String>
+}
+
+fn error_response(request: &Request, status: Status, error: String, code: &'static str) -> Custom<Json<ErrorResponse>> {
+    Custom(status, Json(ErrorResponse { error: localized_error(request, error, code), code, field: None }))
+}
+
+/// The error message in the language asked for by the request's `Accept-Language` header, if there is a
+/// translation for the code, and otherwise the given English message.
+fn localized_error(request: &Request, error: String, code: &str) -> String {
+    messages::localized_message(code, messages::Language::of_request(request))
+        .map(String::from)
+        .unwrap_or(error)
+}
+
+/// A JSON request body. Unlike `Json`, the reason that the body could not be parsed is kept so that the
+/// error catchers can report it back to the client.
+#[derive(Debug)]
+pub struct JsonBody<T>(pub T);
+
+impl<T> JsonBody<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for JsonBody<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// Why a JSON request body could not be parsed, and the field responsible if known
+struct JsonBodyError {
+    message: String,
+    field: Option<String>
+}
+
+impl JsonBodyError {
+    fn new(error: &json::Error) -> JsonBodyError {
+        let message = match error {
+            json::Error::Io(e) => e.to_string(),
+            json::Error::Parse(_, e) => e.to_string()
+        };
+        // serde reports missing, unknown and duplicate fields as e.g. "missing field `name` at line 1 column 2"
+        let field = ["missing field `", "unknown field `", "duplicate field `"].iter()
+            .find_map(|prefix| message.strip_prefix(prefix))
+            .and_then(|rest| rest.split('`').next())
+            .map(String::from);
+        JsonBodyError { message, field }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: Deserialize<'r>> FromData<'r> for JsonBody<T> {
+    type Error = json::Error<'r>;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        match Json::<T>::from_data(request, data).await {
+            Outcome::Success(json) => Outcome::Success(JsonBody(json.into_inner())),
+            Outcome::Error((status, e)) => {
+                request.local_cache(|| Some(JsonBodyError::new(&e)));
+                Outcome::Error((status, e))
+            },
+            Outcome::Forward(f) => Outcome::Forward(f)
+        }
+    }
+}
+
+fn json_body_error_response(request: &Request, status: Status, default: &str, code: &'static str) -> Custom<Json<ErrorResponse>> {
+    match request.local_cache::<Option<JsonBodyError>, _>(|| None) {
+        Some(e) => Custom(status, Json(ErrorResponse { error: localized_error(request, e.message.clone(), code), code, field: e.field.clone() })),
+        None => error_response(request, status, default.to_string(), code)
+    }
+}
+
+/// Reports why authentication failed, if it did, with a code that says whether the token was missing, expired or invalid
+fn authentication_error_response(request: &Request, status: Status, default: &str, default_code: &'static str) -> Custom<Json<ErrorResponse>> {
+    match request.local_cache::<Option<AuthenticationError>, _>(|| None) {
+        Some(e) => error_response(request, status, e.to_string(), e.code()),
+        None => error_response(request, status, default.to_string(), default_code)
+    }
+}
+
+#[catch(400)]
+pub fn bad_request(request: &Request) -> Custom<Json<ErrorResponse>> {
+    json_body_error_response(request, Status::BadRequest, "bad request", "BAD_REQUEST")
+}
+
+#[catch(401)]
+pub fn unauthorized(request: &Request) -> Custom<Json<ErrorResponse>> {
+    authentication_error_response(request, Status::Unauthorized, "NOT AUTH", "UNAUTHORIZED")
+}
+
 #[catch(403)]
-pub fn forbidden(request: &Request) -> Custom<String> {
-    let auth_error = request.local_cache::<Option<AuthenticationError>, _>(|| None);
-    let message = match auth_error {
-        Some(msg) => msg.to_string(),
-        None      => "NOT AUTH".to_string()
-    };
-    Custom(Status::Forbidden, message)
+pub fn forbidden(request: &Request) -> Custom<Json<ErrorResponse>> {
+    authentication_error_response(request, Status::Forbidden, "NOT AUTH", "FORBIDDEN")
+}
+
+#[catch(404)]
+pub fn not_found(request: &Request) -> Custom<Json<ErrorResponse>> {
+    error_response(request, Status::NotFound, format!("{} {} not found", request.method(), request.uri()), "NOT_FOUND")
+}
+
+#[catch(422)]
+pub fn unprocessable_entity(request: &Request) -> Custom<Json<ErrorResponse>> {
+    json_body_error_response(request, Status::UnprocessableEntity, "unprocessable entity", "UNPROCESSABLE_ENTITY")
+}
+
+#[catch(500)]
+pub fn internal_error(request: &Request) -> Custom<Json<ErrorResponse>> {
+    error_response(request, Status::InternalServerError, "internal server error".to_string(), "INTERNAL_SERVER_ERROR")
 }
 
 #[shuttle_runtime::main]
@@ -105,22 +352,46 @@ async fn rocket(
         allowed_methods: vec![Method::Get, Method::Post, Method::Options, Method::Head, Method::Delete, Method::Put].into_iter().map(From::from).collect(),
         allowed_headers: AllowedHeaders::All,
         allow_credentials: true,
+        expose_headers: [TIMEZONE_HEADER].iter().map(ToString::to_string).collect(),
         ..Default::default()
     }.to_cors().map_err(CustomError::new)?;
 
+    // Purge stale rows and send session reminders in the background
+    let timezone: Tz = config.timezone_name.as_str().parse().unwrap();
+    cleanup::spawn_cleanup_task(pool.clone(), &config);
+    reminders::spawn_reminder_task(pool.clone(), secrets.clone(), config.clone(), timezone);
+
     // Configure Rocket
-    let timezone = config.timezone_name.as_str().parse().unwrap();
-    let state = AppState { pool, secrets, config, timezone };
+    let jwt_algorithm = claims::parse_algorithm(&config.jwt_algorithm).map_err(CustomError::msg)?;
+    login::temp_password_generator(&config).generate_one()
+        .map_err(|e| CustomError::msg(format!("invalid temp password settings: {}", e)))?;
+    let timezone_header = Header::new(TIMEZONE_HEADER, timezone.name());
+    let state = AppState { pool, secrets, config, timezone, jwt_algorithm };
     let rocket = rocket::build()
         .attach(cors)
-        .register("/", catchers![forbidden])
+        .attach(AdHoc::on_response("Retry-After", |_, response| Box::pin(async move {
+            // Handlers return 503 for transient failures, such as running out of database connections
+            if response.status() == Status::ServiceUnavailable && !response.headers().contains("Retry-After") {
+                response.set_header(Header::new("Retry-After", RETRY_AFTER_SECS.to_string()));
+            }
+        })))
+        .attach(AdHoc::on_response(TIMEZONE_HEADER, move |_, response| {
+            // So that clients can interpret local times in any response
+            let timezone_header = timezone_header.clone();
+            Box::pin(async move {
+                response.set_header(timezone_header);
+            })
+        }))
+        .register("/", catchers![bad_request, unauthorized, forbidden, not_found, unprocessable_entity, internal_error])
         .mount("/", routes![
-            static_files,
-            login::login, login::validate_login, login::change_password, login::register_user, login::request_pwd_reset, login::reset_pwd, login::get_user, login::list_users, login::delete_user, login::update_user,
-            sessions::list_sessions, sessions::get_session, sessions::create_session, sessions::delete_session,
-            sessions::list_locations, sessions::list_session_types, sessions::update_session,
-            bookings::list_bookings, bookings::create_booking, bookings::delete_booking, bookings::update_booking, bookings::get_attendance_stats,
-            backup::backup_all
+            static_files, version, public_config, livez, readyz, health,
+            login::login, login::verify_login_code, login::validate_login, login::change_password, login::register_user, login::request_pwd_reset, login::check_temp_password, login::reset_pwd, login::get_me, login::update_me, login::get_user, login::list_users, login::import_users, login::upsert_user, login::delete_user, login::delete_temp_password, login::request_delete_me, login::delete_me, login::update_user, login::confirm_email_change,
+            sessions::list_sessions, sessions::list_sessions_needing_attention, sessions::list_unstaffed_sessions, sessions::list_sessions_in_week, sessions::list_sessions_grouped, sessions::search_sessions, sessions::list_session_summaries, sessions::list_trainer_sessions, sessions::get_trainer_roster_summary, sessions::list_trainer_qualifications, sessions::add_trainer_qualification, sessions::delete_trainer_qualification, sessions::get_session, sessions::get_session_booking_count, sessions::preview_session_capacity, sessions::create_session, sessions::delete_session, sessions::cancel_sessions_in_range,
+            sessions::list_locations, sessions::list_session_types, sessions::set_session_type_color, sessions::get_my_trainer_summary, sessions::get_trainer_summary, sessions::update_session, sessions::reassign_trainer, sessions::notify_session_members,
+            bookings::list_bookings, bookings::export_bookings, bookings::get_booking, bookings::get_next_booking, bookings::create_booking, bookings::list_booking_requests, bookings::delete_booking_request, bookings::approve_booking, bookings::preview_booking, bookings::delete_booking, bookings::delete_bookings_in_range, bookings::list_cancellations, bookings::get_timeline, bookings::transfer_booking, bookings::swap_booking, bookings::update_booking, bookings::get_checkin_code, bookings::checkin, bookings::get_attendance_stats, bookings::get_occupancy_stats, bookings::get_capacity_suggestions, bookings::get_disengaged_members,
+            data_export::export_personal_data, waivers::accept_waiver, waitlist::list_my_waitlist, waitlist::join_waitlist, waitlist::list_session_waitlist, waitlist::promote_from_waitlist,
+            backup::backup_all,
+            audit::list_audit_log
         ])
         .manage(state);
 
@@ -135,24 +406,83 @@ pub struct UserLoginRecord {
     phone: Option<String>,
     pwd: Option<String>,
     roles: String,
-    credits: i16
+    credits: i16,
+    #[sqlx(default)]
+    must_change_pwd: bool,
+    #[sqlx(default)]
+    two_factor_enabled: bool
 }
 
 impl UserLoginRecord {
     pub async fn load_by_id(pool: &PgPool, user_id: i64) -> Result<Option<UserLoginRecord>, sqlx::Error> {
-        query_as("SELECT id, name, email, phone, pwd, roles, credits FROM person WHERE id = $1")
+        query_as("SELECT id, name, email, phone, pwd, roles, credits, must_change_pwd, two_factor_enabled FROM person WHERE id = $1")
             .bind(user_id)
             .fetch_optional(pool)
             .await
     }
     pub async fn load_by_email(pool: &PgPool, user_email: &str) -> Result<Option<UserLoginRecord>, sqlx::Error> {
-        query_as("SELECT id, name, email, phone, pwd, roles, credits FROM person WHERE email = $1")
+        query_as("SELECT id, name, email, phone, pwd, roles, credits, must_change_pwd, two_factor_enabled FROM person WHERE lower(email) = lower($1)")
             .bind(user_email)
             .fetch_optional(pool)
             .await
     }
 }
 
+/// A CSV file download, e.g. for pasting into a spreadsheet
+#[derive(Responder)]
+#[response(status = 200, content_type = "text/csv")]
+pub struct CsvDownload {
+    inner: String,
+    disposition: Header<'static>
+}
+
+impl CsvDownload {
+    /// Builds the file from a header row and data rows, quoting any fields that need it
+    fn new(filename: &str, header: &[&str], rows: Vec<Vec<String>>) -> CsvDownload {
+        let mut csv = String::new();
+        for row in std::iter::once(header.iter().map(|h| h.to_string()).collect()).chain(rows) {
+            csv.push_str(&row.iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(","));
+            csv.push_str("\r\n");
+        }
+        CsvDownload {
+            inner: csv,
+            disposition: Header::new("Content-Disposition", format!("attachment; filename=\"{}\"", filename))
+        }
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Optional `limit` and `offset` query parameters for paginated listings
+#[derive(FromForm, Default, Debug)]
+pub struct Page {
+    limit: Option<i64>,
+    offset: Option<i64>
+}
+
+impl Page {
+    fn push_limit_offset(&self, qb: &mut QueryBuilder<Postgres>) -> Result<(), Custom<String>> {
+        if self.limit.is_some_and(|limit| limit < 0) || self.offset.is_some_and(|offset| offset < 0) {
+            return Err(Custom(Status::BadRequest, "limit and offset must not be negative".to_string()));
+        }
+        if let Some(limit) = self.limit {
+            qb.push(" LIMIT ");
+            qb.push_bind(limit);
+        }
+        if let Some(offset) = self.offset {
+            qb.push(" OFFSET ");
+            qb.push_bind(offset);
+        }
+        Ok(())
+    }
+}
+
 #[derive(FromRow, Serialize)]
 struct BigintRecord {
     id: i64
@@ -163,7 +493,21 @@ pub struct SessionType {
     id: i32,
     name: String,
     requires_trainer: bool,
-    cost: i16
+    cost: i16,
+    /// Members must have attended a session of this type before they can book this one
+    #[sqlx(default)]
+    prerequisite_session_type_id: Option<i32>,
+    /// Hex color for showing sessions of this type, e.g. "#1e90ff"
+    #[sqlx(default)]
+    color: Option<String>,
+    /// Bookings by members are pending until an admin approves them
+    #[sqlx(default)]
+    requires_approval: bool,
+    /// Members must accept the waiver at this URL before booking, if it is required
+    #[sqlx(default)]
+    waiver_url: Option<String>,
+    #[sqlx(default)]
+    requires_waiver: bool
 }
 
 impl SessionType {
@@ -187,10 +531,13 @@ pub struct SessionTrainer {
 pub struct SessionLocation {
     id: i32,
     name: String,
-    address: String
+    address: String,
+    /// How many people fit at the location at once, across all the sessions there. Unlimited if not set.
+    #[sqlx(default)]
+    capacity: Option<i32>
 }
 
-#[derive(FromRow, Debug)]
+#[derive(FromRow, Serialize, Debug)]
 struct CountResult {
     count: i64
 }
@@ -203,4 +550,151 @@ fn parse_opt_date(str: Option<String>) -> Result<Option<DateTime<FixedOffset>>,
     println!("Parsed input {:?} to {:?}", &str, parsed);
     //.map_err(|e| BadRequest(e.to_string()))?;
     Ok(Some(parsed.map_err(|e| Custom(Status::UnprocessableEntity, e.to_string()))?))
-}
\ No newline at end of file
+}
+
+/// Returns the start (inclusive) and end (exclusive) of the week containing the given local date/time,
+/// where weeks begin on the given day
+fn week_bounds(datetime_in_local: DateTime<Tz>, week_start: Weekday) -> (DateTime<Tz>, DateTime<Tz>) {
+    let start_of_week_local = datetime_in_local
+        .checked_sub_days(Days::new(datetime_in_local.weekday().days_since(week_start) as u64)).unwrap()
+        .with_time(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
+        .unwrap();
+    let end_of_week_local = start_of_week_local
+        .checked_add_days(Days::new(7)).unwrap();
+    (start_of_week_local, end_of_week_local)
+}
+
+/// Starts a transaction in which any statement running for longer than the configured query timeout is cancelled,
+/// so that expensive read queries cannot tie up a connection indefinitely
+async fn begin_with_timeout(pool: &PgPool, config: &Config) -> Result<Transaction<'static, Postgres>, Custom<String>> {
+    let mut tx = pool.begin()
+        .await
+        .map_err(db_error)?;
+    // As for Postgres itself, a timeout of zero means no timeout, leaving the server's default in place
+    if let Some(timeout_ms) = config.query_timeout_ms.filter(|&timeout_ms| timeout_ms > 0) {
+        // SET does not accept bind parameters, but the timeout is numeric
+        query(&format!("SET LOCAL statement_timeout = {}", timeout_ms))
+            .execute(&mut *tx)
+            .await
+            .map_err(db_error)?;
+    }
+    Ok(tx)
+}
+
+/// Maps a database error to a response, with queries cancelled by the statement timeout reported as 503
+fn query_error(e: sqlx::Error) -> Custom<String> {
+    match e.as_database_error().and_then(|db_error| db_error.code()) {
+        Some(code) if code == "57014" => Custom(Status::ServiceUnavailable, "query took too long, try narrowing the search".to_string()),
+        _ => db_error(e)
+    }
+}
+
+/// Prefix for a listing's query to have Postgres explain the query rather than run it
+const EXPLAIN: &str = "EXPLAIN ";
+
+/// The compiled SQL of a listing's query, without its bound values, and the plan that Postgres would use to run it.
+/// Admins can ask for this instead of the listing with `explain=true`, to diagnose unexpected results.
+#[derive(Serialize, Debug)]
+pub struct QueryExplanation {
+    sql: String,
+    plan: Vec<String>
+}
+
+/// Explains a query built after `EXPLAIN`, without running it
+async fn explain_query<'c, E: Executor<'c, Database = Postgres>>(executor: E, mut qb: QueryBuilder<'_, Postgres>) -> Result<QueryExplanation, Custom<String>> {
+    let sql = qb.sql().strip_prefix(EXPLAIN).unwrap_or(qb.sql()).to_string();
+    let plan = qb.build_query_scalar()
+        .fetch_all(executor)
+        .await
+        .map_err(query_error)?;
+    Ok(QueryExplanation { sql, plan })
+}
+
+/// Maps a database error to a response. Timing out while waiting for a pooled connection means that the server is
+/// overloaded rather than broken, so it is reported as 503 for clients to try again later.
+fn db_error(e: sqlx::Error) -> Custom<String> {
+    match e {
+        sqlx::Error::PoolTimedOut => Custom(Status::ServiceUnavailable, "server is busy, try again shortly".to_string()),
+        _ => Custom(Status::InternalServerError, e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use rocket::http::{ContentType, Status};
+    use rocket::serde::json::Value;
+    use sqlx::{Executor, PgPool, query_scalar};
+    use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+    use crate::{begin_with_timeout, Config};
+    use crate::claims::Claims;
+    use crate::test_support::{bearer, test_client};
+
+    #[sqlx::test]
+    async fn health_probes(pool: PgPool) {
+        let client = test_client(pool.clone(), routes![crate::livez, crate::readyz]).await;
+
+        let response = client.get("/livez").dispatch().await;
+        assert_eq!(Status::Ok, response.status());
+        assert_eq!("OK", response.into_string().await.unwrap());
+        let response = client.head("/livez").dispatch().await;
+        assert_eq!(Status::Ok, response.status());
+        assert_eq!(None, response.into_string().await.filter(|body| !body.is_empty()));
+
+        let response = client.get("/readyz").dispatch().await;
+        assert_eq!(Status::Ok, response.status());
+        assert_eq!("OK", response.into_string().await.unwrap());
+
+        // Once the database cannot be queried, the server is alive but not ready
+        pool.close().await;
+        assert_eq!(Status::ServiceUnavailable, client.get("/readyz").dispatch().await.status());
+        assert_eq!(Status::Ok, client.get("/livez").dispatch().await.status());
+    }
+
+    #[sqlx::test]
+    async fn malformed_json_bodies(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let client = test_client(pool, routes![crate::waivers::accept_waiver]).await;
+        let member = || Claims::create(1, "member@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let post = |body: &'static str| client.post("/waivers/accept").header(bearer(member())).header(ContentType::JSON).body(body);
+
+        // Bodies that are valid JSON but the wrong shape name the field responsible
+        let response = post(r#"{"session_type": 1}"#).dispatch().await;
+        assert_eq!(Status::UnprocessableEntity, response.status());
+        let error: Value = response.into_json().await.unwrap();
+        assert_eq!("UNPROCESSABLE_ENTITY", error["code"]);
+        assert_eq!("session_type_id", error["field"]);
+        assert!(error["error"].as_str().unwrap().starts_with("missing field `session_type_id`"));
+
+        let response = post(r#"{"session_type_id": "HIIT"}"#).dispatch().await;
+        assert_eq!(Status::UnprocessableEntity, response.status());
+        let error: Value = response.into_json().await.unwrap();
+        assert!(error["error"].as_str().unwrap().starts_with("invalid type: string"));
+        assert_eq!(None, error.get("field"));
+
+        // Bodies that are not JSON at all are bad requests
+        let response = post("session_type_id=1").dispatch().await;
+        assert_eq!(Status::BadRequest, response.status());
+        let error: Value = response.into_json().await.unwrap();
+        assert_eq!("BAD_REQUEST", error["code"]);
+        assert_eq!(None, error.get("field"));
+    }
+
+    #[sqlx::test]
+    async fn query_timeout(pool_options: PgPoolOptions, connect_options: PgConnectOptions) {
+        let pool = pool_options.connect_with(connect_options.options([("statement_timeout", "5s")])).await.unwrap();
+        let statement_timeout = |query_timeout_ms: Option<u64>| {
+            let pool = &pool;
+            async move {
+                let config = Config { query_timeout_ms, ..Config::default() };
+                let mut tx = begin_with_timeout(pool, &config).await.unwrap();
+                query_scalar::<_, String>("SHOW statement_timeout").fetch_one(&mut *tx).await.unwrap()
+            }
+        };
+
+        assert_eq!("1234ms", statement_timeout(Some(1234)).await);
+        // Without a timeout of its own, the server's default applies
+        assert_eq!("5s", statement_timeout(Some(0)).await);
+        assert_eq!("5s", statement_timeout(None).await);
+    }
+}
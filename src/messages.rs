@@ -0,0 +1,90 @@
+use rocket::Request;
+
+/// The languages that error messages can be returned in. English messages come from wherever the error
+/// is raised and can be more specific, e.g. naming the field that could not be parsed, whereas the others
+/// come from the catalog below and depend only on the error code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Language {
+    English,
+    French
+}
+
+impl Language {
+    fn from_tag(tag: &str) -> Option<Language> {
+        let primary = tag.split('-').next().unwrap_or_default().trim();
+        if primary.eq_ignore_ascii_case("en") {
+            Some(Language::English)
+        } else if primary.eq_ignore_ascii_case("fr") {
+            Some(Language::French)
+        } else {
+            None
+        }
+    }
+
+    /// The supported language that is most preferred in an `Accept-Language` header such as
+    /// `fr-CH, fr;q=0.9, en;q=0.8`, or English if there is none.
+    pub(crate) fn from_accept_language(header: Option<&str>) -> Language {
+        let mut best: Option<(f32, Language)> = None;
+        for range in header.unwrap_or_default().split(',') {
+            let mut params = range.split(';');
+            let tag = params.next().unwrap_or_default();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if let Some(language) = Language::from_tag(tag) {
+                if quality > 0.0 && best.is_none_or(|(q, _)| quality > q) {
+                    best = Some((quality, language));
+                }
+            }
+        }
+        best.map(|(_, language)| language).unwrap_or(Language::English)
+    }
+
+    pub(crate) fn of_request(request: &Request) -> Language {
+        Language::from_accept_language(request.headers().get_one("Accept-Language"))
+    }
+}
+
+/// The message for an error code in the given language, if the catalog has one. Callers fall back to
+/// their own English message when it doesn't.
+pub(crate) fn localized_message(code: &str, language: Language) -> Option<&'static str> {
+    match language {
+        Language::English => None,
+        Language::French => match code {
+            "BAD_REQUEST" => Some("Requête invalide."),
+            "UNAUTHORIZED" => Some("Authentification requise."),
+            "FORBIDDEN" => Some("Accès refusé."),
+            "NOT_FOUND" => Some("Ressource introuvable."),
+            "UNPROCESSABLE_ENTITY" => Some("Le contenu de la requête est invalide."),
+            "INTERNAL_SERVER_ERROR" => Some("Erreur interne du serveur."),
+            "TOKEN_MISSING" => Some("Jeton d'authentification manquant."),
+            "TOKEN_INVALID" => Some("Jeton d'authentification invalide."),
+            "TOKEN_EXPIRED" => Some("Le jeton d'authentification a expiré."),
+            "PASSWORD_CHANGE_REQUIRED" => Some("Vous devez changer votre mot de passe avant de continuer."),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::messages::{Language, localized_message};
+
+    #[test]
+    fn negotiate_language() {
+        assert_eq!(Language::English, Language::from_accept_language(None));
+        assert_eq!(Language::English, Language::from_accept_language(Some("de-DE, de;q=0.9")));
+        assert_eq!(Language::French, Language::from_accept_language(Some("fr-CH, fr;q=0.9, en;q=0.8")));
+        assert_eq!(Language::English, Language::from_accept_language(Some("fr;q=0.5, en-GB")));
+        assert_eq!(Language::French, Language::from_accept_language(Some("de, FR;q=0.7, *;q=0.5")));
+        assert_eq!(Language::English, Language::from_accept_language(Some("fr;q=0, en;q=0.1")));
+    }
+
+    #[test]
+    fn fall_back_to_english() {
+        assert_eq!(Some("Accès refusé."), localized_message("FORBIDDEN", Language::French));
+        assert_eq!(None, localized_message("NO_SUCH_CODE", Language::French));
+        assert_eq!(None, localized_message("FORBIDDEN", Language::English));
+    }
+}
@@ -0,0 +1,139 @@
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use mail_send::mail_builder::headers::address::Address;
+use mail_send::mail_builder::MessageBuilder;
+use mail_send::smtp::message::IntoMessage;
+use sqlx::{FromRow, PgPool, query_as};
+
+use crate::Config;
+use crate::email::{EmailTemplate, NotificationEvent, NotificationPrefs, render_body, render_subject, should_notify};
+use crate::login::send_email;
+
+/// How often to look for bookings that are due a reminder
+const REMINDER_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// Starts a background task that emails members before the sessions they have booked, once for each of the
+/// configured lead times. Nothing is sent if no lead times are configured.
+pub(crate) fn spawn_reminder_task(pool: PgPool, secrets: shuttle_runtime::SecretStore, config: Config, timezone: Tz) {
+    if config.reminder_lead_times_mins.is_empty() {
+        return;
+    }
+    rocket::tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(REMINDER_CHECK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            match claim_due_reminders(&pool, &config.reminder_lead_times_mins).await {
+                Ok(reminders) => send_reminders(&pool, &secrets, &config, &timezone, reminders).await,
+                Err(e) => error!("Failed to find due session reminders: {}", e)
+            }
+        }
+    });
+}
+
+#[derive(FromRow, Debug)]
+struct DueReminder {
+    person_id: i64,
+    session_id: i64,
+    name: String,
+    email: String,
+    session_datetime: DateTime<Utc>,
+    session_type_name: String,
+    location_name: Option<String>
+}
+
+/// Records the reminders that are now due, returning one for each booking that is due any. Recording them
+/// before they are sent means that each fires once, even if sending fails. A booking that is due reminders
+/// for several lead times at once, say after downtime, gets just the one email, and reminders that fell
+/// due before the booking was made are not sent at all.
+async fn claim_due_reminders(pool: &PgPool, lead_times_mins: &[u32]) -> Result<Vec<DueReminder>, sqlx::Error> {
+    let lead_times_mins: Vec<i32> = lead_times_mins.iter().map(|&mins| mins as i32).collect();
+    query_as("WITH claimed AS ( \
+            INSERT INTO booking_reminder (person_id, session_id, lead_time_mins) \
+            SELECT b.person_id, b.session_id, l.mins \
+            FROM booking AS b \
+            JOIN session AS s ON b.session_id = s.id \
+            CROSS JOIN unnest($1::int4[]) AS l(mins) \
+            WHERE s.datetime > now() \
+                AND s.datetime <= now() + l.mins * interval '1 minute' \
+                AND b.created_at < s.datetime - l.mins * interval '1 minute' \
+            ON CONFLICT DO NOTHING \
+            RETURNING person_id, session_id) \
+        SELECT DISTINCT c.person_id, c.session_id, p.name, p.email, s.datetime AS session_datetime, \
+            t.name AS session_type_name, loc.name AS location_name \
+        FROM claimed AS c \
+        JOIN person AS p ON c.person_id = p.id \
+        JOIN session AS s ON c.session_id = s.id \
+        JOIN session_type AS t ON s.session_type = t.id \
+        LEFT JOIN location AS loc ON s.location = loc.id")
+        .bind(lead_times_mins)
+        .fetch_all(pool)
+        .await
+}
+
+async fn send_reminders(pool: &PgPool, secrets: &shuttle_runtime::SecretStore, config: &Config, timezone: &Tz, reminders: Vec<DueReminder>) {
+    let sender = Address::new_address(Some(&config.email_sender_name), &config.email_sender_address);
+    for reminder in reminders {
+        match NotificationPrefs::load(pool, reminder.person_id).await {
+            Ok(prefs) if !should_notify(&prefs, NotificationEvent::Reminder) => continue,
+            Ok(_) => {},
+            Err(e) => {
+                error!("Failed to load notification preferences of person id {}: {}", reminder.person_id, e);
+                continue;
+            }
+        }
+        let session_datetime = reminder.session_datetime.with_timezone(timezone);
+        let session_time = session_datetime.format("%H:%M");
+        let session_date = session_datetime.format("%A %-d %B %Y");
+        let location = reminder.location_name.as_deref().unwrap_or("the usual place");
+        let email = MessageBuilder::new()
+            .from(sender.clone())
+            .reply_to(sender.clone())
+            .to(Address::new_address(Some(&reminder.name), &reminder.email))
+            .subject(render_subject(config, EmailTemplate::SessionReminder))
+            .text_body(render_body(config, EmailTemplate::SessionReminder, &[&reminder.session_type_name, &session_time, &session_date, &location]))
+            .into_message();
+        let result = match email {
+            Ok(email) => send_email(email, secrets).await.map_err(|e| e.1),
+            Err(e) => Err(e.to_string())
+        };
+        match result {
+            Ok(()) => info!("Sent reminder to person id {} for session id {}", reminder.person_id, reminder.session_id),
+            Err(e) => error!("Failed to send session reminder to {}: {}", &reminder.email, e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use sqlx::{Executor, PgPool, query_scalar};
+    use crate::reminders::claim_due_reminders;
+    use crate::test_support::{create_person, create_session};
+
+    #[sqlx::test]
+    async fn claim_each_reminder_once(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let person_id = create_person(&pool, "member@example.org", "member").await;
+        let session_id = create_session(&pool, TimeDelta::hours(12)).await;
+        pool.execute(format!("insert into booking (person_id, session_id, created_at) values ({person_id}, {session_id}, now() - interval '2 days')").as_str()).await.unwrap();
+        let lead_times = [1440, 60];
+
+        // The 24-hour reminder is due, and fires once
+        let due = claim_due_reminders(&pool, &lead_times).await.unwrap();
+        assert_eq!(vec![(person_id, session_id)], due.iter().map(|r| (r.person_id, r.session_id)).collect::<Vec<_>>());
+        assert!(claim_due_reminders(&pool, &lead_times).await.unwrap().is_empty());
+
+        // Then the 1-hour reminder
+        pool.execute(format!("update session set datetime = now() + interval '30 minutes' where id = {session_id}").as_str()).await.unwrap();
+        assert_eq!(1, claim_due_reminders(&pool, &lead_times).await.unwrap().len());
+        assert!(claim_due_reminders(&pool, &lead_times).await.unwrap().is_empty());
+        let sent: Vec<i32> = query_scalar("select lead_time_mins from booking_reminder order by lead_time_mins").fetch_all(&pool).await.unwrap();
+        assert_eq!(vec![60, 1440], sent);
+
+        // Bookings made after a reminder was due are not sent it
+        pool.execute(format!("delete from booking where session_id = {session_id}").as_str()).await.unwrap();
+        pool.execute(format!("insert into booking (person_id, session_id) values ({person_id}, {session_id})").as_str()).await.unwrap();
+        assert!(claim_due_reminders(&pool, &lead_times).await.unwrap().is_empty());
+    }
+}
@@ -1,16 +1,29 @@
-use chrono::{DateTime, Utc};
-use rocket::form::validate::Contains;
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, DurationRound, FixedOffset, NaiveDate, NaiveTime, Timelike, Utc};
+use chrono_tz::Tz;
+use mail_send::mail_builder::headers::address::Address;
+use mail_send::mail_builder::MessageBuilder;
+use mail_send::smtp::message::IntoMessage;
 use rocket::http::Status;
 use rocket::response::status::{Created, Custom, NoContent};
 use rocket::serde::Deserialize;
 use rocket::serde::json::Json;
 use rocket::State;
 use serde::Serialize;
-use sqlx::{Error, FromRow, PgPool, Postgres, query_as, QueryBuilder, Row};
+use sqlx::{Error, Executor, FromRow, PgPool, Postgres, query, query_as, query_scalar, QueryBuilder, Row};
 use sqlx::postgres::PgRow;
 
-use crate::{AppState, BigintRecord, parse_opt_date, SessionLocation, SessionTrainer, SessionType};
+use crate::{AppState, begin_with_timeout, BigintRecord, Config, db_error, EXPLAIN, explain_query, JsonBody, parse_opt_date, QueryExplanation, SessionLocation, SessionTrainer, SessionType, query_error, week_bounds};
+use crate::audit;
 use crate::claims::Claims;
+use crate::email::{EmailTemplate, NotificationEvent, NotificationPrefs, render_body, render_subject, should_notify};
+use crate::login::send_email;
+
+const ROLE_ADMIN: &str = "admin";
+const ROLE_TRAINER: &str = "trainer";
+/// Minimum time between messages to the members booked on a session, so that a double submit does not spam them
+const SESSION_MESSAGE_MINIMUM_INTERVAL: Duration = Duration::minutes(10);
 
 #[derive(Serialize, Clone, Debug)]
 pub struct SessionFullRecord {
@@ -21,10 +34,20 @@ pub struct SessionFullRecord {
     location: Option<SessionLocation>,
     trainer: Option<SessionTrainer>,
     booked: bool,
-    booking_count: i64,
+    /// Booking is waiting for an admin to approve it
+    pending: bool,
+    bookable: bool,
+    /// Missing for private sessions unless the caller is an admin or the session's trainer
+    booking_count: Option<i64>,
     max_booking_count: Option<i64>,
+    waitlist_count: i64,
+    max_waitlist_count: Option<i64>,
     notes: Option<String>,
-    cost: i16
+    cost: i16,
+    tags: Vec<String>,
+    private: bool,
+    booking_opens_at: Option<DateTime<Utc>>,
+    booking_closes_at: Option<DateTime<Utc>>
 }
 
 impl FromRow<'_, PgRow> for SessionFullRecord {
@@ -45,7 +68,8 @@ impl FromRow<'_, PgRow> for SessionFullRecord {
             Some(id) => Some(SessionLocation{
                 id,
                 name: row.try_get("location_name")?,
-                address: row.try_get("location_address")?
+                address: row.try_get("location_address")?,
+                capacity: row.try_get("location_capacity").ok().flatten()
             }),
             None => None
         };
@@ -58,15 +82,28 @@ impl FromRow<'_, PgRow> for SessionFullRecord {
                 id: row.try_get("session_type_id")?,
                 name: row.try_get("session_type_name")?,
                 requires_trainer: row.try_get("session_type_requires_trainer").ok().unwrap_or(true),
-                cost: row.try_get("session_type_cost")?
+                cost: row.try_get("session_type_cost")?,
+                prerequisite_session_type_id: row.try_get("session_type_prerequisite_id").ok().flatten(),
+                color: row.try_get("session_type_color").ok().flatten(),
+                requires_approval: row.try_get("session_type_requires_approval").ok().unwrap_or(false),
+                waiver_url: row.try_get("session_type_waiver_url").ok().flatten(),
+                requires_waiver: row.try_get("session_type_requires_waiver").ok().unwrap_or(false)
             },
             location,
             trainer,
             booked: row.try_get("booked").ok().unwrap_or(false),
+            pending: row.try_get("pending").ok().unwrap_or(false),
+            bookable: row.try_get("bookable").ok().unwrap_or(true),
             booking_count: row.try_get("booking_count")?,
             max_booking_count: row.try_get("max_booking_count").ok(),
+            waitlist_count: row.try_get("waitlist_count").ok().unwrap_or(0),
+            max_waitlist_count: row.try_get("max_waitlist_count").ok().flatten(),
             notes: row.try_get("notes").ok(),
-            cost: row.try_get("cost")?
+            cost: row.try_get("cost")?,
+            tags: row.try_get("tags").ok().unwrap_or_default(),
+            private: row.try_get("private").ok().unwrap_or(false),
+            booking_opens_at: row.try_get("booking_opens_at").ok().flatten(),
+            booking_closes_at: row.try_get("booking_closes_at").ok().flatten()
         })
     }
 }
@@ -79,65 +116,769 @@ pub struct NewSession {
     location_id: Option<i32>,
     trainer_id: Option<i64>,
     max_bookings: Option<i64>,
+    /// Unlimited if not set
+    #[serde(default)]
+    max_waitlist_count: Option<i64>,
     notes: Option<String>,
-    cost: i16
+    cost: i16,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    allow_unstaffed: bool,
+    /// Private sessions are hidden from members, who can only be booked in by admins
+    #[serde(default)]
+    private: bool,
+    /// Members cannot book before this time; if not set, booking is open as soon as the session is created
+    #[serde(default)]
+    booking_opens_at: Option<DateTime<Utc>>,
+    /// Members cannot book after this time; if not set, booking closes when the session starts
+    #[serde(default)]
+    booking_closes_at: Option<DateTime<Utc>>
 }
 
 impl NewSession {
-    async fn validate(self: &Self, pool: &PgPool) -> Result<(), String> {
-        if self.trainer_id.is_none() {
+    /// Tags are free-form, but stored trimmed, lowercased and without duplicates
+    fn normalized_tags(&self) -> Vec<String> {
+        let mut tags: Vec<String> = Vec::new();
+        for tag in self.tags.iter().map(|tag| normalize_tag(tag)) {
+            if !tag.is_empty() && !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        tags
+    }
+
+    /// Sessions start on the minute, so that schedules read cleanly and overlaps are simple to compare
+    fn normalized_datetime(&self) -> DateTime<Utc> {
+        self.datetime.duration_trunc(Duration::minutes(1)).unwrap_or(self.datetime)
+    }
+
+    /// Fails with a bad request if the session is invalid, or with a server error if it cannot be checked
+    async fn validate(self: &Self, pool: &PgPool, timezone: &Tz, claims: &Claims, config: &Config) -> Result<(), Custom<String>> {
+        // Sessions can be required to start on a grid, e.g. every 5 minutes, in local time as not all
+        // timezones are offset from UTC by whole hours
+        if let Some(grid_mins) = config.session_time_grid_mins.filter(|&mins| mins > 1) {
+            if !self.normalized_datetime().with_timezone(timezone).minute().is_multiple_of(grid_mins) {
+                return Err(Custom(Status::BadRequest, format!("Sessions must start on a multiple of {} minutes past the hour.", grid_mins)));
+            }
+        }
+        // Admins may leave out the trainer when planning sessions, but such sessions cannot be booked
+        // until a trainer is assigned
+        if self.allow_unstaffed {
+            if !claims.has_role(ROLE_ADMIN) {
+                return Err(Custom(Status::BadRequest, "Only admins can create sessions without a trainer.".to_string()));
+            }
+        } else if self.trainer_id.is_none() {
             let session_type: SessionType = SessionType::find_by_id(pool, self.session_type_id)
-                .await?
-                .ok_or(format!("Session type not found with id {}", self.session_type_id))?;
+                .await
+                .map_err(|e| Custom(Status::InternalServerError, e))?
+                .ok_or(Custom(Status::BadRequest, format!("Session type not found with id {}", self.session_type_id)))?;
             if session_type.requires_trainer {
-                return Err(format!("Sessions of type '{}' require a trainer.", session_type.name));
+                return Err(Custom(Status::BadRequest, format!("Sessions of type '{}' require a trainer.", session_type.name)));
+            }
+        }
+        // Admins may assign any trainer, e.g. to cover a session at short notice
+        if let Some(trainer_id) = self.trainer_id {
+            if !claims.has_role(ROLE_ADMIN) && !is_qualified(pool, trainer_id, self.session_type_id).await? {
+                return Err(Custom(Status::BadRequest, format!("Trainer {} is not qualified for session type {}.", trainer_id, self.session_type_id)));
             }
         }
         Ok(())
     }
 }
 
-#[get("/sessions?<from>&<to>&<trainer_id>")]
-pub async fn list_sessions(state: &State<AppState>, claim: Claims, from: Option<String>, to: Option<String>, trainer_id: Option<i64>) -> Result<Json<Vec<SessionFullRecord>>, Custom<String>> {
+/// Checks that a session fits at its location along with the sessions that overlap it there, counting sessions
+/// without a limit on bookings as filling the location. Depending on the config, sessions that do not fit are
+/// rejected or only logged.
+async fn check_location_capacity(pool: &PgPool, config: &Config, new_session: &NewSession, session_id: Option<i64>) -> Result<(), Custom<String>> {
+    let Some(location_id) = new_session.location_id else {
+        return Ok(());
+    };
+    let start = new_session.normalized_datetime();
+    let end = start + Duration::minutes(new_session.duration_mins as i64);
+    let usage: Option<(i32, i64)> = query_as("SELECT loc.capacity, ( \
+            SELECT COALESCE(SUM(COALESCE(s.max_booking_count, loc.capacity)), 0) \
+            FROM session AS s \
+            WHERE s.location = loc.id \
+                AND s.datetime < $2 \
+                AND s.datetime + s.duration_mins * interval '1 minute' > $3 \
+                AND s.id IS DISTINCT FROM $4)::int8 \
+        FROM location AS loc \
+        WHERE loc.id = $1 AND loc.capacity IS NOT NULL")
+        .bind(location_id)
+        .bind(end)
+        .bind(start)
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?;
+    let Some((capacity, overlapping)) = usage else {
+        return Ok(());
+    };
+    let total = overlapping + new_session.max_bookings.unwrap_or(capacity as i64);
+    if total > capacity as i64 {
+        let message = format!("Sessions at location {} would have up to {} people at once, more than its capacity of {}.", location_id, total, capacity);
+        if config.enforce_location_capacity {
+            return Err(Custom(Status::Conflict, message));
+        }
+        warn!("{}", message);
+    }
+    Ok(())
+}
+
+async fn is_qualified(pool: &PgPool, trainer_id: i64, session_type_id: i32) -> Result<bool, Custom<String>> {
+    query_scalar("SELECT EXISTS (SELECT 1 FROM trainer_qualification WHERE trainer_id = $1 AND session_type_id = $2)")
+        .bind(trainer_id)
+        .bind(session_type_id)
+        .fetch_one(pool)
+        .await
+        .map_err(db_error)
+}
+
+fn normalize_tag(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
+
+/// Sessions, or how the query to list them would run
+#[derive(Responder)]
+pub enum SessionListing {
+    Sessions(Json<Vec<SessionFullRecord>>),
+    Explained(Json<QueryExplanation>)
+}
+
+/// Lists sessions or, for admins with `explain=true`, explains the query that would list them
+#[get("/sessions?<from>&<to>&<trainer_id>&<tag>&<explain>")]
+pub async fn list_sessions(state: &State<AppState>, claim: Claims, from: Option<String>, to: Option<String>, trainer_id: Vec<i64>, tag: Option<String>, explain: bool) -> Result<SessionListing, Custom<String>> {
+    let mut tx = begin_with_timeout(&state.pool, &state.config).await?;
+    let listing = _list_sessions(&mut *tx, &claim, from, to, trainer_id, tag, explain).await?;
+    tx.commit().await.map_err(query_error)?;
+    Ok(listing)
+}
+
+async fn _list_sessions<'c, E: Executor<'c, Database = Postgres>>(
+    executor: E,
+    claim: &Claims,
+    from: Option<String>,
+    to: Option<String>,
+    trainer_id: Vec<i64>,
+    tag: Option<String>,
+    explain: bool
+) -> Result<SessionListing, Custom<String>> {
+    // The SQL reveals the schema, so is only for admins
+    if explain {
+        claim.assert_roles_contains(ROLE_ADMIN)?;
+    }
+    let mut qb: QueryBuilder<Postgres> = QueryBuilder::new(if explain { EXPLAIN } else { "" });
+    build_session_query(Some(claim.uid), SessionFilter {
+        from: parse_opt_date(from)?,
+        to: parse_opt_date(to)?,
+        trainer_ids: trainer_id,
+        tag,
+        include_private: claim.has_role(ROLE_ADMIN),
+        private_booking_counts: PrivateBookingCounts::for_claims(claim),
+        ..Default::default()
+    }, &mut qb)?;
+    qb.push(" ORDER BY s.datetime ASC");
+    info!("build_session_query compiled SQL: {}", qb.sql());
+
+    if explain {
+        let explanation = explain_query(executor, qb).await?;
+        return Ok(SessionListing::Explained(Json(explanation)));
+    }
+    let sessions = qb.build_query_as()
+        .fetch_all(executor)
+        .await
+        .map_err(query_error)?;
+    Ok(SessionListing::Sessions(Json(sessions)))
+}
+
+/// Lists the future sessions that are nearly full or have a waitlist, for admins to decide where to add capacity
+#[get("/admin/sessions/attention?<from>&<to>")]
+pub async fn list_sessions_needing_attention(state: &State<AppState>, claim: Claims, from: Option<String>, to: Option<String>) -> Result<Json<Vec<SessionFullRecord>>, Custom<String>> {
+    _list_sessions_needing_attention(&state.pool, &state.config, &claim, parse_opt_date(from)?, parse_opt_date(to)?).await
+}
+
+async fn _list_sessions_needing_attention(pool: &PgPool, config: &Config, claim: &Claims, from: Option<DateTime<FixedOffset>>, to: Option<DateTime<FixedOffset>>) -> Result<Json<Vec<SessionFullRecord>>, Custom<String>> {
+    claim.assert_roles_contains(ROLE_ADMIN)?;
+    let now = Utc::now().fixed_offset();
     let mut qb: QueryBuilder<Postgres> = QueryBuilder::default();
-    build_session_query(Some(claim.uid), from, to, trainer_id, &mut qb)?;
+    build_session_query(None, SessionFilter {
+        from: Some(from.map_or(now, |from| from.max(now))),
+        to,
+        include_private: true,
+        max_spaces_left: Some(config.attention_spaces_threshold as i64),
+        private_booking_counts: PrivateBookingCounts::Shown,
+        ..Default::default()
+    }, &mut qb)?;
+    qb.push(" ORDER BY s.datetime ASC");
+
+    let mut tx = begin_with_timeout(pool, config).await?;
+    let sessions = qb.build_query_as()
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(query_error)?;
+    tx.commit().await.map_err(query_error)?;
+    Ok(Json(sessions))
+}
+
+/// Lists the future sessions that need a trainer but have none, soonest first
+#[get("/admin/sessions/unstaffed?<from>&<to>")]
+pub async fn list_unstaffed_sessions(state: &State<AppState>, claim: Claims, from: Option<String>, to: Option<String>) -> Result<Json<Vec<SessionFullRecord>>, Custom<String>> {
+    _list_unstaffed_sessions(&state.pool, &state.config, &claim, parse_opt_date(from)?, parse_opt_date(to)?).await
+}
+
+async fn _list_unstaffed_sessions(pool: &PgPool, config: &Config, claim: &Claims, from: Option<DateTime<FixedOffset>>, to: Option<DateTime<FixedOffset>>) -> Result<Json<Vec<SessionFullRecord>>, Custom<String>> {
+    claim.assert_roles_contains(ROLE_ADMIN)?;
+    let now = Utc::now().fixed_offset();
+    let mut qb: QueryBuilder<Postgres> = QueryBuilder::default();
+    build_session_query(None, SessionFilter {
+        from: Some(from.map_or(now, |from| from.max(now))),
+        to,
+        include_private: true,
+        unstaffed_only: true,
+        private_booking_counts: PrivateBookingCounts::Shown,
+        ..Default::default()
+    }, &mut qb)?;
+    qb.push(" ORDER BY s.datetime ASC");
+
+    let mut tx = begin_with_timeout(pool, config).await?;
+    let sessions = qb.build_query_as()
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(query_error)?;
+    tx.commit().await.map_err(query_error)?;
+    Ok(Json(sessions))
+}
+
+/// Searches are ignored unless they have at least this many characters, as shorter ones match almost everything
+const MIN_SEARCH_LENGTH: usize = 3;
+
+/// Escapes the characters that have a special meaning in LIKE patterns
+fn escape_like_pattern(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Lists the sessions whose notes, type, location or trainer name contain the query text, ignoring case
+#[get("/sessions/search?<q>&<from>&<to>")]
+pub async fn search_sessions(state: &State<AppState>, claim: Claims, q: String, from: Option<String>, to: Option<String>) -> Result<Json<Vec<SessionFullRecord>>, Custom<String>> {
+    let q = q.trim();
+    if q.chars().count() < MIN_SEARCH_LENGTH {
+        return Ok(Json(vec![]));
+    }
+    let mut qb: QueryBuilder<Postgres> = QueryBuilder::default();
+    build_session_query(Some(claim.uid), SessionFilter {
+        from: parse_opt_date(from)?,
+        to: parse_opt_date(to)?,
+        search: Some(q.to_string()),
+        include_private: claim.has_role(ROLE_ADMIN),
+        private_booking_counts: PrivateBookingCounts::for_claims(&claim),
+        ..Default::default()
+    }, &mut qb)?;
     qb.push(" ORDER BY s.datetime ASC");
     info!("build_session_query compiled SQL: {}", qb.sql());
 
+    let mut tx = begin_with_timeout(&state.pool, &state.config).await?;
     let sessions = qb.build_query_as()
-        .fetch_all(&state.pool)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(query_error)?;
+    tx.commit().await.map_err(query_error)?;
+    Ok(Json(sessions))
+}
+
+/// Just enough of a session to show it in a timetable. The full details are available from `get_session`.
+#[derive(Serialize, FromRow, Debug)]
+pub struct SessionSummary {
+    id: i64,
+    datetime: DateTime<Utc>,
+    session_type_name: String,
+    location_name: Option<String>,
+    /// Unlimited if not set
+    spaces_left: Option<i64>,
+    booked: bool
+}
+
+#[get("/sessions/summary?<from>&<to>")]
+pub async fn list_session_summaries(state: &State<AppState>, claim: Claims, from: Option<String>, to: Option<String>) -> Result<Json<Vec<SessionSummary>>, Custom<String>> {
+    let mut qb: QueryBuilder<Postgres> = QueryBuilder::new("SELECT s.id, s.datetime, t.name AS session_type_name, loc.name AS location_name, \
+        CASE WHEN s.max_booking_count IS NULL THEN NULL \
+            ELSE GREATEST(s.max_booking_count - (SELECT COUNT(*) FROM booking WHERE booking.session_id = s.id), 0) END AS spaces_left, \
+        EXISTS (SELECT 1 FROM booking WHERE booking.session_id = s.id AND booking.person_id = ");
+    qb.push_bind(claim.uid);
+    qb.push(") AS booked \
+        FROM session AS s \
+        INNER JOIN session_type AS t ON s.session_type = t.id \
+        LEFT JOIN location AS loc ON s.location = loc.id \
+        WHERE TRUE");
+    if let Some(from) = parse_opt_date(from)? {
+        qb.push(" AND s.datetime >= ");
+        qb.push_bind(from);
+    }
+    if let Some(to) = parse_opt_date(to)? {
+        qb.push(" AND s.datetime <= ");
+        qb.push_bind(to);
+    }
+    if !claim.has_role(ROLE_ADMIN) {
+        qb.push(" AND ");
+        push_not_private(Some(claim.uid), &mut qb);
+    }
+    qb.push(" ORDER BY s.datetime ASC");
+
+    let mut tx = begin_with_timeout(&state.pool, &state.config).await?;
+    let sessions = qb.build_query_as()
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(query_error)?;
+    tx.commit().await.map_err(query_error)?;
+    Ok(Json(sessions))
+}
+
+/// Lists the sessions in the week (in the configured timezone and starting on the configured day) containing
+/// the given date, formatted as YYYY-MM-DD. Defaults to the current week.
+#[get("/sessions/week?<date>")]
+pub async fn list_sessions_in_week(state: &State<AppState>, claim: Claims, date: Option<String>) -> Result<Json<Vec<SessionFullRecord>>, Custom<String>> {
+    let datetime_in_local = match date {
+        // Use midday to stay clear of any DST transitions around midnight
+        Some(date) => NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+            .map_err(|e| Custom(Status::UnprocessableEntity, e.to_string()))?
+            .and_time(NaiveTime::from_hms_opt(12, 0, 0).unwrap())
+            .and_local_timezone(state.timezone)
+            .earliest()
+            .ok_or_else(|| Custom(Status::UnprocessableEntity, format!("invalid local date: {}", date)))?,
+        None => Utc::now().with_timezone(&state.timezone)
+    };
+    let (start_of_week_local, end_of_week_local) = week_bounds(datetime_in_local, state.config.week_start_day);
+
+    let mut qb: QueryBuilder<Postgres> = QueryBuilder::default();
+    build_session_query(Some(claim.uid), SessionFilter {
+        from: Some(start_of_week_local.fixed_offset()),
+        include_private: claim.has_role(ROLE_ADMIN),
+        private_booking_counts: PrivateBookingCounts::for_claims(&claim),
+        ..Default::default()
+    }, &mut qb)?;
+    qb.push(" AND s.datetime < ");
+    qb.push_bind(end_of_week_local);
+    qb.push(" ORDER BY s.datetime ASC");
+    info!("build_session_query compiled SQL: {}", qb.sql());
+
+    let mut tx = begin_with_timeout(&state.pool, &state.config).await?;
+    let sessions = qb.build_query_as()
+        .fetch_all(&mut *tx)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+        .map_err(query_error)?;
+    tx.commit().await.map_err(query_error)?;
     Ok(Json(sessions))
 }
 
+#[derive(Serialize, Debug)]
+pub struct SessionDay {
+    date: NaiveDate,
+    sessions: Vec<SessionFullRecord>
+}
+
+/// Lists sessions grouped by their date in the configured timezone, so that clients need not work out day boundaries
+#[get("/sessions/grouped?<from>&<to>")]
+pub async fn list_sessions_grouped(state: &State<AppState>, claim: Claims, from: Option<String>, to: Option<String>) -> Result<Json<Vec<SessionDay>>, Custom<String>> {
+    let mut qb: QueryBuilder<Postgres> = QueryBuilder::default();
+    build_session_query(Some(claim.uid), SessionFilter {
+        from: parse_opt_date(from)?,
+        to: parse_opt_date(to)?,
+        include_private: claim.has_role(ROLE_ADMIN),
+        private_booking_counts: PrivateBookingCounts::for_claims(&claim),
+        ..Default::default()
+    }, &mut qb)?;
+    qb.push(" ORDER BY s.datetime ASC");
+
+    let mut tx = begin_with_timeout(&state.pool, &state.config).await?;
+    let sessions = qb.build_query_as()
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(query_error)?;
+    tx.commit().await.map_err(query_error)?;
+    Ok(Json(group_sessions_by_day(sessions, &state.timezone)))
+}
+
+/// Groups sessions, which must already be in date order, by their local date
+fn group_sessions_by_day(sessions: Vec<SessionFullRecord>, timezone: &Tz) -> Vec<SessionDay> {
+    let mut days: Vec<SessionDay> = Vec::new();
+    for session in sessions {
+        let date = session.datetime.with_timezone(timezone).date_naive();
+        match days.last_mut() {
+            Some(day) if day.date == date => day.sessions.push(session),
+            _ => days.push(SessionDay { date, sessions: vec![session] })
+        }
+    }
+    days
+}
+
+#[derive(Serialize, Debug)]
+pub struct TrainerSession {
+    #[serde(flatten)]
+    session: SessionFullRecord,
+    roster: Vec<RosterEntry>
+}
+
+#[derive(Serialize, FromRow, Debug)]
+pub struct RosterEntry {
+    #[serde(skip)]
+    session_id: i64,
+    person_id: i64,
+    name: String,
+    attended: bool
+}
+
+/// Lists the session types that a trainer is qualified to lead
+#[get("/trainers/<trainer_id>/qualifications")]
+pub async fn list_trainer_qualifications(state: &State<AppState>, claim: Claims, trainer_id: i64) -> Result<Json<Vec<SessionType>>, Custom<String>> {
+    claim.assert_roles_contains(ROLE_ADMIN)?;
+    query_as("SELECT t.* FROM trainer_qualification AS q \
+        INNER JOIN session_type AS t ON q.session_type_id = t.id \
+        WHERE q.trainer_id = $1 \
+        ORDER BY t.name")
+        .bind(trainer_id)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(db_error)
+        .map(Json)
+}
+
+#[put("/trainers/<trainer_id>/qualifications/<session_type_id>")]
+pub async fn add_trainer_qualification(state: &State<AppState>, claim: Claims, trainer_id: i64, session_type_id: i32) -> Result<NoContent, Custom<String>> {
+    claim.assert_roles_contains(ROLE_ADMIN)?;
+    query("INSERT INTO trainer_qualification (trainer_id, session_type_id) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+        .bind(trainer_id)
+        .bind(session_type_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| match e.as_database_error() {
+            Some(db_error) if db_error.is_foreign_key_violation() => Custom(Status::NotFound, format!("No trainer with id {} or session type with id {}.", trainer_id, session_type_id)),
+            _ => Custom(Status::InternalServerError, e.to_string())
+        })?;
+    audit::record(&state.pool, claim.uid, "add_trainer_qualification", format!("trainer {} session type {}", trainer_id, session_type_id)).await;
+    Ok(NoContent)
+}
+
+#[delete("/trainers/<trainer_id>/qualifications/<session_type_id>")]
+pub async fn delete_trainer_qualification(state: &State<AppState>, claim: Claims, trainer_id: i64, session_type_id: i32) -> Result<NoContent, Custom<String>> {
+    claim.assert_roles_contains(ROLE_ADMIN)?;
+    let result = query("DELETE FROM trainer_qualification WHERE trainer_id = $1 AND session_type_id = $2")
+        .bind(trainer_id)
+        .bind(session_type_id)
+        .execute(&state.pool)
+        .await
+        .map_err(db_error)?;
+    if result.rows_affected() == 0 {
+        return Err(Custom(Status::NotFound, format!("Trainer {} has no qualification for session type {}.", trainer_id, session_type_id)));
+    }
+    audit::record(&state.pool, claim.uid, "delete_trainer_qualification", format!("trainer {} session type {}", trainer_id, session_type_id)).await;
+    Ok(NoContent)
+}
+
+#[derive(Serialize, FromRow, Debug)]
+pub struct RosterSummary {
+    session_id: i64,
+    datetime: DateTime<Utc>,
+    type_name: String,
+    booked_count: i64,
+    max_booking_count: Option<i64>
+}
+
+/// Counts the bookings on each of the caller's sessions as trainer, from now unless `from` is given
+#[get("/trainers/me/roster_summary?<from>&<to>")]
+pub async fn get_trainer_roster_summary(state: &State<AppState>, claim: Claims, from: Option<String>, to: Option<String>) -> Result<Json<Vec<RosterSummary>>, Custom<String>> {
+    if !claim.has_role(ROLE_TRAINER) {
+        return Err(Custom(Status::Forbidden, "only trainers can list their sessions".to_string()));
+    }
+
+    let mut qb: QueryBuilder<Postgres> = QueryBuilder::new("SELECT s.id AS session_id, s.datetime, t.name AS type_name, \
+        (SELECT COUNT(*) FROM booking WHERE booking.session_id = s.id) AS booked_count, s.max_booking_count \
+        FROM session AS s \
+        INNER JOIN session_type AS t ON s.session_type = t.id \
+        WHERE s.trainer = ");
+    qb.push_bind(claim.uid);
+    qb.push(" AND s.datetime >= ");
+    match parse_opt_date(from)? {
+        Some(from) => qb.push_bind(from),
+        None => qb.push_bind(Utc::now())
+    };
+    if let Some(to) = parse_opt_date(to)? {
+        qb.push(" AND s.datetime <= ");
+        qb.push_bind(to);
+    }
+    qb.push(" ORDER BY s.datetime ASC");
+
+    let mut tx = begin_with_timeout(&state.pool, &state.config).await?;
+    let summaries = qb.build_query_as()
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(query_error)?;
+    tx.commit().await.map_err(query_error)?;
+    Ok(Json(summaries))
+}
+
+#[derive(Serialize, FromRow, Debug)]
+pub struct TrainerSessionSummary {
+    session_id: i64,
+    datetime: DateTime<Utc>,
+    duration_mins: i32,
+    type_name: String,
+    booked_count: i64,
+    attended_count: i64
+}
+
+/// The sessions that a trainer led over a period, with totals for invoicing
+#[derive(Serialize, Debug)]
+pub struct TrainerSummary {
+    trainer_id: i64,
+    sessions: Vec<TrainerSessionSummary>,
+    session_count: i64,
+    total_duration_mins: i64,
+    booked_count: i64,
+    attended_count: i64
+}
+
+/// Summarises the sessions that the caller led as trainer between `from` and `to`
+#[get("/trainers/me/summary?<from>&<to>")]
+pub async fn get_my_trainer_summary(state: &State<AppState>, claim: Claims, from: Option<String>, to: Option<String>) -> Result<Json<TrainerSummary>, Custom<String>> {
+    if !claim.has_role(ROLE_TRAINER) {
+        return Err(Custom(Status::Forbidden, "only trainers can summarise their sessions".to_string()));
+    }
+    _get_trainer_summary(&state.pool, &state.config, claim.uid, parse_opt_date(from)?, parse_opt_date(to)?).await.map(Json)
+}
+
+/// Summarises the sessions that any trainer led between `from` and `to`, for admins
+#[get("/trainers/<trainer_id>/summary?<from>&<to>")]
+pub async fn get_trainer_summary(state: &State<AppState>, claim: Claims, trainer_id: i64, from: Option<String>, to: Option<String>) -> Result<Json<TrainerSummary>, Custom<String>> {
+    claim.assert_roles_contains(ROLE_ADMIN)?;
+    _get_trainer_summary(&state.pool, &state.config, trainer_id, parse_opt_date(from)?, parse_opt_date(to)?).await.map(Json)
+}
+
+/// Only sessions that have started are counted, as those yet to run may still change. Cancelled sessions are
+/// deleted, so are never counted.
+async fn _get_trainer_summary(pool: &PgPool, config: &Config, trainer_id: i64, from: Option<DateTime<FixedOffset>>, to: Option<DateTime<FixedOffset>>) -> Result<TrainerSummary, Custom<String>> {
+    let mut tx = begin_with_timeout(pool, config).await?;
+    let sessions: Vec<TrainerSessionSummary> = query_as("SELECT s.id AS session_id, s.datetime, s.duration_mins, t.name AS type_name, \
+            c.booked_count, c.attended_count \
+            FROM session AS s \
+            INNER JOIN session_type AS t ON s.session_type = t.id, \
+            LATERAL (SELECT COUNT(*) AS booked_count, COUNT(*) FILTER (WHERE booking.attended) AS attended_count \
+                FROM booking WHERE booking.session_id = s.id) AS c \
+            WHERE s.trainer = $1 AND s.datetime < now() \
+            AND ($2::timestamptz IS NULL OR s.datetime >= $2) \
+            AND ($3::timestamptz IS NULL OR s.datetime <= $3) \
+            ORDER BY s.datetime ASC")
+        .bind(trainer_id)
+        .bind(from)
+        .bind(to)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(query_error)?;
+    tx.commit().await.map_err(query_error)?;
+
+    Ok(TrainerSummary {
+        trainer_id,
+        session_count: sessions.len() as i64,
+        total_duration_mins: sessions.iter().map(|s| s.duration_mins as i64).sum(),
+        booked_count: sessions.iter().map(|s| s.booked_count).sum(),
+        attended_count: sessions.iter().map(|s| s.attended_count).sum(),
+        sessions
+    })
+}
+
+/// Lists the sessions that the caller is assigned to as trainer, each with the names of the members booked on it
+#[get("/trainers/me/sessions?<from>&<to>")]
+pub async fn list_trainer_sessions(state: &State<AppState>, claim: Claims, from: Option<String>, to: Option<String>) -> Result<Json<Vec<TrainerSession>>, Custom<String>> {
+    if !claim.has_role(ROLE_TRAINER) {
+        return Err(Custom(Status::Forbidden, "only trainers can list their sessions".to_string()));
+    }
+
+    let mut qb: QueryBuilder<Postgres> = QueryBuilder::default();
+    build_session_query(None, SessionFilter {
+        from: parse_opt_date(from)?,
+        to: parse_opt_date(to)?,
+        trainer_ids: vec![claim.uid],
+        include_private: true,
+        private_booking_counts: PrivateBookingCounts::for_claims(&claim),
+        ..Default::default()
+    }, &mut qb)?;
+    qb.push(" ORDER BY s.datetime ASC");
+    let mut tx = begin_with_timeout(&state.pool, &state.config).await?;
+    let sessions: Vec<SessionFullRecord> = qb.build_query_as()
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(query_error)?;
+
+    let session_ids: Vec<i64> = sessions.iter().map(|s| s.id).collect();
+    let roster_entries: Vec<RosterEntry> = query_as("SELECT b.session_id, p.id AS person_id, p.name, b.attended \
+            FROM booking AS b JOIN person AS p ON b.person_id = p.id \
+            WHERE b.session_id = ANY($1) \
+            ORDER BY p.name")
+        .bind(&session_ids)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(query_error)?;
+    tx.commit().await.map_err(query_error)?;
+
+    let mut rosters: HashMap<i64, Vec<RosterEntry>> = HashMap::new();
+    for entry in roster_entries {
+        rosters.entry(entry.session_id).or_default().push(entry);
+    }
+    let trainer_sessions = sessions.into_iter()
+        .map(|session| {
+            let roster = rosters.remove(&session.id).unwrap_or_default();
+            TrainerSession { session, roster }
+        })
+        .collect();
+    Ok(Json(trainer_sessions))
+}
+
 #[get("/sessions/<session_id>")]
 pub async fn get_session(state: &State<AppState>, claim: Claims, session_id: i64) -> Result<Json<SessionFullRecord>, Custom<String>> {
     let mut qb: QueryBuilder<Postgres> = QueryBuilder::default();
-    build_session_query(Some(claim.uid), None, None, None, &mut qb)?;
-    qb.push(" WHERE s.id = ");
-    qb.push_bind(session_id);
+    build_session_query(Some(claim.uid), SessionFilter {
+        session_id: Some(session_id),
+        include_private: claim.has_role(ROLE_ADMIN),
+        private_booking_counts: PrivateBookingCounts::for_claims(&claim),
+        ..Default::default()
+    }, &mut qb)?;
     info!("build_session_query compiled SQL: {}", qb.sql());
 
     qb.build_query_as()
         .fetch_optional(&state.pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?
+        .map_err(db_error)?
         .ok_or_else(|| Custom(Status::NotFound, format!("session with id {} not found", session_id)))
         .map(|r| Json(r))
 }
 
-fn build_session_query<'a>(booking_person_id: Option<i64>, from: Option<String>, to: Option<String>, trainer_id: Option<i64>, qb: &'a mut QueryBuilder<Postgres>) -> Result<(), Custom<String>> {
-    qb.push("SELECT s.id, s.datetime, s.duration_mins, s.notes, s.cost, \
+/// How full a session is. For private sessions, only admins and the session's trainer see the counts.
+#[derive(Serialize, FromRow, Debug)]
+pub struct SessionBookingCount {
+    booking_count: Option<i64>,
+    max_booking_count: Option<i64>,
+    spaces_left: Option<i64>
+}
+
+#[get("/sessions/<session_id>/booking_count")]
+pub async fn get_session_booking_count(state: &State<AppState>, claim: Claims, session_id: i64) -> Result<Json<SessionBookingCount>, Custom<String>> {
+    _get_session_booking_count(&state.pool, &claim, session_id).await
+}
+
+async fn _get_session_booking_count(pool: &PgPool, claim: &Claims, session_id: i64) -> Result<Json<SessionBookingCount>, Custom<String>> {
+    query_as("SELECT c.booking_count, s.max_booking_count, CASE WHEN s.max_booking_count IS NULL OR c.booking_count IS NULL THEN NULL ELSE GREATEST(s.max_booking_count - c.booking_count, 0) END AS spaces_left \
+            FROM session AS s, LATERAL (SELECT CASE WHEN s.private AND NOT $2 AND s.trainer IS DISTINCT FROM $3 THEN NULL ELSE COUNT(*) END AS booking_count \
+                FROM booking WHERE booking.session_id = s.id) AS c \
+            WHERE s.id = $1")
+        .bind(session_id)
+        .bind(claim.has_role(ROLE_ADMIN))
+        .bind(claim.uid)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| Custom(Status::NotFound, format!("session with id {} not found", session_id)))
+        .map(Json)
+}
+
+#[derive(Serialize, Debug)]
+pub struct CapacityPreview {
+    booking_count: i64,
+    proposed_max_booking_count: i64,
+    /// Bookings beyond the proposed capacity
+    over_capacity: i64
+}
+
+/// Shows how many of a session's current bookings would not fit if its capacity were changed, without changing it
+#[get("/sessions/<session_id>/capacity_preview?<max>")]
+pub async fn preview_session_capacity(state: &State<AppState>, claim: Claims, session_id: i64, max: i64) -> Result<Json<CapacityPreview>, Custom<String>> {
+    claim.assert_roles_contains(ROLE_ADMIN)?;
+    if max < 0 {
+        return Err(Custom(Status::BadRequest, "max must not be negative".to_string()));
+    }
+    let booking_count: i64 = query_scalar("SELECT (SELECT COUNT(*) FROM booking WHERE booking.session_id = s.id) FROM session AS s WHERE s.id = $1")
+        .bind(session_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| Custom(Status::NotFound, format!("session with id {} not found", session_id)))?;
+    Ok(Json(CapacityPreview {
+        booking_count,
+        proposed_max_booking_count: max,
+        over_capacity: (booking_count - max).max(0)
+    }))
+}
+
+/// Criteria for the sessions listed by `build_session_query`
+#[derive(Default, Debug)]
+struct SessionFilter {
+    session_id: Option<i64>,
+    from: Option<DateTime<FixedOffset>>,
+    to: Option<DateTime<FixedOffset>>,
+    /// Sessions with any of these trainers, or all sessions if empty
+    trainer_ids: Vec<i64>,
+    tag: Option<String>,
+    search: Option<String>,
+    /// Otherwise private sessions are only listed for the members booked on them
+    include_private: bool,
+    /// Only sessions with at most this many spaces left, or with anyone on the waitlist
+    max_spaces_left: Option<i64>,
+    /// Only sessions that need a trainer but have none
+    unstaffed_only: bool,
+    private_booking_counts: PrivateBookingCounts
+}
+
+/// Whether to show how many are booked on private sessions, which only admins and the session's trainer can see
+#[derive(Default, Debug)]
+enum PrivateBookingCounts {
+    #[default]
+    Hidden,
+    /// Shown on the sessions that this person is the trainer of
+    ForTrainer(i64),
+    Shown
+}
+
+impl PrivateBookingCounts {
+    fn for_claims(claims: &Claims) -> Self {
+        if claims.has_role(ROLE_ADMIN) {
+            Self::Shown
+        } else {
+            Self::ForTrainer(claims.uid)
+        }
+    }
+}
+
+fn build_session_query(booking_person_id: Option<i64>, filter: SessionFilter, qb: &mut QueryBuilder<Postgres>) -> Result<(), Custom<String>> {
+    qb.push("SELECT s.id, s.datetime, s.duration_mins, s.notes, s.cost, s.tags, s.private, s.booking_opens_at, s.booking_closes_at, \
+        NOT (COALESCE(t.requires_trainer, true) AND s.trainer IS NULL) AS bookable, \
         t.id AS session_type_id, t.name AS session_type_name, t.requires_trainer AS session_type_requires_trainer, t.cost AS session_type_cost, \
-        loc.id AS location_id, loc.name AS location_name, loc.address AS location_address, \
-        trainer.id AS trainer_id, trainer.name AS trainer_name, trainer.email AS trainer_email, \
-        (SELECT COUNT(*) FROM booking WHERE booking.session_id = s.id) AS booking_count, s.max_booking_count as max_booking_count");
+        t.prerequisite_session_type_id AS session_type_prerequisite_id, t.color AS session_type_color, t.requires_approval AS session_type_requires_approval, \
+        t.waiver_url AS session_type_waiver_url, t.requires_waiver AS session_type_requires_waiver, \
+        loc.id AS location_id, loc.name AS location_name, loc.address AS location_address, loc.capacity AS location_capacity, \
+        trainer.id AS trainer_id, trainer.name AS trainer_name, trainer.email AS trainer_email, s.max_booking_count as max_booking_count, \
+        (SELECT COUNT(*) FROM waitlist WHERE waitlist.session_id = s.id) AS waitlist_count, s.max_waitlist_count");
+    let booking_count = "(SELECT COUNT(*) FROM booking WHERE booking.session_id = s.id)";
+    match filter.private_booking_counts {
+        PrivateBookingCounts::Hidden => {
+            qb.push(format!(", CASE WHEN s.private THEN NULL ELSE {} END AS booking_count", booking_count));
+        },
+        PrivateBookingCounts::ForTrainer(trainer_id) => {
+            qb.push(", CASE WHEN s.private AND s.trainer IS DISTINCT FROM ");
+            qb.push_bind(trainer_id);
+            qb.push(format!(" THEN NULL ELSE {} END AS booking_count", booking_count));
+        },
+        PrivateBookingCounts::Shown => {
+            qb.push(format!(", {} AS booking_count", booking_count));
+        }
+    }
 
     if let Some(booking_person_id) = booking_person_id {
         qb.push(", CASE WHEN EXISTS (SELECT 1 FROM booking WHERE booking.session_id = s.id AND booking.person_id = ");
         qb.push_bind(booking_person_id);
         qb.push(") THEN true ELSE false END AS booked");
+        qb.push(", EXISTS (SELECT 1 FROM booking_request WHERE booking_request.session_id = s.id AND booking_request.person_id = ");
+        qb.push_bind(booking_person_id);
+        qb.push(") AS pending");
     }
 
     qb.push(" FROM session as s \
@@ -145,50 +886,136 @@ fn build_session_query<'a>(booking_person_id: Option<i64>, from: Option<String>,
         LEFT JOIN location AS loc ON s.location = loc.id \
         LEFT JOIN person AS trainer ON s.trainer = trainer.id");
 
-    let parsed_from = parse_opt_date(from)?;
-    let parsed_to = parse_opt_date(to)?;
     let mut operator: String = " WHERE".to_string();
-    if let Some(from) = parsed_from {
+    if let Some(session_id) = filter.session_id {
+        qb.push(operator + " s.id = ");
+        qb.push_bind(session_id);
+        operator = " AND".to_string();
+    }
+    if let Some(from) = filter.from {
         qb.push(operator + " s.datetime >= ");
         qb.push_bind(from);
         operator = " AND".to_string();
     }
-    if let Some(to) = parsed_to {
+    if let Some(to) = filter.to {
         qb.push(operator + " s.datetime <= ");
         qb.push_bind(to);
         operator = " AND".to_string();
     }
-    if let Some(trainer_id) = trainer_id {
-        qb.push(operator + " trainer.id = ");
-        qb.push_bind(trainer_id);
+    if !filter.trainer_ids.is_empty() {
+        qb.push(operator + " trainer.id IN (");
+        let mut separated = qb.separated(", ");
+        for trainer_id in filter.trainer_ids {
+            separated.push_bind(trainer_id);
+        }
+        qb.push(")");
+        operator = " AND".to_string();
+    }
+    if let Some(tag) = filter.tag {
+        qb.push(operator + " ");
+        qb.push_bind(normalize_tag(&tag));
+        qb.push(" = ANY(s.tags)");
+        operator = " AND".to_string();
+    }
+    if let Some(search) = filter.search {
+        let pattern = format!("%{}%", escape_like_pattern(&search));
+        qb.push(operator + " concat_ws(' ', s.notes, t.name, loc.name, trainer.name) ILIKE ");
+        qb.push_bind(pattern);
+        operator = " AND".to_string();
+    }
+    if let Some(max_spaces_left) = filter.max_spaces_left {
+        qb.push(operator + " (s.max_booking_count - (SELECT COUNT(*) FROM booking WHERE booking.session_id = s.id) <= ");
+        qb.push_bind(max_spaces_left);
+        qb.push(" OR EXISTS (SELECT 1 FROM waitlist WHERE waitlist.session_id = s.id))");
+        operator = " AND".to_string();
+    }
+    if filter.unstaffed_only {
+        qb.push(operator + " COALESCE(t.requires_trainer, true) AND s.trainer IS NULL");
+        operator = " AND".to_string();
+    }
+    if !filter.include_private {
+        qb.push(operator + " ");
+        push_not_private(booking_person_id, qb);
     }
     Ok(())
 }
 
+/// Hides private sessions, other than those that the given person is booked on
+fn push_not_private(booking_person_id: Option<i64>, qb: &mut QueryBuilder<Postgres>) {
+    qb.push("(NOT s.private");
+    if let Some(booking_person_id) = booking_person_id {
+        qb.push(" OR EXISTS (SELECT 1 FROM booking WHERE booking.session_id = s.id AND booking.person_id = ");
+        qb.push_bind(booking_person_id);
+        qb.push(")");
+    }
+    qb.push(")");
+}
+
+/// Which sessions a user is allowed to create, update and delete
+#[derive(Debug, PartialEq)]
+enum SessionManagement {
+    Any,
+    OwnOnly
+}
+
+/// Admins can always manage any session. Trainers, if configured as session managers, can only manage
+/// the sessions that they are the trainer for. Any other configured session manager role can manage any session.
+fn session_management(claims: &Claims, config: &Config) -> Option<SessionManagement> {
+    if claims.has_role(ROLE_ADMIN) {
+        return Some(SessionManagement::Any);
+    }
+    let manager_roles = config.session_manager_roles.iter()
+        .filter(|role| claims.has_role(role))
+        .collect::<Vec<_>>();
+    if manager_roles.iter().any(|role| *role != ROLE_TRAINER) {
+        Some(SessionManagement::Any)
+    } else if !manager_roles.is_empty() {
+        Some(SessionManagement::OwnOnly)
+    } else {
+        None
+    }
+}
+
+/// Whether the user may create sessions at all, whether or not they are limited to their own
+pub(crate) fn can_manage_sessions(claims: &Claims, config: &Config) -> bool {
+    session_management(claims, config).is_some()
+}
+
+fn session_manager_roles_description(config: &Config) -> String {
+    let mut roles = vec![ROLE_ADMIN];
+    roles.extend(config.session_manager_roles.iter()
+        .map(String::as_str)
+        .filter(|role| *role != ROLE_ADMIN));
+    format!("users with roles {}", roles.join(", "))
+}
+
 #[post("/sessions", data="<new_session>")]
 pub async fn create_session(
     state:  &State<AppState>,
     claims: Claims,
-    new_session: Json<NewSession>
+    new_session: JsonBody<NewSession>
 ) -> Result<Created<Json<BigintRecord>>, Custom<String>> {
-    // Admins can create any session. Trainers can only create sessions with themselves as the trainer.
-    // Nobody else can create sessions.
-    if !claims.has_role("admin") {
-        if claims.has_role("trainer") {
+    _create_session(&state.pool, &state.timezone, &state.config, &claims, &new_session).await
+}
+
+async fn _create_session(pool: &PgPool, timezone: &Tz, config: &Config, claims: &Claims, new_session: &NewSession) -> Result<Created<Json<BigintRecord>>, Custom<String>> {
+    // Admins and other session manager roles can create any session. Trainers can only create sessions with
+    // themselves as the trainer. Nobody else can create sessions.
+    match session_management(claims, config) {
+        Some(SessionManagement::Any) => {},
+        Some(SessionManagement::OwnOnly) => {
             if !Some(claims.uid).eq(&new_session.trainer_id) {
                 return Err(Custom(Status::Forbidden, "trainers can only create sessions for themselves".to_string()));
             }
-        } else {
-            return Err(Custom(Status::Forbidden, "only admins or trainers can create sessions".to_string()));
-        }
+        },
+        None => return Err(Custom(Status::Forbidden, format!("only {} can create sessions", session_manager_roles_description(config))))
     }
 
-    new_session.validate(&state.pool)
-        .await
-        .map_err(|e| Custom(Status::BadRequest, e.to_string()))?;
+    new_session.validate(pool, timezone, claims, config).await?;
+    check_location_capacity(pool, config, new_session, None).await?;
 
-    let id_record: BigintRecord = query_as("INSERT INTO session (datetime, duration_mins, session_type, location, trainer, max_booking_count, notes, cost) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id")
-        .bind(&new_session.datetime)
+    let id_record: BigintRecord = query_as("INSERT INTO session (datetime, duration_mins, session_type, location, trainer, max_booking_count, notes, cost, tags, private, booking_opens_at, booking_closes_at, max_waitlist_count) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13) RETURNING id")
+        .bind(new_session.normalized_datetime())
         .bind(&new_session.duration_mins)
         .bind(&new_session.session_type_id)
         .bind(&new_session.location_id)
@@ -196,11 +1023,17 @@ pub async fn create_session(
         .bind(&new_session.max_bookings)
         .bind(&new_session.notes)
         .bind(&new_session.cost)
-        .fetch_optional(&state.pool)
+        .bind(new_session.normalized_tags())
+        .bind(new_session.private)
+        .bind(new_session.booking_opens_at)
+        .bind(new_session.booking_closes_at)
+        .bind(new_session.max_waitlist_count)
+        .fetch_optional(pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?
+        .map_err(db_error)?
         .ok_or_else(|| Custom(Status::Conflict, "no new record created".to_string()))?;
     info!("Created session id {}", id_record.id);
+    audit::record(pool, claims.uid, "create_session", format!("session {}", id_record.id)).await;
     Ok(Created::new(format!("/sessions/{}", id_record.id)).body(Json(id_record)))
 }
 
@@ -209,22 +1042,312 @@ pub async fn delete_session(state: &State<AppState>, claims: Claims, session_id:
     let mut qb = QueryBuilder::new("DELETE FROM session WHERE id = ");
     qb.push_bind(session_id);
 
-    if !claims.roles.contains(&"admin".to_string()) {
-        if claims.roles.contains(&"trainer".to_string()) {
+    match session_management(&claims, &state.config) {
+        Some(SessionManagement::Any) => {},
+        Some(SessionManagement::OwnOnly) => {
             qb.push(" AND trainer = ");
             qb.push_bind(claims.uid);
-        } else {
-            return Err(Custom(Status::Forbidden, "only admins and trainers can delete sessions".to_string()));
-        }
+        },
+        None => return Err(Custom(Status::Forbidden, format!("only {} can delete sessions", session_manager_roles_description(&state.config))))
     }
     qb.push(" RETURNING id");
     let id_record: BigintRecord= qb.build_query_as()
         .fetch_optional(&state.pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?
+        .map_err(db_error)?
         .ok_or_else(|| Custom(Status::NotFound, format!("session id {} not found, or not deletable by current user", session_id)))?;
     info!("Deleted session id {}", id_record.id);
+    audit::record(&state.pool, claims.uid, "delete_session", format!("session {}", id_record.id)).await;
+
+    Ok(NoContent)
+}
 
+#[derive(Serialize, Debug)]
+pub struct SessionsCancelled {
+    sessions: usize,
+    bookings: usize,
+    credits_refunded: i64,
+    /// Members emailed about their cancelled bookings, leaving out those who have opted out
+    notified: usize
+}
+
+#[derive(FromRow, Debug)]
+struct CancelledSessionBooking {
+    person_id: i64,
+    session_id: i64,
+    name: String,
+    email: String,
+    session_datetime: DateTime<Utc>,
+    session_type_name: String,
+    credits_used: Option<i16>
+}
+
+/// Cancels every session yet to start in a period, e.g. while the gym is closed for a holiday, refunding and
+/// emailing the members booked on them. Past sessions are kept with their attendance. Given how much this
+/// deletes, `confirm=true` must be passed.
+#[delete("/sessions?<from>&<to>&<confirm>")]
+pub async fn cancel_sessions_in_range(state: &State<AppState>, claims: Claims, from: Option<String>, to: Option<String>, confirm: Option<bool>) -> Result<Json<SessionsCancelled>, Custom<String>> {
+    let (mut result, bookings) = _cancel_sessions_in_range(&state.pool, &claims, from, to, confirm).await?;
+
+    let sender = Address::new_address(Some(&state.config.email_sender_name), &state.config.email_sender_address);
+    for booking in bookings {
+        // The sessions are already cancelled, so failing to notify one member should not stop the others
+        let prefs = match NotificationPrefs::load(&state.pool, booking.person_id).await {
+            Ok(prefs) => prefs,
+            Err(e) => {
+                error!("Failed to load notification preferences for person id {}: {:?}", booking.person_id, e);
+                continue;
+            }
+        };
+        if !should_notify(&prefs, NotificationEvent::Cancellation) {
+            continue;
+        }
+        let session_datetime = booking.session_datetime.with_timezone(&state.timezone);
+        let session_time = session_datetime.format("%H:%M");
+        let session_date = session_datetime.format("%A %-d %B %Y");
+        let email = MessageBuilder::new()
+            .from(sender.clone())
+            .reply_to(sender.clone())
+            .to(Address::new_address(Some(&booking.name), &booking.email))
+            .subject(render_subject(&state.config, EmailTemplate::SessionCancelled))
+            .text_body(render_body(&state.config, EmailTemplate::SessionCancelled, &[&booking.session_type_name, &session_time, &session_date]))
+            .into_message();
+        let email = match email {
+            Ok(email) => email,
+            Err(e) => {
+                error!("Failed to build session cancellation email to {}: {:?}", &booking.email, e);
+                continue;
+            }
+        };
+        match send_email(email, &state.secrets).await {
+            Ok(()) => result.notified += 1,
+            Err(e) => error!("Failed to send session cancellation email to {}: {:?}", &booking.email, e)
+        }
+    }
+    Ok(Json(result))
+}
+
+async fn _cancel_sessions_in_range(pool: &PgPool, claims: &Claims, from: Option<String>, to: Option<String>, confirm: Option<bool>) -> Result<(SessionsCancelled, Vec<CancelledSessionBooking>), Custom<String>> {
+    claims.assert_roles_contains(ROLE_ADMIN)?;
+    let (from, to) = match (parse_opt_date(from)?, parse_opt_date(to)?) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return Err(Custom(Status::BadRequest, "from and to are required".to_string()))
+    };
+    if confirm != Some(true) {
+        return Err(Custom(Status::BadRequest, "confirm=true is required to cancel all sessions in a period".to_string()));
+    }
+
+    let mut tx = pool.begin()
+        .await
+        .map_err(db_error)?;
+    let bookings: Vec<CancelledSessionBooking> = query_as("DELETE FROM booking AS b \
+            USING session AS s, person AS p, session_type AS t \
+            WHERE b.session_id = s.id AND b.person_id = p.id AND s.session_type = t.id \
+            AND s.datetime >= $1 AND s.datetime <= $2 AND s.datetime >= now() \
+            RETURNING b.person_id, b.session_id, p.name, p.email, s.datetime AS session_datetime, t.name AS session_type_name, b.credits_used")
+        .bind(from)
+        .bind(to)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+    // The cancellations outlive the sessions, so say which session each was for
+    for booking in &bookings {
+        query("INSERT INTO cancellation (person_id, session_id, reason) VALUES ($1, $2, $3)")
+            .bind(booking.person_id)
+            .bind(booking.session_id)
+            .bind(format!("Session cancelled: {} at {}", booking.session_type_name, booking.session_datetime.to_rfc3339()))
+            .execute(&mut *tx)
+            .await
+            .map_err(db_error)?;
+    }
+
+    // Restore the credits used, once per member
+    let mut refunds: HashMap<i64, i64> = HashMap::new();
+    for booking in bookings.iter().filter(|b| b.credits_used.unwrap_or(0) > 0) {
+        *refunds.entry(booking.person_id).or_default() += booking.credits_used.unwrap_or(0) as i64;
+    }
+    for (person_id, credits) in &refunds {
+        let credits = i16::try_from(*credits)
+            .map_err(|_| Custom(Status::InternalServerError, format!("Cannot refund {} credits to person id {}", credits, person_id)))?;
+        query("UPDATE person SET credits = credits + $1 WHERE id = $2")
+            .bind(credits)
+            .bind(person_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(db_error)?;
+    }
+
+    let sessions: Vec<i64> = query_scalar("DELETE FROM session WHERE datetime >= $1 AND datetime <= $2 AND datetime >= now() RETURNING id")
+        .bind(from)
+        .bind(to)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    tx.commit()
+        .await
+        .map_err(db_error)?;
+
+    let credits_refunded = refunds.values().sum();
+    info!("Cancelled {} session(s) from {} to {} with {} booking(s), restoring {} credit(s)", sessions.len(), from, to, bookings.len(), credits_refunded);
+    audit::record(pool, claims.uid, "cancel_sessions", format!("{} session(s) from {} to {}", sessions.len(), from.to_rfc3339(), to.to_rfc3339())).await;
+    Ok((SessionsCancelled { sessions: sessions.len(), bookings: bookings.len(), credits_refunded, notified: 0 }, bookings))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SessionMessage {
+    subject: String,
+    body: String
+}
+
+#[derive(Serialize, Debug)]
+pub struct SessionMessageResult {
+    sent: usize,
+    /// Members whose email could not be sent. Those who have opted out of announcements are not counted.
+    not_sent: usize
+}
+
+#[derive(FromRow, Debug)]
+struct MessageRecipient {
+    id: i64,
+    name: String,
+    email: String
+}
+
+/// Emails a message to all the members booked on a session, e.g. to ask them to bring a towel
+#[post("/sessions/<session_id>/notify", data="<message>")]
+pub async fn notify_session_members(state: &State<AppState>, claims: Claims, session_id: i64, message: JsonBody<SessionMessage>) -> Result<Json<SessionMessageResult>, Custom<String>> {
+    if message.subject.trim().is_empty() || message.body.trim().is_empty() {
+        return Err(Custom(Status::UnprocessableEntity, "A subject and body are required.".to_string()));
+    }
+    let recipients = session_message_recipients(&state.pool, &claims, session_id, &message.subject).await?;
+
+    let sender = Address::new_address(Some(&state.config.email_sender_name), &state.config.email_sender_address);
+    let mut result = SessionMessageResult { sent: 0, not_sent: 0 };
+    for recipient in recipients {
+        let email = MessageBuilder::new()
+            .from(sender.clone())
+            .reply_to(sender.clone())
+            .to(Address::new_address(Some(&recipient.name), &recipient.email))
+            .subject(message.subject.as_str())
+            .text_body(message.body.as_str())
+            .into_message()
+            .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+        match send_email(email, &state.secrets).await {
+            Ok(()) => result.sent += 1,
+            Err(e) => {
+                error!("Failed to send session {} message to {}: {:?}", session_id, &recipient.email, e);
+                result.not_sent += 1;
+            }
+        }
+    }
+    audit::record(&state.pool, claims.uid, "notify_session_members", format!("session {} sent {}", session_id, result.sent)).await;
+    Ok(Json(result))
+}
+
+/// Records a message to a session's members and returns those to email, leaving out those who have opted out of
+/// announcements. Errors unless the caller is an admin or the session's trainer, or if the session's members have
+/// been messaged too recently.
+async fn session_message_recipients(pool: &PgPool, claims: &Claims, session_id: i64, subject: &str) -> Result<Vec<MessageRecipient>, Custom<String>> {
+    let trainer_id: Option<i64> = query_scalar("SELECT trainer FROM session WHERE id = $1")
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| Custom(Status::NotFound, format!("session id {} not found", session_id)))?;
+    if !claims.has_role(ROLE_ADMIN) && trainer_id != Some(claims.uid) {
+        return Err(Custom(Status::Forbidden, "only admins or the session's trainer can message its members".to_string()));
+    }
+
+    // Lock the session so that concurrent messages cannot both pass the check before either is recorded
+    let mut tx = pool.begin()
+        .await
+        .map_err(db_error)?;
+    query("SELECT id FROM session WHERE id = $1 FOR NO KEY UPDATE")
+        .bind(session_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    let last_sent: Option<DateTime<Utc>> = query_scalar("SELECT max(sent) FROM session_message WHERE session_id = $1")
+        .bind(session_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    if last_sent.is_some_and(|last_sent| last_sent > Utc::now() - SESSION_MESSAGE_MINIMUM_INTERVAL) {
+        return Err(Custom(Status::TooManyRequests, format!("The members of this session were messaged in the last {} minutes.", SESSION_MESSAGE_MINIMUM_INTERVAL.num_minutes())));
+    }
+    query("INSERT INTO session_message (session_id, sender_id, subject) VALUES ($1, $2, $3)")
+        .bind(session_id)
+        .bind(claims.uid)
+        .bind(subject)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    tx.commit()
+        .await
+        .map_err(db_error)?;
+
+    let booked: Vec<MessageRecipient> = query_as("SELECT p.id, p.name, p.email FROM booking AS b JOIN person AS p ON b.person_id = p.id WHERE b.session_id = $1 ORDER BY p.id")
+        .bind(session_id)
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)?;
+    let mut recipients = Vec::new();
+    for recipient in booked {
+        let prefs = NotificationPrefs::load(pool, recipient.id)
+            .await
+            .map_err(db_error)?;
+        if should_notify(&prefs, NotificationEvent::Announcement) {
+            recipients.push(recipient);
+        }
+    }
+    Ok(recipients)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TrainerReassignment {
+    trainer_id: i64,
+    /// Assign the trainer even if they are not qualified for the session type, e.g. to cover at short notice
+    #[serde(default)]
+    override_qualification: bool
+}
+
+/// Replaces the trainer of a session, e.g. when the assigned trainer is unwell
+#[post("/sessions/<session_id>/trainer", data="<reassignment>")]
+pub async fn reassign_trainer(state: &State<AppState>, claims: Claims, session_id: i64, reassignment: JsonBody<TrainerReassignment>) -> Result<NoContent, Custom<String>> {
+    _reassign_trainer(&state.pool, &claims, session_id, reassignment.into_inner()).await
+}
+
+async fn _reassign_trainer(pool: &PgPool, claims: &Claims, session_id: i64, reassignment: TrainerReassignment) -> Result<NoContent, Custom<String>> {
+    claims.assert_roles_contains(ROLE_ADMIN)?;
+
+    let is_trainer: bool = query_scalar("SELECT EXISTS (SELECT 1 FROM person WHERE id = $1 AND $2 = ANY(string_to_array(replace(roles, ' ', ''), ',')))")
+        .bind(reassignment.trainer_id)
+        .bind(ROLE_TRAINER)
+        .fetch_one(pool)
+        .await
+        .map_err(db_error)?;
+    if !is_trainer {
+        return Err(Custom(Status::BadRequest, format!("Person {} is not a trainer.", reassignment.trainer_id)));
+    }
+    let session_type_id: i32 = query_scalar("SELECT session_type FROM session WHERE id = $1")
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| Custom(Status::NotFound, format!("session id {} not found", session_id)))?;
+    if !reassignment.override_qualification && !is_qualified(pool, reassignment.trainer_id, session_type_id).await? {
+        return Err(Custom(Status::BadRequest, format!("Trainer {} is not qualified for session type {}.", reassignment.trainer_id, session_type_id)));
+    }
+
+    query("UPDATE session SET trainer = $1 WHERE id = $2")
+        .bind(reassignment.trainer_id)
+        .bind(session_id)
+        .execute(pool)
+        .await
+        .map_err(db_error)?;
+    info!("Reassigned session id {} to trainer id {}", session_id, reassignment.trainer_id);
+    audit::record(pool, claims.uid, "reassign_trainer", format!("session {} trainer {}", session_id, reassignment.trainer_id)).await;
     Ok(NoContent)
 }
 
@@ -233,10 +1356,10 @@ pub async fn update_session(
     state: &State<AppState>,
     claims: Claims,
     session_id: i64,
-    new_session: Json<NewSession>
+    new_session: JsonBody<NewSession>
 ) -> Result<NoContent, Custom<String>> {
     let mut qb: QueryBuilder<Postgres> = QueryBuilder::new("UPDATE session SET datetime = ");
-    qb.push_bind(new_session.datetime);
+    qb.push_bind(new_session.normalized_datetime());
 
     qb.push(", duration_mins = ");
     qb.push_bind(new_session.duration_mins);
@@ -259,46 +1382,877 @@ pub async fn update_session(
     qb.push(", notes = ");
     qb.push_bind(&new_session.notes);
 
+    qb.push(", tags = ");
+    qb.push_bind(new_session.normalized_tags());
+
+    qb.push(", private = ");
+    qb.push_bind(new_session.private);
+
+    qb.push(", booking_opens_at = ");
+    qb.push_bind(new_session.booking_opens_at);
+
+    qb.push(", booking_closes_at = ");
+    qb.push_bind(new_session.booking_closes_at);
+
+    qb.push(", max_waitlist_count = ");
+    qb.push_bind(new_session.max_waitlist_count);
+
     qb.push(" WHERE id = ");
     qb.push_bind(session_id);
 
-    if !claims.has_role("admin") {
-        if claims.has_role("trainer") {
+    match session_management(&claims, &state.config) {
+        Some(SessionManagement::Any) => {},
+        Some(SessionManagement::OwnOnly) => {
             qb.push(" AND trainer = ");
             qb.push_bind(claims.uid);
-        } else {
-            return Err(Custom(Status::NotFound, "only admins and trainers can update sessions".to_string()));
-        }
+        },
+        None => return Err(Custom(Status::NotFound, format!("only {} can update sessions", session_manager_roles_description(&state.config))))
     }
     qb.push(" RETURNING id");
 
-    new_session.validate(&state.pool)
-        .await
-        .map_err(|e| Custom(Status::BadRequest, e.to_string()))?;
+    new_session.validate(&state.pool, &state.timezone, &claims, &state.config).await?;
+    check_location_capacity(&state.pool, &state.config, &new_session, Some(session_id)).await?;
 
     let id_record: BigintRecord = qb.build_query_as()
         .fetch_optional(&state.pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?
+        .map_err(db_error)?
         .ok_or_else(|| Custom(Status::NotFound, format!("session id {} not found, or not updatable by current user", session_id)))?;
     info!("Updating session id {} with data {:?}", id_record.id, new_session);
+    audit::record(&state.pool, claims.uid, "update_session", format!("session {}", id_record.id)).await;
     Ok(NoContent)
 }
 
 #[get("/locations")]
 pub async fn list_locations(state: &State<AppState>) -> Result<Json<Vec<SessionLocation>>, Custom<String>> {
-    query_as("SELECT id, name, address FROM location")
+    query_as("SELECT id, name, address, capacity FROM location")
         .fetch_all(&state.pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))
+        .map_err(db_error)
         .map(|v| Json(v))
 }
 
 #[get("/session_types")]
 pub async fn list_session_types(state: &State<AppState>) -> Result<Json<Vec<SessionType>>, Custom<String>> {
-    query_as("SELECT id, name, requires_trainer, cost FROM session_type ORDER BY requires_trainer DESC, name")
+    query_as("SELECT id, name, requires_trainer, cost, prerequisite_session_type_id, color, requires_approval, waiver_url, requires_waiver FROM session_type ORDER BY requires_trainer DESC, name")
         .fetch_all(&state.pool)
         .await
-        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))
+        .map_err(db_error)
         .map(|v| Json(v))
-}
\ No newline at end of file
+}
+
+/// Whether a color is given in hex as "#rgb" or "#rrggbb"
+fn is_hex_color(color: &str) -> bool {
+    color.strip_prefix('#')
+        .is_some_and(|hex| (hex.len() == 3 || hex.len() == 6) && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SessionTypeColor {
+    /// Or null to remove the color
+    color: Option<String>
+}
+
+/// Sets the color that clients show sessions of a type in
+#[put("/session_types/<session_type_id>/color", data="<color>")]
+pub async fn set_session_type_color(state: &State<AppState>, claim: Claims, session_type_id: i32, color: JsonBody<SessionTypeColor>) -> Result<NoContent, Custom<String>> {
+    _set_session_type_color(&state.pool, &claim, session_type_id, &color).await
+}
+
+async fn _set_session_type_color(pool: &PgPool, claim: &Claims, session_type_id: i32, color: &SessionTypeColor) -> Result<NoContent, Custom<String>> {
+    claim.assert_roles_contains(ROLE_ADMIN)?;
+    let color = color.color.as_deref().map(str::trim).map(str::to_lowercase);
+    if let Some(color) = &color {
+        if !is_hex_color(color) {
+            return Err(Custom(Status::BadRequest, format!("'{}' is not a hex color such as #1e90ff.", color)));
+        }
+    }
+    query_scalar::<_, i32>("UPDATE session_type SET color = $1 WHERE id = $2 RETURNING id")
+        .bind(&color)
+        .bind(session_type_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("No session type with id {}.", session_type_id)))?;
+    audit::record(pool, claim.uid, "set_session_type_color", format!("session type {} color {}", session_type_id, color.as_deref().unwrap_or("none"))).await;
+    Ok(NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration, Utc};
+    use chrono_tz::Asia::Kathmandu;
+    use chrono_tz::Europe::London;
+    use sqlx::{Executor, PgPool, Postgres, query, query_scalar, QueryBuilder};
+    use crate::claims::Claims;
+    use rocket::http::Status;
+    use rocket::response::status::Custom;
+    use rocket::serde::json::Value;
+    use crate::Config;
+    use crate::test_support::{bearer, create_person, test_client};
+    use crate::sessions::{_cancel_sessions_in_range, _create_session, _get_session_booking_count, _list_sessions, _get_trainer_summary, _list_sessions_needing_attention, _list_unstaffed_sessions, _reassign_trainer, _set_session_type_color, build_session_query, group_sessions_by_day, is_hex_color, session_message_recipients, NewSession, PrivateBookingCounts, SessionFilter, SessionFullRecord, SessionListing, SessionTypeColor, TrainerReassignment};
+
+    fn new_session(session_type_id: i32, trainer_id: i64) -> NewSession {
+        NewSession {
+            datetime: Utc::now() + Duration::days(1),
+            duration_mins: 60,
+            session_type_id,
+            location_id: None,
+            trainer_id: Some(trainer_id),
+            max_bookings: None,
+            max_waitlist_count: None,
+            notes: None,
+            cost: 0,
+            tags: vec![],
+            allow_unstaffed: false,
+            private: false,
+            booking_opens_at: None,
+            booking_closes_at: None
+        }
+    }
+
+    async fn list_session_ids(pool: &PgPool, person_id: i64, include_private: bool) -> Vec<i64> {
+        let mut qb: QueryBuilder<Postgres> = QueryBuilder::default();
+        build_session_query(Some(person_id), SessionFilter { include_private, ..Default::default() }, &mut qb).unwrap();
+        qb.push(" ORDER BY s.id");
+        let sessions: Vec<SessionFullRecord> = qb.build_query_as().fetch_all(pool).await.unwrap();
+        sessions.iter().map(|s| s.id).collect()
+    }
+
+    #[sqlx::test]
+    async fn private_sessions_hidden_from_members(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let trainer_id = create_person(&pool, "trainer@example.com", "trainer").await;
+        let member_id = create_person(&pool, "member@example.com", "member").await;
+        let session_type_id: i32 = query_scalar("select id from session_type where name = 'HIIT'")
+            .fetch_one(&pool).await.unwrap();
+        let public_id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, trainer) values (now(), 60, $1, $2) returning id")
+            .bind(session_type_id)
+            .bind(trainer_id)
+            .fetch_one(&pool).await.unwrap();
+        let private_id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, trainer, private) values (now(), 60, $1, $2, true) returning id")
+            .bind(session_type_id)
+            .bind(trainer_id)
+            .fetch_one(&pool).await.unwrap();
+
+        assert_eq!(vec![public_id], list_session_ids(&pool, member_id, false).await);
+        assert_eq!(vec![public_id, private_id], list_session_ids(&pool, member_id, true).await);
+
+        // Members booked onto a private session can see it
+        query("insert into booking (person_id, session_id) values ($1, $2)")
+            .bind(member_id)
+            .bind(private_id)
+            .execute(&pool).await.unwrap();
+        assert_eq!(vec![public_id, private_id], list_session_ids(&pool, member_id, false).await);
+
+        // But not how many others are booked on it, unlike its trainer and admins
+        let member = Claims::create(member_id, "member@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let trainer = Claims::create(trainer_id, "trainer@example.com", &None, &vec!["trainer".to_string()], Duration::minutes(1));
+        let admin = Claims::create(0, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let booking_counts = |claims: &Claims| {
+            let mut qb: QueryBuilder<Postgres> = QueryBuilder::default();
+            build_session_query(Some(claims.uid), SessionFilter { include_private: true, private_booking_counts: PrivateBookingCounts::for_claims(claims), ..Default::default() }, &mut qb).unwrap();
+            qb.push(" ORDER BY s.id");
+            qb
+        };
+        for (claims, expected) in [(&member, None), (&trainer, Some(1)), (&admin, Some(1))] {
+            let sessions: Vec<SessionFullRecord> = booking_counts(claims).build_query_as().fetch_all(&pool).await.unwrap();
+            assert_eq!(vec![Some(0), expected], sessions.iter().map(|s| s.booking_count).collect::<Vec<_>>());
+            let count = _get_session_booking_count(&pool, claims, private_id).await.unwrap();
+            assert_eq!(expected, count.booking_count);
+        }
+    }
+
+    #[sqlx::test]
+    async fn explain_sessions_query(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let admin = Claims::create(1, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let listing = _list_sessions(&pool, &admin, Some("2024-01-01T00:00:00Z".to_string()), None, vec![], None, true).await.unwrap();
+        assert!(matches!(listing, SessionListing::Explained(_)));
+
+        // The SQL reveals the schema, so members cannot see it
+        let member = Claims::create(2, "member@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let result = _list_sessions(&pool, &member, Some("2024-01-01T00:00:00Z".to_string()), None, vec![], None, true).await;
+        assert_eq!(Status::Forbidden, result.err().unwrap().0);
+        let listing = _list_sessions(&pool, &member, Some("2024-01-01T00:00:00Z".to_string()), None, vec![], None, false).await.unwrap();
+        assert!(matches!(listing, SessionListing::Sessions(_)));
+    }
+
+    #[sqlx::test]
+    async fn filter_sessions_by_trainers(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let mut trainer_ids = Vec::new();
+        let mut session_ids = Vec::new();
+        for email in ["one@example.com", "two@example.com", "three@example.com"] {
+            let trainer_id = create_person(&pool, email, "trainer").await;
+            let session_id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, trainer) \
+                    select now(), 60, id, $1 from session_type where name = 'HIIT' returning id")
+                .bind(trainer_id)
+                .fetch_one(&pool).await.unwrap();
+            trainer_ids.push(trainer_id);
+            session_ids.push(session_id);
+        }
+
+        let list_session_ids = |trainer_ids: Vec<i64>| {
+            let pool = pool.clone();
+            async move {
+                let mut qb: QueryBuilder<Postgres> = QueryBuilder::default();
+                build_session_query(None, SessionFilter { trainer_ids, ..Default::default() }, &mut qb).unwrap();
+                qb.push(" ORDER BY s.id");
+                let sessions: Vec<SessionFullRecord> = qb.build_query_as().fetch_all(&pool).await.unwrap();
+                sessions.iter().map(|s| s.id).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(vec![session_ids[0], session_ids[2]], list_session_ids(vec![trainer_ids[0], trainer_ids[2]]).await);
+        assert_eq!(vec![session_ids[1]], list_session_ids(vec![trainer_ids[1]]).await);
+        assert_eq!(session_ids, list_session_ids(vec![]).await);
+    }
+
+    #[sqlx::test]
+    async fn search_sessions_by_text(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let trainer_id: i64 = query_scalar("insert into person (name, email, roles) values ('Alice Jones', 'alice@example.com', 'trainer') returning id")
+            .fetch_one(&pool).await.unwrap();
+        let member_id = create_person(&pool, "member@example.com", "member").await;
+        let hiit_id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, location, trainer, notes) \
+                select now(), 60, t.id, loc.id, $1, 'Bring a mat' from session_type AS t, location AS loc \
+                where t.name = 'HIIT' and loc.name = 'Oak Hill Park' returning id")
+            .bind(trainer_id)
+            .fetch_one(&pool).await.unwrap();
+        let strong_id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, location, notes) \
+                select now(), 60, t.id, loc.id, '50% off' from session_type AS t, location AS loc \
+                where t.name = 'Strong' and loc.name = 'Trent Park' returning id")
+            .fetch_one(&pool).await.unwrap();
+        let client = test_client(pool.clone(), routes![super::search_sessions]).await;
+        let member = || Claims::create(member_id, "member@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let search = |q: &str| {
+            let request = client.get(format!("/sessions/search?q={}", urlencoding::encode(q))).header(bearer(member()));
+            async move {
+                let sessions: Vec<Value> = request.dispatch().await.into_json().await.unwrap();
+                sessions.iter().map(|s| s["id"].as_i64().unwrap()).collect::<Vec<_>>()
+            }
+        };
+
+        // Notes, type, location and trainer names all match, ignoring case
+        assert_eq!(vec![hiit_id], search("MAT").await);
+        assert_eq!(vec![strong_id], search("strong").await);
+        assert_eq!(vec![strong_id], search("trent").await);
+        assert_eq!(vec![hiit_id], search("alice jones").await);
+        // Wildcards are matched literally
+        assert_eq!(vec![strong_id], search("50%").await);
+        assert_eq!(Vec::<i64>::new(), search("a_i").await);
+        // Queries too short to be useful match nothing rather than everything
+        assert_eq!(Vec::<i64>::new(), search(" a ").await);
+    }
+
+    #[sqlx::test]
+    async fn list_session_summaries(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let member_id = create_person(&pool, "member@example.com", "member").await;
+        let other_id = create_person(&pool, "other@example.com", "member").await;
+        let full_id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, location, max_booking_count) \
+                select now() + interval '1 day', 60, t.id, loc.id, 2 from session_type AS t, location AS loc \
+                where t.name = 'HIIT' and loc.name = 'Trent Park' returning id")
+            .fetch_one(&pool).await.unwrap();
+        let unlimited_id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type) \
+                select now() + interval '2 days', 60, id from session_type where name = 'Strong' returning id")
+            .fetch_one(&pool).await.unwrap();
+        query("insert into session (datetime, duration_mins, session_type, private) \
+                select now() + interval '3 days', 60, id, true from session_type where name = 'Strong'")
+            .execute(&pool).await.unwrap();
+        for person_id in [member_id, other_id] {
+            query("insert into booking (person_id, session_id) values ($1, $2)")
+                .bind(person_id)
+                .bind(full_id)
+                .execute(&pool).await.unwrap();
+        }
+        let client = test_client(pool.clone(), routes![super::list_session_summaries]).await;
+        let member = Claims::create(member_id, "member@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
+
+        // Private sessions are left out, and unlimited sessions have no spaces left to count down
+        let sessions: Vec<Value> = client.get("/sessions/summary").header(bearer(member)).dispatch().await.into_json().await.unwrap();
+        assert_eq!(vec![full_id, unlimited_id], sessions.iter().map(|s| s["id"].as_i64().unwrap()).collect::<Vec<_>>());
+        assert_eq!("HIIT", sessions[0]["session_type_name"]);
+        assert_eq!("Trent Park", sessions[0]["location_name"]);
+        assert_eq!(0, sessions[0]["spaces_left"]);
+        assert_eq!(true, sessions[0]["booked"]);
+        assert_eq!(Value::Null, sessions[1]["location_name"]);
+        assert_eq!(Value::Null, sessions[1]["spaces_left"]);
+        assert_eq!(false, sessions[1]["booked"]);
+    }
+
+    #[sqlx::test]
+    async fn waitlist_counts_in_listings(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let member_ids = [create_person(&pool, "one@example.com", "member").await, create_person(&pool, "two@example.com", "member").await];
+        let mut session_ids = Vec::new();
+        for waiting in [2, 0] {
+            let session_id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, max_booking_count) \
+                    select now(), 60, id, 0 from session_type where name = 'HIIT' returning id")
+                .fetch_one(&pool).await.unwrap();
+            for person_id in &member_ids[..waiting] {
+                query("insert into waitlist (person_id, session_id) values ($1, $2)")
+                    .bind(person_id)
+                    .bind(session_id)
+                    .execute(&pool).await.unwrap();
+            }
+            session_ids.push(session_id);
+        }
+
+        let mut qb: QueryBuilder<Postgres> = QueryBuilder::default();
+        build_session_query(Some(member_ids[0]), SessionFilter::default(), &mut qb).unwrap();
+        qb.push(" ORDER BY s.id");
+        let sessions: Vec<SessionFullRecord> = qb.build_query_as().fetch_all(&pool).await.unwrap();
+        assert_eq!(vec![(session_ids[0], 2), (session_ids[1], 0)], sessions.iter().map(|s| (s.id, s.waitlist_count)).collect::<Vec<_>>());
+    }
+
+    #[sqlx::test]
+    async fn session_message_recipients_by_role_and_prefs(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let trainer_id = create_person(&pool, "trainer@example.com", "trainer").await;
+        let other_trainer_id = create_person(&pool, "other@example.com", "trainer").await;
+        let member_id = create_person(&pool, "member@example.com", "member").await;
+        let opted_out_id = create_person(&pool, "quiet@example.com", "member").await;
+        query("update person set notification_prefs = '{\"announcements\": false}' where id = $1")
+            .bind(opted_out_id)
+            .execute(&pool).await.unwrap();
+        let session_id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, trainer) \
+                select now(), 60, id, $1 from session_type where name = 'HIIT' returning id")
+            .bind(trainer_id)
+            .fetch_one(&pool).await.unwrap();
+        for person_id in [member_id, opted_out_id] {
+            query("insert into booking (person_id, session_id) values ($1, $2)")
+                .bind(person_id)
+                .bind(session_id)
+                .execute(&pool).await.unwrap();
+        }
+
+        // Only admins and the session's own trainer can message its members
+        let other_trainer = Claims::create(other_trainer_id, "other@example.com", &None, &vec!["trainer".to_string()], Duration::minutes(1));
+        assert_eq!(Status::Forbidden, session_message_recipients(&pool, &other_trainer, session_id, "Towels").await.err().unwrap().0);
+        let trainer = Claims::create(trainer_id, "trainer@example.com", &None, &vec!["trainer".to_string()], Duration::minutes(1));
+        let recipients = session_message_recipients(&pool, &trainer, session_id, "Towels").await.unwrap();
+        assert_eq!(vec![member_id], recipients.iter().map(|r| r.id).collect::<Vec<_>>());
+
+        // Another message cannot be sent straight after the last, which was recorded
+        assert_eq!(Status::TooManyRequests, session_message_recipients(&pool, &trainer, session_id, "Water").await.err().unwrap().0);
+        let subjects: Vec<String> = query_scalar("select subject from session_message where session_id = $1")
+            .bind(session_id)
+            .fetch_all(&pool).await.unwrap();
+        assert_eq!(vec!["Towels".to_string()], subjects);
+    }
+
+    #[sqlx::test]
+    async fn group_sessions_by_local_day(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        // 23:30 UTC is already the next day in London in summer
+        for datetime in ["2024-06-01T09:00:00Z", "2024-06-01T23:30:00Z", "2024-06-02T18:00:00Z", "2024-06-04T08:00:00Z"] {
+            query("insert into session (datetime, duration_mins, session_type) select $1::timestamptz, 60, id from session_type where name = 'HIIT'")
+                .bind(datetime)
+                .execute(&pool).await.unwrap();
+        }
+        let mut qb: QueryBuilder<Postgres> = QueryBuilder::default();
+        build_session_query(None, SessionFilter::default(), &mut qb).unwrap();
+        qb.push(" ORDER BY s.datetime ASC");
+        let sessions: Vec<SessionFullRecord> = qb.build_query_as().fetch_all(&pool).await.unwrap();
+
+        let days = group_sessions_by_day(sessions, &"Europe/London".parse().unwrap());
+        let summary: Vec<(String, usize)> = days.iter().map(|d| (d.date.to_string(), d.sessions.len())).collect();
+        assert_eq!(vec![("2024-06-01".to_string(), 1), ("2024-06-02".to_string(), 2), ("2024-06-04".to_string(), 1)], summary);
+    }
+
+    #[sqlx::test]
+    async fn reassign_trainer(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let trainer_id = create_person(&pool, "trainer@example.com", "trainer").await;
+        let cover_id = create_person(&pool, "cover@example.com", "member,trainer").await;
+        let member_id = create_person(&pool, "member@example.com", "member").await;
+        let session_type_id: i32 = query_scalar("select id from session_type where name = 'HIIT'")
+            .fetch_one(&pool).await.unwrap();
+        let session_id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, trainer) values (now(), 60, $1, $2) returning id")
+            .bind(session_type_id)
+            .bind(trainer_id)
+            .fetch_one(&pool).await.unwrap();
+        let admin = Claims::create(member_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let session_trainer = |pool: PgPool| async move {
+            query_scalar::<_, i64>("select trainer from session where id = $1").bind(session_id).fetch_one(&pool).await.unwrap()
+        };
+
+        // Only trainers can be assigned
+        let result = _reassign_trainer(&pool, &admin, session_id, TrainerReassignment { trainer_id: member_id, override_qualification: true }).await;
+        assert_eq!(Status::BadRequest, result.err().unwrap().0);
+
+        // The cover trainer is not qualified unless overridden
+        let result = _reassign_trainer(&pool, &admin, session_id, TrainerReassignment { trainer_id: cover_id, override_qualification: false }).await;
+        assert_eq!(Status::BadRequest, result.err().unwrap().0);
+        assert_eq!(trainer_id, session_trainer(pool.clone()).await);
+
+        query("insert into trainer_qualification (trainer_id, session_type_id) values ($1, $2)")
+            .bind(cover_id)
+            .bind(session_type_id)
+            .execute(&pool).await.unwrap();
+        _reassign_trainer(&pool, &admin, session_id, TrainerReassignment { trainer_id: cover_id, override_qualification: false }).await.unwrap();
+        assert_eq!(cover_id, session_trainer(pool.clone()).await);
+
+        // Only admins can reassign trainers
+        let trainer = Claims::create(trainer_id, "trainer@example.com", &None, &vec!["trainer".to_string()], Duration::minutes(1));
+        let result = _reassign_trainer(&pool, &trainer, session_id, TrainerReassignment { trainer_id, override_qualification: true }).await;
+        assert_eq!(Status::Forbidden, result.err().unwrap().0);
+    }
+
+    #[sqlx::test]
+    async fn trainer_qualification_required(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let trainer_id = create_person(&pool, "trainer@example.com", "trainer").await;
+        let admin_id = create_person(&pool, "admin@example.com", "admin").await;
+        let qualified_type_id: i32 = query_scalar("select id from session_type where name = 'HIIT'")
+            .fetch_one(&pool).await.unwrap();
+        let unqualified_type_id: i32 = query_scalar("select id from session_type where name = 'Strong'")
+            .fetch_one(&pool).await.unwrap();
+        query("insert into trainer_qualification (trainer_id, session_type_id) values ($1, $2)")
+            .bind(trainer_id)
+            .bind(qualified_type_id)
+            .execute(&pool).await.unwrap();
+
+        let trainer_claim = Claims::create(trainer_id, "trainer@example.com", &None, &vec!["trainer".to_string()], Duration::minutes(1));
+        assert_eq!(Ok(()), new_session(qualified_type_id, trainer_id).validate(&pool, &London, &trainer_claim, &Config::default()).await);
+        assert_eq!(
+            Err(Custom(Status::BadRequest, format!("Trainer {} is not qualified for session type {}.", trainer_id, unqualified_type_id))),
+            new_session(unqualified_type_id, trainer_id).validate(&pool, &London, &trainer_claim, &Config::default()).await);
+
+        // Admins can override the qualification check
+        let admin_claim = Claims::create(admin_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        assert_eq!(Ok(()), new_session(unqualified_type_id, trainer_id).validate(&pool, &London, &admin_claim, &Config::default()).await);
+    }
+
+    #[sqlx::test]
+    async fn filter_sessions_by_tag(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let admin_id = create_person(&pool, "admin@example.com", "admin").await;
+        let trainer_id = create_person(&pool, "trainer@example.com", "trainer").await;
+        let session_type_id: i32 = query_scalar("select id from session_type where name = 'HIIT'")
+            .fetch_one(&pool).await.unwrap();
+        let admin = Claims::create(admin_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+
+        // Tags are stored trimmed and in lower case, without blanks or duplicates
+        let tags = vec![" Beginner ".to_string(), "beginner".to_string(), "".to_string(), "Low Impact".to_string()];
+        _create_session(&pool, &London, &Config::default(), &admin, &NewSession { tags, ..new_session(session_type_id, trainer_id) }).await.unwrap();
+        _create_session(&pool, &London, &Config::default(), &admin, &NewSession { tags: vec!["advanced".to_string()], ..new_session(session_type_id, trainer_id) }).await.unwrap();
+        let stored: Vec<Vec<String>> = query_scalar("select tags from session order by id").fetch_all(&pool).await.unwrap();
+        assert_eq!(vec![vec!["beginner".to_string(), "low impact".to_string()], vec!["advanced".to_string()]], stored);
+
+        let list_tags = |tag: &str| {
+            let (pool, admin, tag) = (&pool, &admin, Some(tag.to_string()));
+            async move {
+                let SessionListing::Sessions(sessions) = _list_sessions(pool, admin, None, None, vec![], tag, false).await.unwrap() else { panic!("expected sessions") };
+                sessions.iter().map(|s| s.tags.clone()).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(vec![vec!["beginner".to_string(), "low impact".to_string()]], list_tags(" BEGINNER").await);
+        assert_eq!(vec![vec!["advanced".to_string()]], list_tags("Advanced").await);
+        assert!(list_tags("intermediate").await.is_empty());
+    }
+
+    #[sqlx::test]
+    async fn create_session_on_the_minute(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let admin_id = create_person(&pool, "admin@example.com", "admin").await;
+        let trainer_id = create_person(&pool, "trainer@example.com", "trainer").await;
+        let session_type_id: i32 = query_scalar("select id from session_type where name = 'HIIT'")
+            .fetch_one(&pool).await.unwrap();
+        let admin = Claims::create(admin_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+
+        // Seconds and below are dropped
+        let datetime = "2030-01-07T10:02:37.250Z".parse::<DateTime<Utc>>().unwrap();
+        _create_session(&pool, &London, &Config::default(), &admin, &NewSession { datetime, ..new_session(session_type_id, trainer_id) }).await.unwrap();
+        let created: DateTime<Utc> = query_scalar("select datetime from session").fetch_one(&pool).await.unwrap();
+        assert_eq!("2030-01-07T10:02:00Z".parse::<DateTime<Utc>>().unwrap(), created);
+
+        // Sessions can be required to start on a grid
+        let config = Config { session_time_grid_mins: Some(5), ..Default::default() };
+        let result = _create_session(&pool, &London, &config, &admin, &NewSession { datetime, ..new_session(session_type_id, trainer_id) }).await;
+        assert_eq!(Custom(Status::BadRequest, "Sessions must start on a multiple of 5 minutes past the hour.".to_string()), result.err().unwrap());
+        let datetime = "2030-01-07T10:05:37Z".parse::<DateTime<Utc>>().unwrap();
+        _create_session(&pool, &London, &config, &admin, &NewSession { datetime, ..new_session(session_type_id, trainer_id) }).await.unwrap();
+
+        // The grid is in local time, which can be offset from UTC by part of an hour
+        let config = Config { session_time_grid_mins: Some(30), ..Default::default() };
+        let datetime = "2030-01-07T04:15:00Z".parse::<DateTime<Utc>>().unwrap();
+        _create_session(&pool, &Kathmandu, &config, &admin, &NewSession { datetime, ..new_session(session_type_id, trainer_id) }).await.unwrap();
+        let result = _create_session(&pool, &London, &config, &admin, &NewSession { datetime, ..new_session(session_type_id, trainer_id) }).await;
+        assert_eq!(Status::BadRequest, result.err().unwrap().0);
+    }
+
+    #[sqlx::test]
+    async fn overlapping_sessions_within_location_capacity(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let location_id: i32 = query_scalar("update location set capacity = 20 where name = 'Oak Hill Park' returning id").fetch_one(&pool).await.unwrap();
+
+        let admin_id = create_person(&pool, "admin@example.org", "admin").await;
+        let admin = Claims::create(admin_id, "admin@example.org", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let session_type_id: i32 = query_scalar("select id from session_type where name = 'HIIT'").fetch_one(&pool).await.unwrap();
+        let ten_am = "2030-01-07T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let session = |datetime: DateTime<Utc>, max_bookings: Option<i64>| NewSession {
+            datetime,
+            location_id: Some(location_id),
+            max_bookings,
+            ..new_session(session_type_id, admin_id)
+        };
+        let config = Config { enforce_location_capacity: true, ..Default::default() };
+        _create_session(&pool, &London, &config, &admin, &session(ten_am, Some(12))).await.unwrap();
+
+        // Together with the first session, these would need room for 22 or, with no limit, the whole location
+        let half_past_ten = ten_am + Duration::minutes(30);
+        for max_bookings in [Some(10), None] {
+            let result = _create_session(&pool, &London, &config, &admin, &session(half_past_ten, max_bookings)).await;
+            assert_eq!(Status::Conflict, result.err().unwrap().0);
+        }
+
+        // But fit if they are small enough, or after the earlier sessions have ended
+        _create_session(&pool, &London, &config, &admin, &session(half_past_ten, Some(8))).await.unwrap();
+        _create_session(&pool, &London, &config, &admin, &session(half_past_ten + Duration::hours(1), None)).await.unwrap();
+
+        // Unless enforced, the location can be overfilled
+        _create_session(&pool, &London, &Config::default(), &admin, &session(half_past_ten, Some(10))).await.unwrap();
+        let count: i64 = query_scalar("select count(*) from session where location = $1").bind(location_id).fetch_one(&pool).await.unwrap();
+        assert_eq!(4, count);
+    }
+
+    #[sqlx::test]
+    async fn sessions_needing_attention(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let admin_id = create_person(&pool, "admin@example.org", "admin").await;
+        let mut member_ids = Vec::new();
+        for email in ["first@example.org", "second@example.org", "third@example.org"] {
+            member_ids.push(create_person(&pool, email, "member").await);
+        }
+        // Sessions with their start, capacity, bookings and waitlist
+        let mut session_ids = Vec::new();
+        for (hours, max, booked, waiting) in [(1, Some(4), 2, 0), (2, Some(10), 3, 0), (3, None, 1, 1), (-2, Some(1), 1, 0)] {
+            let id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, max_booking_count) \
+                    select now() + $1 * interval '1 hour', 60, id, $2 from session_type where name = 'HIIT' returning id")
+                .bind(hours)
+                .bind(max)
+                .fetch_one(&pool).await.unwrap();
+            for person_id in &member_ids[..booked] {
+                query("insert into booking (person_id, session_id) values ($1, $2)").bind(person_id).bind(id).execute(&pool).await.unwrap();
+            }
+            for person_id in &member_ids[..waiting] {
+                query("insert into waitlist (person_id, session_id) values ($1, $2)").bind(person_id).bind(id).execute(&pool).await.unwrap();
+            }
+            session_ids.push(id);
+        }
+
+        // Admins only
+        let member = Claims::create(member_ids[0], "first@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        assert_eq!(Status::Forbidden, _list_sessions_needing_attention(&pool, &Config::default(), &member, None, None).await.err().unwrap().0);
+
+        // Nearly full or with a waitlist, and not past
+        let admin = Claims::create(admin_id, "admin@example.org", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let sessions = _list_sessions_needing_attention(&pool, &Config::default(), &admin, None, None).await.unwrap();
+        assert_eq!(vec![session_ids[0], session_ids[2]], sessions.iter().map(|s| s.id).collect::<Vec<_>>());
+        assert_eq!(1, sessions[1].waitlist_count);
+
+        // The threshold is configurable
+        let config = Config { attention_spaces_threshold: 7, ..Default::default() };
+        let sessions = _list_sessions_needing_attention(&pool, &config, &admin, None, None).await.unwrap();
+        assert_eq!(vec![session_ids[0], session_ids[1], session_ids[2]], sessions.iter().map(|s| s.id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn hex_colors() {
+        assert!(is_hex_color("#1e90ff"));
+        assert!(is_hex_color("#FFF"));
+        assert!(!is_hex_color("1e90ff"));
+        assert!(!is_hex_color("#1e90f"));
+        assert!(!is_hex_color("#ggg"));
+        assert!(!is_hex_color("blue"));
+    }
+
+    #[sqlx::test]
+    async fn preview_session_capacity(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let admin_id = create_person(&pool, "admin@example.com", "admin").await;
+        let member_ids = [create_person(&pool, "one@example.com", "member").await, create_person(&pool, "two@example.com", "member").await];
+        let session_id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, max_booking_count) \
+                select now() + interval '1 day', 60, id, 5 from session_type where name = 'HIIT' returning id")
+            .fetch_one(&pool).await.unwrap();
+        for person_id in member_ids {
+            query("insert into booking (person_id, session_id) values ($1, $2)")
+                .bind(person_id)
+                .bind(session_id)
+                .execute(&pool).await.unwrap();
+        }
+        let client = test_client(pool.clone(), routes![super::preview_session_capacity]).await;
+        let admin = || Claims::create(admin_id, "admin@example.com", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let preview = |max: i64| client.get(format!("/sessions/{}/capacity_preview?max={}", session_id, max)).header(bearer(admin()));
+
+        for (max, over_capacity) in [(1, 1), (2, 0), (0, 2)] {
+            let capacity: Value = preview(max).dispatch().await.into_json().await.unwrap();
+            assert_eq!((2, max, over_capacity), (capacity["booking_count"].as_i64().unwrap(), capacity["proposed_max_booking_count"].as_i64().unwrap(), capacity["over_capacity"].as_i64().unwrap()));
+        }
+        assert_eq!(Status::BadRequest, preview(-1).dispatch().await.status());
+
+        // Nothing is changed
+        let max_booking_count: Option<i64> = query_scalar("select max_booking_count from session where id = $1")
+            .bind(session_id)
+            .fetch_one(&pool).await.unwrap();
+        assert_eq!(Some(5), max_booking_count);
+
+        let member = Claims::create(member_ids[0], "one@example.com", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let response = client.get(format!("/sessions/{}/capacity_preview?max=1", session_id)).header(bearer(member)).dispatch().await;
+        assert_eq!(Status::Forbidden, response.status());
+        let response = client.get(format!("/sessions/{}/capacity_preview?max=1", session_id + 1)).header(bearer(admin())).dispatch().await;
+        assert_eq!(Status::NotFound, response.status());
+    }
+
+    #[sqlx::test]
+    async fn set_session_type_color(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let admin_id = create_person(&pool, "admin@example.org", "admin").await;
+        let admin = Claims::create(admin_id, "admin@example.org", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let session_type_id: i32 = query_scalar("select id from session_type where name = 'HIIT'").fetch_one(&pool).await.unwrap();
+        let session_id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type) values (now(), 60, $1) returning id")
+            .bind(session_type_id)
+            .fetch_one(&pool).await.unwrap();
+
+        _set_session_type_color(&pool, &admin, session_type_id, &SessionTypeColor { color: Some(" #1E90FF ".to_string()) }).await.unwrap();
+        let mut qb: QueryBuilder<Postgres> = QueryBuilder::default();
+        build_session_query(None, SessionFilter { session_id: Some(session_id), include_private: true, ..Default::default() }, &mut qb).unwrap();
+        let session: SessionFullRecord = qb.build_query_as().fetch_one(&pool).await.unwrap();
+        assert_eq!(Some("#1e90ff".to_string()), session.session_type.color);
+
+        let result = _set_session_type_color(&pool, &admin, session_type_id, &SessionTypeColor { color: Some("blue".to_string()) }).await;
+        assert_eq!(Status::BadRequest, result.err().unwrap().0);
+        let result = _set_session_type_color(&pool, &admin, session_type_id + 100, &SessionTypeColor { color: None }).await;
+        assert_eq!(Status::NotFound, result.err().unwrap().0);
+
+        _set_session_type_color(&pool, &admin, session_type_id, &SessionTypeColor { color: None }).await.unwrap();
+        let color: Option<String> = query_scalar("select color from session_type where id = $1").bind(session_type_id).fetch_one(&pool).await.unwrap();
+        assert_eq!(None, color);
+    }
+
+    #[sqlx::test]
+    async fn list_unstaffed_sessions(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        pool.execute("update session_type set requires_trainer = false where name = 'On The Move'").await.unwrap();
+
+        let admin_id = create_person(&pool, "admin@example.org", "admin").await;
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer").await;
+        // Sessions with their start, type and trainer
+        let mut session_ids = Vec::new();
+        for (hours, session_type, trainer) in [(5, "HIIT", None), (1, "Strong", None), (2, "HIIT", Some(trainer_id)), (3, "On The Move", None), (-2, "HIIT", None)] {
+            let id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, trainer) \
+                    select now() + $1 * interval '1 hour', 60, id, $2 from session_type where name = $3 returning id")
+                .bind(hours)
+                .bind(trainer)
+                .bind(session_type)
+                .fetch_one(&pool).await.unwrap();
+            session_ids.push(id);
+        }
+
+        let trainer = Claims::create(trainer_id, "trainer@example.org", &None, &vec!["trainer".to_string()], Duration::minutes(1));
+        assert_eq!(Status::Forbidden, _list_unstaffed_sessions(&pool, &Config::default(), &trainer, None, None).await.err().unwrap().0);
+
+        // Future sessions that need a trainer, soonest first
+        let admin = Claims::create(admin_id, "admin@example.org", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let sessions = _list_unstaffed_sessions(&pool, &Config::default(), &admin, None, None).await.unwrap();
+        assert_eq!(vec![session_ids[1], session_ids[0]], sessions.iter().map(|s| s.id).collect::<Vec<_>>());
+    }
+
+    #[sqlx::test]
+    async fn cancel_sessions_in_closure_period(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let admin_id = create_person(&pool, "admin@example.org", "admin").await;
+        let member_id = create_person(&pool, "member@example.org", "member").await;
+        let mut session_ids = Vec::new();
+        for days in [-1, 1, 5, 20] {
+            let id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, cost) \
+                    select $1, 60, id, 2 from session_type where name = 'HIIT' returning id")
+                .bind(Utc::now() + Duration::days(days))
+                .fetch_one(&pool).await.unwrap();
+            session_ids.push(id);
+        }
+        for session_id in &session_ids {
+            query("insert into booking (person_id, session_id, credits_used, attended) values ($1, $2, 2, $3)")
+                .bind(member_id)
+                .bind(session_id)
+                .bind(*session_id == session_ids[0])
+                .execute(&pool).await.unwrap();
+        }
+        // Bookings without any credits used recorded have nothing to refund
+        let other_member_id = create_person(&pool, "other@example.org", "member").await;
+        query("insert into booking (person_id, session_id, credits_used) values ($1, $2, NULL)")
+            .bind(other_member_id)
+            .bind(session_ids[1])
+            .execute(&pool).await.unwrap();
+        let from = || Some((Utc::now() - Duration::days(2)).to_rfc3339());
+        let to = || Some((Utc::now() + Duration::days(10)).to_rfc3339());
+
+        // Admins only, and only with confirmation
+        let member = Claims::create(member_id, "member@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        assert_eq!(Status::Forbidden, _cancel_sessions_in_range(&pool, &member, from(), to(), Some(true)).await.err().unwrap().0);
+        let admin = Claims::create(admin_id, "admin@example.org", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        assert_eq!(Status::BadRequest, _cancel_sessions_in_range(&pool, &admin, from(), to(), None).await.err().unwrap().0);
+        assert_eq!(Status::BadRequest, _cancel_sessions_in_range(&pool, &admin, from(), None, Some(true)).await.err().unwrap().0);
+
+        let (result, bookings) = _cancel_sessions_in_range(&pool, &admin, from(), to(), Some(true)).await.unwrap();
+        assert_eq!((2, 3, 4), (result.sessions, result.bookings, result.credits_refunded));
+        assert_eq!(3, bookings.len());
+
+        // The session already held and the one after the closure are left, the member has their credits back
+        // for the cancelled sessions only, and the cancelled bookings are recorded
+        let remaining: Vec<i64> = query_scalar("select id from session order by datetime").fetch_all(&pool).await.unwrap();
+        assert_eq!(vec![session_ids[0], session_ids[3]], remaining);
+        let credits: i16 = query_scalar("select credits from person where id = $1").bind(member_id).fetch_one(&pool).await.unwrap();
+        assert_eq!(4, credits);
+        let cancellations: i64 = query_scalar("select count(*) from cancellation where person_id = $1 and session_id is null and reason like 'Session cancelled: HIIT at %'")
+            .bind(member_id)
+            .fetch_one(&pool).await.unwrap();
+        assert_eq!(2, cancellations);
+    }
+
+    #[sqlx::test]
+    async fn summarise_trainer_sessions(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer").await;
+        let other_trainer_id = create_person(&pool, "other@example.org", "member,trainer").await;
+        let member_ids = [create_person(&pool, "one@example.org", "member").await, create_person(&pool, "two@example.org", "member").await];
+        // Sessions with their start, trainer, duration and bookings as whether each member attended
+        let sessions = [
+            (Duration::days(-20), trainer_id, 60, vec![true]),
+            (Duration::days(-3), trainer_id, 45, vec![true, false]),
+            (Duration::days(-2), trainer_id, 30, vec![]),
+            (Duration::days(-2), other_trainer_id, 60, vec![true, true]),
+            (Duration::days(2), trainer_id, 60, vec![false])
+        ];
+        for (start, trainer, duration_mins, attendance) in sessions {
+            let session_id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, trainer) select $1, $2, id, $3 from session_type where name = 'HIIT' returning id")
+                .bind(Utc::now() + start)
+                .bind(duration_mins)
+                .bind(trainer)
+                .fetch_one(&pool).await.unwrap();
+            for (person_id, attended) in member_ids.iter().zip(attendance) {
+                query("insert into booking (person_id, session_id, attended) values ($1, $2, $3)")
+                    .bind(person_id)
+                    .bind(session_id)
+                    .bind(attended)
+                    .execute(&pool).await.unwrap();
+            }
+        }
+
+        // The last two weeks leave out the older session, the future one and those of other trainers
+        let from = (Utc::now() - Duration::days(14)).fixed_offset();
+        let summary = _get_trainer_summary(&pool, &Config::default(), trainer_id, Some(from), None).await.unwrap();
+        assert_eq!(vec![(2, 1), (0, 0)], summary.sessions.iter().map(|s| (s.booked_count, s.attended_count)).collect::<Vec<_>>());
+        assert_eq!((2, 75, 2, 1), (summary.session_count, summary.total_duration_mins, summary.booked_count, summary.attended_count));
+
+        let summary = _get_trainer_summary(&pool, &Config::default(), trainer_id, None, None).await.unwrap();
+        assert_eq!((3, 135, 3, 2), (summary.session_count, summary.total_duration_mins, summary.booked_count, summary.attended_count));
+    }
+
+    #[sqlx::test]
+    async fn list_trainer_sessions_with_rosters(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer").await;
+        let other_trainer_id = create_person(&pool, "other@example.org", "member,trainer").await;
+        let member_ids: Vec<i64> = query_scalar("insert into person (name, email, roles) values ('Bea', 'bea@example.org', 'member'), ('Abe', 'abe@example.org', 'member') returning id")
+            .fetch_all(&pool).await.unwrap();
+        let mut session_ids = Vec::new();
+        for (trainer, private) in [(trainer_id, false), (trainer_id, true), (other_trainer_id, false)] {
+            let session_id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, trainer, private) \
+                    select now() + interval '1 day', 60, id, $1, $2 from session_type where name = 'HIIT' returning id")
+                .bind(trainer)
+                .bind(private)
+                .fetch_one(&pool).await.unwrap();
+            session_ids.push(session_id);
+        }
+        for person_id in &member_ids {
+            query("insert into booking (person_id, session_id) values ($1, $2)")
+                .bind(person_id)
+                .bind(session_ids[0])
+                .execute(&pool).await.unwrap();
+        }
+        let client = test_client(pool.clone(), routes![super::list_trainer_sessions]).await;
+        let trainer = Claims::create(trainer_id, "trainer@example.org", &None, &vec!["member".to_string(), "trainer".to_string()], Duration::minutes(1));
+        let member = Claims::create(member_ids[0], "bea@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+
+        // The trainer's own sessions, including private ones, each with who is booked on it by name
+        let sessions: Vec<Value> = client.get("/trainers/me/sessions").header(bearer(trainer)).dispatch().await.into_json().await.unwrap();
+        assert_eq!(vec![session_ids[0], session_ids[1]], sessions.iter().map(|s| s["id"].as_i64().unwrap()).collect::<Vec<_>>());
+        assert_eq!(2, sessions[0]["booking_count"]);
+        let roster: Vec<_> = sessions[0]["roster"].as_array().unwrap().iter().map(|r| r["name"].as_str().unwrap()).collect();
+        assert_eq!(vec!["Abe", "Bea"], roster);
+        assert!(sessions[1]["roster"].as_array().unwrap().is_empty());
+
+        assert_eq!(Status::Forbidden, client.get("/trainers/me/sessions").header(bearer(member)).dispatch().await.status());
+    }
+
+    #[sqlx::test]
+    async fn trainer_roster_summary(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer").await;
+        let other_trainer_id = create_person(&pool, "other@example.org", "member,trainer").await;
+        let member_id = create_person(&pool, "member@example.org", "member").await;
+        // Sessions with their start, trainer, capacity and whether the member booked them
+        let sessions = [
+            (Duration::days(-1), trainer_id, Some(10), true),
+            (Duration::days(1), trainer_id, Some(10), true),
+            (Duration::days(2), trainer_id, None, false),
+            (Duration::days(1), other_trainer_id, None, true)
+        ];
+        let mut session_ids = Vec::new();
+        for (start, trainer, max_booking_count, booked) in sessions {
+            let session_id: i64 = query_scalar("insert into session (datetime, duration_mins, session_type, trainer, max_booking_count) select $1, 60, id, $2, $3 from session_type where name = 'HIIT' returning id")
+                .bind(Utc::now() + start)
+                .bind(trainer)
+                .bind(max_booking_count)
+                .fetch_one(&pool).await.unwrap();
+            if booked {
+                query("insert into booking (person_id, session_id) values ($1, $2)")
+                    .bind(member_id)
+                    .bind(session_id)
+                    .execute(&pool).await.unwrap();
+            }
+            session_ids.push(session_id);
+        }
+        let client = test_client(pool.clone(), routes![super::get_trainer_roster_summary]).await;
+        let trainer = Claims::create(trainer_id, "trainer@example.org", &None, &vec!["member".to_string(), "trainer".to_string()], Duration::minutes(1));
+        let member = Claims::create(member_id, "member@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+
+        // Only the trainer's own upcoming sessions are counted
+        let summaries: Vec<Value> = client.get("/trainers/me/roster_summary").header(bearer(trainer)).dispatch().await.into_json().await.unwrap();
+        let counts: Vec<_> = summaries.iter()
+            .map(|s| (s["session_id"].as_i64().unwrap(), s["booked_count"].as_i64().unwrap(), s["max_booking_count"].as_i64()))
+            .collect();
+        assert_eq!(vec![(session_ids[1], 1, Some(10)), (session_ids[2], 0, None)], counts);
+
+        assert_eq!(Status::Forbidden, client.get("/trainers/me/roster_summary").header(bearer(member)).dispatch().await.status());
+    }
+
+    #[sqlx::test]
+    async fn trainer_summary_access(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let member_id = create_person(&pool, "member@example.org", "member").await;
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer").await;
+        let other_trainer_id = create_person(&pool, "other@example.org", "member,trainer").await;
+        let client = test_client(pool.clone(), routes![super::get_my_trainer_summary, super::get_trainer_summary]).await;
+        let member = Claims::create(member_id, "member@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let trainer = || Claims::create(trainer_id, "trainer@example.org", &None, &vec!["member".to_string(), "trainer".to_string()], Duration::minutes(1));
+        let admin = Claims::create(member_id + 100, "admin@example.org", &None, &vec!["admin".to_string()], Duration::minutes(1));
+
+        // Members have no sessions of their own to summarise
+        assert_eq!(Status::Forbidden, client.get("/trainers/me/summary").header(bearer(member)).dispatch().await.status());
+        assert_eq!(Status::Ok, client.get("/trainers/me/summary").header(bearer(trainer())).dispatch().await.status());
+
+        // Only admins can summarise the sessions of other trainers
+        let other_summary = format!("/trainers/{}/summary", other_trainer_id);
+        assert_eq!(Status::Forbidden, client.get(&other_summary).header(bearer(trainer())).dispatch().await.status());
+        assert_eq!(Status::Ok, client.get(&other_summary).header(bearer(admin)).dispatch().await.status());
+    }
+}
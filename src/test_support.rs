@@ -0,0 +1,53 @@
+use std::collections::BTreeMap;
+use chrono::{TimeDelta, Utc};
+use jsonwebtoken::Algorithm;
+use rocket::http::Header;
+use rocket::local::asynchronous::Client;
+use rocket::Route;
+use sqlx::{PgPool, query_scalar};
+
+use crate::{AppState, Config};
+use crate::claims::Claims;
+
+/// The key that access tokens are signed with in tests
+pub(crate) const TEST_ACCESS_TOKEN_KEY: &str = "test access token key";
+
+/// A client for requests to the given routes, with the app's state and error catchers, so that requests pass through
+/// the same guards as they would when deployed
+pub(crate) async fn test_client(pool: PgPool, routes: Vec<Route>) -> Client {
+    let secrets = shuttle_runtime::SecretStore::new(BTreeMap::from([
+        ("ACCESS_TOKEN_KEY".to_string(), TEST_ACCESS_TOKEN_KEY.to_string().into()),
+        ("REFRESH_TOKEN_KEY".to_string(), "test refresh token key".to_string().into())
+    ]));
+    let config = Config::default();
+    let timezone = config.timezone_name.parse().unwrap();
+    let state = AppState { pool, secrets, config, timezone, jwt_algorithm: Algorithm::HS256 };
+    let rocket = rocket::build()
+        .register("/", catchers![crate::bad_request, crate::unauthorized, crate::forbidden, crate::not_found, crate::unprocessable_entity, crate::internal_error])
+        .mount("/", routes)
+        .manage(state);
+    Client::tracked(rocket).await.unwrap()
+}
+
+/// The Authorization header for a request by the given user
+pub(crate) fn bearer(claims: Claims) -> Header<'static> {
+    let token = claims.into_token(TEST_ACCESS_TOKEN_KEY, Algorithm::HS256).unwrap();
+    Header::new("Authorization", format!("Bearer {}", token))
+}
+
+/// Creates a person with the given email and roles, returning their id
+pub(crate) async fn create_person(pool: &PgPool, email: &str, roles: &str) -> i64 {
+    query_scalar("insert into person (name, email, roles) values ('Test User', $1, $2) returning id")
+        .bind(email)
+        .bind(roles)
+        .fetch_one(pool).await.unwrap()
+}
+
+/// Creates a HIIT session at Oak Hill Park starting after the given time from now, returning its id
+pub(crate) async fn create_session(pool: &PgPool, starts_in: TimeDelta) -> i64 {
+    query_scalar("insert into session (datetime, duration_mins, session_type, location) \
+            select $1, 60, t.id, l.id from session_type as t, location as l where t.name = 'HIIT' and l.name = 'Oak Hill Park' \
+            returning id")
+        .bind(Utc::now() + starts_in)
+        .fetch_one(pool).await.unwrap()
+}
@@ -0,0 +1,344 @@
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use rocket::http::Status;
+use rocket::response::status::{Created, Custom, NoContent};
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Serialize;
+use sqlx::{FromRow, PgPool, query, query_as, query_scalar};
+
+use crate::{AppState, Config, db_error};
+use crate::audit;
+use crate::bookings::{book_as_member, BookingCreated, is_session_in_past};
+use crate::claims::Claims;
+
+#[derive(FromRow, Serialize, Debug)]
+pub struct WaitlistEntry {
+    session_id: i64,
+    session_datetime: DateTime<Utc>,
+    session_type_name: String,
+    location_name: Option<String>,
+    joined: DateTime<Utc>,
+    /// Place in the queue for the session, starting from 1
+    position: i64
+}
+
+/// The sessions that the caller, or for admins the given person, is waiting for a space on
+#[get("/waitlist/mine?<person_id>")]
+pub async fn list_my_waitlist(state: &State<AppState>, claim: Claims, person_id: Option<i64>) -> Result<Json<Vec<WaitlistEntry>>, Custom<String>> {
+    _list_my_waitlist(&state.pool, &claim, person_id).await
+}
+
+async fn _list_my_waitlist(pool: &PgPool, claim: &Claims, person_id: Option<i64>) -> Result<Json<Vec<WaitlistEntry>>, Custom<String>> {
+    let person_id = person_id.unwrap_or(claim.uid);
+    if person_id != claim.uid {
+        claim.assert_roles_contains("admin")?;
+    }
+    waitlist_of(pool, person_id).await
+        .map(Json)
+        .map_err(db_error)
+}
+
+/// The sessions that a person is waiting for a space on, soonest first
+pub(crate) async fn waitlist_of(pool: &PgPool, person_id: i64) -> Result<Vec<WaitlistEntry>, sqlx::Error> {
+    query_as("SELECT w.session_id, s.datetime AS session_datetime, t.name AS session_type_name, \
+            loc.name AS location_name, w.joined, w.position \
+        FROM (SELECT person_id, session_id, joined, row_number() OVER (PARTITION BY session_id ORDER BY joined, person_id) AS position FROM waitlist) AS w \
+        JOIN session AS s ON w.session_id = s.id \
+        JOIN session_type AS t ON s.session_type = t.id \
+        LEFT JOIN location AS loc ON s.location = loc.id \
+        WHERE w.person_id = $1 \
+        ORDER BY s.datetime")
+        .bind(person_id)
+        .fetch_all(pool)
+        .await
+}
+
+#[derive(FromRow)]
+struct WaitlistSession {
+    datetime: DateTime<Utc>,
+    private: bool,
+    max_booking_count: Option<i64>,
+    booking_count: i64,
+    max_waitlist_count: Option<i64>,
+    waitlist_count: i64,
+    booked: bool
+}
+
+/// Adds the caller to the waitlist for a full session, unless the waitlist is already full too
+#[post("/waitlist/<session_id>")]
+pub async fn join_waitlist(state: &State<AppState>, claim: Claims, session_id: i64) -> Result<NoContent, Custom<String>> {
+    _join_waitlist(&state.pool, &claim, session_id).await
+}
+
+async fn _join_waitlist(pool: &PgPool, claim: &Claims, session_id: i64) -> Result<NoContent, Custom<String>> {
+    let mut tx = pool.begin()
+        .await
+        .map_err(db_error)?;
+    // Lock the session so that concurrent joins cannot both take the last place on the waitlist
+    let session: WaitlistSession = query_as("SELECT s.datetime, s.private, s.max_booking_count, s.max_waitlist_count, \
+            (SELECT COUNT(*) FROM booking WHERE booking.session_id = s.id) AS booking_count, \
+            (SELECT COUNT(*) FROM waitlist WHERE waitlist.session_id = s.id) AS waitlist_count, \
+            EXISTS (SELECT 1 FROM booking WHERE booking.session_id = s.id AND booking.person_id = $2) AS booked \
+            FROM session AS s WHERE s.id = $1 FOR NO KEY UPDATE")
+        .bind(session_id)
+        .bind(claim.uid)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("no session with id {}", session_id)))?;
+    // Members are booked onto private sessions by admins, so cannot wait for them either
+    if session.private && !claim.has_role("admin") {
+        info!("person id {} attempted to join the waitlist for private session id {}; denied: missing admin role", claim.uid, session_id);
+        return Err(Custom(Status::Forbidden, "Session is private: only admins can book members onto it.".to_string()));
+    }
+    if is_session_in_past(session.datetime) {
+        return Err(Custom(Status::Forbidden, "Cannot join the waitlist for a past session.".to_string()));
+    }
+    if session.booked {
+        return Err(Custom(Status::Conflict, "Session is already booked.".to_string()));
+    }
+    if session.max_booking_count.is_none_or(|max_booking_count| session.booking_count < max_booking_count) {
+        return Err(Custom(Status::Conflict, "Session has space: book it instead.".to_string()));
+    }
+    if let Some(max_waitlist_count) = session.max_waitlist_count {
+        if session.waitlist_count >= max_waitlist_count {
+            return Err(Custom(Status::Conflict, format!("The waitlist for this session is full: {} member(s) waiting.", max_waitlist_count)));
+        }
+    }
+
+    let inserted = query("INSERT INTO waitlist (person_id, session_id) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+        .bind(claim.uid)
+        .bind(session_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    if inserted.rows_affected() == 0 {
+        return Err(Custom(Status::Conflict, "Already on the waitlist for this session.".to_string()));
+    }
+    tx.commit()
+        .await
+        .map_err(db_error)?;
+    info!("person id {} joined the waitlist for session id {}", claim.uid, session_id);
+    Ok(NoContent)
+}
+
+#[derive(FromRow, Serialize, Debug)]
+pub struct SessionWaitlistEntry {
+    person_id: i64,
+    name: String,
+    email: String,
+    phone: Option<String>,
+    joined: DateTime<Utc>,
+    /// Place in the queue, starting from 1
+    position: i64
+}
+
+/// Checks that the caller is an admin or the session's trainer, who manage its waitlist
+async fn assert_session_staff(pool: &PgPool, claim: &Claims, session_id: i64) -> Result<(), Custom<String>> {
+    let trainer_id: Option<i64> = query_scalar("SELECT trainer FROM session WHERE id = $1")
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("no session with id {}", session_id)))?;
+    if !claim.has_role("admin") && trainer_id != Some(claim.uid) {
+        return Err(Custom(Status::Forbidden, "Only admins and the session's trainer can manage its waitlist.".to_string()));
+    }
+    Ok(())
+}
+
+/// The members waiting for a space on a session, in the order they joined
+#[get("/sessions/<session_id>/waitlist")]
+pub async fn list_session_waitlist(state: &State<AppState>, claim: Claims, session_id: i64) -> Result<Json<Vec<SessionWaitlistEntry>>, Custom<String>> {
+    _list_session_waitlist(&state.pool, &claim, session_id).await
+}
+
+async fn _list_session_waitlist(pool: &PgPool, claim: &Claims, session_id: i64) -> Result<Json<Vec<SessionWaitlistEntry>>, Custom<String>> {
+    assert_session_staff(pool, claim, session_id).await?;
+    let entries: Vec<SessionWaitlistEntry> = query_as("SELECT w.person_id, p.name, p.email, p.phone, w.joined, \
+            row_number() OVER (ORDER BY w.joined, w.person_id) AS position \
+        FROM waitlist AS w \
+        JOIN person AS p ON w.person_id = p.id \
+        WHERE w.session_id = $1 \
+        ORDER BY position")
+        .bind(session_id)
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)?;
+    Ok(Json(entries))
+}
+
+/// Books a member from the waitlist, whatever their place in the queue. They are booked as if they had booked
+/// themselves, so the session must have space and they must have a membership or credits that cover it.
+/// Making the booking takes them off the waitlist.
+#[post("/sessions/<session_id>/waitlist/promote/<person_id>")]
+pub async fn promote_from_waitlist(state: &State<AppState>, claim: Claims, session_id: i64, person_id: i64) -> Result<Created<Json<BookingCreated>>, Custom<String>> {
+    _promote_from_waitlist(&state.pool, &state.timezone, &state.config, &claim, session_id, person_id).await
+}
+
+async fn _promote_from_waitlist(pool: &PgPool, timezone: &Tz, config: &Config, claim: &Claims, session_id: i64, person_id: i64) -> Result<Created<Json<BookingCreated>>, Custom<String>> {
+    assert_session_staff(pool, claim, session_id).await?;
+    let waiting: bool = query_scalar("SELECT EXISTS (SELECT 1 FROM waitlist WHERE session_id = $1 AND person_id = $2)")
+        .bind(session_id)
+        .bind(person_id)
+        .fetch_one(pool)
+        .await
+        .map_err(db_error)?;
+    if !waiting {
+        return Err(Custom(Status::NotFound, format!("person id {} is not on the waitlist for session id {}", person_id, session_id)));
+    }
+
+    let created = book_as_member(pool, timezone, config, person_id, session_id).await?;
+    info!("person id {} promoted person id {} from the waitlist for session id {}", claim.uid, person_id, session_id);
+    audit::record(pool, claim.uid, "promote_waitlist", format!("booking person {} session {}", person_id, session_id)).await;
+    Ok(created)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeDelta};
+    use chrono_tz::Tz;
+    use rocket::http::Status;
+    use rocket::response::status::Custom;
+    use sqlx::{Executor, PgPool, query_scalar};
+    use sqlx::postgres::PgPoolOptions;
+    use crate::claims::Claims;
+    use crate::Config;
+    use crate::test_support::{create_person, create_session};
+    use crate::waitlist::{_join_waitlist, _list_my_waitlist, _list_session_waitlist, _promote_from_waitlist};
+
+    #[sqlx::test]
+    async fn list_waitlist_positions(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let admin_id = create_person(&pool, "admin@example.org", "admin").await;
+        let first_id = create_person(&pool, "first@example.org", "member").await;
+        let member_id = create_person(&pool, "member@example.org", "member").await;
+        let later_session_id = create_session(&pool, TimeDelta::days(2)).await;
+        let earlier_session_id = create_session(&pool, TimeDelta::days(1)).await;
+        pool.execute(format!("insert into waitlist (person_id, session_id, joined) values \
+            ({first_id}, {later_session_id}, now() - interval '1 hour'), \
+            ({member_id}, {later_session_id}, now()), \
+            ({member_id}, {earlier_session_id}, now())").as_str()).await.unwrap();
+
+        // Ordered by session date, with the member's place in each queue
+        let member = Claims::create(member_id, "member@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        let entries = _list_my_waitlist(&pool, &member, None).await.unwrap();
+        assert_eq!(vec![(earlier_session_id, 1), (later_session_id, 2)],
+            entries.iter().map(|e| (e.session_id, e.position)).collect::<Vec<_>>());
+
+        // Only admins can see other members' waitlists
+        assert_eq!(Status::Forbidden, _list_my_waitlist(&pool, &member, Some(first_id)).await.err().unwrap().0);
+        let admin = Claims::create(admin_id, "admin@example.org", &None, &vec!["admin".to_string()], Duration::minutes(1));
+        let entries = _list_my_waitlist(&pool, &admin, Some(first_id)).await.unwrap();
+        assert_eq!(vec![(later_session_id, 1)], entries.iter().map(|e| (e.session_id, e.position)).collect::<Vec<_>>());
+    }
+
+    #[sqlx::test]
+    async fn join_waitlist_up_to_cap(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let session_id = create_session(&pool, TimeDelta::days(1)).await;
+        pool.execute(format!("update session set max_waitlist_count = 2, max_booking_count = 1 where id = {}", session_id).as_str()).await.unwrap();
+        let mut members = Vec::new();
+        for email in ["first@example.org", "second@example.org", "third@example.org"] {
+            let id = create_person(&pool, email, "member").await;
+            members.push(Claims::create(id, email, &None, &vec!["member".to_string()], Duration::minutes(1)));
+        }
+
+        // Only full sessions have a waitlist
+        let result = _join_waitlist(&pool, &members[0], session_id).await;
+        assert_eq!(Custom(Status::Conflict, "Session has space: book it instead.".to_string()), result.err().unwrap());
+        let booked_id = create_person(&pool, "booked@example.org", "member").await;
+        pool.execute(format!("insert into booking (person_id, session_id) values ({booked_id}, {session_id})").as_str()).await.unwrap();
+
+        // Nor can members wait for private sessions
+        pool.execute(format!("update session set private = true where id = {}", session_id).as_str()).await.unwrap();
+        assert_eq!(Status::Forbidden, _join_waitlist(&pool, &members[0], session_id).await.err().unwrap().0);
+        pool.execute(format!("update session set private = false where id = {}", session_id).as_str()).await.unwrap();
+
+        _join_waitlist(&pool, &members[0], session_id).await.unwrap();
+        assert_eq!(Status::Conflict, _join_waitlist(&pool, &members[0], session_id).await.err().unwrap().0);
+        _join_waitlist(&pool, &members[1], session_id).await.unwrap();
+
+        // The waitlist is full
+        let result = _join_waitlist(&pool, &members[2], session_id).await;
+        assert_eq!(Custom(Status::Conflict, "The waitlist for this session is full: 2 member(s) waiting.".to_string()), result.err().unwrap());
+        let waiting: i64 = query_scalar("select count(*) from waitlist where session_id = $1").bind(session_id).fetch_one(&pool).await.unwrap();
+        assert_eq!(2, waiting);
+
+        // No cap when not set
+        pool.execute(format!("update session set max_waitlist_count = null where id = {}", session_id).as_str()).await.unwrap();
+        _join_waitlist(&pool, &members[2], session_id).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn promote_from_session_waitlist(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+
+        let trainer_id = create_person(&pool, "trainer@example.org", "member,trainer").await;
+        let other_trainer_id = create_person(&pool, "other.trainer@example.org", "member,trainer").await;
+        let member_id = create_person(&pool, "member@example.org", "member").await;
+        let guest_id = create_person(&pool, "guest@example.org", "").await;
+        let booked_id = create_person(&pool, "booked@example.org", "member").await;
+        let session_id = create_session(&pool, TimeDelta::days(1)).await;
+        pool.execute(format!("update session set trainer = {trainer_id}, max_booking_count = 1, cost = 1 where id = {session_id}").as_str()).await.unwrap();
+        pool.execute(format!("insert into booking (person_id, session_id) values ({booked_id}, {session_id})").as_str()).await.unwrap();
+        pool.execute(format!("insert into waitlist (person_id, session_id, joined) values \
+            ({guest_id}, {session_id}, now() - interval '1 hour'), \
+            ({member_id}, {session_id}, now())").as_str()).await.unwrap();
+        let timezone: Tz = "Europe/London".parse().unwrap();
+
+        // Only admins and the session's own trainer can see the waitlist
+        let member = Claims::create(member_id, "member@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        assert_eq!(Status::Forbidden, _list_session_waitlist(&pool, &member, session_id).await.err().unwrap().0);
+        let other_trainer = Claims::create(other_trainer_id, "other.trainer@example.org", &None, &vec!["trainer".to_string()], Duration::minutes(1));
+        assert_eq!(Status::Forbidden, _list_session_waitlist(&pool, &other_trainer, session_id).await.err().unwrap().0);
+        let trainer = Claims::create(trainer_id, "trainer@example.org", &None, &vec!["trainer".to_string()], Duration::minutes(1));
+        let entries = _list_session_waitlist(&pool, &trainer, session_id).await.unwrap();
+        assert_eq!(vec![(guest_id, 1), (member_id, 2)], entries.iter().map(|e| (e.person_id, e.position)).collect::<Vec<_>>());
+        assert_eq!("guest@example.org", entries[0].email);
+
+        // Promoted members are booked under the usual rules, so the session needs a space...
+        let result = _promote_from_waitlist(&pool, &timezone, &Config::default(), &trainer, session_id, member_id).await;
+        assert_eq!(Status::Conflict, result.err().unwrap().0);
+        pool.execute(format!("update session set max_booking_count = 3 where id = {session_id}").as_str()).await.unwrap();
+
+        // ...and they need a membership or credits to pay for it
+        let result = _promote_from_waitlist(&pool, &timezone, &Config::default(), &trainer, session_id, guest_id).await;
+        assert_eq!(Custom(Status::Forbidden, "Missing or expired membership, and no PAYG credits.".to_string()), result.err().unwrap());
+        _promote_from_waitlist(&pool, &timezone, &Config::default(), &trainer, session_id, member_id).await.unwrap();
+        let booked: bool = query_scalar("select exists (select 1 from booking where person_id = $1 and session_id = $2)")
+            .bind(member_id).bind(session_id).fetch_one(&pool).await.unwrap();
+        assert!(booked);
+        let entries = _list_session_waitlist(&pool, &trainer, session_id).await.unwrap();
+        assert_eq!(vec![(guest_id, 1)], entries.iter().map(|e| (e.person_id, e.position)).collect::<Vec<_>>());
+        let audited: i64 = query_scalar("select count(*) from audit_log where actor_id = $1 and action = 'promote_waitlist'")
+            .bind(trainer_id).fetch_one(&pool).await.unwrap();
+        assert_eq!(1, audited);
+
+        // Only members on the waitlist can be promoted
+        let result = _promote_from_waitlist(&pool, &timezone, &Config::default(), &trainer, session_id, member_id).await;
+        assert_eq!(Status::NotFound, result.err().unwrap().0);
+    }
+
+    #[sqlx::test]
+    async fn list_waitlist_when_pool_exhausted(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        let member_id = create_person(&pool, "member@example.org", "member").await;
+
+        // Reported as unavailable rather than as a server error while all connections are in use
+        let small_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .acquire_timeout(std::time::Duration::from_millis(100))
+            .connect_with((*pool.connect_options()).clone())
+            .await.unwrap();
+        let connection = small_pool.acquire().await.unwrap();
+        let member = Claims::create(member_id, "member@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+        assert_eq!(Status::ServiceUnavailable, _list_my_waitlist(&small_pool, &member, None).await.err().unwrap().0);
+
+        drop(connection);
+        assert!(_list_my_waitlist(&small_pool, &member, None).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool, query_as, query_scalar};
+
+use crate::{AppState, db_error, JsonBody};
+use crate::claims::Claims;
+
+#[derive(Deserialize, Debug)]
+pub struct WaiverAcceptance {
+    session_type_id: i32
+}
+
+/// A waiver that a person has accepted, as it was when they accepted it
+#[derive(FromRow, Serialize, Debug)]
+pub struct AcceptedWaiver {
+    session_type_id: i32,
+    waiver_url: String,
+    accepted: DateTime<Utc>
+}
+
+/// Accepts the current waiver for a session type on the caller's behalf, so that they can book sessions of that type
+#[post("/waivers/accept", data="<acceptance>")]
+pub async fn accept_waiver(state: &State<AppState>, claim: Claims, acceptance: JsonBody<WaiverAcceptance>) -> Result<Json<AcceptedWaiver>, Custom<String>> {
+    _accept_waiver(&state.pool, &claim, &acceptance).await.map(Json)
+}
+
+async fn _accept_waiver(pool: &PgPool, claim: &Claims, acceptance: &WaiverAcceptance) -> Result<AcceptedWaiver, Custom<String>> {
+    let waiver_url: Option<String> = query_scalar("SELECT waiver_url FROM session_type WHERE id = $1")
+        .bind(acceptance.session_type_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or(Custom(Status::NotFound, format!("no session type with id {}", acceptance.session_type_id)))?;
+    let waiver_url = waiver_url
+        .ok_or(Custom(Status::BadRequest, format!("Session type {} has no waiver to accept.", acceptance.session_type_id)))?;
+
+    // Accepting the same waiver again keeps the time it was first accepted
+    let accepted: AcceptedWaiver = query_as("INSERT INTO person_waiver (person_id, session_type_id, waiver_url) VALUES ($1, $2, $3) \
+            ON CONFLICT (person_id, session_type_id, waiver_url) DO UPDATE SET waiver_url = EXCLUDED.waiver_url \
+            RETURNING session_type_id, waiver_url, accepted")
+        .bind(claim.uid)
+        .bind(acceptance.session_type_id)
+        .bind(&waiver_url)
+        .fetch_one(pool)
+        .await
+        .map_err(db_error)?;
+    info!("person id {} accepted the waiver {} for session type {}", claim.uid, waiver_url, acceptance.session_type_id);
+    Ok(accepted)
+}
+
+/// The waivers that a person has accepted, oldest first
+pub(crate) async fn waivers_of(pool: &PgPool, person_id: i64) -> Result<Vec<AcceptedWaiver>, sqlx::Error> {
+    query_as("SELECT session_type_id, waiver_url, accepted FROM person_waiver WHERE person_id = $1 ORDER BY accepted, session_type_id")
+        .bind(person_id)
+        .fetch_all(pool)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use rocket::http::Status;
+    use sqlx::{Executor, PgPool, query_scalar};
+    use crate::claims::Claims;
+    use crate::test_support::create_person;
+    use crate::waivers::{_accept_waiver, waivers_of, WaiverAcceptance};
+
+    #[sqlx::test]
+    async fn accept_waiver(pool: PgPool) {
+        pool.execute(include_str!("../schema.sql")).await.unwrap();
+        pool.execute("update session_type set waiver_url = 'https://example.org/waivers/hiit-v1.pdf', requires_waiver = true where name = 'HIIT'").await.unwrap();
+
+        let member_id = create_person(&pool, "member@example.org", "member").await;
+        let session_type_id: i32 = query_scalar("select id from session_type where name = 'HIIT'")
+            .fetch_one(&pool).await.unwrap();
+        let member = Claims::create(member_id, "member@example.org", &None, &vec!["member".to_string()], Duration::minutes(1));
+
+        // Accepting the same waiver again keeps the original acceptance
+        let accepted = _accept_waiver(&pool, &member, &WaiverAcceptance { session_type_id }).await.unwrap();
+        assert_eq!("https://example.org/waivers/hiit-v1.pdf", accepted.waiver_url);
+        let accepted_again = _accept_waiver(&pool, &member, &WaiverAcceptance { session_type_id }).await.unwrap();
+        assert_eq!(accepted.accepted, accepted_again.accepted);
+
+        // A replaced waiver is accepted separately
+        pool.execute("update session_type set waiver_url = 'https://example.org/waivers/hiit-v2.pdf' where name = 'HIIT'").await.unwrap();
+        _accept_waiver(&pool, &member, &WaiverAcceptance { session_type_id }).await.unwrap();
+        assert_eq!(2, waivers_of(&pool, member_id).await.unwrap().len());
+
+        // Session types without a waiver have nothing to accept
+        let result = _accept_waiver(&pool, &member, &WaiverAcceptance { session_type_id: session_type_id + 1 }).await;
+        assert_eq!(Status::BadRequest, result.unwrap_err().0);
+        let result = _accept_waiver(&pool, &member, &WaiverAcceptance { session_type_id: session_type_id + 100 }).await;
+        assert_eq!(Status::NotFound, result.unwrap_err().0);
+    }
+}